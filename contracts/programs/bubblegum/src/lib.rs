@@ -6,8 +6,8 @@ use {
         metaplex_adapter::{Creator, MetadataArgs, TokenProgramVersion},
         metaplex_anchor::{MasterEdition, TokenMetadata},
         request::{MintRequest, MINT_REQUEST_SIZE},
-        NFTDecompressionEvent, NewNFTEvent, TreeConfig, Voucher, ASSET_PREFIX, TREE_AUTHORITY_SIZE,
-        VOUCHER_PREFIX, VOUCHER_SIZE,
+        NFTDecompressionEvent, NewNFTEvent, ProofStalenessPolicy, TreeConfig, Voucher,
+        ASSET_PREFIX, TREE_AUTHORITY_SIZE, VOUCHER_PREFIX, VOUCHER_SIZE,
     },
     crate::utils::{
         append_leaf, assert_metadata_is_mpl_compatible, assert_pubkey_equal, cmp_bytes,
@@ -23,7 +23,12 @@ use {
             system_instruction,
         },
     },
-    gummyroll::{program::Gummyroll, state::CandyWrapper, utils::wrap_event, Node},
+    gummyroll::{
+        program::Gummyroll,
+        state::{CandyWrapper, MerkleRollHeader},
+        utils::wrap_event,
+        Node,
+    },
     spl_token::state::Mint as SplMint,
 };
 
@@ -402,6 +407,44 @@ pub struct SetTreeDelegate<'info> {
     pub tree_authority: Account<'info, TreeConfig>,
 }
 
+#[derive(Accounts)]
+pub struct SetTreeStalenessPolicy<'info> {
+    pub creator: Signer<'info>,
+    #[account(
+        seeds = [merkle_slab.key().as_ref()],
+        bump,
+        has_one = creator
+    )]
+    pub authority: Account<'info, TreeConfig>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+    pub gummyroll_program: Program<'info, Gummyroll>,
+    #[account(mut)]
+    /// CHECK: This account is modified in the downstream program
+    pub merkle_slab: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateTree<'info> {
+    pub creator: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [merkle_slab.key().as_ref()],
+        bump,
+        has_one = creator
+    )]
+    pub authority: Account<'info, TreeConfig>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub candy_wrapper: Program<'info, CandyWrapper>,
+    pub system_program: Program<'info, System>,
+    pub gummyroll_program: Program<'info, Gummyroll>,
+    #[account(mut)]
+    /// CHECK: This account is modified in the downstream program
+    pub merkle_slab: UncheckedAccount<'info>,
+}
+
 pub fn hash_metadata(metadata: &MetadataArgs) -> Result<[u8; 32]> {
     let metadata_args_hash = keccak::hashv(&[metadata.try_to_vec()?.as_slice()]);
     Ok(keccak::hashv(&[
@@ -594,6 +637,66 @@ pub mod bubblegum {
         Ok(())
     }
 
+    /// Lets the tree creator choose how stale the proofs used to transfer, delegate
+    /// or burn NFTs of the tree can be. See `gummyroll::set_staleness_policy`.
+    ///
+    /// Trees created without room for a config are resized by 16 bytes, whose rent
+    /// `payer` funds.
+    pub fn set_tree_staleness_policy(
+        ctx: Context<SetTreeStalenessPolicy>,
+        policy: ProofStalenessPolicy,
+    ) -> Result<()> {
+        let merkle_slab = ctx.accounts.merkle_slab.to_account_info();
+        let seed = merkle_slab.key();
+        let seeds = &[seed.as_ref(), &[*ctx.bumps.get("authority").unwrap()]];
+        let authority_pda_signer = &[&seeds[..]];
+        let cpi_ctx = CpiContext::new_with_signer(
+            ctx.accounts.gummyroll_program.to_account_info(),
+            gummyroll::cpi::accounts::SetStalenessPolicy {
+                authority: ctx.accounts.authority.to_account_info(),
+                merkle_roll: merkle_slab,
+                payer: ctx.accounts.payer.to_account_info(),
+                system_program: ctx.accounts.system_program.to_account_info(),
+            },
+            authority_pda_signer,
+        );
+        gummyroll::cpi::set_staleness_policy(cpi_ctx, policy.adapt())
+    }
+
+    /// Lets the tree creator grow the depth or buffer size of the tree, which keeps its
+    /// public key so that asset ids are unchanged. Once the tree is deeper, its extra leaves
+    /// can be minted. See `gummyroll::migrate_tree`.
+    pub fn migrate_tree(
+        ctx: Context<MigrateTree>,
+        max_depth: u32,
+        max_buffer_size: u32,
+    ) -> Result<()> {
+        let merkle_slab = ctx.accounts.merkle_slab.to_account_info();
+        let seed = merkle_slab.key();
+        let seeds = &[seed.as_ref(), &[*ctx.bumps.get("authority").unwrap()]];
+        let authority_pda_signer = &[&seeds[..]];
+        let cpi_ctx = CpiContext::new_with_signer(
+            ctx.accounts.gummyroll_program.to_account_info(),
+            gummyroll::cpi::accounts::MigrateTree {
+                merkle_roll: merkle_slab,
+                authority: ctx.accounts.authority.to_account_info(),
+                payer: ctx.accounts.payer.to_account_info(),
+                system_program: ctx.accounts.system_program.to_account_info(),
+                candy_wrapper: ctx.accounts.candy_wrapper.to_account_info(),
+            },
+            authority_pda_signer,
+        );
+        gummyroll::cpi::migrate_tree(cpi_ctx, max_depth, max_buffer_size)?;
+
+        // Large migrations take several instructions, so the depth is read back from the tree
+        let merkle_bytes = ctx.accounts.merkle_slab.try_borrow_data()?;
+        let (header_bytes, _) = merkle_bytes.split_at(std::mem::size_of::<MerkleRollHeader>());
+        let header = Box::new(MerkleRollHeader::try_from_slice(header_bytes)?);
+        let authority = &mut ctx.accounts.authority;
+        authority.total_mint_capacity = authority.total_mint_capacity.max(1 << header.max_depth);
+        Ok(())
+    }
+
     pub fn mint_v1(ctx: Context<MintV1>, message: MetadataArgs) -> Result<()> {
         // TODO -> Pass collection in check collection authority or collection delegate authority signer
         // TODO -> Separate V1 / V1 into seperate instructions
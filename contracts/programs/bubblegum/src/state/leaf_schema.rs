@@ -104,6 +104,30 @@ impl LeafSchema {
         }
     }
 
+    /// Returns the bytes hashed by `to_node`. They can be passed to gummyroll's
+    /// `verify_leaf_preimage`, with the keccak scheme, to check that this exact leaf is in a tree.
+    pub fn to_preimage(&self) -> Vec<u8> {
+        match self {
+            LeafSchema::V1 {
+                id,
+                owner,
+                delegate,
+                nonce,
+                data_hash,
+                creator_hash,
+            } => [
+                &[self.version().to_bytes()],
+                id.as_ref(),
+                owner.as_ref(),
+                delegate.as_ref(),
+                nonce.to_le_bytes().as_ref(),
+                data_hash.as_ref(),
+                creator_hash.as_ref(),
+            ]
+            .concat(),
+        }
+    }
+
     pub fn to_node(&self) -> Node {
         let hashed_leaf = match self {
             LeafSchema::V1 {
@@ -12,6 +12,29 @@ pub const TREE_AUTHORITY_SIZE: usize = 88 + 8;
 pub const VOUCHER_SIZE: usize = 8 + 1 + 32 + 32 + 32 + 8 + 32 + 32 + 4 + 32;
 pub const VOUCHER_PREFIX: &str = "voucher";
 pub const ASSET_PREFIX: &str = "asset";
+/// How a tree handles proofs for roots that are no longer in its changelog buffer.
+/// Mirrors `gummyroll::state::ProofStalenessPolicy` so that it is part of the Bubblegum IDL.
+#[derive(AnchorSerialize, AnchorDeserialize, PartialEq, Debug, Copy, Clone)]
+pub enum ProofStalenessPolicy {
+    FullReplay,
+    RejectUnknownRoot,
+    ReplayLast { change_logs: u16 },
+}
+
+impl ProofStalenessPolicy {
+    pub fn adapt(&self) -> gummyroll::state::ProofStalenessPolicy {
+        match *self {
+            ProofStalenessPolicy::FullReplay => gummyroll::state::ProofStalenessPolicy::FullReplay,
+            ProofStalenessPolicy::RejectUnknownRoot => {
+                gummyroll::state::ProofStalenessPolicy::RejectUnknownRoot
+            }
+            ProofStalenessPolicy::ReplayLast { change_logs } => {
+                gummyroll::state::ProofStalenessPolicy::ReplayLast { change_logs }
+            }
+        }
+    }
+}
+
 #[account]
 #[derive(Copy)]
 pub struct TreeConfig {
@@ -4,16 +4,17 @@ use concurrent_merkle_tree::error::CMTError;
 /// Errors related to misconfiguration or misuse of the Merkle tree
 #[error_code]
 pub enum GummyrollError {
-    /// This error is currently not used.
+    /// A node passed to the concurrent merkle tree was not 32 bytes long.
     #[msg("Incorrect leaf length. Expected vec of 32 bytes")]
     IncorrectLeafLength,
 
-    /// A modification to the tree was invalid and a changelog was not emitted.
-    /// The proof may be invalid or out-of-date, or the provided leaf hash was invalid.
+    /// This error is currently not used: each concurrent merkle tree error
+    /// is mapped to one of the more specific errors below.
     #[msg("Concurrent merkle tree error")]
     ConcurrentMerkleTreeError,
 
     /// An issue was detected with loading the provided account data for this Gummyroll tree.
+    /// The account data may have the wrong length for the tree's max depth and max buffer size.
     #[msg("Issue zero copying concurrent merkle tree data")]
     ZeroCopyError,
 
@@ -24,10 +25,146 @@ pub enum GummyrollError {
     /// When using Canopy, the stored byte length should a multiple of the node's byte length (32 bytes)
     #[msg("Expected a different byte length for the merkle roll canopy")]
     CanopyLengthMismatch,
+
+    /// A batch operation would write more changelogs than the buffer can hold,
+    /// or touch more canopy nodes than can be kept up to date.
+    #[msg("Batch is too large for this merkle roll's buffer or canopy")]
+    BatchTooLarge,
+
+    /// The free list is stored after the canopy, and takes 4 bytes per tracked leaf plus 4 bytes for its length
+    #[msg("Expected a different byte length for the merkle roll free list")]
+    FreeListLengthMismatch,
+
+    /// Only trees created with `init_empty_gummyroll_with_free_list` can reuse emptied leaves
+    #[msg("This merkle roll does not track emptied leaves")]
+    FreeListNotEnabled,
+
+    /// When replacing several leaves at once, the remaining accounts must hold
+    /// one proof per leaf, all of the same length
+    #[msg("Remaining accounts cannot be split into one proof per leaf")]
+    ProofLengthMismatch,
+
+    /// Received an index larger than the rightmost index, or past the end of the tree
+    #[msg("Leaf index is out of bounds")]
+    LeafIndexOutOfBounds,
+
+    /// The root recomputed from the proof is not in the changelog buffer.
+    /// The proof may be invalid, or the leaf it proves may have been modified.
+    #[msg("Invalid root recomputed from proof")]
+    InvalidProof,
+
+    /// The empty node is reserved for leaves that were never set or have been removed
+    #[msg("Cannot append an empty node")]
+    CannotAppendEmptyNode,
+
+    /// Every leaf of the tree has been appended
+    #[msg("Tree is full, cannot append")]
+    TreeFull,
+
+    /// A tree can only be initialized once
+    #[msg("Tree already initialized")]
+    TreeAlreadyInitialized,
+
+    /// The proof was issued for a root that is no longer in the changelog buffer
+    #[msg("Root not found in changelog buffer")]
+    RootNotFound,
+
+    /// The proof is valid, but the leaf has changed since it was issued
+    #[msg("Leaf has been modified since the proof was issued")]
+    LeafContentsModified,
+
+    /// After filling in nodes from the canopy, a proof must have one node per level of the tree
+    #[msg("Proof length does not match the tree depth")]
+    InvalidProofLength,
+
+    /// Subtrees can only be appended at an index that is a multiple of their leaf count
+    #[msg("Subtree is not aligned with the rightmost index")]
+    SubtreeNotAligned,
+
+    /// Every slot of the free list is already in use
+    #[msg("Free list is full")]
+    FreeListFull,
+
+    /// Only leaves tracked by the free list can be reused
+    #[msg("Leaf is not tracked by the free list")]
+    LeafNotFree,
+
+    /// A leaf count proof found a non-empty leaf at or after the given leaf count
+    #[msg("Tree holds more leaves than the given leaf count")]
+    LeafCountExceeded,
+
+    /// The same leaf cannot be replaced twice in one instruction
+    #[msg("Leaf index is replaced more than once")]
+    DuplicateLeafIndex,
+
+    /// The root cannot be recomputed from the rightmost leaf and proof
+    #[msg("Root does not match the rightmost leaf and proof")]
+    RootMismatchOnInit,
+
+    /// Merkle roll snapshots must be written by a supported version of the concurrent merkle tree
+    #[msg("Unsupported merkle roll snapshot version")]
+    UnsupportedSnapshotVersion,
+
+    /// A merkle roll snapshot can only be loaded into a tree of the same depth
+    #[msg("Merkle roll snapshot does not match the tree dimensions")]
+    InvalidSnapshot,
+
+    /// The proof staleness policy stored in the tree's config is not a known policy
+    #[msg("Invalid proof staleness policy")]
+    InvalidStalenessPolicy,
+
+    /// See [LeafHashScheme](/concurrent_merkle_tree/hasher/enum.LeafHashScheme.html) for valid scheme ids
+    #[msg("Unknown leaf hash scheme")]
+    UnknownLeafHashScheme,
+
+    /// The tail of the account must have room for the root history described by its config
+    #[msg("Expected a different byte length for the root history")]
+    RootHistoryLengthMismatch,
+
+    /// Only trees created with `init_empty_gummyroll_with_root_history` record their past roots
+    #[msg("This merkle roll does not record its past roots")]
+    RootHistoryNotEnabled,
+
+    /// Leaves proven by a multiproof must be passed sorted by increasing index, without duplicates
+    #[msg("Multiproof leaves must be sorted by increasing index")]
+    InvalidMultiproofLeaves,
+
+    /// The tail that follows the canopy is 16 bytes more than a multiple of 32, and ends with the tree's config
+    #[msg("Expected a different byte length for the merkle roll tail")]
+    TailLengthMismatch,
+
+    /// The config can only be written to an account with a tail, see `set_staleness_policy`
+    #[msg("This merkle roll has no config")]
+    TreeConfigNotFound,
 }
 
 impl From<&CMTError> for GummyrollError {
-    fn from(_error: &CMTError) -> Self {
-        GummyrollError::ConcurrentMerkleTreeError
+    fn from(error: &CMTError) -> Self {
+        match error {
+            CMTError::LeafIndexOutOfBounds => GummyrollError::LeafIndexOutOfBounds,
+            CMTError::InvalidProof => GummyrollError::InvalidProof,
+            CMTError::CannotAppendEmptyNode => GummyrollError::CannotAppendEmptyNode,
+            CMTError::TreeFull => GummyrollError::TreeFull,
+            CMTError::TreeAlreadyInitialized => GummyrollError::TreeAlreadyInitialized,
+            CMTError::InvalidNodeByteLength => GummyrollError::IncorrectLeafLength,
+            CMTError::RootNotFound => GummyrollError::RootNotFound,
+            CMTError::LeafContentsModified => GummyrollError::LeafContentsModified,
+            CMTError::InvalidDepthOrBufferSize => GummyrollError::MerkleRollConstantsError,
+            CMTError::InvalidMerkleRollBytes => GummyrollError::ZeroCopyError,
+            CMTError::ProofLengthMismatch => GummyrollError::InvalidProofLength,
+            CMTError::SubtreeNotAligned => GummyrollError::SubtreeNotAligned,
+            CMTError::InvalidFreeListBytes => GummyrollError::FreeListLengthMismatch,
+            CMTError::FreeListFull => GummyrollError::FreeListFull,
+            CMTError::LeafNotFree => GummyrollError::LeafNotFree,
+            CMTError::LeafCountExceeded => GummyrollError::LeafCountExceeded,
+            CMTError::DuplicateLeafIndex => GummyrollError::DuplicateLeafIndex,
+            CMTError::RootMismatchOnInit => GummyrollError::RootMismatchOnInit,
+            CMTError::UnsupportedSnapshotVersion => GummyrollError::UnsupportedSnapshotVersion,
+            CMTError::InvalidSnapshot => GummyrollError::InvalidSnapshot,
+            CMTError::UnknownLeafHashScheme => GummyrollError::UnknownLeafHashScheme,
+            CMTError::InvalidRootHistoryBytes => GummyrollError::RootHistoryLengthMismatch,
+            CMTError::InvalidCanopyBytes => GummyrollError::CanopyLengthMismatch,
+            CMTError::InvalidMultiproofLeaves => GummyrollError::InvalidMultiproofLeaves,
+        }
     }
 }
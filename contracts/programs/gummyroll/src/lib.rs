@@ -19,11 +19,24 @@
 use anchor_lang::{
     emit,
     prelude::*,
-    solana_program::sysvar::{clock::Clock, rent::Rent},
+    solana_program::{
+        entrypoint::MAX_PERMITTED_DATA_INCREASE,
+        sysvar::{clock::Clock, rent::Rent},
+    },
+    system_program,
 };
 use borsh::{BorshDeserialize, BorshSerialize};
-use bytemuck::cast_slice_mut;
-use concurrent_merkle_tree::{state::EMPTY, utils::empty_node_cached};
+use concurrent_merkle_tree::{
+    canopy::{migrate_canopy_in_place, Canopy, CanopyMut},
+    free_list::{free_list_size, FreeListMut},
+    hasher::Keccak,
+    merkle_roll_view::{merkle_roll_size, migrate_in_place, MerkleRollMut, MerkleRollRef},
+    multiproof::expand_multiproof,
+    root_history::{root_history_size, RootHistoryMut, RootHistoryRef},
+    sparse_merkle_roll::{decompress_proof, SparseMerkleRollMut, SPARSE_DEPTH},
+    state::EMPTY,
+};
+use std::convert::TryFrom;
 use std::mem::size_of;
 
 pub mod error;
@@ -31,9 +44,17 @@ pub mod state;
 pub mod utils;
 
 use crate::error::GummyrollError;
-use crate::state::{CandyWrapper, ChangeLogEvent, MerkleRollHeader};
-use crate::utils::{wrap_event, ZeroCopy};
-pub use concurrent_merkle_tree::{error::CMTError, merkle_roll::MerkleRoll, state::Node};
+#[cfg(feature = "compute-stats")]
+use crate::state::ComputeStatsEvent;
+use crate::state::{
+    AppendBatchEvent, CandyWrapper, ChangeLogEvent, IndexedLeaf, LeafReplacement, MerkleRollConfig,
+    MerkleRollHeader, MigrationEvent, ProofStalenessPolicy, SparseLeafEvent,
+    SparseMerkleRollHeader,
+};
+use crate::utils::wrap_event;
+pub use concurrent_merkle_tree::{
+    error::CMTError, hasher::LeafHashScheme, merkle_roll::MerkleRoll, state::Node,
+};
 
 declare_id!("GRoLLzvxpxxu2PGNJMMeZPyMxjAUH9pKqxGXV9DGiceU");
 
@@ -89,47 +110,55 @@ pub struct TransferAuthority<'info> {
     pub authority: Signer<'info>,
 }
 
-#[inline(always)]
-fn check_canopy_bytes(canopy_bytes: &mut [u8]) -> Result<()> {
-    if canopy_bytes.len() % size_of::<Node>() != 0 {
-        msg!(
-            "Canopy byte length {} is not a multiple of {}",
-            canopy_bytes.len(),
-            size_of::<Node>()
-        );
-        err!(GummyrollError::CanopyLengthMismatch)
-    } else {
-        Ok(())
-    }
+/// Context for changing how a tree handles stale proofs
+#[derive(Accounts)]
+pub struct SetStalenessPolicy<'info> {
+    #[account(mut)]
+    /// CHECK: This account is validated in the instruction, and resized if it has no tail
+    pub merkle_roll: UncheckedAccount<'info>,
+
+    /// Authority that validates the content of the trees.
+    /// Typically a program, e.g., the Bubblegum contract validates that leaves are valid NFTs.
+    pub authority: Signer<'info>,
+
+    /// Pays for the rent of the bytes added to the account
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
 }
 
-#[inline(always)]
-fn get_cached_path_length(canopy: &mut [Node], max_depth: u32) -> Result<u32> {
-    // The offset of 2 is applied because the canopy is a full binary tree without the root node
-    // Size: (2^n - 2) -> Size + 2 must be a power of 2
-    let closest_power_of_2 = (canopy.len() + 2) as u32;
-    // This expression will return true if `closest_power_of_2` is actually a power of 2
-    if closest_power_of_2 & (closest_power_of_2 - 1) == 0 {
-        // (1 << max_depth) returns the number of leaves in the full merkle tree
-        // (1 << (max_depth + 1)) - 1 returns the number of nodes in the full tree
-        // The canopy size cannot exceed the size of the tree
-        if closest_power_of_2 > (1 << (max_depth + 1)) {
-            msg!(
-                "Canopy size is too large. Size: {}. Max size: {}",
-                closest_power_of_2 - 2,
-                (1 << (max_depth + 1)) - 2
-            );
-            return err!(GummyrollError::CanopyLengthMismatch);
+/// Context for growing a tree inside of its account
+#[derive(Accounts)]
+pub struct MigrateTree<'info> {
+    #[account(mut)]
+    /// CHECK: This account is validated in the instruction, and resized to fit the migrated tree
+    pub merkle_roll: UncheckedAccount<'info>,
+
+    /// Authority that validates the content of the trees.
+    /// Typically a program, e.g., the Bubblegum contract validates that leaves are valid NFTs.
+    pub authority: Signer<'info>,
+
+    /// Pays for the rent of the bytes added to the account
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+
+    /// Program used to emit changelogs as instruction data.
+    /// See `WRAPYChf58WFCnyjXKJHtrPgzKXgHp6MD9aVDqJBbGh`
+    pub candy_wrapper: Program<'info, CandyWrapper>,
+}
+
+/// Loads the canopy that follows the merkle roll
+fn load_canopy(canopy_bytes: &[u8], max_depth: u32) -> Result<Canopy<'_>> {
+    match Canopy::new(canopy_bytes, max_depth) {
+        Ok(canopy) => Ok(canopy),
+        Err(err) => {
+            msg!("Error loading canopy: {}", err);
+            err!(GummyrollError::from(&err))
         }
-    } else {
-        msg!(
-            "Canopy length {} is not 2 less than a power of 2",
-            canopy.len()
-        );
-        return err!(GummyrollError::CanopyLengthMismatch);
     }
-    // 1 is subtracted from the trailing zeros because the root is not stored in the canopy
-    Ok(closest_power_of_2.trailing_zeros() - 1)
 }
 
 fn update_canopy(
@@ -137,113 +166,398 @@ fn update_canopy(
     max_depth: u32,
     change_log: Option<Box<ChangeLogEvent>>,
 ) -> Result<()> {
-    check_canopy_bytes(canopy_bytes)?;
-    let canopy = cast_slice_mut::<u8, Node>(canopy_bytes);
-    let path_len = get_cached_path_length(canopy, max_depth)?;
+    let mut canopy = match CanopyMut::new(canopy_bytes, max_depth) {
+        Ok(canopy) => canopy,
+        Err(err) => {
+            msg!("Error loading canopy: {}", err);
+            return err!(GummyrollError::from(&err));
+        }
+    };
     if let Some(cl) = change_log {
-        // Update the canopy from the newest change log
-        for path_node in cl.path.iter().rev().skip(1).take(path_len as usize) {
-            // node_idx - 2 maps to the canopy index
-            canopy[(path_node.index - 2) as usize] = path_node.node;
+        // Update the canopy from the newest change log, whose path ends with the root
+        let path: Vec<Node> = cl
+            .path
+            .iter()
+            .take(max_depth as usize)
+            .map(|path_node| path_node.node)
+            .collect();
+        if let Err(err) = canopy.update(&path, cl.index) {
+            msg!("Error updating canopy: {}", err);
+            return err!(GummyrollError::from(&err));
         }
     }
     Ok(())
 }
 
 fn fill_in_proof_from_canopy(
-    canopy_bytes: &mut [u8],
+    canopy_bytes: &[u8],
     max_depth: u32,
     index: u32,
     proof: &mut Vec<Node>,
 ) -> Result<()> {
-    // 26 is hard coded as it is the current max depth that Gummyroll supports
-    let mut empty_node_cache = Box::new([EMPTY; 30]);
-    check_canopy_bytes(canopy_bytes)?;
-    let canopy = cast_slice_mut::<u8, Node>(canopy_bytes);
-    let path_len = get_cached_path_length(canopy, max_depth)?;
-    // We want to compute the node index (w.r.t. the canopy) where the current path
-    // intersects the leaves of the canopy
-    let mut node_idx = ((1 << max_depth) + index) >> (max_depth - path_len);
-    let mut inferred_nodes = vec![];
-    while node_idx > 1 {
-        // node_idx - 2 maps to the canopy index
-        let shifted_index = node_idx as usize - 2;
-        let cached_idx = if shifted_index % 2 == 0 {
-            shifted_index + 1
-        } else {
-            shifted_index - 1
-        };
-        if canopy[cached_idx] == EMPTY {
-            let level = max_depth - (31 - node_idx.leading_zeros());
-            let empty_node = empty_node_cached::<30>(level, &mut empty_node_cache);
-            canopy[cached_idx] = empty_node;
-            inferred_nodes.push(empty_node);
-        } else {
-            inferred_nodes.push(canopy[cached_idx]);
-        }
-        node_idx >>= 1;
-    }
-    // We only want to add inferred canopy nodes such that the proof length
-    // is equal to the tree depth. If the lengh of proof + lengh of canopy nodes is
-    // less than the tree depth, the instruction will fail.
-    let overlap = (proof.len() + inferred_nodes.len()).saturating_sub(max_depth as usize);
-    proof.extend(inferred_nodes.iter().skip(overlap));
+    let canopy = load_canopy(canopy_bytes, max_depth)?;
+    match canopy.rebuild_proof::<Keccak>(index, proof) {
+        Ok(()) => Ok(()),
+        Err(err) => {
+            msg!("Error filling in proof from canopy: {}", err);
+            err!(GummyrollError::from(&err))
+        }
+    }
+}
+
+/// Expands the multiproof passed as remaining accounts into one full proof per leaf.
+/// The multiproof only covers the levels below the canopy, which fills in the others.
+fn proofs_from_multiproof(
+    canopy_bytes: &[u8],
+    max_depth: u32,
+    leaves: &[(u32, Node)],
+    remaining_accounts: &[AccountInfo],
+) -> Result<Vec<Vec<Node>>> {
+    let canopy = load_canopy(canopy_bytes, max_depth)?;
+    let multiproof: Vec<Node> = remaining_accounts
+        .iter()
+        .map(|node| node.key().to_bytes())
+        .collect();
+    let mut proofs = match expand_multiproof::<Keccak>(leaves, &multiproof, canopy.proof_len()) {
+        Ok(proofs) => proofs,
+        Err(err) => {
+            msg!("Error expanding multiproof: {}", err);
+            return err!(GummyrollError::from(&err));
+        }
+    };
+    for ((index, _), proof) in leaves.iter().zip(proofs.iter_mut()) {
+        fill_in_proof_from_canopy(canopy_bytes, max_depth, *index, proof)?;
+    }
+    Ok(proofs)
+}
+
+/// Transfers lamports from `payer` so that the resized merkle roll stays rent exempt
+fn fund_rent_exemption<'info>(
+    merkle_roll: &AccountInfo<'info>,
+    payer: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+) -> Result<()> {
+    let minimum_balance = Rent::get()?.minimum_balance(merkle_roll.data_len());
+    let lamports = merkle_roll.lamports();
+    if minimum_balance <= lamports {
+        return Ok(());
+    }
+    let cpi_ctx = CpiContext::new(
+        system_program.clone(),
+        system_program::Transfer {
+            from: payer.clone(),
+            to: merkle_roll.clone(),
+        },
+    );
+    system_program::transfer(cpi_ctx, minimum_balance - lamports)
+}
+
+/// Offset of the size of a tail from a multiple of the node size, see `split_tail`
+const TAIL_SIZE_OFFSET: usize = 16;
+
+/// Number of bytes used by a free list of `free_list_capacity` leaves, which is empty
+/// when the tree does not track emptied leaves
+fn free_list_bytes_len(free_list_capacity: u32) -> usize {
+    match free_list_capacity {
+        0 => 0,
+        capacity => free_list_size(capacity as usize),
+    }
+}
+
+/// Offset of the root history in the tail, after the free list, so that it is 8-byte aligned
+fn root_history_offset(free_list_capacity: u32) -> usize {
+    (free_list_bytes_len(free_list_capacity) + 7) / 8 * 8
+}
+
+/// Number of bytes used by a root history of `root_history_capacity` roots, which is empty
+/// when the tree does not record its past roots
+fn root_history_bytes_len(root_history_capacity: u32) -> usize {
+    match root_history_capacity {
+        0 => 0,
+        capacity => root_history_size(capacity as usize),
+    }
+}
+
+/// Number of bytes at the start of the tail used by the free list and the root history
+fn tail_data_len(free_list_capacity: u32, root_history_capacity: u32) -> usize {
+    match root_history_capacity {
+        0 => free_list_bytes_len(free_list_capacity),
+        capacity => root_history_offset(free_list_capacity) + root_history_bytes_len(capacity),
+    }
+}
+
+/// Number of bytes of the smallest tail that holds a free list of `free_list_capacity`
+/// leaves and a root history of `root_history_capacity` roots, followed by the config
+fn tail_size(free_list_capacity: u32, root_history_capacity: u32) -> usize {
+    let node_size = size_of::<Node>();
+    let data_len = tail_data_len(free_list_capacity, root_history_capacity);
+    (data_len + node_size - 1) / node_size * node_size + TAIL_SIZE_OFFSET
+}
+
+/// Splits the bytes that follow the merkle roll into the canopy and the tail, which holds
+/// the free list and the root history at its start, and the tree's `MerkleRollConfig` at its end.
+///
+/// The canopy is a whole number of nodes, and the size of a tail is 16 bytes more than a
+/// multiple of the node size, so accounts created without a tail are told apart by their
+/// size. Their tail is empty.
+fn split_tail(bytes: &mut [u8]) -> Result<(&mut [u8], &mut [u8])> {
+    let tail_len = match bytes.len() % size_of::<Node>() {
+        0 => 0,
+        TAIL_SIZE_OFFSET => {
+            let config = MerkleRollConfig::try_from_slice(
+                &bytes[bytes.len() - size_of::<MerkleRollConfig>()..],
+            )?;
+            config.tail_size as usize
+        }
+        _ => {
+            msg!("Canopy and tail byte length {} is invalid", bytes.len());
+            return err!(GummyrollError::TailLengthMismatch);
+        }
+    };
+    if tail_len > bytes.len()
+        || (bytes.len() - tail_len) % size_of::<Node>() != 0
+        || (tail_len != 0 && tail_len < size_of::<MerkleRollConfig>())
+    {
+        msg!("Tail byte length {} is invalid", tail_len);
+        return err!(GummyrollError::TailLengthMismatch);
+    }
+    let canopy_bytes_len = bytes.len() - tail_len;
+    Ok(bytes.split_at_mut(canopy_bytes_len))
+}
+
+/// Reads the config at the end of `tail`, or the default config if the account has no tail
+fn load_config(tail: &[u8]) -> Result<MerkleRollConfig> {
+    if tail.is_empty() {
+        return Ok(MerkleRollConfig::default());
+    }
+    Ok(MerkleRollConfig::try_from_slice(
+        &tail[tail.len() - size_of::<MerkleRollConfig>()..],
+    )?)
+}
+
+/// Writes `config` at the end of `tail`, which must not be empty
+fn save_config(tail: &mut [u8], config: &MerkleRollConfig) -> Result<()> {
+    if tail.is_empty() {
+        msg!("Account has no tail to store the config");
+        return err!(GummyrollError::TreeConfigNotFound);
+    }
+    let tail_len = tail.len();
+    config.serialize(&mut &mut tail[tail_len - size_of::<MerkleRollConfig>()..])?;
+    Ok(())
+}
+
+/// Writes the config of a new tree, whose account has a tail if the bytes that follow
+/// the merkle roll are not a multiple of the node size
+fn init_tail(bytes: &mut [u8], free_list_capacity: u32, root_history_capacity: u32) -> Result<()> {
+    if bytes.len() % size_of::<Node>() == 0 {
+        if free_list_capacity > 0 {
+            msg!("Account has no tail to store a free list");
+            return err!(GummyrollError::FreeListLengthMismatch);
+        }
+        if root_history_capacity > 0 {
+            msg!("Account has no tail to store a root history");
+            return err!(GummyrollError::RootHistoryLengthMismatch);
+        }
+        return Ok(());
+    }
+    let tail_len = tail_size(free_list_capacity, root_history_capacity);
+    if bytes.len() < tail_len {
+        msg!("Account is too small to store a tail of {} bytes", tail_len);
+        return err!(GummyrollError::TailLengthMismatch);
+    }
+    let canopy_bytes_len = bytes.len() - tail_len;
+    let config = MerkleRollConfig {
+        tail_size: tail_len as u32,
+        free_list_capacity,
+        root_history_capacity,
+        ..MerkleRollConfig::default()
+    };
+    save_config(&mut bytes[canopy_bytes_len..], &config)
+}
+
+/// Splits the bytes that follow the merkle roll into the canopy and the free list,
+/// and reads the tree's config, see `split_tail`.
+/// The free list is empty when the tree does not track emptied leaves.
+fn split_canopy_and_free_list(
+    bytes: &mut [u8],
+) -> Result<(&mut [u8], &mut [u8], MerkleRollConfig)> {
+    let (canopy_bytes, tail) = split_tail(bytes)?;
+    let config = load_config(tail)?;
+    check_tail(tail, &config)?;
+    let free_list_bytes_len = free_list_bytes_len(config.free_list_capacity);
+    Ok((canopy_bytes, &mut tail[..free_list_bytes_len], config))
+}
+
+/// Checks that `tail` has room for the free list and the root history described by `config`
+fn check_tail(tail: &[u8], config: &MerkleRollConfig) -> Result<()> {
+    let free_list_bytes_len = free_list_bytes_len(config.free_list_capacity);
+    if free_list_bytes_len > 0 && tail.len() < free_list_bytes_len + size_of::<MerkleRollConfig>() {
+        msg!(
+            "Account is too small to store a free list of {} leaves",
+            config.free_list_capacity
+        );
+        return err!(GummyrollError::FreeListLengthMismatch);
+    }
+    let tail_data_len = tail_data_len(config.free_list_capacity, config.root_history_capacity);
+    if config.root_history_capacity > 0
+        && tail.len() < tail_data_len + size_of::<MerkleRollConfig>()
+    {
+        msg!(
+            "Account is too small to store a root history of {} roots",
+            config.root_history_capacity
+        );
+        return err!(GummyrollError::RootHistoryLengthMismatch);
+    }
     Ok(())
 }
 
+/// Returns the root history in `tail`, which must have been checked with `check_tail`.
+/// The root history is empty when the tree does not record its past roots.
+fn root_history_bytes<'a>(tail: &'a mut [u8], config: &MerkleRollConfig) -> &'a mut [u8] {
+    let offset = root_history_offset(config.free_list_capacity);
+    match config.root_history_capacity {
+        0 => &mut [],
+        capacity => &mut tail[offset..offset + root_history_bytes_len(capacity)],
+    }
+}
+
+fn load_free_list(free_list_bytes: &mut [u8]) -> Result<Option<FreeListMut<'_>>> {
+    if free_list_bytes.is_empty() {
+        return Ok(None);
+    }
+    match FreeListMut::new(free_list_bytes) {
+        Ok(free_list) => Ok(Some(free_list)),
+        Err(err) => {
+            msg!("Error loading free list: {}", err);
+            err!(GummyrollError::FreeListLengthMismatch)
+        }
+    }
+}
+
 /// This macro applies functions on a merkle roll and emits leaf information
 /// needed to sync the merkle tree state with off-chain indexers.
-macro_rules! merkle_roll_depth_size_apply_fn {
-    ($max_depth:literal, $max_size:literal, $id:ident, $bytes:ident, $func:ident, $($arg:tt)*) => {
-        match MerkleRoll::<$max_depth, $max_size>::load_mut_bytes($bytes) {
-            Ok(merkle_roll) => {
+///
+/// The merkle roll is loaded as a runtime-sized view, using the
+/// dimensions stored in the header and the staleness policy stored in the config on-chain
+macro_rules! merkle_roll_apply_fn {
+    ($header:ident, $config:ident, $id:ident, $bytes:ident, $func:ident, $($arg:tt)*) => {{
+        let staleness_policy = $config.staleness_policy()?;
+        match MerkleRollMut::new(
+            $bytes,
+            $header.max_depth as usize,
+            $header.max_buffer_size as usize,
+        )
+        .map(|merkle_roll| merkle_roll.with_staleness_policy(staleness_policy)) {
+            // `prove_*` functions only need an immutable borrow of the merkle roll
+            #[allow(unused_mut)]
+            Ok(mut merkle_roll) => {
                 match merkle_roll.$func($($arg)*) {
                     Ok(_) => {
-                        Ok(Box::<ChangeLogEvent>::from((merkle_roll.get_change_log(), $id, merkle_roll.sequence_number)))
+                        #[cfg(feature = "compute-stats")]
+                        emit_compute_stats(&merkle_roll, $id);
+                        Ok(Box::<ChangeLogEvent>::from((merkle_roll.get_change_log(), $id, merkle_roll.sequence_number())))
                     }
                     Err(err) => {
                         msg!("Error using concurrent merkle tree: {}", err);
-                        err!(GummyrollError::ConcurrentMerkleTreeError)
+                        err!(GummyrollError::from(&err))
                     }
                 }
             }
             Err(err) => {
                 msg!("Error zero copying merkle roll: {}", err);
-                err!(GummyrollError::ZeroCopyError)
+                err!(GummyrollError::from(&err))
             }
         }
+    }};
+}
+
+/// Logs the work performed by the merkle roll during this instruction
+#[cfg(feature = "compute-stats")]
+fn emit_compute_stats(merkle_roll: &MerkleRollMut, id: Pubkey) {
+    let stats = merkle_roll.compute_stats();
+    emit!(ComputeStatsEvent {
+        id,
+        seq: merkle_roll.sequence_number(),
+        hashes: stats.hashes,
+        change_logs_scanned: stats.change_logs_scanned,
+        proof_nodes_patched: stats.proof_nodes_patched,
+    });
+}
+
+/// Collects a `ChangeLogEvent` for every change log written after `prev_seq`, oldest first.
+/// Fails if some of those change logs have already been overwritten in the buffer.
+fn get_change_log_events(
+    merkle_roll: &MerkleRollMut,
+    id: Pubkey,
+    prev_seq: u64,
+) -> Result<Vec<Box<ChangeLogEvent>>> {
+    let merkle_roll = merkle_roll.view();
+    let seq = merkle_roll.sequence_number();
+    if seq - prev_seq > merkle_roll.max_buffer_size() as u64 {
+        msg!(
+            "Operation wrote {} change logs, but the buffer only holds {}",
+            seq - prev_seq,
+            merkle_roll.max_buffer_size()
+        );
+        return err!(GummyrollError::BatchTooLarge);
     }
+    let mask = merkle_roll.max_buffer_size() as u64 - 1;
+    Ok((prev_seq + 1..=seq)
+        .map(|change_log_seq| {
+            let index = merkle_roll
+                .active_index()
+                .wrapping_sub(seq - change_log_seq)
+                & mask;
+            Box::<ChangeLogEvent>::from((
+                merkle_roll.change_log(index as usize),
+                id,
+                change_log_seq,
+            ))
+        })
+        .collect())
 }
 
-/// This applies a given function on a merkle roll by
-/// allowing the compiler to infer the size of the tree based
-/// upon the header information stored on-chain
+/// Same as `merkle_roll_apply_fn`, but for functions that can write
+/// several change logs. Returns the leaf information for each of them.
+macro_rules! merkle_roll_apply_batch_fn {
+    ($header:ident, $config:ident, $id:ident, $bytes:ident, $func:ident, $($arg:tt)*) => {{
+        let staleness_policy = $config.staleness_policy()?;
+        match MerkleRollMut::new(
+            $bytes,
+            $header.max_depth as usize,
+            $header.max_buffer_size as usize,
+        )
+        .map(|merkle_roll| merkle_roll.with_staleness_policy(staleness_policy)) {
+            Ok(mut merkle_roll) => {
+                let prev_seq = merkle_roll.sequence_number();
+                match merkle_roll.$func($($arg)*) {
+                    Ok(_) => {
+                        #[cfg(feature = "compute-stats")]
+                        emit_compute_stats(&merkle_roll, $id);
+                        get_change_log_events(&merkle_roll, $id, prev_seq)
+                    }
+                    Err(err) => {
+                        msg!("Error using concurrent merkle tree: {}", err);
+                        err!(GummyrollError::from(&err))
+                    }
+                }
+            }
+            Err(err) => {
+                msg!("Error zero copying merkle roll: {}", err);
+                err!(GummyrollError::from(&err))
+            }
+        }
+    }};
+}
+
+/// Returns the number of bytes used by the merkle roll described
+/// by the header information stored on-chain
 macro_rules! merkle_roll_get_size {
     ($header:ident) => {
         // Note: max_buffer_size MUST be a power of 2
-        match ($header.max_depth, $header.max_buffer_size) {
-            (3, 8) => Ok(size_of::<MerkleRoll<3, 8>>()),
-            (5, 8) => Ok(size_of::<MerkleRoll<5, 8>>()),
-            (14, 64) => Ok(size_of::<MerkleRoll<14, 64>>()),
-            (14, 256) => Ok(size_of::<MerkleRoll<14, 256>>()),
-            (14, 1024) => Ok(size_of::<MerkleRoll<14, 1024>>()),
-            (14, 2048) => Ok(size_of::<MerkleRoll<14, 2048>>()),
-            (20, 64) => Ok(size_of::<MerkleRoll<20, 64>>()),
-            (20, 256) => Ok(size_of::<MerkleRoll<20, 256>>()),
-            (20, 1024) => Ok(size_of::<MerkleRoll<20, 1024>>()),
-            (20, 2048) => Ok(size_of::<MerkleRoll<20, 2048>>()),
-            (24, 64) => Ok(size_of::<MerkleRoll<24, 64>>()),
-            (24, 256) => Ok(size_of::<MerkleRoll<24, 256>>()),
-            (24, 512) => Ok(size_of::<MerkleRoll<24, 512>>()),
-            (24, 1024) => Ok(size_of::<MerkleRoll<24, 1024>>()),
-            (24, 2048) => Ok(size_of::<MerkleRoll<24, 2048>>()),
-            (26, 512) => Ok(size_of::<MerkleRoll<26, 512>>()),
-            (26, 1024) => Ok(size_of::<MerkleRoll<26, 1024>>()),
-            (26, 2048) => Ok(size_of::<MerkleRoll<26, 2048>>()),
-            (30, 512) => Ok(size_of::<MerkleRoll<30, 512>>()),
-            (30, 1024) => Ok(size_of::<MerkleRoll<30, 1024>>()),
-            (30, 2048) => Ok(size_of::<MerkleRoll<30, 2048>>()),
-            _ => {
+        match merkle_roll_size($header.max_depth as usize, $header.max_buffer_size as usize) {
+            Ok(size) => Ok(size),
+            Err(_) => {
                 msg!(
                     "Failed to get size of max depth {} and max buffer size {}",
                     $header.max_depth,
@@ -255,40 +569,148 @@ macro_rules! merkle_roll_get_size {
     };
 }
 
-/// This applies a given function on a merkle roll by
-/// allowing the compiler to infer the size of the tree based
-/// upon the header information stored on-chain
-macro_rules! merkle_roll_apply_fn {
-    ($header:ident, $id:ident, $bytes:ident, $func:ident, $($arg:tt)*) => {
-        // Note: max_buffer_size MUST be a power of 2
-        match ($header.max_depth, $header.max_buffer_size) {
-            (3, 8) => merkle_roll_depth_size_apply_fn!(3, 8, $id, $bytes, $func, $($arg)*),
-            (5, 8) => merkle_roll_depth_size_apply_fn!(5, 8, $id, $bytes, $func, $($arg)*),
-            (14, 64) => merkle_roll_depth_size_apply_fn!(14, 64, $id, $bytes, $func, $($arg)*),
-            (14, 256) => merkle_roll_depth_size_apply_fn!(14, 256, $id, $bytes, $func, $($arg)*),
-            (14, 1024) => merkle_roll_depth_size_apply_fn!(14, 1024, $id, $bytes, $func, $($arg)*),
-            (14, 2048) => merkle_roll_depth_size_apply_fn!(14, 2048, $id, $bytes, $func, $($arg)*),
-            (20, 64) => merkle_roll_depth_size_apply_fn!(20, 64, $id, $bytes, $func, $($arg)*),
-            (20, 256) => merkle_roll_depth_size_apply_fn!(20, 256, $id, $bytes, $func, $($arg)*),
-            (20, 1024) => merkle_roll_depth_size_apply_fn!(20, 1024, $id, $bytes, $func, $($arg)*),
-            (20, 2048) => merkle_roll_depth_size_apply_fn!(20, 2048, $id, $bytes, $func, $($arg)*),
-            (24, 64) => merkle_roll_depth_size_apply_fn!(24, 64, $id, $bytes, $func, $($arg)*),
-            (24, 256) => merkle_roll_depth_size_apply_fn!(24, 256, $id, $bytes, $func, $($arg)*),
-            (24, 512) => merkle_roll_depth_size_apply_fn!(24, 512, $id, $bytes, $func, $($arg)*),
-            (24, 1024) => merkle_roll_depth_size_apply_fn!(24, 1024, $id, $bytes, $func, $($arg)*),
-            (24, 2048) => merkle_roll_depth_size_apply_fn!(24, 2048, $id, $bytes, $func, $($arg)*),
-            (26, 512) => merkle_roll_depth_size_apply_fn!(26, 512, $id, $bytes, $func, $($arg)*),
-            (26, 1024) => merkle_roll_depth_size_apply_fn!(26, 1024, $id, $bytes, $func, $($arg)*),
-            (26, 2048) => merkle_roll_depth_size_apply_fn!(26, 2048, $id, $bytes, $func, $($arg)*),
-            (30, 512) => merkle_roll_depth_size_apply_fn!(30, 512, $id, $bytes, $func, $($arg)*),
-            (30, 1024) => merkle_roll_depth_size_apply_fn!(30, 1024, $id, $bytes, $func, $($arg)*),
-            (30, 2048) => merkle_roll_depth_size_apply_fn!(30, 2048, $id, $bytes, $func, $($arg)*),
-            _ => {
-                msg!("Failed to apply {} on merkle roll with max depth {} and max buffer size {}", stringify!($func), $header.max_depth, $header.max_buffer_size);
-                err!(GummyrollError::MerkleRollConstantsError)
-            }
+/// Records the roots of the merkle roll that are missing from the root history in its
+/// tail, if the tree has one. `bytes` are the bytes that follow the header.
+///
+/// Every instruction that modifies a tree calls this last, so that the root history
+/// has no gaps.
+fn record_roots(header: &MerkleRollHeader, bytes: &mut [u8]) -> Result<()> {
+    let merkle_roll_size = merkle_roll_get_size!(header)?;
+    let (roll_bytes, rest) = bytes.split_at_mut(merkle_roll_size);
+    let (_, tail) = split_tail(rest)?;
+    let config = load_config(tail)?;
+    if config.root_history_capacity == 0 {
+        return Ok(());
+    }
+    check_tail(tail, &config)?;
+    let merkle_roll = load_merkle_roll(header, roll_bytes)?;
+    let mut root_history = match RootHistoryMut::new(root_history_bytes(tail, &config)) {
+        Ok(root_history) => root_history,
+        Err(err) => {
+            msg!("Error zero copying root history: {}", err);
+            return err!(GummyrollError::from(&err));
         }
     };
+    root_history.record(&merkle_roll, Clock::get()?.slot);
+    Ok(())
+}
+
+/// Loads the merkle roll that follows the header, without its canopy and free list
+fn load_merkle_roll<'a>(
+    header: &MerkleRollHeader,
+    merkle_roll_bytes: &'a [u8],
+) -> Result<MerkleRollRef<'a>> {
+    let merkle_roll_size = merkle_roll_get_size!(header)?;
+    match MerkleRollRef::new(
+        &merkle_roll_bytes[..merkle_roll_size],
+        header.max_depth as usize,
+        header.max_buffer_size as usize,
+    ) {
+        Ok(merkle_roll) => Ok(merkle_roll),
+        Err(err) => {
+            msg!("Error zero copying merkle roll: {}", err);
+            err!(GummyrollError::from(&err))
+        }
+    }
+}
+
+/// Loads the sparse merkle roll that follows the header
+fn load_sparse_merkle_roll<'a>(
+    header: &SparseMerkleRollHeader,
+    sparse_merkle_roll_bytes: &'a mut [u8],
+) -> Result<SparseMerkleRollMut<'a>> {
+    if header.depth != SPARSE_DEPTH as u32 {
+        msg!("Account is not a sparse merkle roll");
+        return err!(GummyrollError::MerkleRollConstantsError);
+    }
+    match SparseMerkleRollMut::new(sparse_merkle_roll_bytes, header.max_buffer_size as usize) {
+        Ok(sparse_merkle_roll) => Ok(sparse_merkle_roll),
+        Err(err) => {
+            msg!("Error zero copying sparse merkle roll: {}", err);
+            err!(GummyrollError::from(&err))
+        }
+    }
+}
+
+/// Expands the proof of a sparse merkle roll leaf. Bit `i` of `proof_bitmap`, counting from
+/// the least significant bit of its last byte, is set if the sibling at height `i` is not empty.
+/// Non-empty siblings are passed as remaining accounts, from the leaf up.
+fn sparse_proof_from_accounts(
+    proof_bitmap: &[u8; 32],
+    remaining_accounts: &[AccountInfo],
+) -> Result<Vec<Node>> {
+    let nodes: Vec<Node> = remaining_accounts
+        .iter()
+        .map(|node| node.key().to_bytes())
+        .collect();
+    // Kept on the heap, since a full proof takes 8KB
+    let mut proof = vec![EMPTY; SPARSE_DEPTH];
+    match decompress_proof(proof_bitmap, &nodes, &mut proof) {
+        Ok(()) => Ok(proof),
+        Err(err) => {
+            msg!(
+                "Proof bitmap does not match the {} proof nodes received",
+                nodes.len()
+            );
+            err!(GummyrollError::from(&err))
+        }
+    }
+}
+
+/// Replaces leaves given a full proof for `root` of each of them, see `replace_leaves`
+fn replace_leaves_with_proofs(
+    header: &MerkleRollHeader,
+    config: &MerkleRollConfig,
+    id: Pubkey,
+    roll_bytes: &mut [u8],
+    canopy_bytes: &mut [u8],
+    free_list_bytes: &mut [u8],
+    candy_wrapper: &Program<CandyWrapper>,
+    root: Node,
+    replacements: &[LeafReplacement],
+    proofs: &mut [Vec<Node>],
+) -> Result<()> {
+    // The free list is updated first, so that a full free list fails before any hashing.
+    // Every change is reverted if the instruction fails.
+    if let Some(mut free_list) = load_free_list(free_list_bytes)? {
+        for replacement in replacements.iter() {
+            if replacement.new_leaf != EMPTY {
+                free_list.remove(replacement.index);
+            } else if let Err(err) = free_list.push(replacement.index) {
+                msg!("Cannot track emptied leaf {}: {}", replacement.index, err);
+                return err!(GummyrollError::from(&err));
+            }
+        }
+    }
+    let mut updates: Vec<(u32, Node, Node, &mut [Node])> = replacements
+        .iter()
+        .zip(proofs.iter_mut())
+        .map(|(replacement, proof)| {
+            (
+                replacement.index,
+                replacement.previous_leaf,
+                replacement.new_leaf,
+                proof.as_mut_slice(),
+            )
+        })
+        .collect();
+
+    // A call is made to MerkleRoll::set_leaves(root, updates)
+    let change_logs = merkle_roll_apply_batch_fn!(
+        header,
+        config,
+        id,
+        roll_bytes,
+        set_leaves,
+        root,
+        &mut updates
+    )?;
+    for change_log in change_logs {
+        wrap_event(change_log.try_to_vec()?, candy_wrapper)?;
+        emit!(*change_log);
+        update_canopy(canopy_bytes, header.max_depth, Some(change_log))?;
+    }
+    Ok(())
 }
 
 #[program]
@@ -309,6 +731,43 @@ pub mod gummyroll {
         ctx: Context<Initialize>,
         max_depth: u32,
         max_buffer_size: u32,
+    ) -> Result<()> {
+        init_empty_gummyroll_with_free_list(ctx, max_depth, max_buffer_size, 0)
+    }
+
+    /// Same as `init_empty_gummyroll`, but also tracks up to `free_list_capacity` leaves
+    /// that are emptied through `replace_leaf`, so that they can be reused with `insert_into_free_slot`.
+    ///
+    /// The free list is stored in the tail of the account, after the canopy, and takes
+    /// `4 * (free_list_capacity + 1)` bytes, see `split_tail`.
+    pub fn init_empty_gummyroll_with_free_list(
+        ctx: Context<Initialize>,
+        max_depth: u32,
+        max_buffer_size: u32,
+        free_list_capacity: u32,
+    ) -> Result<()> {
+        init_empty_gummyroll_with_root_history(
+            ctx,
+            max_depth,
+            max_buffer_size,
+            free_list_capacity,
+            0,
+        )
+    }
+
+    /// Same as `init_empty_gummyroll_with_free_list`, but also keeps the last
+    /// `root_history_capacity` roots of the tree, so that proofs for roots that are no longer
+    /// in the changelog buffer can be checked with `verify_leaf_at_root`.
+    ///
+    /// Every instruction that modifies the tree records its new roots. The root history
+    /// follows the free list in the tail of the account, 8-byte aligned, and takes
+    /// `16 + 48 * root_history_capacity` bytes, see `split_tail`.
+    pub fn init_empty_gummyroll_with_root_history(
+        ctx: Context<Initialize>,
+        max_depth: u32,
+        max_buffer_size: u32,
+        free_list_capacity: u32,
+        root_history_capacity: u32,
     ) -> Result<()> {
         let mut merkle_roll_bytes = ctx.accounts.merkle_roll.try_borrow_mut_data()?;
 
@@ -324,12 +783,19 @@ pub mod gummyroll {
         );
         header.serialize(&mut header_bytes)?;
         let merkle_roll_size = merkle_roll_get_size!(header)?;
-        let (roll_bytes, canopy_bytes) = rest.split_at_mut(merkle_roll_size);
+        let (roll_bytes, rest) = rest.split_at_mut(merkle_roll_size);
+        init_tail(rest, free_list_capacity, root_history_capacity)?;
+        let (canopy_bytes, free_list_bytes, config) = split_canopy_and_free_list(rest)?;
+        load_free_list(free_list_bytes)?;
         let id = ctx.accounts.merkle_roll.key();
-        let change_log = merkle_roll_apply_fn!(header, id, roll_bytes, initialize,)?;
+        let change_log = merkle_roll_apply_fn!(header, config, id, roll_bytes, initialize,)?;
         wrap_event(change_log.try_to_vec()?, &ctx.accounts.candy_wrapper)?;
         emit!(*change_log);
-        update_canopy(canopy_bytes, header.max_depth, None)
+        update_canopy(canopy_bytes, header.max_depth, None)?;
+        record_roots(
+            &header,
+            &mut merkle_roll_bytes[size_of::<MerkleRollHeader>()..],
+        )
     }
 
     /// Note:
@@ -362,7 +828,8 @@ pub mod gummyroll {
         );
         header.serialize(&mut header_bytes)?;
         let merkle_roll_size = merkle_roll_get_size!(header)?;
-        let (roll_bytes, canopy_bytes) = rest.split_at_mut(merkle_roll_size);
+        let (roll_bytes, rest) = rest.split_at_mut(merkle_roll_size);
+        let (canopy_bytes, _, config) = split_canopy_and_free_list(rest)?;
 
         // Get rightmost proof from accounts
         let mut proof = vec![];
@@ -370,12 +837,12 @@ pub mod gummyroll {
             proof.push(node.key().to_bytes());
         }
         fill_in_proof_from_canopy(canopy_bytes, header.max_depth, index, &mut proof)?;
-        assert_eq!(proof.len(), max_depth as usize);
 
         let id = ctx.accounts.merkle_roll.key();
         // A call is made to MerkleRoll::initialize_with_root(root, leaf, proof, index)
         let change_log = merkle_roll_apply_fn!(
             header,
+            config,
             id,
             roll_bytes,
             initialize_with_root,
@@ -386,12 +853,20 @@ pub mod gummyroll {
         )?;
         wrap_event(change_log.try_to_vec()?, &ctx.accounts.candy_wrapper)?;
         emit!(*change_log);
-        update_canopy(canopy_bytes, header.max_depth, Some(change_log))
+        update_canopy(canopy_bytes, header.max_depth, Some(change_log))?;
+        record_roots(
+            &header,
+            &mut merkle_roll_bytes[size_of::<MerkleRollHeader>()..],
+        )
     }
 
     /// Executes an instruction that overwrites a leaf node.
     /// Composing programs should check that the data hashed into previous_leaf
     /// matches the authority information necessary to execute this instruction.
+    ///
+    /// If the tree has a free list, leaves that are replaced with an empty node are tracked
+    /// so that they can be reused with `insert_into_free_slot`, and the replacement fails
+    /// with `FreeListFull` if the free list has no room left.
     pub fn replace_leaf(
         ctx: Context<Modify>,
         root: [u8; 32],
@@ -405,7 +880,9 @@ pub mod gummyroll {
         let header = Box::new(MerkleRollHeader::try_from_slice(header_bytes)?);
         assert_eq!(header.authority, ctx.accounts.authority.key());
         let merkle_roll_size = merkle_roll_get_size!(header)?;
-        let (roll_bytes, canopy_bytes) = rest.split_at_mut(merkle_roll_size);
+        let (roll_bytes, rest) = rest.split_at_mut(merkle_roll_size);
+        let (canopy_bytes, free_list_bytes, config) = split_canopy_and_free_list(rest)?;
+        let mut free_list = load_free_list(free_list_bytes)?;
 
         let mut proof = vec![];
         for node in ctx.remaining_accounts.iter() {
@@ -413,21 +890,164 @@ pub mod gummyroll {
         }
         fill_in_proof_from_canopy(canopy_bytes, header.max_depth, index, &mut proof)?;
         let id = ctx.accounts.merkle_roll.key();
-        // A call is made to MerkleRoll::set_leaf(root, previous_leaf, new_leaf, proof, index)
-        let change_log = merkle_roll_apply_fn!(
-            header,
-            id,
+        let change_log = match free_list.as_mut() {
+            Some(free_list) if new_leaf == EMPTY => {
+                // A call is made to MerkleRoll::remove_leaf(root, previous_leaf, proof, index, free_list)
+                merkle_roll_apply_fn!(
+                    header,
+                    config,
+                    id,
+                    roll_bytes,
+                    remove_leaf,
+                    root,
+                    previous_leaf,
+                    &proof,
+                    index,
+                    free_list,
+                )?
+            }
+            // A call is made to MerkleRoll::set_leaf(root, previous_leaf, new_leaf, proof, index)
+            _ => merkle_roll_apply_fn!(
+                header,
+                config,
+                id,
+                roll_bytes,
+                set_leaf,
+                root,
+                previous_leaf,
+                new_leaf,
+                &proof,
+                index,
+            )?,
+        };
+        // A leaf that is filled in without going through the free list can no longer be reused
+        if let Some(free_list) = free_list.as_mut() {
+            if new_leaf != EMPTY {
+                free_list.remove(index);
+            }
+        }
+        wrap_event(change_log.try_to_vec()?, &ctx.accounts.candy_wrapper)?;
+        emit!(*change_log);
+        update_canopy(canopy_bytes, header.max_depth, Some(change_log))?;
+        record_roots(
+            &header,
+            &mut merkle_roll_bytes[size_of::<MerkleRollHeader>()..],
+        )
+    }
+
+    /// Atomically replaces several leaves, using proofs that are all for `root`.
+    /// Either every leaf is replaced or none are, which allows, for example,
+    /// two leaves to be swapped between their owners. A changelog is emitted for each leaf.
+    ///
+    /// The proofs are passed as remaining accounts one after the other, and must all have
+    /// the same length. They are completed with nodes from the canopy.
+    pub fn replace_leaves(
+        ctx: Context<Modify>,
+        root: [u8; 32],
+        replacements: Vec<LeafReplacement>,
+    ) -> Result<()> {
+        let mut merkle_roll_bytes = ctx.accounts.merkle_roll.try_borrow_mut_data()?;
+        let (header_bytes, rest) = merkle_roll_bytes.split_at_mut(size_of::<MerkleRollHeader>());
+
+        let header = Box::new(MerkleRollHeader::try_from_slice(header_bytes)?);
+        assert_eq!(header.authority, ctx.accounts.authority.key());
+        let merkle_roll_size = merkle_roll_get_size!(header)?;
+        let (roll_bytes, rest) = rest.split_at_mut(merkle_roll_size);
+        let (canopy_bytes, free_list_bytes, config) = split_canopy_and_free_list(rest)?;
+
+        if replacements.is_empty() {
+            return Ok(());
+        }
+        if ctx.remaining_accounts.len() % replacements.len() != 0 {
+            msg!(
+                "Cannot split {} proof nodes between {} leaves",
+                ctx.remaining_accounts.len(),
+                replacements.len()
+            );
+            return err!(GummyrollError::ProofLengthMismatch);
+        }
+        let proof_len = ctx.remaining_accounts.len() / replacements.len();
+        let mut proofs = Vec::with_capacity(replacements.len());
+        for (i, replacement) in replacements.iter().enumerate() {
+            let mut proof = vec![];
+            for node in ctx.remaining_accounts[i * proof_len..(i + 1) * proof_len].iter() {
+                proof.push(node.key().to_bytes());
+            }
+            fill_in_proof_from_canopy(
+                canopy_bytes,
+                header.max_depth,
+                replacement.index,
+                &mut proof,
+            )?;
+            proofs.push(proof);
+        }
+        replace_leaves_with_proofs(
+            &header,
+            &config,
+            ctx.accounts.merkle_roll.key(),
             roll_bytes,
-            set_leaf,
+            canopy_bytes,
+            free_list_bytes,
+            &ctx.accounts.candy_wrapper,
             root,
-            previous_leaf,
-            new_leaf,
-            &proof,
-            index,
+            &replacements,
+            &mut proofs,
         )?;
-        wrap_event(change_log.try_to_vec()?, &ctx.accounts.candy_wrapper)?;
-        emit!(*change_log);
-        update_canopy(canopy_bytes, header.max_depth, Some(change_log))
+        record_roots(
+            &header,
+            &mut merkle_roll_bytes[size_of::<MerkleRollHeader>()..],
+        )
+    }
+
+    /// Same as `replace_leaves`, but the proofs are passed as a single multiproof of the
+    /// previous leaves, which shares the nodes that their proofs have in common.
+    /// `replacements` must be sorted by increasing index.
+    ///
+    /// The multiproof only covers the levels below the canopy, see
+    /// `concurrent_merkle_tree::multiproof` for how its nodes are ordered.
+    pub fn replace_leaves_with_multiproof(
+        ctx: Context<Modify>,
+        root: [u8; 32],
+        replacements: Vec<LeafReplacement>,
+    ) -> Result<()> {
+        let mut merkle_roll_bytes = ctx.accounts.merkle_roll.try_borrow_mut_data()?;
+        let (header_bytes, rest) = merkle_roll_bytes.split_at_mut(size_of::<MerkleRollHeader>());
+
+        let header = Box::new(MerkleRollHeader::try_from_slice(header_bytes)?);
+        assert_eq!(header.authority, ctx.accounts.authority.key());
+        let merkle_roll_size = merkle_roll_get_size!(header)?;
+        let (roll_bytes, rest) = rest.split_at_mut(merkle_roll_size);
+        let (canopy_bytes, free_list_bytes, config) = split_canopy_and_free_list(rest)?;
+
+        if replacements.is_empty() {
+            return Ok(());
+        }
+        let previous_leaves: Vec<(u32, Node)> = replacements
+            .iter()
+            .map(|replacement| (replacement.index, replacement.previous_leaf))
+            .collect();
+        let mut proofs = proofs_from_multiproof(
+            canopy_bytes,
+            header.max_depth,
+            &previous_leaves,
+            ctx.remaining_accounts,
+        )?;
+        replace_leaves_with_proofs(
+            &header,
+            &config,
+            ctx.accounts.merkle_roll.key(),
+            roll_bytes,
+            canopy_bytes,
+            free_list_bytes,
+            &ctx.accounts.candy_wrapper,
+            root,
+            &replacements,
+            &mut proofs,
+        )?;
+        record_roots(
+            &header,
+            &mut merkle_roll_bytes[size_of::<MerkleRollHeader>()..],
+        )
     }
 
     /// Transfers `authority`
@@ -449,30 +1069,550 @@ pub mod gummyroll {
         Ok(())
     }
 
-    /// Verifies a provided proof and leaf.
-    /// If invalid, throws an error.
-    pub fn verify_leaf(
-        ctx: Context<VerifyLeaf>,
-        root: [u8; 32],
-        leaf: [u8; 32],
-        index: u32,
+    /// Grows the `max_depth` and/or `max_buffer_size` of a tree inside of its own account,
+    /// which is resized, so that the tree keeps its public key. `payer` funds the rent of the
+    /// added bytes. Requires `authority` to sign, and keeps the tree's authority, config,
+    /// free list and root history.
+    ///
+    /// When `max_depth` grows, the old tree becomes the leftmost subtree of the new one,
+    /// so that leaves keep their index and proofs only need to be padded with empty nodes.
+    /// When `max_buffer_size` grows, all changelogs are kept, so proofs for recent roots
+    /// can still be fast-forwarded. A `MigrationEvent` is emitted so that indexers can
+    /// follow the new dimensions.
+    ///
+    /// An account can only grow by `MAX_PERMITTED_DATA_INCREASE` bytes per instruction, so
+    /// larger migrations first grow the tail of the account, and must be repeated until the
+    /// tree is migrated. The tree stays usable in between.
+    pub fn migrate_tree(
+        ctx: Context<MigrateTree>,
+        max_depth: u32,
+        max_buffer_size: u32,
     ) -> Result<()> {
-        let mut merkle_roll_bytes = ctx.accounts.merkle_roll.try_borrow_mut_data()?;
+        let merkle_roll = ctx.accounts.merkle_roll.to_account_info();
+        let account_len = merkle_roll.data_len();
+        let mut merkle_roll_bytes = merkle_roll.try_borrow_mut_data()?;
         let (header_bytes, rest) = merkle_roll_bytes.split_at_mut(size_of::<MerkleRollHeader>());
-        let header = Box::new(MerkleRollHeader::try_from_slice(header_bytes)?);
+        let mut header = Box::new(MerkleRollHeader::try_from_slice(header_bytes)?);
+        assert_eq!(header.authority, ctx.accounts.authority.key());
+        let old_max_depth = header.max_depth;
+        let old_max_buffer_size = header.max_buffer_size;
+        let old_merkle_roll_size = merkle_roll_get_size!(header)?;
+        let (_, rest) = rest.split_at_mut(old_merkle_roll_size);
+        let (canopy_bytes, tail) = split_tail(rest)?;
+        let canopy_bytes_len = canopy_bytes.len();
+        let tail_len = tail.len();
+        let mut config = load_config(tail)?;
+        check_tail(tail, &config)?;
+        let tail_data_len = tail_data_len(config.free_list_capacity, config.root_history_capacity);
+
+        if max_depth < old_max_depth || max_buffer_size < old_max_buffer_size {
+            msg!(
+                "Cannot migrate a tree of max depth {} and max buffer size {} to max depth {} and max buffer size {}",
+                old_max_depth,
+                old_max_buffer_size,
+                max_depth,
+                max_buffer_size
+            );
+            return err!(GummyrollError::MerkleRollConstantsError);
+        }
+        header.max_depth = max_depth;
+        header.max_buffer_size = max_buffer_size;
         let merkle_roll_size = merkle_roll_get_size!(header)?;
-        let (roll_bytes, canopy_bytes) = rest.split_at_mut(merkle_roll_size);
+        let new_tail_len = tail_size(config.free_list_capacity, config.root_history_capacity);
+        let new_account_len =
+            size_of::<MerkleRollHeader>() + merkle_roll_size + canopy_bytes_len + new_tail_len;
+        if account_len > new_account_len {
+            msg!(
+                "Account of {} bytes is larger than the {} bytes of the migrated tree",
+                account_len,
+                new_account_len
+            );
+            return err!(GummyrollError::MerkleRollConstantsError);
+        }
 
-        let mut proof = vec![];
-        for node in ctx.remaining_accounts.iter() {
-            proof.push(node.key().to_bytes());
+        if new_account_len - account_len > MAX_PERMITTED_DATA_INCREASE {
+            // The tail grows by a multiple of the node size, so that it is still told apart
+            // from the canopy, see `split_tail`
+            let increase = match tail_len {
+                0 => MAX_PERMITTED_DATA_INCREASE - TAIL_SIZE_OFFSET,
+                _ => MAX_PERMITTED_DATA_INCREASE,
+            };
+            // Only the config moves, to the end of the grown tail
+            tail[tail_data_len..].fill(0);
+            config.tail_size = (tail_len + increase) as u32;
+            drop(merkle_roll_bytes);
+            merkle_roll.realloc(account_len + increase, true)?;
+            let mut merkle_roll_bytes = merkle_roll.try_borrow_mut_data()?;
+            let tail_start =
+                size_of::<MerkleRollHeader>() + old_merkle_roll_size + canopy_bytes_len;
+            save_config(&mut merkle_roll_bytes[tail_start..], &config)?;
+            drop(merkle_roll_bytes);
+            fund_rent_exemption(
+                &merkle_roll,
+                &ctx.accounts.payer.to_account_info(),
+                &ctx.accounts.system_program.to_account_info(),
+            )?;
+            msg!(
+                "Account grew to {} of {} bytes, migrate_tree must be called again",
+                account_len + increase,
+                new_account_len
+            );
+            return Ok(());
         }
-        fill_in_proof_from_canopy(canopy_bytes, header.max_depth, index, &mut proof)?;
-        let id = ctx.accounts.merkle_roll.key();
 
-        merkle_roll_apply_fn!(header, id, roll_bytes, prove_leaf, root, leaf, &proof, index)?;
-        Ok(())
-    }
+        drop(merkle_roll_bytes);
+        merkle_roll.realloc(new_account_len, true)?;
+        fund_rent_exemption(
+            &merkle_roll,
+            &ctx.accounts.payer.to_account_info(),
+            &ctx.accounts.system_program.to_account_info(),
+        )?;
+        let mut merkle_roll_bytes = merkle_roll.try_borrow_mut_data()?;
+        let (mut header_bytes, rest) =
+            merkle_roll_bytes.split_at_mut(size_of::<MerkleRollHeader>());
+        header.serialize(&mut header_bytes)?;
+
+        // Parts of the account are moved from the last one down, since they all move to
+        // higher offsets. The free list and the root history move to the start of the new
+        // tail, and the config to its end.
+        let old_tail_start = old_merkle_roll_size + canopy_bytes_len;
+        let tail_start = merkle_roll_size + canopy_bytes_len;
+        rest.copy_within(old_tail_start..old_tail_start + tail_data_len, tail_start);
+        rest[tail_start + tail_data_len..].fill(0);
+        config.tail_size = new_tail_len as u32;
+        save_config(&mut rest[tail_start..], &config)?;
+        let (roll_and_canopy_bytes, _) = rest.split_at_mut(tail_start);
+        if let Err(err) = migrate_canopy_in_place(
+            &mut roll_and_canopy_bytes[old_merkle_roll_size..],
+            canopy_bytes_len,
+            old_max_depth,
+            max_depth,
+        ) {
+            msg!("Error migrating canopy: {}", err);
+            return err!(GummyrollError::from(&err));
+        }
+        if let Err(err) = migrate_in_place::<Keccak>(
+            &mut roll_and_canopy_bytes[..merkle_roll_size],
+            old_max_depth as usize,
+            old_max_buffer_size as usize,
+            max_depth as usize,
+            max_buffer_size as usize,
+        ) {
+            msg!("Error migrating merkle roll: {}", err);
+            return err!(GummyrollError::from(&err));
+        }
+
+        let (roll_bytes, rest) = rest.split_at_mut(merkle_roll_size);
+        let (canopy_bytes, _, _) = split_canopy_and_free_list(rest)?;
+        let id = ctx.accounts.merkle_roll.key();
+        let change_log =
+            match MerkleRollMut::new(roll_bytes, max_depth as usize, max_buffer_size as usize) {
+                Ok(merkle_roll) => Box::<ChangeLogEvent>::from((
+                    merkle_roll.get_change_log(),
+                    id,
+                    merkle_roll.sequence_number(),
+                )),
+                Err(err) => {
+                    msg!("Error zero copying merkle roll: {}", err);
+                    return err!(GummyrollError::from(&err));
+                }
+            };
+
+        let migration = MigrationEvent {
+            id,
+            old_max_depth,
+            old_max_buffer_size,
+            new_max_depth: max_depth,
+            new_max_buffer_size: max_buffer_size,
+            seq: change_log.seq,
+        };
+        wrap_event(migration.try_to_vec()?, &ctx.accounts.candy_wrapper)?;
+        emit!(migration);
+        wrap_event(change_log.try_to_vec()?, &ctx.accounts.candy_wrapper)?;
+        emit!(*change_log);
+        update_canopy(canopy_bytes, header.max_depth, Some(change_log))?;
+        record_roots(
+            &header,
+            &mut merkle_roll_bytes[size_of::<MerkleRollHeader>()..],
+        )
+    }
+
+    /// Sets how the tree handles proofs for roots that are no longer in its changelog buffer.
+    /// Requires `authority` to sign.
+    ///
+    /// New trees fast-forward such proofs through the whole buffer. Rejecting them, or only
+    /// replaying the most recent changelogs, bounds how stale a proof can be and how much
+    /// compute is spent on it, at the cost of more writes failing under contention.
+    ///
+    /// The policy is stored in the config at the end of the account. Trees created without
+    /// a tail are first given one: their account grows by 16 bytes, whose rent `payer` funds.
+    pub fn set_staleness_policy(
+        ctx: Context<SetStalenessPolicy>,
+        policy: ProofStalenessPolicy,
+    ) -> Result<()> {
+        let merkle_roll = ctx.accounts.merkle_roll.to_account_info();
+        let account_len = merkle_roll.data_len();
+        let mut merkle_roll_bytes = merkle_roll.try_borrow_mut_data()?;
+        let (header_bytes, rest) = merkle_roll_bytes.split_at_mut(size_of::<MerkleRollHeader>());
+
+        let header = Box::new(MerkleRollHeader::try_from_slice(header_bytes)?);
+        assert_eq!(header.authority, ctx.accounts.authority.key());
+        let merkle_roll_size = merkle_roll_get_size!(header)?;
+        let tail_start = size_of::<MerkleRollHeader>() + merkle_roll_size;
+        let (_, rest) = rest.split_at_mut(merkle_roll_size);
+        if rest.len() % size_of::<Node>() == 0 {
+            // The account has no tail, see `split_tail`
+            drop(merkle_roll_bytes);
+            merkle_roll.realloc(account_len + tail_size(0, 0), true)?;
+            fund_rent_exemption(
+                &merkle_roll,
+                &ctx.accounts.payer.to_account_info(),
+                &ctx.accounts.system_program.to_account_info(),
+            )?;
+            merkle_roll_bytes = merkle_roll.try_borrow_mut_data()?;
+            init_tail(&mut merkle_roll_bytes[tail_start..], 0, 0)?;
+        }
+        let (_, tail) = split_tail(&mut merkle_roll_bytes[tail_start..])?;
+
+        let mut config = load_config(tail)?;
+        config.set_staleness_policy(policy);
+        save_config(tail, &config)?;
+        msg!("Proof staleness policy set to: {:?}", policy);
+
+        Ok(())
+    }
+
+    /// Verifies a provided proof and leaf.
+    /// If invalid, throws an error.
+    pub fn verify_leaf(
+        ctx: Context<VerifyLeaf>,
+        root: [u8; 32],
+        leaf: [u8; 32],
+        index: u32,
+    ) -> Result<()> {
+        let mut merkle_roll_bytes = ctx.accounts.merkle_roll.try_borrow_mut_data()?;
+        let (header_bytes, rest) = merkle_roll_bytes.split_at_mut(size_of::<MerkleRollHeader>());
+        let header = Box::new(MerkleRollHeader::try_from_slice(header_bytes)?);
+        let merkle_roll_size = merkle_roll_get_size!(header)?;
+        let (roll_bytes, rest) = rest.split_at_mut(merkle_roll_size);
+        let (canopy_bytes, _, config) = split_canopy_and_free_list(rest)?;
+
+        let mut proof = vec![];
+        for node in ctx.remaining_accounts.iter() {
+            proof.push(node.key().to_bytes());
+        }
+        fill_in_proof_from_canopy(canopy_bytes, header.max_depth, index, &mut proof)?;
+        let id = ctx.accounts.merkle_roll.key();
+
+        merkle_roll_apply_fn!(
+            header, config, id, roll_bytes, prove_leaf, root, leaf, &proof, index
+        )?;
+        Ok(())
+    }
+
+    /// Verifies several leaves at once, with a multiproof passed as remaining accounts.
+    /// `leaves` must be sorted by increasing index. If any leaf is invalid, throws an error.
+    ///
+    /// The multiproof only covers the levels below the canopy, see
+    /// `concurrent_merkle_tree::multiproof` for how its nodes are ordered.
+    pub fn verify_leaves(
+        ctx: Context<VerifyLeaf>,
+        root: [u8; 32],
+        leaves: Vec<IndexedLeaf>,
+    ) -> Result<()> {
+        let mut merkle_roll_bytes = ctx.accounts.merkle_roll.try_borrow_mut_data()?;
+        let (header_bytes, rest) = merkle_roll_bytes.split_at_mut(size_of::<MerkleRollHeader>());
+        let header = Box::new(MerkleRollHeader::try_from_slice(header_bytes)?);
+        let merkle_roll_size = merkle_roll_get_size!(header)?;
+        let (roll_bytes, rest) = rest.split_at_mut(merkle_roll_size);
+        let (canopy_bytes, _, config) = split_canopy_and_free_list(rest)?;
+
+        let leaves: Vec<(u32, Node)> = leaves
+            .iter()
+            .map(|indexed_leaf| (indexed_leaf.index, indexed_leaf.leaf))
+            .collect();
+        let proofs = proofs_from_multiproof(
+            canopy_bytes,
+            header.max_depth,
+            &leaves,
+            ctx.remaining_accounts,
+        )?;
+        let id = ctx.accounts.merkle_roll.key();
+        for ((index, leaf), proof) in leaves.iter().zip(proofs.iter()) {
+            merkle_roll_apply_fn!(
+                header, config, id, roll_bytes, prove_leaf, root, *leaf, proof, *index
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Verifies a provided proof for the leaf obtained by hashing `leaf_preimage` with the
+    /// scheme identified by `leaf_hash_scheme` (0 for keccak, 1 for sha256).
+    /// If invalid, throws an error.
+    ///
+    /// Lets other programs check that some exact data, like a serialized Bubblegum `LeafSchema`,
+    /// is in the tree without hashing it themselves.
+    pub fn verify_leaf_preimage(
+        ctx: Context<VerifyLeaf>,
+        root: [u8; 32],
+        leaf_preimage: Vec<u8>,
+        leaf_hash_scheme: u8,
+        index: u32,
+    ) -> Result<()> {
+        let leaf_hash_scheme = match LeafHashScheme::try_from(leaf_hash_scheme) {
+            Ok(leaf_hash_scheme) => leaf_hash_scheme,
+            Err(err) => return err!(GummyrollError::from(&err)),
+        };
+        let mut merkle_roll_bytes = ctx.accounts.merkle_roll.try_borrow_mut_data()?;
+        let (header_bytes, rest) = merkle_roll_bytes.split_at_mut(size_of::<MerkleRollHeader>());
+        let header = Box::new(MerkleRollHeader::try_from_slice(header_bytes)?);
+        let merkle_roll_size = merkle_roll_get_size!(header)?;
+        let (roll_bytes, rest) = rest.split_at_mut(merkle_roll_size);
+        let (canopy_bytes, _, config) = split_canopy_and_free_list(rest)?;
+
+        let mut proof = vec![];
+        for node in ctx.remaining_accounts.iter() {
+            proof.push(node.key().to_bytes());
+        }
+        fill_in_proof_from_canopy(canopy_bytes, header.max_depth, index, &mut proof)?;
+        let id = ctx.accounts.merkle_roll.key();
+
+        merkle_roll_apply_fn!(
+            header,
+            config,
+            id,
+            roll_bytes,
+            prove_leaf_preimage,
+            root,
+            leaf_hash_scheme,
+            &leaf_preimage,
+            &proof,
+            index
+        )?;
+        Ok(())
+    }
+
+    /// Verifies that the leaf at `index` is empty, using a proof for `root`.
+    /// If the leaf holds a value, throws an error.
+    pub fn verify_empty_leaf(ctx: Context<VerifyLeaf>, root: [u8; 32], index: u32) -> Result<()> {
+        let mut merkle_roll_bytes = ctx.accounts.merkle_roll.try_borrow_mut_data()?;
+        let (header_bytes, rest) = merkle_roll_bytes.split_at_mut(size_of::<MerkleRollHeader>());
+        let header = Box::new(MerkleRollHeader::try_from_slice(header_bytes)?);
+        let merkle_roll_size = merkle_roll_get_size!(header)?;
+        let (roll_bytes, rest) = rest.split_at_mut(merkle_roll_size);
+        let (canopy_bytes, _, config) = split_canopy_and_free_list(rest)?;
+
+        let mut proof = vec![];
+        for node in ctx.remaining_accounts.iter() {
+            proof.push(node.key().to_bytes());
+        }
+        if index < 1 << header.max_depth {
+            fill_in_proof_from_canopy(canopy_bytes, header.max_depth, index, &mut proof)?;
+        }
+        let id = ctx.accounts.merkle_roll.key();
+
+        merkle_roll_apply_fn!(
+            header,
+            config,
+            id,
+            roll_bytes,
+            prove_empty_leaf,
+            root,
+            &proof,
+            index
+        )?;
+        Ok(())
+    }
+
+    /// Verifies that every leaf at or after index `leaf_count` is empty,
+    /// i.e. that the tree holds no leaves past index `leaf_count - 1`.
+    /// If it does, throws an error.
+    ///
+    /// The proof of the leaf at index `leaf_count`, for `root`, is passed as remaining accounts.
+    pub fn verify_leaf_count(
+        ctx: Context<VerifyLeaf>,
+        root: [u8; 32],
+        leaf_count: u32,
+    ) -> Result<()> {
+        let mut merkle_roll_bytes = ctx.accounts.merkle_roll.try_borrow_mut_data()?;
+        let (header_bytes, rest) = merkle_roll_bytes.split_at_mut(size_of::<MerkleRollHeader>());
+        let header = Box::new(MerkleRollHeader::try_from_slice(header_bytes)?);
+        let merkle_roll_size = merkle_roll_get_size!(header)?;
+        let (roll_bytes, rest) = rest.split_at_mut(merkle_roll_size);
+        let (canopy_bytes, _, config) = split_canopy_and_free_list(rest)?;
+
+        let mut proof = vec![];
+        for node in ctx.remaining_accounts.iter() {
+            proof.push(node.key().to_bytes());
+        }
+        if leaf_count < 1 << header.max_depth {
+            fill_in_proof_from_canopy(canopy_bytes, header.max_depth, leaf_count, &mut proof)?;
+        }
+        let id = ctx.accounts.merkle_roll.key();
+
+        merkle_roll_apply_fn!(
+            header,
+            config,
+            id,
+            roll_bytes,
+            prove_leaf_count,
+            root,
+            &proof,
+            leaf_count
+        )?;
+        Ok(())
+    }
+
+    /// Verifies a provided proof and leaf for `root`, which can be any root in the changelog
+    /// buffer or in the root history of the tree, see `init_empty_gummyroll_with_root_history`.
+    /// The proof is not fast-forwarded, so the leaf may have been modified since.
+    /// If invalid, throws an error.
+    ///
+    /// The canopy only holds nodes of the current tree, so the full proof of the leaf
+    /// must be passed as remaining accounts.
+    pub fn verify_leaf_at_root(
+        ctx: Context<VerifyLeaf>,
+        root: [u8; 32],
+        leaf: [u8; 32],
+        index: u32,
+    ) -> Result<()> {
+        let mut merkle_roll_bytes = ctx.accounts.merkle_roll.try_borrow_mut_data()?;
+        let (header_bytes, rest) = merkle_roll_bytes.split_at_mut(size_of::<MerkleRollHeader>());
+        let header = Box::new(MerkleRollHeader::try_from_slice(header_bytes)?);
+        let merkle_roll_size = merkle_roll_get_size!(header)?;
+        let (roll_bytes, rest) = rest.split_at_mut(merkle_roll_size);
+        let (_, tail) = split_tail(rest)?;
+        let config = load_config(tail)?;
+        if config.root_history_capacity == 0 {
+            msg!("This tree does not record its past roots");
+            return err!(GummyrollError::RootHistoryNotEnabled);
+        }
+        check_tail(tail, &config)?;
+        let merkle_roll = load_merkle_roll(&header, roll_bytes)?;
+        let root_history = match RootHistoryRef::new(root_history_bytes(tail, &config)) {
+            Ok(root_history) => root_history,
+            Err(err) => {
+                msg!("Error zero copying root history: {}", err);
+                return err!(GummyrollError::from(&err));
+            }
+        };
+
+        let mut proof = vec![];
+        for node in ctx.remaining_accounts.iter() {
+            proof.push(node.key().to_bytes());
+        }
+        match merkle_roll.prove_leaf_at_root(&root_history, root, leaf, &proof, index) {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                msg!("Error using concurrent merkle tree: {}", err);
+                err!(GummyrollError::from(&err))
+            }
+        }
+    }
+
+    /// Creates an empty sparse merkle tree, whose leaves are addressed by 256-bit keys
+    /// instead of indices. The concurrency limit is `max_buffer_size`, as for `init_empty_gummyroll`.
+    ///
+    /// The account must hold a `SparseMerkleRollHeader` followed by the sparse merkle roll,
+    /// `48 + 24 + 8256 * max_buffer_size` bytes in total.
+    pub fn init_empty_sparse_merkle_roll(
+        ctx: Context<Initialize>,
+        max_buffer_size: u32,
+    ) -> Result<()> {
+        let mut sparse_merkle_roll_bytes = ctx.accounts.merkle_roll.try_borrow_mut_data()?;
+
+        let (mut header_bytes, rest) =
+            sparse_merkle_roll_bytes.split_at_mut(size_of::<SparseMerkleRollHeader>());
+
+        let mut header = Box::new(SparseMerkleRollHeader::try_from_slice(&header_bytes)?);
+        header.initialize(
+            max_buffer_size,
+            &ctx.accounts.authority.key(),
+            Clock::get()?.slot,
+        );
+        header.serialize(&mut header_bytes)?;
+        let mut sparse_merkle_roll = load_sparse_merkle_roll(&header, rest)?;
+        match sparse_merkle_roll.initialize() {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                msg!("Error using concurrent merkle tree: {}", err);
+                err!(GummyrollError::from(&err))
+            }
+        }
+    }
+
+    /// Replaces the value at `key` of a sparse merkle tree, which must be `previous_value`,
+    /// with `new_value`. Keys are inserted by replacing an empty value, and removed by
+    /// setting an empty value.
+    ///
+    /// The proof is for `root`, and only its non-empty nodes are passed as remaining accounts,
+    /// as described by `proof_bitmap`. See `verify_sparse_leaf`.
+    pub fn set_sparse_leaf(
+        ctx: Context<Modify>,
+        root: [u8; 32],
+        key: [u8; 32],
+        previous_value: [u8; 32],
+        new_value: [u8; 32],
+        proof_bitmap: [u8; 32],
+    ) -> Result<()> {
+        let mut sparse_merkle_roll_bytes = ctx.accounts.merkle_roll.try_borrow_mut_data()?;
+        let (header_bytes, rest) =
+            sparse_merkle_roll_bytes.split_at_mut(size_of::<SparseMerkleRollHeader>());
+
+        let header = Box::new(SparseMerkleRollHeader::try_from_slice(header_bytes)?);
+        assert_eq!(header.authority, ctx.accounts.authority.key());
+        let mut sparse_merkle_roll = load_sparse_merkle_roll(&header, rest)?;
+
+        let mut proof = sparse_proof_from_accounts(&proof_bitmap, ctx.remaining_accounts)?;
+        let new_root =
+            match sparse_merkle_roll.set_leaf(root, &key, previous_value, new_value, &mut proof) {
+                Ok(new_root) => new_root,
+                Err(err) => {
+                    msg!("Error using concurrent merkle tree: {}", err);
+                    return err!(GummyrollError::from(&err));
+                }
+            };
+        let event = SparseLeafEvent {
+            id: ctx.accounts.merkle_roll.key(),
+            seq: sparse_merkle_roll.sequence_number(),
+            key,
+            value: new_value,
+            root: new_root,
+        };
+        wrap_event(event.try_to_vec()?, &ctx.accounts.candy_wrapper)?;
+        emit!(event);
+        Ok(())
+    }
+
+    /// Verifies that the value at `key` of a sparse merkle tree is `value`, which is empty
+    /// if the tree holds no value for `key`. If invalid, throws an error.
+    ///
+    /// Most siblings on the path of a key are empty subtrees, so only the non-empty nodes
+    /// of the proof for `root` are passed as remaining accounts, from the leaf up.
+    /// Bit `i` of `proof_bitmap`, counting from the least significant bit of its last byte,
+    /// is set if the sibling at height `i` is one of them.
+    pub fn verify_sparse_leaf(
+        ctx: Context<VerifyLeaf>,
+        root: [u8; 32],
+        key: [u8; 32],
+        value: [u8; 32],
+        proof_bitmap: [u8; 32],
+    ) -> Result<()> {
+        let mut sparse_merkle_roll_bytes = ctx.accounts.merkle_roll.try_borrow_mut_data()?;
+        let (header_bytes, rest) =
+            sparse_merkle_roll_bytes.split_at_mut(size_of::<SparseMerkleRollHeader>());
+        let header = Box::new(SparseMerkleRollHeader::try_from_slice(header_bytes)?);
+        let sparse_merkle_roll = load_sparse_merkle_roll(&header, rest)?;
+
+        let mut proof = sparse_proof_from_accounts(&proof_bitmap, ctx.remaining_accounts)?;
+        match sparse_merkle_roll.prove_leaf(root, &key, value, &mut proof) {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                msg!("Error using concurrent merkle tree: {}", err);
+                err!(GummyrollError::from(&err))
+            }
+        }
+    }
 
     /// This instruction allows the tree's `authority` to append a new leaf to the tree
     /// without having to supply a valid proof.
@@ -488,11 +1628,135 @@ pub mod gummyroll {
 
         let id = ctx.accounts.merkle_roll.key();
         let merkle_roll_size = merkle_roll_get_size!(header)?;
-        let (roll_bytes, canopy_bytes) = rest.split_at_mut(merkle_roll_size);
-        let change_log = merkle_roll_apply_fn!(header, id, roll_bytes, append, leaf)?;
+        let (roll_bytes, rest) = rest.split_at_mut(merkle_roll_size);
+        let (canopy_bytes, _, config) = split_canopy_and_free_list(rest)?;
+        let change_log = merkle_roll_apply_fn!(header, config, id, roll_bytes, append, leaf)?;
+        wrap_event(change_log.try_to_vec()?, &ctx.accounts.candy_wrapper)?;
+        emit!(*change_log);
+        update_canopy(canopy_bytes, header.max_depth, Some(change_log))?;
+        record_roots(
+            &header,
+            &mut merkle_roll_bytes[size_of::<MerkleRollHeader>()..],
+        )
+    }
+
+    /// This instruction allows the tree's `authority` to append several leaves to the tree
+    /// at once, without having to supply a valid proof.
+    ///
+    /// Leaves are appended as aligned, full subtrees which share hashing work, so a batch
+    /// emits far fewer changelogs than appending each leaf individually. Every appended leaf
+    /// is also emitted in an `AppendBatchEvent`, since the changelogs only hold the path of
+    /// the last leaf of each subtree.
+    ///
+    /// When the tree has a canopy, at most `power(2, max_depth - canopy_depth)` leaves
+    /// can be appended at once, so that every cached node stays up to date.
+    pub fn append_batch(ctx: Context<Modify>, leaves: Vec<[u8; 32]>) -> Result<()> {
+        let mut merkle_roll_bytes = ctx.accounts.merkle_roll.try_borrow_mut_data()?;
+        let (header_bytes, rest) = merkle_roll_bytes.split_at_mut(size_of::<MerkleRollHeader>());
+
+        let header = Box::new(MerkleRollHeader::try_from_slice(header_bytes)?);
+        assert_eq!(header.authority, ctx.accounts.authority.key());
+
+        let id = ctx.accounts.merkle_roll.key();
+        let merkle_roll_size = merkle_roll_get_size!(header)?;
+        let (roll_bytes, rest) = rest.split_at_mut(merkle_roll_size);
+        let (canopy_bytes, _, config) = split_canopy_and_free_list(rest)?;
+
+        let path_len = load_canopy(canopy_bytes, header.max_depth)?.depth();
+        if leaves.len() > 1 << (header.max_depth - path_len) {
+            msg!(
+                "Cannot append {} leaves at once to a tree with max depth {} and canopy depth {}",
+                leaves.len(),
+                header.max_depth,
+                path_len
+            );
+            return err!(GummyrollError::BatchTooLarge);
+        }
+
+        // A call is made to MerkleRoll::append_batch(leaves)
+        let change_logs =
+            merkle_roll_apply_batch_fn!(header, config, id, roll_bytes, append_batch, &leaves)?;
+        if let Some(last_change_log) = change_logs.last() {
+            // The last changelog is the path of the last appended leaf
+            let batch = AppendBatchEvent {
+                id,
+                seq: last_change_log.seq,
+                start_index: last_change_log.index + 1 - leaves.len() as u32,
+                leaves,
+            };
+            wrap_event(batch.try_to_vec()?, &ctx.accounts.candy_wrapper)?;
+            emit!(batch);
+        }
+        for change_log in change_logs {
+            wrap_event(change_log.try_to_vec()?, &ctx.accounts.candy_wrapper)?;
+            emit!(*change_log);
+            update_canopy(canopy_bytes, header.max_depth, Some(change_log))?;
+        }
+        record_roots(
+            &header,
+            &mut merkle_roll_bytes[size_of::<MerkleRollHeader>()..],
+        )
+    }
+
+    /// This instruction allows the tree's `authority` to append a precomputed, full subtree
+    /// of `power(2, subtree_depth)` leaves to the tree, starting at the rightmost index.
+    ///
+    /// The proof of `subtree_rightmost_leaf` within the subtree is passed as remaining accounts.
+    /// The rightmost index of the tree must be a multiple of the subtree's leaf count.
+    ///
+    /// When the tree has a canopy, `subtree_depth` can be at most `max_depth - canopy_depth`,
+    /// so that every cached node stays up to date.
+    pub fn append_subtree(
+        ctx: Context<Modify>,
+        subtree_root: [u8; 32],
+        subtree_rightmost_leaf: [u8; 32],
+        subtree_depth: u32,
+    ) -> Result<()> {
+        let mut merkle_roll_bytes = ctx.accounts.merkle_roll.try_borrow_mut_data()?;
+        let (header_bytes, rest) = merkle_roll_bytes.split_at_mut(size_of::<MerkleRollHeader>());
+
+        let header = Box::new(MerkleRollHeader::try_from_slice(header_bytes)?);
+        assert_eq!(header.authority, ctx.accounts.authority.key());
+
+        let id = ctx.accounts.merkle_roll.key();
+        let merkle_roll_size = merkle_roll_get_size!(header)?;
+        let (roll_bytes, rest) = rest.split_at_mut(merkle_roll_size);
+        let (canopy_bytes, _, config) = split_canopy_and_free_list(rest)?;
+
+        let path_len = load_canopy(canopy_bytes, header.max_depth)?.depth();
+        if subtree_depth > header.max_depth - path_len {
+            msg!(
+                "Cannot append a subtree of depth {} to a tree with max depth {} and canopy depth {}",
+                subtree_depth,
+                header.max_depth,
+                path_len
+            );
+            return err!(GummyrollError::BatchTooLarge);
+        }
+
+        let mut subtree_proof = vec![];
+        for node in ctx.remaining_accounts.iter() {
+            subtree_proof.push(node.key().to_bytes());
+        }
+        // A call is made to MerkleRoll::append_subtree(subtree_root, subtree_depth, subtree_rightmost_leaf, subtree_proof)
+        let change_log = merkle_roll_apply_fn!(
+            header,
+            config,
+            id,
+            roll_bytes,
+            append_subtree,
+            subtree_root,
+            subtree_depth,
+            subtree_rightmost_leaf,
+            &subtree_proof,
+        )?;
         wrap_event(change_log.try_to_vec()?, &ctx.accounts.candy_wrapper)?;
         emit!(*change_log);
-        update_canopy(canopy_bytes, header.max_depth, Some(change_log))
+        update_canopy(canopy_bytes, header.max_depth, Some(change_log))?;
+        record_roots(
+            &header,
+            &mut merkle_roll_bytes[size_of::<MerkleRollHeader>()..],
+        )
     }
 
     /// This instruction takes a proof, and will attempt to write the given leaf
@@ -510,7 +1774,8 @@ pub mod gummyroll {
         let header = Box::new(MerkleRollHeader::try_from_slice(header_bytes)?);
         assert_eq!(header.authority, ctx.accounts.authority.key());
         let merkle_roll_size = merkle_roll_get_size!(header)?;
-        let (roll_bytes, canopy_bytes) = rest.split_at_mut(merkle_roll_size);
+        let (roll_bytes, rest) = rest.split_at_mut(merkle_roll_size);
+        let (canopy_bytes, free_list_bytes, config) = split_canopy_and_free_list(rest)?;
 
         let mut proof = vec![];
         for node in ctx.remaining_accounts.iter() {
@@ -521,6 +1786,7 @@ pub mod gummyroll {
         let id = ctx.accounts.merkle_roll.key();
         let change_log = merkle_roll_apply_fn!(
             header,
+            config,
             id,
             roll_bytes,
             fill_empty_or_append,
@@ -529,8 +1795,70 @@ pub mod gummyroll {
             &proof,
             index,
         )?;
+        // The leaf may have been written to a slot that was tracked by the free list
+        if let Some(mut free_list) = load_free_list(free_list_bytes)? {
+            free_list.remove(change_log.index);
+        }
+        wrap_event(change_log.try_to_vec()?, &ctx.accounts.candy_wrapper)?;
+        emit!(*change_log);
+        update_canopy(canopy_bytes, header.max_depth, Some(change_log))?;
+        record_roots(
+            &header,
+            &mut merkle_roll_bytes[size_of::<MerkleRollHeader>()..],
+        )
+    }
+
+    /// This instruction writes `leaf` to a leaf that was previously emptied with `replace_leaf`,
+    /// and stops tracking it in the tree's free list.
+    ///
+    /// The indices that can be reused are stored in the free list, at the end of the account.
+    /// The proof of the empty leaf at `index` is passed as remaining accounts.
+    pub fn insert_into_free_slot(
+        ctx: Context<Modify>,
+        root: [u8; 32],
+        leaf: [u8; 32],
+        index: u32,
+    ) -> Result<()> {
+        let mut merkle_roll_bytes = ctx.accounts.merkle_roll.try_borrow_mut_data()?;
+        let (header_bytes, rest) = merkle_roll_bytes.split_at_mut(size_of::<MerkleRollHeader>());
+        let header = Box::new(MerkleRollHeader::try_from_slice(header_bytes)?);
+        assert_eq!(header.authority, ctx.accounts.authority.key());
+        let merkle_roll_size = merkle_roll_get_size!(header)?;
+        let (roll_bytes, rest) = rest.split_at_mut(merkle_roll_size);
+        let (canopy_bytes, free_list_bytes, config) = split_canopy_and_free_list(rest)?;
+        let mut free_list = match load_free_list(free_list_bytes)? {
+            Some(free_list) => free_list,
+            None => {
+                msg!("This tree does not track emptied leaves");
+                return err!(GummyrollError::FreeListNotEnabled);
+            }
+        };
+
+        let mut proof = vec![];
+        for node in ctx.remaining_accounts.iter() {
+            proof.push(node.key().to_bytes());
+        }
+        fill_in_proof_from_canopy(canopy_bytes, header.max_depth, index, &mut proof)?;
+        let id = ctx.accounts.merkle_roll.key();
+        // A call is made to MerkleRoll::insert_into_free_slot(root, leaf, proof, index, free_list)
+        let change_log = merkle_roll_apply_fn!(
+            header,
+            config,
+            id,
+            roll_bytes,
+            insert_into_free_slot,
+            root,
+            leaf,
+            &proof,
+            index,
+            &mut free_list,
+        )?;
         wrap_event(change_log.try_to_vec()?, &ctx.accounts.candy_wrapper)?;
         emit!(*change_log);
-        update_canopy(canopy_bytes, header.max_depth, Some(change_log))
+        update_canopy(canopy_bytes, header.max_depth, Some(change_log))?;
+        record_roots(
+            &header,
+            &mut merkle_roll_bytes[size_of::<MerkleRollHeader>()..],
+        )
     }
 }
@@ -1,8 +1,13 @@
 //! State related to storing a buffer of Merkle tree roots on-chain.
 //!
+use crate::error::GummyrollError;
 use anchor_lang::prelude::*;
 use borsh::{BorshDeserialize, BorshSerialize};
-use concurrent_merkle_tree::state::{ChangeLog, Node};
+use concurrent_merkle_tree::{
+    merkle_roll_view::StalenessPolicy,
+    sparse_merkle_roll::SPARSE_DEPTH,
+    state::{ChangeLog, ChangeLogRef, Node},
+};
 
 #[derive(AnchorDeserialize, AnchorSerialize, Clone, Copy, Debug)]
 pub struct PathNode {
@@ -19,6 +24,33 @@ impl PathNode {
     }
 }
 
+/// A single leaf replacement, used to replace several leaves in one instruction
+#[derive(AnchorDeserialize, AnchorSerialize, Clone, Copy, Debug)]
+pub struct LeafReplacement {
+    pub previous_leaf: [u8; 32],
+    pub new_leaf: [u8; 32],
+    pub index: u32,
+}
+
+/// A leaf and its index, used to verify several leaves in one instruction
+#[derive(AnchorDeserialize, AnchorSerialize, Clone, Copy, Debug)]
+pub struct IndexedLeaf {
+    pub leaf: [u8; 32],
+    pub index: u32,
+}
+
+/// How a tree handles proofs for roots that are no longer in its changelog buffer.
+/// See `concurrent_merkle_tree::merkle_roll_view::StalenessPolicy`.
+#[derive(AnchorDeserialize, AnchorSerialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProofStalenessPolicy {
+    /// Proofs are fast-forwarded through the whole changelog buffer. Used by new trees.
+    FullReplay,
+    /// Proofs must be for a root that is still in the changelog buffer
+    RejectUnknownRoot,
+    /// Proofs are fast-forwarded through the `change_logs` most recent changelogs only
+    ReplayLast { change_logs: u16 },
+}
+
 #[event]
 pub struct NewLeafEvent {
     /// Public key of the merkle roll
@@ -26,6 +58,63 @@ pub struct NewLeafEvent {
     pub leaf: [u8; 32],
 }
 
+/// Emitted by `append_batch` with every appended leaf, since its changelogs only hold
+/// the path of the last leaf of each appended subtree
+#[event]
+pub struct AppendBatchEvent {
+    /// Public key of the merkle roll
+    pub id: Pubkey,
+    /// Sequence number of the tree after the batch was appended
+    pub seq: u64,
+    /// Index of the first appended leaf, the others follow it
+    pub start_index: u32,
+    pub leaves: Vec<[u8; 32]>,
+}
+
+/// Emitted when a tree is grown inside of its account with `migrate_tree`.
+/// Indexers should use the new dimensions of the tree from `seq` onwards.
+#[event]
+pub struct MigrationEvent {
+    /// Public key of the Merkle Roll
+    pub id: Pubkey,
+    pub old_max_depth: u32,
+    pub old_max_buffer_size: u32,
+    pub new_max_depth: u32,
+    pub new_max_buffer_size: u32,
+    /// Sequence number of the tree when it was migrated
+    pub seq: u64,
+}
+
+/// Work performed by the merkle roll during an instruction, see `ComputeStats`.
+/// Only emitted when the program is built with the `compute-stats` feature.
+#[event]
+pub struct ComputeStatsEvent {
+    /// Public key of the Merkle Roll
+    pub id: Pubkey,
+    /// Sequence number of the tree after the instruction
+    pub seq: u64,
+    pub hashes: u32,
+    pub change_logs_scanned: u32,
+    pub proof_nodes_patched: u32,
+}
+
+/// Emitted when a value of a sparse merkle roll is set with `set_sparse_leaf`.
+///
+/// Unlike `ChangeLogEvent`, the path of the leaf is not included, since it is 256 nodes long.
+/// Indexers can recompute it from every value that was set in the tree.
+#[event]
+pub struct SparseLeafEvent {
+    /// Public key of the sparse merkle roll
+    pub id: Pubkey,
+    /// Number of values set in the tree so far, including this one
+    pub seq: u64,
+    pub key: [u8; 32],
+    /// New value at `key`, which is empty if the key was removed
+    pub value: [u8; 32],
+    /// Root of the tree after the value was set
+    pub root: [u8; 32],
+}
+
 #[event]
 pub struct ChangeLogEvent {
     /// Public key of the Merkle Roll
@@ -46,6 +135,13 @@ impl<const MAX_DEPTH: usize> From<(Box<ChangeLog<MAX_DEPTH>>, Pubkey, u64)>
     for Box<ChangeLogEvent>
 {
     fn from(log_info: (Box<ChangeLog<MAX_DEPTH>>, Pubkey, u64)) -> Self {
+        let (changelog, tree_id, seq) = log_info;
+        Box::<ChangeLogEvent>::from((changelog.view(), tree_id, seq))
+    }
+}
+
+impl<'a> From<(ChangeLogRef<'a>, Pubkey, u64)> for Box<ChangeLogEvent> {
+    fn from(log_info: (ChangeLogRef<'a>, Pubkey, u64)) -> Self {
         let (changelog, tree_id, seq) = log_info;
         let path_len = changelog.path.len() as u32;
         let mut path: Vec<PathNode> = changelog
@@ -59,7 +155,7 @@ impl<const MAX_DEPTH: usize> From<(Box<ChangeLog<MAX_DEPTH>>, Pubkey, u64)>
                 )
             })
             .collect();
-        path.push(PathNode::new(changelog.root, 1));
+        path.push(PathNode::new(*changelog.root, 1));
         Box::new(ChangeLogEvent {
             id: tree_id,
             path,
@@ -71,26 +167,19 @@ impl<const MAX_DEPTH: usize> From<(Box<ChangeLog<MAX_DEPTH>>, Pubkey, u64)>
 
 /// Initialization parameters for a Gummyroll Merkle tree.
 ///
-/// Only the following permutations are valid:
-///
-/// | max_depth | max_buffer_size       |
-/// | --------- | --------------------- |
-/// | 14        | (64, 256, 1024, 2048) |           
-/// | 20        | (64, 256, 1024, 2048) |           
-/// | 24        | (64, 256, 512, 1024, 2048) |           
-/// | 26        | (64, 256, 512, 1024, 2048) |           
-/// | 30        | (512, 1024, 2048) |           
+/// Any `max_depth` between 1 and 30 (inclusive) can be combined with
+/// any `max_buffer_size` that is a power of 2.
 ///
 #[derive(BorshDeserialize, BorshSerialize)]
 #[repr(C)]
 pub struct MerkleRollHeader {
     /// Buffer of changelogs stored on-chain.
-    /// Must be a power of 2; see above table for valid combinations.
+    /// Must be a power of 2.
     pub max_buffer_size: u32,
 
     /// Depth of the Merkle tree to store.
     /// Tree capacity can be calculated as power(2, max_depth).
-    /// See above table for valid options.
+    /// Must be between 1 and 30 (inclusive).
     pub max_depth: u32,
 
     /// Authority that validates the content of the trees.
@@ -102,6 +191,68 @@ pub struct MerkleRollHeader {
     pub creation_slot: u64,
 }
 
+/// Settings of a Gummyroll Merkle tree that are stored at the very end of its account,
+/// in the tail that follows the canopy, so that the layout of existing trees is unchanged.
+///
+/// Trees whose account has no tail use the default config, which is all zeros.
+#[derive(BorshDeserialize, BorshSerialize, Clone, Copy, Default)]
+#[repr(C)]
+pub struct MerkleRollConfig {
+    /// Number of bytes of the tail, including this config
+    pub tail_size: u32,
+
+    /// Variant of the tree's `ProofStalenessPolicy`, where 0 replays the full buffer
+    pub staleness_policy: u8,
+
+    /// Keeps `staleness_replay_limit` 2-byte aligned
+    pub _padding: [u8; 1],
+
+    /// Number of changelogs replayed by the `ReplayLast` staleness policy
+    pub staleness_replay_limit: u16,
+
+    /// Maximum number of emptied leaves tracked for reuse.
+    /// When non-zero, the free list is stored at the start of the tail.
+    pub free_list_capacity: u32,
+
+    /// Maximum number of past roots kept in the root history.
+    /// When non-zero, the root history follows the free list, 8-byte aligned.
+    pub root_history_capacity: u32,
+}
+
+/// Header of a sparse merkle roll account, followed by the sparse merkle roll itself.
+///
+/// A sparse merkle roll has one leaf for every 256-bit key, so that values can be stored
+/// under ids like public keys or hashes. Its buffer size must be a power of 2.
+#[derive(BorshDeserialize, BorshSerialize)]
+#[repr(C)]
+pub struct SparseMerkleRollHeader {
+    /// Buffer of changelogs stored on-chain.
+    /// Must be a power of 2.
+    pub max_buffer_size: u32,
+
+    /// Always 256. Stored where `MerkleRollHeader` stores its max depth, which is at most 30,
+    /// so that a merkle roll can never be loaded as a sparse merkle roll.
+    pub depth: u32,
+
+    /// Authority that validates the content of the tree
+    pub authority: Pubkey,
+
+    /// Slot corresponding to when the tree was created
+    pub creation_slot: u64,
+}
+
+impl SparseMerkleRollHeader {
+    pub fn initialize(&mut self, max_buffer_size: u32, authority: &Pubkey, creation_slot: u64) {
+        // Check header is empty
+        assert_eq!(self.max_buffer_size, 0);
+        assert_eq!(self.depth, 0);
+        self.max_buffer_size = max_buffer_size;
+        self.depth = SPARSE_DEPTH as u32;
+        self.authority = *authority;
+        self.creation_slot = creation_slot;
+    }
+}
+
 impl MerkleRollHeader {
     pub fn initialize(
         &mut self,
@@ -120,6 +271,33 @@ impl MerkleRollHeader {
     }
 }
 
+impl MerkleRollConfig {
+    /// Returns the policy used to fast-forward proofs for roots that are no longer in the buffer
+    pub fn staleness_policy(&self) -> Result<StalenessPolicy> {
+        match self.staleness_policy {
+            0 => Ok(StalenessPolicy::FullReplay),
+            1 => Ok(StalenessPolicy::RejectUnknownRoot),
+            2 => Ok(StalenessPolicy::ReplayLast(
+                self.staleness_replay_limit as u32,
+            )),
+            policy => {
+                msg!("Unknown proof staleness policy {}", policy);
+                err!(GummyrollError::InvalidStalenessPolicy)
+            }
+        }
+    }
+
+    pub fn set_staleness_policy(&mut self, policy: ProofStalenessPolicy) {
+        let (staleness_policy, staleness_replay_limit) = match policy {
+            ProofStalenessPolicy::FullReplay => (0, 0),
+            ProofStalenessPolicy::RejectUnknownRoot => (1, 0),
+            ProofStalenessPolicy::ReplayLast { change_logs } => (2, change_logs),
+        };
+        self.staleness_policy = staleness_policy;
+        self.staleness_replay_limit = staleness_replay_limit;
+    }
+}
+
 #[derive(Clone)]
 pub struct CandyWrapper;
 
@@ -10,10 +10,12 @@ import { readPublicKey } from "../../../utils";
 export class OnChainMerkleRoll {
   header: MerkleRollHeader;
   roll: MerkleRoll;
+  config: MerkleRollConfig;
 
-  constructor(header: MerkleRollHeader, roll: MerkleRoll) {
+  constructor(header: MerkleRollHeader, roll: MerkleRoll, config: MerkleRollConfig) {
     this.header = header;
     this.roll = roll;
+    this.config = config;
   }
 
   getChangeLogsWithNodeIndex(): PathNode[][] {
@@ -48,6 +50,18 @@ type MerkleRollHeader = {
   creationSlot: BN;
 };
 
+/**
+ * Stored in the last 16 bytes of the account, after the canopy, the free list and the root history.
+ * Accounts allocated without a tail have the default config, which is all zeros.
+ */
+type MerkleRollConfig = {
+  tailSize: number; // u32
+  stalenessPolicy: number; // u8
+  stalenessReplayLimit: number; // u16
+  freeListCapacity: number; // u32
+  rootHistoryCapacity: number; // u32
+};
+
 type MerkleRoll = {
   sequenceNumber: BN; // u64
   activeIndex: number; // u64
@@ -136,13 +150,37 @@ export function decodeMerkleRoll(buffer: Buffer): OnChainMerkleRoll {
       "Failed to process whole buffer when deserializing Merkle Account Data"
     );
   }
-  return new OnChainMerkleRoll(header, roll);
+  return new OnChainMerkleRoll(header, roll, decodeMerkleRollConfig(buffer, reader.offset));
+}
+
+function decodeMerkleRollConfig(buffer: Buffer, rollEnd: number): MerkleRollConfig {
+  let config: MerkleRollConfig = {
+    tailSize: 0,
+    stalenessPolicy: 0,
+    stalenessReplayLimit: 0,
+    freeListCapacity: 0,
+    rootHistoryCapacity: 0,
+  };
+  // The canopy is a whole number of nodes, so only accounts with a tail have 16 extra bytes
+  if ((buffer.length - rollEnd) % 32 == 16) {
+    let reader = new borsh.BinaryReader(buffer.subarray(buffer.length - 16));
+    config.tailSize = reader.readU32();
+    config.stalenessPolicy = reader.readU8();
+    // Skip config padding
+    reader.readU8();
+    config.stalenessReplayLimit = reader.readU16();
+    config.freeListCapacity = reader.readU32();
+    config.rootHistoryCapacity = reader.readU32();
+  }
+  return config;
 }
 
 export function getMerkleRollAccountSize(
   maxDepth: number,
   maxBufferSize: number,
-  canopyDepth?: number
+  canopyDepth?: number,
+  freeListCapacity?: number,
+  rootHistoryCapacity?: number
 ): number {
   let headerSize = 8 + 32;
   let changeLogSize = (maxDepth * 32 + 32 + 4 + 4) * maxBufferSize;
@@ -152,7 +190,82 @@ export function getMerkleRollAccountSize(
   if (canopyDepth) {
     canopySize = ((1 << canopyDepth + 1) - 2) * 32
   }
-  return merkleRollSize + headerSize + canopySize;
+  // Passing a free list capacity, even 0, reserves the tail that holds the free list,
+  // the root history and the config
+  let tailSize = 0;
+  if (freeListCapacity !== undefined) {
+    let tailDataSize = freeListCapacity ? (freeListCapacity + 1) * 4 : 0;
+    if (rootHistoryCapacity) {
+      tailDataSize = getRootHistoryOffset(freeListCapacity) + getRootHistorySize(rootHistoryCapacity);
+    }
+    tailSize = Math.ceil(tailDataSize / 32) * 32 + 16;
+  }
+  return merkleRollSize + headerSize + canopySize + tailSize;
+}
+
+/**
+ * Root that a merkle roll had at some point
+ */
+export type RootHistoryEntry = {
+  seq: BN; // u64
+  slot: BN; // u64
+  root: PublicKey;
+};
+
+export type OnChainRootHistory = {
+  capacity: number;
+  // Recorded roots, most recent first
+  entries: RootHistoryEntry[];
+};
+
+// The root history follows the free list in the tail, 8-byte aligned
+function getRootHistoryOffset(freeListCapacity: number): number {
+  let freeListSize = freeListCapacity ? (freeListCapacity + 1) * 4 : 0;
+  return Math.ceil(freeListSize / 8) * 8;
+}
+
+function getRootHistorySize(capacity: number): number {
+  return 8 + 8 + capacity * (8 + 8 + 32);
+}
+
+/**
+ * Decodes the root history stored in the tail of a merkle roll account, which is empty
+ * when the tree was created without one
+ */
+export function decodeRootHistory(buffer: Buffer): OnChainRootHistory {
+  const { config } = decodeMerkleRoll(buffer);
+  const capacity = config.rootHistoryCapacity;
+  if (!capacity) {
+    return { capacity, entries: [] };
+  }
+  const tailStart = buffer.length - config.tailSize;
+  const offset = tailStart + getRootHistoryOffset(config.freeListCapacity);
+  let reader = new borsh.BinaryReader(
+    buffer.subarray(offset, offset + getRootHistorySize(capacity))
+  );
+  const len = reader.readU64().toNumber();
+  const activeIndex = reader.readU64().toNumber();
+
+  let slots: RootHistoryEntry[] = [];
+  for (let i = 0; i < capacity; i++) {
+    slots.push({
+      seq: reader.readU64(),
+      slot: reader.readU64(),
+      root: readPublicKey(reader),
+    });
+  }
+  let entries: RootHistoryEntry[] = [];
+  for (let age = 0; age < len; age++) {
+    entries.push(slots[(activeIndex + capacity - age) % capacity]);
+  }
+  return { capacity, entries };
+}
+
+export function getSparseMerkleRollAccountSize(maxBufferSize: number): number {
+  let headerSize = 4 + 4 + 32 + 8;
+  // Root, key, and the 256 nodes on the path of the key
+  let changeLogSize = (32 + 32 + 256 * 32) * maxBufferSize;
+  return headerSize + 8 + 8 + 8 + changeLogSize;
 }
 
 export async function assertOnChainMerkleRollProperties(
@@ -16,7 +16,8 @@ export async function createAllocTreeIx(
     const requiredSpace = getMerkleRollAccountSize(
         maxDepth,
         maxBufferSize,
-        canopyDepth ?? 0
+        canopyDepth ?? 0,
+        0
     );
     return SystemProgram.createAccount({
         fromPubkey: payer,
@@ -0,0 +1,253 @@
+use crate::{
+    error::CMTError,
+    state::{Node, EMPTY},
+};
+use core::mem::size_of;
+
+#[cfg(feature = "std")]
+use crate::hasher::Hasher;
+
+/// Size limit of a serialized Solana transaction
+pub const MAX_TRANSACTION_SIZE: usize = 1232;
+
+/// Number of transaction bytes taken by a proof node passed as a remaining account:
+/// 32 bytes for its account key, and 1 byte for its index in the instruction's account list
+pub const PROOF_NODE_TRANSACTION_SIZE: usize = 33;
+
+/// Number of bytes used to store a canopy of depth `canopy_depth`.
+///
+/// A canopy caches the top `canopy_depth` levels of a tree below its root, which are
+/// `2^(canopy_depth + 1) - 2` nodes, so that clients can leave them out of proofs.
+pub fn canopy_size(canopy_depth: u32) -> usize {
+    ((1 << (canopy_depth + 1)) - 2) * size_of::<Node>()
+}
+
+/// Returns the smallest canopy depth that lets a proof for a tree of depth `max_depth`
+/// fit in a transaction of at most `target_transaction_size` bytes, where
+/// `transaction_size` is the size of the transaction without any proof node.
+///
+/// Returns `max_depth`, i.e. the whole tree is cached, if no proof node fits.
+pub fn required_canopy_depth(
+    max_depth: u32,
+    transaction_size: usize,
+    target_transaction_size: usize,
+) -> u32 {
+    let proof_len =
+        target_transaction_size.saturating_sub(transaction_size) / PROOF_NODE_TRANSACTION_SIZE;
+    max_depth.saturating_sub(proof_len.min(max_depth as usize) as u32)
+}
+
+/// Returns the depth of a canopy of `len` nodes for a tree of depth `max_depth`
+fn canopy_depth(len: usize, max_depth: u32) -> Result<u32, CMTError> {
+    // The offset of 2 is applied because the canopy is a full binary tree without the root node
+    // Size: (2^n - 2) -> Size + 2 must be a power of 2
+    let closest_power_of_2 = len + 2;
+    if closest_power_of_2 & (closest_power_of_2 - 1) != 0 {
+        solana_logging!("Canopy length {} is not 2 less than a power of 2", len);
+        return Err(CMTError::InvalidCanopyBytes);
+    }
+    // 1 is subtracted from the trailing zeros because the root is not stored in the canopy
+    let depth = closest_power_of_2.trailing_zeros() - 1;
+    // The canopy cannot be deeper than the tree
+    if depth > max_depth {
+        solana_logging!(
+            "Canopy size is too large. Size: {}. Max size: {}",
+            len,
+            (1_usize << (max_depth + 1)) - 2
+        );
+        return Err(CMTError::InvalidCanopyBytes);
+    }
+    Ok(depth)
+}
+
+/// Read-only view of the canopy of a merkle roll, usually stored right after it.
+///
+/// Nodes of the canopy are stored level by level from the root down, leftmost first,
+/// so that the node at index `i` of a full binary tree, where the root is at index 1,
+/// is stored at `i - 2`. Nodes that were never written are `EMPTY`, and stand for
+/// empty subtrees.
+pub struct Canopy<'a> {
+    max_depth: u32,
+    nodes: &'a [Node],
+}
+
+impl<'a> Canopy<'a> {
+    pub fn new(bytes: &'a [u8], max_depth: u32) -> Result<Self, CMTError> {
+        let nodes = bytemuck::try_cast_slice(bytes).map_err(|_| CMTError::InvalidCanopyBytes)?;
+        Self::from_nodes(nodes, max_depth)
+    }
+
+    pub fn from_nodes(nodes: &'a [Node], max_depth: u32) -> Result<Self, CMTError> {
+        canopy_depth(nodes.len(), max_depth)?;
+        Ok(Self { max_depth, nodes })
+    }
+
+    /// Depth of the tree whose top levels are cached
+    pub fn max_depth(&self) -> u32 {
+        self.max_depth
+    }
+
+    /// Number of cached levels below the root
+    pub fn depth(&self) -> u32 {
+        (self.nodes.len() as u32 + 2).trailing_zeros() - 1
+    }
+
+    pub fn nodes(&self) -> &'a [Node] {
+        self.nodes
+    }
+
+    /// Number of proof nodes that clients must send, the others are read from the canopy
+    pub fn proof_len(&self) -> u32 {
+        self.max_depth - self.depth()
+    }
+
+    /// Returns the part of a full proof that clients must send
+    pub fn truncate_proof<'p>(&self, proof: &'p [Node]) -> &'p [Node] {
+        &proof[..proof.len().min(self.proof_len() as usize)]
+    }
+
+    /// Returns the node of the canopy that is the sibling at height `level`
+    /// of the path of the leaf at `index`
+    #[cfg(feature = "std")]
+    fn sibling(&self, index: u32, level: u32) -> Node {
+        let node_idx = ((1 << self.max_depth) + index) >> level;
+        // node_idx - 2 maps to the canopy index, and flipping the last bit to the sibling
+        self.nodes[(node_idx ^ 1) as usize - 2]
+    }
+
+    /// Completes a proof truncated with `truncate_proof` with the nodes of the canopy.
+    ///
+    /// Canopy nodes are only added until the proof has one node per level of the tree,
+    /// so a proof that is longer than needed keeps its extra nodes.
+    #[cfg(feature = "std")]
+    pub fn rebuild_proof<H: Hasher>(
+        &self,
+        index: u32,
+        proof: &mut Vec<Node>,
+    ) -> Result<(), CMTError> {
+        if index >= 1 << self.max_depth {
+            return Err(CMTError::LeafIndexOutOfBounds);
+        }
+        let first_level = self
+            .proof_len()
+            .max(proof.len().min(self.max_depth as usize) as u32);
+        for level in first_level..self.max_depth {
+            let node = match self.sibling(index, level) {
+                EMPTY => H::empty_node(level),
+                node => node,
+            };
+            proof.push(node);
+        }
+        Ok(())
+    }
+}
+
+/// Mutable view of the canopy of a merkle roll, see [Canopy]
+pub struct CanopyMut<'a> {
+    max_depth: u32,
+    nodes: &'a mut [Node],
+}
+
+impl<'a> CanopyMut<'a> {
+    pub fn new(bytes: &'a mut [u8], max_depth: u32) -> Result<Self, CMTError> {
+        let nodes: &mut [Node] =
+            bytemuck::try_cast_slice_mut(bytes).map_err(|_| CMTError::InvalidCanopyBytes)?;
+        canopy_depth(nodes.len(), max_depth)?;
+        Ok(Self { max_depth, nodes })
+    }
+
+    pub fn view(&self) -> Canopy<'_> {
+        Canopy {
+            max_depth: self.max_depth,
+            nodes: self.nodes,
+        }
+    }
+
+    /// Writes the cached nodes of the path of the leaf at `index`, where `path` holds
+    /// the nodes of the path from the leaf up, excluding the root
+    pub fn update(&mut self, path: &[Node], index: u32) -> Result<(), CMTError> {
+        if path.len() != self.max_depth as usize {
+            return Err(CMTError::ProofLengthMismatch);
+        }
+        for level in self.view().proof_len()..self.max_depth {
+            let node_idx = ((1 << self.max_depth) + index) >> level;
+            // node_idx - 2 maps to the canopy index
+            self.nodes[node_idx as usize - 2] = path[level as usize];
+        }
+        Ok(())
+    }
+
+    /// Copies the canopy of a tree of smaller depth into this canopy, where that tree
+    /// is the leftmost subtree.
+    ///
+    /// Nodes outside of the old tree are left empty, and are inferred when rebuilding proofs.
+    pub fn migrate_from(&mut self, old: &Canopy<'_>) -> Result<(), CMTError> {
+        if old.max_depth > self.max_depth {
+            return Err(CMTError::InvalidCanopyBytes);
+        }
+        let depth_increase = self.max_depth - old.max_depth;
+        // Levels of the new canopy that are below the old canopy cannot be filled in
+        if self.view().depth() > old.depth() + depth_increase {
+            solana_logging!(
+                "Canopy depth {} is too large, the migrated tree only caches {} levels",
+                self.view().depth(),
+                old.depth() + depth_increase
+            );
+            return Err(CMTError::InvalidCanopyBytes);
+        }
+        for (i, node) in old.nodes.iter().enumerate() {
+            // i + 2 maps to the node index in the old tree
+            let node_idx = i as u32 + 2;
+            let level_from_root = 31 - node_idx.leading_zeros();
+            let new_node_idx =
+                (1 << (level_from_root + depth_increase)) + node_idx - (1 << level_from_root);
+            if let Some(cached) = self.nodes.get_mut(new_node_idx as usize - 2) {
+                *cached = *node;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Same as [CanopyMut::migrate_from], but in place: `bytes` starts with the canopy of a tree
+/// of depth `old_max_depth`, and ends with the canopy of the same tree re-rooted at depth
+/// `max_depth`, which is overwritten. Both canopies are `canopy_bytes_len` bytes long, and
+/// overlap if `bytes` is shorter than twice that.
+///
+/// This lets a canopy be moved further into a resized account without a second copy of it.
+pub fn migrate_canopy_in_place(
+    bytes: &mut [u8],
+    canopy_bytes_len: usize,
+    old_max_depth: u32,
+    max_depth: u32,
+) -> Result<(), CMTError> {
+    let node_size = size_of::<Node>();
+    let len = canopy_bytes_len / node_size;
+    if old_max_depth > max_depth
+        || canopy_bytes_len > bytes.len()
+        || len * node_size != canopy_bytes_len
+    {
+        return Err(CMTError::InvalidCanopyBytes);
+    }
+    canopy_depth(len, old_max_depth)?;
+    let shift = bytes.len() - canopy_bytes_len;
+    let depth_increase = max_depth - old_max_depth;
+    // A node never moves to a lower index, so moving them from the last one down only
+    // overwrites old nodes that were already moved
+    for i in (0..len).rev() {
+        // i + 2 maps to the node index in the new tree
+        let node_idx = i as u32 + 2;
+        let level_from_root = 31 - node_idx.leading_zeros();
+        let offset_in_level = node_idx - (1 << level_from_root);
+        let destination = shift + i * node_size;
+        match level_from_root.checked_sub(depth_increase) {
+            // The node is below the old root, in the leftmost subtree
+            Some(old_level) if old_level > 0 && offset_in_level < 1 << old_level => {
+                let source = ((1 << old_level) + offset_in_level - 2) as usize * node_size;
+                bytes.copy_within(source..source + node_size, destination);
+            }
+            _ => bytes[destination..destination + node_size].copy_from_slice(&EMPTY),
+        }
+    }
+    Ok(())
+}
@@ -1,38 +1,111 @@
-use thiserror::Error;
+use core::fmt;
 
-#[derive(Error, Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum CMTError {
     /// Received an index larger than the rightmost index
-    #[error("Received an index larger than the rightmost index")]
     LeafIndexOutOfBounds,
 
     /// Invalid root recomputed from proof
-    #[error("Invalid root recomputed from proof")]
     InvalidProof,
 
     /// Node to append cannot be empty
-    #[error("Cannot append an empty node")]
     CannotAppendEmptyNode,
 
     /// The tree is at capacity
-    #[error("Tree is full, cannot append")]
     TreeFull,
 
     /// This tree has already been initialized
-    #[error("Tree already initialized")]
     TreeAlreadyInitialized,
 
     /// Invalid number of bytes passed for node (expected 32 bytes)
-    #[error("Invalid number of bytes passed for node (expected 32 bytes)")]
     InvalidNodeByteLength,
 
     /// Fast forward error: we cannot find a valid point to fast-forward the current proof from
-    #[error("Root not found in changelog buffer")]
     RootNotFound,
 
     /// Valid proof was passed to a leaf, but it's value has changed since the proof was issued
-    #[error(
-        "Valid proof was passed to a leaf, but it's value has changed since the proof was issued"
-    )]
     LeafContentsModified,
+
+    /// Max depth must be at most 30 and max buffer size must be a non-zero power of 2
+    InvalidDepthOrBufferSize,
+
+    /// Merkle roll bytes have the wrong length or alignment for the given depth and buffer size
+    InvalidMerkleRollBytes,
+
+    /// The number of proof nodes does not match the expected depth
+    ProofLengthMismatch,
+
+    /// Subtrees can only be appended at an index that is a multiple of their leaf count
+    SubtreeNotAligned,
+
+    /// Free list bytes have the wrong length or alignment, or track more leaves than they can hold
+    InvalidFreeListBytes,
+
+    /// Every slot of the free list is already in use
+    FreeListFull,
+
+    /// Only leaves tracked by the free list can be reused
+    LeafNotFree,
+
+    /// A leaf count proof found a non-empty leaf at or after the given leaf count
+    LeafCountExceeded,
+
+    /// The same leaf cannot be replaced twice in one operation
+    DuplicateLeafIndex,
+
+    /// The root passed to `initialize_with_root` cannot be recomputed from the rightmost leaf and proof
+    RootMismatchOnInit,
+
+    /// Snapshot was written by an unsupported version of this crate
+    UnsupportedSnapshotVersion,
+
+    /// Snapshot does not match the depth of the merkle roll, or its change logs or proof have the wrong length
+    InvalidSnapshot,
+
+    /// Leaf hash scheme ids must be one of the variants of `LeafHashScheme`
+    UnknownLeafHashScheme,
+
+    /// Root history bytes have the wrong length or alignment, or hold inconsistent counters
+    InvalidRootHistoryBytes,
+
+    /// Canopy bytes are not a full binary tree without its root, or are deeper than the tree
+    InvalidCanopyBytes,
+
+    /// Leaves of a multiproof must be sorted by strictly increasing index, and there must be at least one
+    InvalidMultiproofLeaves,
+}
+
+impl fmt::Display for CMTError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            CMTError::LeafIndexOutOfBounds => "Received an index larger than the rightmost index",
+            CMTError::InvalidProof => "Invalid root recomputed from proof",
+            CMTError::CannotAppendEmptyNode => "Cannot append an empty node",
+            CMTError::TreeFull => "Tree is full, cannot append",
+            CMTError::TreeAlreadyInitialized => "Tree already initialized",
+            CMTError::InvalidNodeByteLength => "Invalid number of bytes passed for node (expected 32 bytes)",
+            CMTError::RootNotFound => "Root not found in changelog buffer",
+            CMTError::LeafContentsModified => "Valid proof was passed to a leaf, but it's value has changed since the proof was issued",
+            CMTError::InvalidDepthOrBufferSize => "Invalid max depth or max buffer size",
+            CMTError::InvalidMerkleRollBytes => "Merkle roll bytes have the wrong length or alignment",
+            CMTError::ProofLengthMismatch => "Proof length does not match the expected depth",
+            CMTError::SubtreeNotAligned => "Subtree is not aligned with the rightmost index",
+            CMTError::InvalidFreeListBytes => "Free list bytes have the wrong length or alignment",
+            CMTError::FreeListFull => "Free list is full",
+            CMTError::LeafNotFree => "Leaf is not tracked by the free list",
+            CMTError::LeafCountExceeded => "Tree holds more leaves than the given leaf count",
+            CMTError::DuplicateLeafIndex => "Leaf index is replaced more than once",
+            CMTError::RootMismatchOnInit => "Root does not match the rightmost leaf and proof",
+            CMTError::UnsupportedSnapshotVersion => "Unsupported snapshot version",
+            CMTError::InvalidSnapshot => "Snapshot does not match the merkle roll dimensions",
+            CMTError::UnknownLeafHashScheme => "Unknown leaf hash scheme",
+            CMTError::InvalidRootHistoryBytes => "Root history bytes have the wrong length or alignment",
+            CMTError::InvalidCanopyBytes => "Canopy bytes have the wrong length for the tree depth",
+            CMTError::InvalidMultiproofLeaves => "Multiproof leaves must be sorted by increasing index",
+        };
+        f.write_str(message)
+    }
 }
+
+#[cfg(feature = "std")]
+impl std::error::Error for CMTError {}
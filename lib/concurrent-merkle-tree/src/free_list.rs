@@ -0,0 +1,122 @@
+use crate::error::CMTError;
+use core::mem::size_of;
+
+/// Number of bytes used to store a free list that tracks up to `capacity` leaves.
+///
+/// The first `u32` holds the number of tracked leaves, followed by `capacity` leaf indices.
+pub fn free_list_size(capacity: usize) -> usize {
+    (capacity + 1) * size_of::<u32>()
+}
+
+fn split_free_list(words: &[u32]) -> Result<(u32, &[u32]), CMTError> {
+    let (len, slots) = words.split_first().ok_or(CMTError::InvalidFreeListBytes)?;
+    if *len as usize > slots.len() {
+        return Err(CMTError::InvalidFreeListBytes);
+    }
+    Ok((*len, slots))
+}
+
+/// Read-only view of the leaves that were emptied from a merkle roll and can be reused
+pub struct FreeListRef<'a> {
+    capacity: usize,
+    slots: &'a [u32],
+}
+
+impl<'a> FreeListRef<'a> {
+    pub fn new(bytes: &'a [u8]) -> Result<Self, CMTError> {
+        let words: &[u32] =
+            bytemuck::try_cast_slice(bytes).map_err(|_| CMTError::InvalidFreeListBytes)?;
+        let (len, slots) = split_free_list(words)?;
+        Ok(Self {
+            capacity: slots.len(),
+            slots: &slots[..len as usize],
+        })
+    }
+
+    /// Maximum number of leaves that can be tracked at once
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.slots.len() == self.capacity
+    }
+
+    /// Indices of the tracked leaves, in the order they were emptied,
+    /// except that removing a slot moves the most recently emptied one into its place
+    pub fn slots(&self) -> &'a [u32] {
+        self.slots
+    }
+
+    pub fn contains(&self, index: u32) -> bool {
+        self.slots.contains(&index)
+    }
+}
+
+/// Mutable view of the leaves that were emptied from a merkle roll and can be reused
+pub struct FreeListMut<'a> {
+    len: &'a mut u32,
+    slots: &'a mut [u32],
+}
+
+impl<'a> FreeListMut<'a> {
+    pub fn new(bytes: &'a mut [u8]) -> Result<Self, CMTError> {
+        let words: &mut [u32] =
+            bytemuck::try_cast_slice_mut(bytes).map_err(|_| CMTError::InvalidFreeListBytes)?;
+        split_free_list(words)?;
+        let (len, slots) = words
+            .split_first_mut()
+            .ok_or(CMTError::InvalidFreeListBytes)?;
+        Ok(Self { len, slots })
+    }
+
+    pub fn view(&self) -> FreeListRef<'_> {
+        FreeListRef {
+            capacity: self.slots.len(),
+            slots: &self.slots[..*self.len as usize],
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.view().is_full()
+    }
+
+    pub fn contains(&self, index: u32) -> bool {
+        self.view().contains(index)
+    }
+
+    /// Starts tracking the leaf at `index`. Does nothing if it is already tracked.
+    pub fn push(&mut self, index: u32) -> Result<(), CMTError> {
+        if self.contains(index) {
+            return Ok(());
+        }
+        if self.is_full() {
+            return Err(CMTError::FreeListFull);
+        }
+        self.slots[*self.len as usize] = index;
+        *self.len += 1;
+        Ok(())
+    }
+
+    /// Stops tracking the leaf at `index`. Returns whether it was tracked.
+    pub fn remove(&mut self, index: u32) -> bool {
+        let len = *self.len as usize;
+        match self.slots[..len].iter().position(|slot| *slot == index) {
+            Some(position) => {
+                self.slots.swap(position, len - 1);
+                self.slots[len - 1] = 0;
+                *self.len -= 1;
+                true
+            }
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,408 @@
+use crate::{
+    error::CMTError,
+    state::{Node, EMPTY},
+};
+#[cfg(not(feature = "solana"))]
+use sha3::Digest;
+#[cfg(feature = "solana")]
+use solana_program::{hash, keccak};
+
+/// Hash function used to compute the parent of two nodes in a merkle tree.
+///
+/// Implementors are zero-sized marker types, so that the hash function
+/// can be chosen with a type parameter, e.g. `MerkleRoll<14, 64, Sha256>`.
+pub trait Hasher: Copy + Default + 'static {
+    /// Hashes `left` and `right`, in that order, into their parent node
+    fn hash_pair(left: &Node, right: &Node) -> Node;
+
+    /// Returns the root of an empty subtree of height `level`.
+    ///
+    /// The default implementation hashes up from an empty leaf, which costs
+    /// `level` hashes. Hashers that ship a precomputed table should override it.
+    fn empty_node(level: u32) -> Node {
+        let mut node = EMPTY;
+        for _ in 0..level {
+            node = Self::hash_pair(&node, &node);
+        }
+        node
+    }
+}
+
+/// Looks up `level` in a precomputed table of empty nodes,
+/// hashing up from the last entry for levels past the end of the table
+fn empty_node_from_table<H: Hasher>(table: &[Node; EMPTY_NODES_LEN], level: u32) -> Node {
+    let last = EMPTY_NODES_LEN as u32 - 1;
+    if level <= last {
+        return table[level as usize];
+    }
+    let mut node = table[last as usize];
+    for _ in last..level {
+        node = H::hash_pair(&node, &node);
+    }
+    node
+}
+
+/// Hashes `left` and `right` with a `RustCrypto` hash function, for builds without the Solana syscalls
+#[cfg(not(feature = "solana"))]
+fn digest_pair<D: Digest>(left: &Node, right: &Node) -> Node {
+    let mut parent = EMPTY;
+    parent.copy_from_slice(&D::new().chain_update(left).chain_update(right).finalize());
+    parent
+}
+
+/// Hashes arbitrary bytes with a `RustCrypto` hash function, for builds without the Solana syscalls
+#[cfg(not(feature = "solana"))]
+fn digest<D: Digest>(bytes: &[u8]) -> Node {
+    let mut node = EMPTY;
+    node.copy_from_slice(&D::new().chain_update(bytes).finalize());
+    node
+}
+
+/// Hash function used to turn the raw bytes of a leaf, its preimage, into a leaf node.
+///
+/// Schemes are identified by a `u8` id, so that they can be passed in instruction data.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LeafHashScheme {
+    /// Keccak-256 of the preimage, id 0
+    Keccak,
+    /// SHA-256 of the preimage, id 1
+    Sha256,
+}
+
+impl LeafHashScheme {
+    pub fn id(&self) -> u8 {
+        match self {
+            LeafHashScheme::Keccak => 0,
+            LeafHashScheme::Sha256 => 1,
+        }
+    }
+
+    /// Hashes `preimage` into a leaf node
+    #[cfg(feature = "solana")]
+    pub fn hash_leaf(&self, preimage: &[u8]) -> Node {
+        match self {
+            LeafHashScheme::Keccak => keccak::hash(preimage).to_bytes(),
+            LeafHashScheme::Sha256 => hash::hash(preimage).to_bytes(),
+        }
+    }
+
+    /// Hashes `preimage` into a leaf node
+    #[cfg(not(feature = "solana"))]
+    pub fn hash_leaf(&self, preimage: &[u8]) -> Node {
+        match self {
+            LeafHashScheme::Keccak => digest::<sha3::Keccak256>(preimage),
+            LeafHashScheme::Sha256 => digest::<sha2::Sha256>(preimage),
+        }
+    }
+}
+
+impl TryFrom<u8> for LeafHashScheme {
+    type Error = CMTError;
+
+    fn try_from(id: u8) -> Result<Self, CMTError> {
+        match id {
+            0 => Ok(LeafHashScheme::Keccak),
+            1 => Ok(LeafHashScheme::Sha256),
+            _ => Err(CMTError::UnknownLeafHashScheme),
+        }
+    }
+}
+
+/// Keccak-256, used by Gummyroll trees on-chain
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Keccak;
+
+impl Hasher for Keccak {
+    #[cfg(feature = "solana")]
+    fn hash_pair(left: &Node, right: &Node) -> Node {
+        keccak::hashv(&[left, right]).to_bytes()
+    }
+
+    #[cfg(not(feature = "solana"))]
+    fn hash_pair(left: &Node, right: &Node) -> Node {
+        digest_pair::<sha3::Keccak256>(left, right)
+    }
+
+    fn empty_node(level: u32) -> Node {
+        empty_node_from_table::<Self>(&KECCAK_EMPTY_NODES, level)
+    }
+}
+
+/// SHA-256
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Sha256;
+
+impl Hasher for Sha256 {
+    #[cfg(feature = "solana")]
+    fn hash_pair(left: &Node, right: &Node) -> Node {
+        hash::hashv(&[left, right]).to_bytes()
+    }
+
+    #[cfg(not(feature = "solana"))]
+    fn hash_pair(left: &Node, right: &Node) -> Node {
+        digest_pair::<sha2::Sha256>(left, right)
+    }
+
+    fn empty_node(level: u32) -> Node {
+        empty_node_from_table::<Self>(&SHA256_EMPTY_NODES, level)
+    }
+}
+
+/// Number of precomputed empty nodes: one per level of a tree of
+/// [MAX_SUPPORTED_DEPTH](crate::merkle_roll_view::MAX_SUPPORTED_DEPTH), plus its root
+pub const EMPTY_NODES_LEN: usize = 31;
+
+/// Keccak-256 roots of empty subtrees, indexed by subtree height
+pub const KECCAK_EMPTY_NODES: [Node; EMPTY_NODES_LEN] = [
+    [
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 0,
+    ],
+    [
+        173, 50, 40, 182, 118, 247, 211, 205, 66, 132, 165, 68, 63, 23, 241, 150, 43, 54, 228, 145,
+        179, 10, 64, 178, 64, 88, 73, 229, 151, 186, 95, 181,
+    ],
+    [
+        180, 193, 25, 81, 149, 124, 111, 143, 100, 44, 74, 246, 28, 214, 178, 70, 64, 254, 198,
+        220, 127, 198, 7, 238, 130, 6, 169, 158, 146, 65, 13, 48,
+    ],
+    [
+        33, 221, 185, 163, 86, 129, 92, 63, 172, 16, 38, 182, 222, 197, 223, 49, 36, 175, 186, 219,
+        72, 92, 155, 165, 163, 227, 57, 138, 4, 183, 186, 133,
+    ],
+    [
+        229, 135, 105, 179, 42, 27, 234, 241, 234, 39, 55, 90, 68, 9, 90, 13, 31, 182, 100, 206,
+        45, 211, 88, 231, 252, 191, 183, 140, 38, 161, 147, 68,
+    ],
+    [
+        14, 176, 30, 191, 201, 237, 39, 80, 12, 212, 223, 201, 121, 39, 45, 31, 9, 19, 204, 159,
+        102, 84, 13, 126, 128, 5, 129, 17, 9, 225, 207, 45,
+    ],
+    [
+        136, 124, 34, 189, 135, 80, 211, 64, 22, 172, 60, 102, 181, 255, 16, 45, 172, 221, 115,
+        246, 176, 20, 231, 16, 181, 30, 128, 34, 175, 154, 25, 104,
+    ],
+    [
+        255, 215, 1, 87, 228, 128, 99, 252, 51, 201, 122, 5, 15, 127, 100, 2, 51, 191, 100, 108,
+        201, 141, 149, 36, 198, 185, 43, 207, 58, 181, 111, 131,
+    ],
+    [
+        152, 103, 204, 95, 127, 25, 107, 147, 186, 225, 226, 126, 99, 32, 116, 36, 69, 210, 144,
+        242, 38, 56, 39, 73, 139, 84, 254, 197, 57, 247, 86, 175,
+    ],
+    [
+        206, 250, 212, 229, 8, 192, 152, 185, 167, 225, 216, 254, 177, 153, 85, 251, 2, 186, 150,
+        117, 88, 80, 120, 113, 9, 105, 211, 68, 15, 80, 84, 224,
+    ],
+    [
+        249, 220, 62, 127, 224, 22, 224, 80, 239, 242, 96, 51, 79, 24, 165, 212, 254, 57, 29, 130,
+        9, 35, 25, 245, 150, 79, 46, 46, 183, 193, 195, 165,
+    ],
+    [
+        248, 177, 58, 73, 226, 130, 246, 9, 195, 23, 168, 51, 251, 141, 151, 109, 17, 81, 124, 87,
+        29, 18, 33, 162, 101, 210, 90, 247, 120, 236, 248, 146,
+    ],
+    [
+        52, 144, 198, 206, 235, 69, 10, 236, 220, 130, 226, 130, 147, 3, 29, 16, 199, 215, 59, 248,
+        94, 87, 191, 4, 26, 151, 54, 10, 162, 197, 217, 156,
+    ],
+    [
+        193, 223, 130, 217, 196, 184, 116, 19, 234, 226, 239, 4, 143, 148, 180, 211, 85, 76, 234,
+        115, 217, 43, 15, 122, 249, 110, 2, 113, 198, 145, 226, 187,
+    ],
+    [
+        92, 103, 173, 215, 198, 202, 243, 2, 37, 106, 222, 223, 122, 177, 20, 218, 10, 207, 232,
+        112, 212, 73, 163, 164, 137, 247, 129, 214, 89, 232, 190, 204,
+    ],
+    [
+        218, 123, 206, 159, 78, 134, 24, 182, 189, 47, 65, 50, 206, 121, 140, 220, 122, 96, 231,
+        225, 70, 10, 114, 153, 227, 198, 52, 42, 87, 150, 38, 210,
+    ],
+    [
+        39, 51, 229, 15, 82, 110, 194, 250, 25, 162, 43, 49, 232, 237, 80, 242, 60, 209, 253, 249,
+        76, 145, 84, 237, 58, 118, 9, 162, 241, 255, 152, 31,
+    ],
+    [
+        225, 211, 181, 200, 7, 178, 129, 228, 104, 60, 198, 214, 49, 92, 249, 91, 154, 222, 134,
+        65, 222, 252, 179, 35, 114, 241, 193, 38, 227, 152, 239, 122,
+    ],
+    [
+        90, 45, 206, 10, 138, 127, 104, 187, 116, 86, 15, 143, 113, 131, 124, 44, 46, 187, 203,
+        247, 255, 251, 66, 174, 24, 150, 241, 63, 124, 116, 121, 160,
+    ],
+    [
+        180, 106, 40, 182, 245, 85, 64, 248, 148, 68, 246, 61, 224, 55, 142, 61, 18, 27, 224, 158,
+        6, 204, 157, 237, 28, 32, 230, 88, 118, 211, 106, 160,
+    ],
+    [
+        198, 94, 150, 69, 100, 71, 134, 182, 32, 226, 221, 42, 214, 72, 221, 252, 191, 74, 126, 91,
+        26, 58, 78, 207, 231, 246, 70, 103, 163, 240, 183, 226,
+    ],
+    [
+        244, 65, 133, 136, 237, 53, 162, 69, 140, 255, 235, 57, 185, 61, 38, 241, 141, 42, 177, 59,
+        220, 230, 174, 229, 142, 123, 153, 53, 158, 194, 223, 217,
+    ],
+    [
+        90, 156, 22, 220, 0, 214, 239, 24, 183, 147, 58, 111, 141, 198, 92, 203, 85, 102, 113, 56,
+        119, 111, 125, 234, 16, 16, 112, 220, 135, 150, 227, 119,
+    ],
+    [
+        77, 248, 79, 64, 174, 12, 130, 41, 208, 214, 6, 158, 92, 143, 57, 167, 194, 153, 103, 122,
+        9, 211, 103, 252, 123, 5, 227, 188, 56, 14, 230, 82,
+    ],
+    [
+        205, 199, 37, 149, 247, 76, 123, 16, 67, 208, 225, 255, 186, 183, 52, 100, 140, 131, 141,
+        251, 5, 39, 217, 113, 182, 2, 188, 33, 108, 150, 25, 239,
+    ],
+    [
+        10, 191, 90, 201, 116, 161, 237, 87, 244, 5, 10, 165, 16, 221, 156, 116, 245, 8, 39, 123,
+        57, 215, 151, 59, 178, 223, 204, 197, 238, 176, 97, 141,
+    ],
+    [
+        184, 205, 116, 4, 111, 243, 55, 240, 167, 191, 44, 142, 3, 225, 15, 100, 44, 24, 134, 121,
+        141, 113, 128, 106, 177, 232, 136, 217, 229, 238, 135, 208,
+    ],
+    [
+        131, 140, 86, 85, 203, 33, 198, 203, 131, 49, 59, 90, 99, 17, 117, 223, 244, 150, 55, 114,
+        204, 233, 16, 129, 136, 179, 74, 200, 124, 129, 196, 30,
+    ],
+    [
+        102, 46, 228, 221, 45, 215, 178, 188, 112, 121, 97, 177, 230, 70, 196, 4, 118, 105, 220,
+        182, 88, 79, 13, 141, 119, 13, 175, 93, 126, 125, 235, 46,
+    ],
+    [
+        56, 138, 178, 14, 37, 115, 209, 113, 168, 129, 8, 231, 157, 130, 14, 152, 242, 108, 11,
+        132, 170, 139, 47, 74, 164, 150, 141, 187, 129, 142, 163, 34,
+    ],
+    [
+        147, 35, 124, 80, 186, 117, 238, 72, 95, 76, 34, 173, 242, 247, 65, 64, 11, 223, 141, 106,
+        156, 199, 223, 126, 202, 229, 118, 34, 22, 101, 215, 53,
+    ],
+];
+/// SHA-256 roots of empty subtrees, indexed by subtree height
+pub const SHA256_EMPTY_NODES: [Node; EMPTY_NODES_LEN] = [
+    [
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 0,
+    ],
+    [
+        245, 165, 253, 66, 209, 106, 32, 48, 39, 152, 239, 110, 211, 9, 151, 155, 67, 0, 61, 35,
+        32, 217, 240, 232, 234, 152, 49, 169, 39, 89, 251, 75,
+    ],
+    [
+        219, 86, 17, 78, 0, 253, 212, 193, 248, 92, 137, 43, 243, 90, 201, 168, 146, 137, 170, 236,
+        177, 235, 208, 169, 108, 222, 96, 106, 116, 139, 93, 113,
+    ],
+    [
+        199, 128, 9, 253, 240, 127, 197, 106, 17, 241, 34, 55, 6, 88, 163, 83, 170, 165, 66, 237,
+        99, 228, 76, 75, 193, 95, 244, 205, 16, 90, 179, 60,
+    ],
+    [
+        83, 109, 152, 131, 127, 45, 209, 101, 165, 93, 94, 234, 233, 20, 133, 149, 68, 114, 213,
+        111, 36, 109, 242, 86, 191, 60, 174, 25, 53, 42, 18, 60,
+    ],
+    [
+        158, 253, 224, 82, 170, 21, 66, 159, 174, 5, 186, 212, 208, 177, 215, 198, 77, 166, 77, 3,
+        215, 161, 133, 74, 88, 140, 44, 184, 67, 12, 13, 48,
+    ],
+    [
+        216, 141, 223, 238, 212, 0, 168, 117, 85, 150, 178, 25, 66, 193, 73, 126, 17, 76, 48, 46,
+        97, 24, 41, 15, 145, 230, 119, 41, 118, 4, 31, 161,
+    ],
+    [
+        135, 235, 13, 219, 165, 126, 53, 246, 210, 134, 103, 56, 2, 164, 175, 89, 117, 226, 37, 6,
+        199, 207, 76, 100, 187, 107, 229, 238, 17, 82, 127, 44,
+    ],
+    [
+        38, 132, 100, 118, 253, 95, 197, 74, 93, 67, 56, 81, 103, 201, 81, 68, 242, 100, 63, 83,
+        60, 200, 91, 185, 209, 107, 120, 47, 141, 125, 177, 147,
+    ],
+    [
+        80, 109, 134, 88, 45, 37, 36, 5, 184, 64, 1, 135, 146, 202, 210, 191, 18, 89, 241, 239, 90,
+        165, 248, 135, 225, 60, 178, 240, 9, 79, 81, 225,
+    ],
+    [
+        255, 255, 10, 215, 230, 89, 119, 47, 149, 52, 193, 149, 200, 21, 239, 196, 1, 78, 241, 225,
+        218, 237, 68, 4, 192, 99, 133, 209, 17, 146, 233, 43,
+    ],
+    [
+        108, 240, 65, 39, 219, 5, 68, 28, 216, 51, 16, 122, 82, 190, 133, 40, 104, 137, 14, 67, 23,
+        230, 160, 42, 180, 118, 131, 170, 117, 150, 66, 32,
+    ],
+    [
+        183, 208, 95, 135, 95, 20, 0, 39, 239, 81, 24, 162, 36, 123, 187, 132, 206, 143, 47, 15,
+        17, 35, 98, 48, 133, 218, 247, 150, 12, 50, 159, 95,
+    ],
+    [
+        223, 106, 245, 245, 187, 219, 107, 233, 239, 138, 166, 24, 228, 191, 128, 115, 150, 8, 103,
+        23, 30, 41, 103, 111, 139, 40, 77, 234, 106, 8, 168, 94,
+    ],
+    [
+        181, 141, 144, 15, 94, 24, 46, 60, 80, 239, 116, 150, 158, 161, 108, 119, 38, 197, 73, 117,
+        124, 194, 53, 35, 195, 105, 88, 125, 167, 41, 55, 132,
+    ],
+    [
+        212, 154, 117, 2, 255, 207, 176, 52, 11, 29, 120, 133, 104, 133, 0, 202, 48, 129, 97, 167,
+        249, 107, 98, 223, 157, 8, 59, 113, 252, 200, 242, 187,
+    ],
+    [
+        143, 230, 177, 104, 146, 86, 192, 211, 133, 244, 47, 91, 190, 32, 39, 162, 44, 25, 150,
+        225, 16, 186, 151, 193, 113, 211, 229, 148, 141, 233, 43, 235,
+    ],
+    [
+        141, 13, 99, 195, 158, 186, 222, 133, 9, 224, 174, 60, 156, 56, 118, 251, 95, 161, 18, 190,
+        24, 249, 5, 236, 172, 254, 203, 146, 5, 118, 3, 171,
+    ],
+    [
+        149, 238, 200, 178, 229, 65, 202, 212, 233, 29, 227, 131, 133, 242, 224, 70, 97, 159, 84,
+        73, 108, 35, 130, 203, 108, 172, 213, 185, 140, 38, 245, 164,
+    ],
+    [
+        248, 147, 233, 8, 145, 119, 117, 182, 43, 255, 35, 41, 77, 187, 227, 161, 205, 142, 108,
+        193, 195, 91, 72, 1, 136, 123, 100, 106, 111, 129, 241, 127,
+    ],
+    [
+        205, 219, 167, 181, 146, 227, 19, 51, 147, 193, 97, 148, 250, 199, 67, 26, 191, 47, 84,
+        133, 237, 113, 29, 178, 130, 24, 60, 129, 158, 8, 235, 170,
+    ],
+    [
+        138, 141, 127, 227, 175, 140, 170, 8, 90, 118, 57, 168, 50, 0, 20, 87, 223, 185, 18, 138,
+        128, 97, 20, 42, 208, 51, 86, 41, 255, 35, 255, 156,
+    ],
+    [
+        254, 179, 195, 55, 215, 165, 26, 111, 191, 0, 185, 227, 76, 82, 225, 201, 25, 92, 150, 155,
+        212, 231, 160, 191, 213, 29, 92, 91, 237, 156, 17, 103,
+    ],
+    [
+        231, 31, 10, 168, 60, 195, 46, 223, 190, 250, 159, 77, 62, 1, 116, 202, 133, 24, 46, 236,
+        159, 58, 9, 246, 166, 192, 223, 99, 119, 165, 16, 215,
+    ],
+    [
+        49, 32, 111, 168, 10, 80, 187, 106, 190, 41, 8, 80, 88, 241, 98, 18, 33, 42, 96, 238, 200,
+        240, 73, 254, 203, 146, 216, 200, 224, 168, 75, 192,
+    ],
+    [
+        33, 53, 43, 254, 203, 237, 221, 233, 147, 131, 159, 97, 76, 61, 172, 10, 62, 227, 117, 67,
+        249, 180, 18, 177, 97, 153, 220, 21, 142, 35, 181, 68,
+    ],
+    [
+        97, 158, 49, 39, 36, 187, 109, 124, 49, 83, 237, 157, 231, 145, 215, 100, 163, 102, 179,
+        137, 175, 19, 197, 139, 248, 168, 217, 4, 129, 164, 103, 101,
+    ],
+    [
+        124, 221, 41, 134, 38, 130, 80, 98, 141, 12, 16, 227, 133, 197, 140, 97, 145, 230, 251,
+        224, 81, 145, 188, 192, 79, 19, 63, 44, 234, 114, 193, 196,
+    ],
+    [
+        132, 137, 48, 189, 123, 168, 202, 197, 70, 97, 7, 33, 19, 251, 39, 136, 105, 224, 123, 184,
+        88, 127, 145, 57, 41, 51, 55, 77, 1, 123, 203, 225,
+    ],
+    [
+        136, 105, 255, 44, 34, 178, 140, 193, 5, 16, 217, 133, 50, 146, 128, 51, 40, 190, 79, 176,
+        232, 4, 149, 232, 187, 141, 39, 31, 91, 136, 150, 54,
+    ],
+    [
+        181, 254, 40, 231, 159, 27, 133, 15, 134, 88, 36, 108, 233, 182, 161, 231, 180, 159, 192,
+        109, 183, 20, 62, 143, 224, 180, 242, 176, 197, 82, 58, 92,
+    ],
+];
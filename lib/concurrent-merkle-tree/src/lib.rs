@@ -1,6 +1,23 @@
+//! Concurrent merkle tree that accepts proofs for recent roots.
+//!
+//! The crate is `no_std` unless the `std` feature is enabled. The default `solana`
+//! feature hashes with the Solana syscalls and enables the `log` and `sol-log` features,
+//! otherwise nodes are hashed with the `sha2` and `sha3` crates.
+#![cfg_attr(not(feature = "std"), no_std)]
+
 pub mod error;
+pub mod free_list;
+pub mod hasher;
 #[macro_use]
 pub mod log;
+// Declared after `log` since it uses its macros
+pub mod canopy;
 pub mod merkle_roll;
+pub mod merkle_roll_view;
+pub mod multiproof;
+pub mod root_history;
+#[cfg(feature = "std")]
+pub mod snapshot;
+pub mod sparse_merkle_roll;
 pub mod state;
 pub mod utils;
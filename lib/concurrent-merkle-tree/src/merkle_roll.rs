@@ -1,27 +1,25 @@
+#[cfg(feature = "std")]
+use crate::snapshot::MerkleRollSnapshot;
 use crate::{
     error::CMTError,
-    state::{ChangeLog, Node, Path, EMPTY},
-    utils::{empty_node, empty_node_cached, fill_in_proof, recompute, hash_to_parent},
+    free_list::FreeListMut,
+    hasher::{Hasher, Keccak, LeafHashScheme},
+    merkle_roll_view::{MerkleRollMut, MerkleRollRef},
+    root_history::RootHistoryRef,
+    state::{ChangeLog, Node, Path},
 };
 use bytemuck::{Pod, Zeroable};
-pub(crate) use log_compute;
-pub(crate) use solana_logging;
+use core::marker::PhantomData;
 
-#[cfg(feature = "sol-log")]
-use solana_program::{log::sol_log_compute_units, msg};
-
-#[inline(always)]
-fn check_bounds(max_depth: usize, max_buffer_size: usize) {
-    assert!(max_depth < 31);
-    // This will return true if MAX_BUFFER_SIZE is a power of 2 or if it is 0
-    assert!(max_buffer_size & (max_buffer_size - 1) == 0);
-}
 /// Tracks updates to off-chain Merkle tree
 ///
 /// Allows for concurrent writes to same merkle tree so long as proof
 /// was generated at most MAX_SIZE updates since the tx was submitted
+///
+/// See [MerkleRollMut] for a version of this struct whose dimensions are chosen at runtime
 #[derive(Copy, Clone)]
-pub struct MerkleRoll<const MAX_DEPTH: usize, const MAX_BUFFER_SIZE: usize> {
+#[repr(C)]
+pub struct MerkleRoll<const MAX_DEPTH: usize, const MAX_BUFFER_SIZE: usize, H: Hasher = Keccak> {
     pub sequence_number: u64,
     /// Index of most recent root & changes
     pub active_index: u64,
@@ -30,18 +28,30 @@ pub struct MerkleRoll<const MAX_DEPTH: usize, const MAX_BUFFER_SIZE: usize> {
     /// Proof for respective root
     pub change_logs: [ChangeLog<MAX_DEPTH>; MAX_BUFFER_SIZE],
     pub rightmost_proof: Path<MAX_DEPTH>,
+    /// Hash function used to compute the nodes of the tree
+    _hasher: PhantomData<H>,
 }
 
-unsafe impl<const MAX_DEPTH: usize, const MAX_BUFFER_SIZE: usize> Zeroable
-    for MerkleRoll<MAX_DEPTH, MAX_BUFFER_SIZE>
+unsafe impl<const MAX_DEPTH: usize, const MAX_BUFFER_SIZE: usize, H: Hasher> Zeroable
+    for MerkleRoll<MAX_DEPTH, MAX_BUFFER_SIZE, H>
 {
 }
-unsafe impl<const MAX_DEPTH: usize, const MAX_BUFFER_SIZE: usize> Pod
-    for MerkleRoll<MAX_DEPTH, MAX_BUFFER_SIZE>
+unsafe impl<const MAX_DEPTH: usize, const MAX_BUFFER_SIZE: usize, H: Hasher> Pod
+    for MerkleRoll<MAX_DEPTH, MAX_BUFFER_SIZE, H>
 {
 }
 
-impl<const MAX_DEPTH: usize, const MAX_BUFFER_SIZE: usize> MerkleRoll<MAX_DEPTH, MAX_BUFFER_SIZE> {
+impl<const MAX_DEPTH: usize, const MAX_BUFFER_SIZE: usize, H: Hasher> Default
+    for MerkleRoll<MAX_DEPTH, MAX_BUFFER_SIZE, H>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const MAX_DEPTH: usize, const MAX_BUFFER_SIZE: usize, H: Hasher>
+    MerkleRoll<MAX_DEPTH, MAX_BUFFER_SIZE, H>
+{
     pub fn new() -> Self {
         Self {
             sequence_number: 0,
@@ -49,151 +59,193 @@ impl<const MAX_DEPTH: usize, const MAX_BUFFER_SIZE: usize> MerkleRoll<MAX_DEPTH,
             buffer_size: 0,
             change_logs: [ChangeLog::<MAX_DEPTH>::default(); MAX_BUFFER_SIZE],
             rightmost_proof: Path::<MAX_DEPTH>::default(),
+            _hasher: PhantomData,
         }
     }
 
+    /// Borrows this merkle roll as a runtime-sized, read-only view.
+    /// Fails if `MAX_DEPTH` or `MAX_BUFFER_SIZE` are not supported
+    pub fn view(&self) -> Result<MerkleRollRef<'_, H>, CMTError> {
+        MerkleRollRef::with_hasher(
+            bytemuck::bytes_of(self),
+            MAX_DEPTH,
+            MAX_BUFFER_SIZE,
+            H::default(),
+        )
+    }
+
+    /// Borrows this merkle roll as a runtime-sized, mutable view.
+    /// Fails if `MAX_DEPTH` or `MAX_BUFFER_SIZE` are not supported
+    pub fn view_mut(&mut self) -> Result<MerkleRollMut<'_, H>, CMTError> {
+        MerkleRollMut::with_hasher(
+            bytemuck::bytes_of_mut(self),
+            MAX_DEPTH,
+            MAX_BUFFER_SIZE,
+            H::default(),
+        )
+    }
+
     pub fn initialize(&mut self) -> Result<Node, CMTError> {
-        check_bounds(MAX_DEPTH, MAX_BUFFER_SIZE);
-        let mut rightmost_proof = Path::default();
-        let mut empty_node_cache = Box::new([Node::default(); MAX_DEPTH]);
-        for (i, node) in rightmost_proof.proof.iter_mut().enumerate() {
-            *node = empty_node_cached::<MAX_DEPTH>(i as u32, &mut empty_node_cache);
-        }
-        let mut path = [Node::default(); MAX_DEPTH];
-        for (i, node) in path.iter_mut().enumerate() {
-            *node = empty_node_cached::<MAX_DEPTH>(i as u32, &mut empty_node_cache);
-        }
-        self.change_logs[0].root = empty_node(MAX_DEPTH as u32);
-        self.change_logs[0].path = path;
-        self.sequence_number = 0;
-        self.active_index = 0;
-        self.buffer_size = 1;
-        self.rightmost_proof = rightmost_proof;
-        Ok(self.change_logs[0].root)
+        self.view_mut()?.initialize()
     }
 
     pub fn initialize_with_root(
         &mut self,
         root: Node,
         rightmost_leaf: Node,
-        proof_vec: &Vec<Node>,
+        proof_vec: &[Node],
         index: u32,
     ) -> Result<Node, CMTError> {
-        check_bounds(MAX_DEPTH, MAX_BUFFER_SIZE);
-        let mut proof: [Node; MAX_DEPTH] = [Node::default(); MAX_DEPTH];
-        proof.copy_from_slice(&proof_vec[..]);
-        let rightmost_proof = Path {
-            proof,
-            index: index + 1,
-            leaf: rightmost_leaf,
-            _padding: 0,
-        };
-        self.change_logs[0].root = root;
-        self.sequence_number = 1;
-        self.active_index = 0;
-        self.buffer_size = 1;
-        self.rightmost_proof = rightmost_proof;
-        assert_eq!(root, recompute(rightmost_leaf, &proof, index,));
-        Ok(root)
+        self.view_mut()?
+            .initialize_with_root(root, rightmost_leaf, proof_vec, index)
+    }
+
+    /// Copies a merkle roll of smaller or equal depth into this one.
+    /// See [MerkleRollMut::migrate_from]
+    pub fn migrate_from<const OLD_DEPTH: usize, const OLD_BUFFER_SIZE: usize>(
+        &mut self,
+        old: &MerkleRoll<OLD_DEPTH, OLD_BUFFER_SIZE, H>,
+    ) -> Result<Node, CMTError> {
+        self.view_mut()?.migrate_from(&old.view()?)
+    }
+
+    /// See [MerkleRollRef::to_snapshot]
+    #[cfg(feature = "std")]
+    pub fn to_snapshot(&self) -> Result<MerkleRollSnapshot, CMTError> {
+        Ok(self.view()?.to_snapshot())
+    }
+
+    /// Creates a merkle roll from a snapshot, possibly taken from a merkle roll with
+    /// a different buffer size. See [MerkleRollMut::load_snapshot]
+    #[cfg(feature = "std")]
+    pub fn from_snapshot(snapshot: &MerkleRollSnapshot) -> Result<Self, CMTError> {
+        let mut merkle_roll = Self::new();
+        merkle_roll.view_mut()?.load_snapshot(snapshot)?;
+        Ok(merkle_roll)
     }
 
-    pub fn get_change_log(&self) -> Box<ChangeLog<MAX_DEPTH>> {
-        Box::new(self.change_logs[self.active_index as usize])
+    pub fn get_change_log(&self) -> &ChangeLog<MAX_DEPTH> {
+        &self.change_logs[self.active_index as usize]
     }
 
     pub fn prove_leaf(
         &mut self,
         current_root: Node,
         leaf: Node,
-        proof_vec: &Vec<Node>,
+        proof_vec: &[Node],
         leaf_index: u32,
     ) -> Result<Node, CMTError> {
-        check_bounds(MAX_DEPTH, MAX_BUFFER_SIZE);
-        if leaf_index > self.rightmost_proof.index {
-            solana_logging!(
-                "Received an index larger than the rightmost index {} > {}",
-                leaf_index,
-                self.rightmost_proof.index
-            );
-            return Err(CMTError::LeafIndexOutOfBounds);
-        } else {
-            let mut proof: [Node; MAX_DEPTH] = [Node::default(); MAX_DEPTH];
-            fill_in_proof::<MAX_DEPTH>(proof_vec, &mut proof);
-            let valid_root =
-                self.check_valid_leaf(current_root, leaf, &mut proof, leaf_index, true)?;
-            if !valid_root {
-                solana_logging!("Proof failed to verify");
-                return Err(CMTError::InvalidProof);
-            }
-            Ok(Node::default())
-        }
+        self.view()?
+            .prove_leaf(current_root, leaf, proof_vec, leaf_index)
     }
 
-    /// Only used to initialize right most path for a completely empty tree
-    #[inline(always)]
-    fn initialize_tree_from_append(
-        &mut self,
+    /// Proves several leaves at once with a multiproof.
+    /// See [MerkleRollRef::prove_leaves]
+    #[cfg(feature = "std")]
+    pub fn prove_leaves(
+        &self,
+        current_root: Node,
+        leaves: &[(u32, Node)],
+        multiproof: &[Node],
+    ) -> Result<Node, CMTError> {
+        self.view()?.prove_leaves(current_root, leaves, multiproof)
+    }
+
+    /// Proves that the leaf hashed from `leaf_preimage` is at `leaf_index`.
+    /// See [MerkleRollRef::prove_leaf_preimage]
+    pub fn prove_leaf_preimage(
+        &self,
+        current_root: Node,
+        leaf_hash_scheme: LeafHashScheme,
+        leaf_preimage: &[u8],
+        proof_vec: &[Node],
+        leaf_index: u32,
+    ) -> Result<Node, CMTError> {
+        self.view()?.prove_leaf_preimage(
+            current_root,
+            leaf_hash_scheme,
+            leaf_preimage,
+            proof_vec,
+            leaf_index,
+        )
+    }
+
+    /// Proves that `leaf` was at `leaf_index` when the tree had root `root`.
+    /// See [MerkleRollRef::prove_leaf_at_root]
+    pub fn prove_leaf_at_root(
+        &self,
+        root_history: &RootHistoryRef<'_>,
+        root: Node,
         leaf: Node,
-        mut proof: [Node; MAX_DEPTH],
+        proof_vec: &[Node],
+        leaf_index: u32,
     ) -> Result<Node, CMTError> {
-        let old_root = recompute(EMPTY, &proof, 0);
-        if old_root == empty_node(MAX_DEPTH as u32) {
-            self.try_apply_proof(old_root, EMPTY, leaf, &mut proof, 0, false)
-        } else {
-            return Err(CMTError::TreeAlreadyInitialized);
-        }
+        self.view()?
+            .prove_leaf_at_root(root_history, root, leaf, proof_vec, leaf_index)
+    }
+
+    /// Proves that the leaf at `leaf_index` is empty.
+    /// See [MerkleRollRef::prove_empty_leaf]
+    pub fn prove_empty_leaf(
+        &self,
+        current_root: Node,
+        proof_vec: &[Node],
+        leaf_index: u32,
+    ) -> Result<Node, CMTError> {
+        self.view()?
+            .prove_empty_leaf(current_root, proof_vec, leaf_index)
+    }
+
+    /// Proves that the tree holds no leaves at or after index `leaf_count`.
+    /// See [MerkleRollRef::prove_leaf_count]
+    pub fn prove_leaf_count(
+        &self,
+        current_root: Node,
+        proof_vec: &[Node],
+        leaf_count: u32,
+    ) -> Result<Node, CMTError> {
+        self.view()?
+            .prove_leaf_count(current_root, proof_vec, leaf_count)
+    }
+
+    /// Fast-forwards a stale proof in place and returns the current root.
+    /// See [MerkleRollRef::refresh_proof]
+    pub fn refresh_proof(
+        &self,
+        root: Node,
+        leaf: Node,
+        proof: &mut [Node],
+        leaf_index: u32,
+    ) -> Result<Node, CMTError> {
+        self.view()?.refresh_proof(root, leaf, proof, leaf_index)
     }
 
     /// Basic operation that always succeeds
-    pub fn append(&mut self, mut node: Node) -> Result<Node, CMTError> {
-        check_bounds(MAX_DEPTH, MAX_BUFFER_SIZE);
-        if node == EMPTY {
-            return Err(CMTError::CannotAppendEmptyNode);
-        }
-        if self.rightmost_proof.index >= 1 << MAX_DEPTH {
-            return Err(CMTError::TreeFull);
-        }
-        if self.rightmost_proof.index == 0 {
-            return self.initialize_tree_from_append(node, self.rightmost_proof.proof);
-        }
-        let leaf = node.clone();
-        let intersection = self.rightmost_proof.index.trailing_zeros() as usize;
-        let mut change_list = [EMPTY; MAX_DEPTH];
-        let mut intersection_node = self.rightmost_proof.leaf;
-        let mut empty_node_cache = Box::new([Node::default(); MAX_DEPTH]);
+    pub fn append(&mut self, node: Node) -> Result<Node, CMTError> {
+        self.view_mut()?.append(node)
+    }
 
-        for i in 0..MAX_DEPTH {
-            change_list[i] = node;
-            if i < intersection {
-                // Compute proof to the appended node from empty nodes
-                let sibling = empty_node_cached::<MAX_DEPTH>(i as u32, &mut empty_node_cache);
-                hash_to_parent(
-                    &mut intersection_node,
-                    &self.rightmost_proof.proof[i],
-                    ((self.rightmost_proof.index - 1) >> i) & 1 == 0,
-                );
-                hash_to_parent(&mut node, &sibling, true);
-                self.rightmost_proof.proof[i] = sibling;
-            } else if i == intersection {
-                // Compute the where the new node intersects the main tree
-                hash_to_parent(&mut node, &intersection_node, false);
-                self.rightmost_proof.proof[intersection] = intersection_node;
-            } else {
-                // Update the change list path up to the root
-                hash_to_parent(
-                    &mut node,
-                    &self.rightmost_proof.proof[i],
-                    ((self.rightmost_proof.index - 1) >> i) & 1 == 0,
-                );
-            }
-        }
+    /// Appends `leaves` to the tree, sharing hashing work across the batch.
+    /// See [MerkleRollMut::append_batch]
+    pub fn append_batch(&mut self, leaves: &[Node]) -> Result<Node, CMTError> {
+        self.view_mut()?.append_batch(leaves)
+    }
 
-        self.update_internal_counters();
-        self.change_logs[self.active_index as usize] =
-            ChangeLog::<MAX_DEPTH>::new(node, change_list, self.rightmost_proof.index);
-        self.rightmost_proof.index = self.rightmost_proof.index + 1;
-        self.rightmost_proof.leaf = leaf;
-        Ok(node)
+    /// Attaches a precomputed, full subtree at the rightmost index.
+    /// See [MerkleRollMut::append_subtree]
+    pub fn append_subtree(
+        &mut self,
+        subtree_root: Node,
+        subtree_depth: u32,
+        subtree_rightmost_leaf: Node,
+        subtree_rightmost_proof: &[Node],
+    ) -> Result<Node, CMTError> {
+        self.view_mut()?.append_subtree(
+            subtree_root,
+            subtree_depth,
+            subtree_rightmost_leaf,
+            subtree_rightmost_proof,
+        )
     }
 
     /// Convenience function for `set_leaf`
@@ -203,22 +255,11 @@ impl<const MAX_DEPTH: usize, const MAX_BUFFER_SIZE: usize> MerkleRoll<MAX_DEPTH,
         &mut self,
         current_root: Node,
         leaf: Node,
-        proof_vec: &Vec<Node>,
+        proof_vec: &[Node],
         index: u32,
     ) -> Result<Node, CMTError> {
-        check_bounds(MAX_DEPTH, MAX_BUFFER_SIZE);
-        let mut proof: [Node; MAX_DEPTH] = [Node::default(); MAX_DEPTH];
-        fill_in_proof::<MAX_DEPTH>(&proof_vec, &mut proof);
-        log_compute!();
-        let root = match self.try_apply_proof(current_root, EMPTY, leaf, &mut proof, index, false) {
-            Ok(new_root) => Ok(new_root),
-            Err(error) => match error {
-                CMTError::LeafContentsModified => self.append(leaf),
-                _ => Err(error),
-            },
-        };
-        log_compute!();
-        root
+        self.view_mut()?
+            .fill_empty_or_append(current_root, leaf, proof_vec, index)
     }
 
     /// On write conflict:
@@ -228,180 +269,61 @@ impl<const MAX_DEPTH: usize, const MAX_BUFFER_SIZE: usize> MerkleRoll<MAX_DEPTH,
         current_root: Node,
         previous_leaf: Node,
         new_leaf: Node,
-        proof_vec: &Vec<Node>,
+        proof_vec: &[Node],
         index: u32,
     ) -> Result<Node, CMTError> {
-        check_bounds(MAX_DEPTH, MAX_BUFFER_SIZE);
-        if index > self.rightmost_proof.index {
-            return Err(CMTError::LeafIndexOutOfBounds);
-        } else {
-            let mut proof: [Node; MAX_DEPTH] = [Node::default(); MAX_DEPTH];
-            fill_in_proof::<MAX_DEPTH>(&proof_vec, &mut proof);
-            log_compute!();
-            let root = self.try_apply_proof(
-                current_root,
-                previous_leaf,
-                new_leaf,
-                &mut proof,
-                index,
-                true,
-            );
-            log_compute!();
-            root
-        }
+        self.view_mut()?
+            .set_leaf(current_root, previous_leaf, new_leaf, proof_vec, index)
     }
 
-    /// Modifies the `proof` for leaf at `leaf_index`
-    /// in place by fast-forwarding the given `proof` through the
-    /// `changelog`s, starting at index `changelog_buffer_index`
-    /// Returns false if the leaf was updated in the change log
-    #[inline(always)]
-    fn fast_forward_proof(
-        &self,
-        leaf: &mut Node,
-        proof: &mut [Node; MAX_DEPTH],
-        leaf_index: u32,
-        mut changelog_buffer_index: u64,
-        use_full_buffer: bool,
-    ) -> bool {
-        solana_logging!(
-            "Fast-forwarding proof, starting index {}",
-            changelog_buffer_index
-        );
-        let mask: usize = MAX_BUFFER_SIZE - 1;
-
-        let mut updated_leaf = *leaf;
-        log_compute!();
-        // Modifies proof by iterating through the change log
-        loop {
-            // If use_full_buffer is false, this loop will terminate if the initial value of changelog_buffer_index is the active index
-            if !use_full_buffer && changelog_buffer_index == self.active_index {
-                break;
-            }
-            changelog_buffer_index = (changelog_buffer_index + 1) & mask as u64;
-            self.change_logs[changelog_buffer_index as usize].update_proof_or_leaf(
-                leaf_index,
-                proof,
-                &mut updated_leaf,
-            );
-            // If use_full_buffer is true, this loop will do 1 full pass of the change logs
-            if use_full_buffer && changelog_buffer_index == self.active_index {
-                break;
-            }
-        }
-        log_compute!();
-        let proof_leaf_unchanged = updated_leaf == *leaf;
-        *leaf = updated_leaf;
-        proof_leaf_unchanged
+    /// Atomically replaces several leaves, using proofs for the same root.
+    /// See [MerkleRollMut::set_leaves]
+    pub fn set_leaves(
+        &mut self,
+        current_root: Node,
+        updates: &mut [(u32, Node, Node, &mut [Node])],
+    ) -> Result<Node, CMTError> {
+        self.view_mut()?.set_leaves(current_root, updates)
     }
 
-    #[inline(always)]
-    fn find_root_in_changelog(&self, current_root: Node) -> Option<u64> {
-        let mask: usize = MAX_BUFFER_SIZE - 1;
-        for i in 0..self.buffer_size {
-            let j = self.active_index.wrapping_sub(i) & mask as u64;
-            if self.change_logs[j as usize].root == current_root {
-                return Some(j);
-            }
-        }
-        None
+    /// Atomically replaces several leaves, using a multiproof for their previous values.
+    /// See [MerkleRollMut::set_leaves_with_multiproof]
+    #[cfg(feature = "std")]
+    pub fn set_leaves_with_multiproof(
+        &mut self,
+        current_root: Node,
+        replacements: &[(u32, Node, Node)],
+        multiproof: &[Node],
+    ) -> Result<Node, CMTError> {
+        self.view_mut()?
+            .set_leaves_with_multiproof(current_root, replacements, multiproof)
     }
 
-    #[inline(always)]
-    fn check_valid_leaf(
-        &self,
+    /// Empties the leaf at `index` and tracks it in `free_list`.
+    /// See [MerkleRollMut::remove_leaf]
+    pub fn remove_leaf(
+        &mut self,
         current_root: Node,
-        leaf: Node,
-        proof: &mut [Node; MAX_DEPTH],
-        leaf_index: u32,
-        allow_inferred_proof: bool,
-    ) -> Result<bool, CMTError> {
-        let mask: usize = MAX_BUFFER_SIZE - 1;
-        let (changelog_index, use_full_buffer) = match self.find_root_in_changelog(current_root) {
-            Some(matching_changelog_index) => (matching_changelog_index, false),
-            None => {
-                if allow_inferred_proof {
-                    solana_logging!("Failed to find root in change log -> replaying full buffer");
-                    (
-                        self.active_index.wrapping_sub(self.buffer_size - 1) & mask as u64,
-                        true,
-                    )
-                } else {
-                    return Err(CMTError::RootNotFound);
-                }
-            }
-        };
-        let mut updatable_leaf_node = leaf;
-        let proof_leaf_unchanged = self.fast_forward_proof(
-            &mut updatable_leaf_node,
-            proof,
-            leaf_index,
-            changelog_index,
-            use_full_buffer,
-        );
-        if !proof_leaf_unchanged {
-            return Err(CMTError::LeafContentsModified);
-        }
-        Ok(recompute(updatable_leaf_node, proof, leaf_index) == self.get_change_log().root)
+        previous_leaf: Node,
+        proof_vec: &[Node],
+        index: u32,
+        free_list: &mut FreeListMut,
+    ) -> Result<Node, CMTError> {
+        self.view_mut()?
+            .remove_leaf(current_root, previous_leaf, proof_vec, index, free_list)
     }
 
-    /// Note: Enabling `allow_inferred_proof` will fast forward the given proof
-    /// from the beginning of the buffer in the case that the supplied root is not in the buffer.
-    #[inline(always)]
-    fn try_apply_proof(
+    /// Reuses the emptied leaf at `index`, which must be tracked by `free_list`.
+    /// See [MerkleRollMut::insert_into_free_slot]
+    pub fn insert_into_free_slot(
         &mut self,
         current_root: Node,
         leaf: Node,
-        new_leaf: Node,
-        proof: &mut [Node; MAX_DEPTH],
-        leaf_index: u32,
-        allow_inferred_proof: bool,
+        proof_vec: &[Node],
+        index: u32,
+        free_list: &mut FreeListMut,
     ) -> Result<Node, CMTError> {
-        solana_logging!("Active Index: {}", self.active_index);
-        solana_logging!("Rightmost Index: {}", self.rightmost_proof.index);
-        solana_logging!("Buffer Size: {}", self.buffer_size);
-        solana_logging!("Leaf Index: {}", leaf_index);
-        let valid_root =
-            self.check_valid_leaf(current_root, leaf, proof, leaf_index, allow_inferred_proof)?;
-        if !valid_root {
-            return Err(CMTError::InvalidProof);
-        }
-        self.update_internal_counters();
-        Ok(self.update_buffers_from_proof(new_leaf, proof, leaf_index))
-    }
-
-    /// Implements circular addition for changelog buffer index
-    fn update_internal_counters(&mut self) {
-        let mask: usize = MAX_BUFFER_SIZE - 1;
-        self.active_index += 1;
-        self.active_index &= mask as u64;
-        if self.buffer_size < MAX_BUFFER_SIZE as u64 {
-            self.buffer_size += 1;
-        }
-        self.sequence_number = self.sequence_number.saturating_add(1);
-    }
-
-    /// Creates a new root from a proof that is valid for the root at `self.active_index`
-    fn update_buffers_from_proof(&mut self, start: Node, proof: &[Node], index: u32) -> Node {
-        let change_log = &mut self.change_logs[self.active_index as usize];
-        // Also updates change_log's current root
-        let root = change_log.replace_and_recompute_path(index, start, proof);
-        // Update rightmost path if possible
-        if self.rightmost_proof.index < (1 << MAX_DEPTH) {
-            if index < self.rightmost_proof.index as u32 {
-                change_log.update_proof_or_leaf(
-                    self.rightmost_proof.index - 1,
-                    &mut self.rightmost_proof.proof,
-                    &mut self.rightmost_proof.leaf,
-                );
-            } else {
-                assert!(index == self.rightmost_proof.index);
-                solana_logging!("Appending rightmost leaf");
-                self.rightmost_proof.proof.copy_from_slice(&proof);
-                self.rightmost_proof.index = index + 1;
-                self.rightmost_proof.leaf = change_log.get_leaf();
-            }
-        }
-        root
+        self.view_mut()?
+            .insert_into_free_slot(current_root, leaf, proof_vec, index, free_list)
     }
 }
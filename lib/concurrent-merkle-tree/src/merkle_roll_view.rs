@@ -0,0 +1,1550 @@
+use crate::{
+    error::CMTError,
+    free_list::FreeListMut,
+    hasher::{Hasher, Keccak, LeafHashScheme},
+    root_history::RootHistoryRef,
+    state::{ChangeLogMut, ChangeLogRef, Node, PathMut, PathRef, EMPTY},
+    utils::{empty_node, fill_in_proof, hash_to_parent, recompute},
+};
+use bytemuck::{Pod, Zeroable};
+use core::{cell::Cell, marker::PhantomData, mem::size_of, ops::AddAssign};
+
+#[cfg(feature = "std")]
+use crate::{
+    multiproof::expand_multiproof,
+    snapshot::{ChangeLogSnapshot, MerkleRollSnapshot, PathSnapshot, SNAPSHOT_VERSION},
+};
+
+/// Largest `max_depth` supported by a merkle roll
+pub const MAX_SUPPORTED_DEPTH: usize = 30;
+
+/// How a proof is handled when its root is no longer in the change log buffer.
+///
+/// Such a proof can still be fast-forwarded through the change logs that are in the buffer,
+/// and is valid if none of the changes that were dropped from the buffer affected it.
+/// Operations that never replay the buffer, like `fill_empty_or_append`, ignore this policy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StalenessPolicy {
+    /// Rejects the proof with `RootNotFound`
+    RejectUnknownRoot,
+    /// Fast-forwards the proof through the given number of most recent change logs.
+    /// `ReplayLast(0)` rejects the proof like `RejectUnknownRoot`.
+    ReplayLast(u32),
+    /// Fast-forwards the proof through every change log in the buffer
+    FullReplay,
+}
+
+/// Work performed by the operations of a merkle roll view, accumulated since the view was
+/// created or since the last call to `take_compute_stats`.
+///
+/// These counters are proportional to the compute units an operation consumes on-chain,
+/// so they can be used to compare tree configurations without running a validator.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ComputeStats {
+    /// Number of nodes hashed, including hashes that only check a proof
+    pub hashes: u32,
+    /// Number of change logs read while looking for a root or fast-forwarding proofs
+    pub change_logs_scanned: u32,
+    /// Number of proof nodes overwritten while fast-forwarding proofs, including
+    /// the rightmost proof
+    pub proof_nodes_patched: u32,
+}
+
+impl AddAssign for ComputeStats {
+    fn add_assign(&mut self, other: Self) {
+        self.hashes = self.hashes.saturating_add(other.hashes);
+        self.change_logs_scanned = self
+            .change_logs_scanned
+            .saturating_add(other.change_logs_scanned);
+        self.proof_nodes_patched = self
+            .proof_nodes_patched
+            .saturating_add(other.proof_nodes_patched);
+    }
+}
+
+fn count_hashes(compute_stats: &Cell<ComputeStats>, hashes: usize) {
+    add_compute_stats(
+        compute_stats,
+        ComputeStats {
+            hashes: hashes as u32,
+            ..ComputeStats::default()
+        },
+    );
+}
+
+fn count_change_logs_scanned(compute_stats: &Cell<ComputeStats>, change_logs: usize) {
+    add_compute_stats(
+        compute_stats,
+        ComputeStats {
+            change_logs_scanned: change_logs as u32,
+            ..ComputeStats::default()
+        },
+    );
+}
+
+fn count_proof_nodes_patched(compute_stats: &Cell<ComputeStats>, nodes: usize) {
+    add_compute_stats(
+        compute_stats,
+        ComputeStats {
+            proof_nodes_patched: nodes as u32,
+            ..ComputeStats::default()
+        },
+    );
+}
+
+fn add_compute_stats(compute_stats: &Cell<ComputeStats>, other: ComputeStats) {
+    let mut stats = compute_stats.get();
+    stats += other;
+    compute_stats.set(stats);
+}
+
+/// Checks that `max_depth` and `max_buffer_size` describe a valid merkle roll
+#[inline(always)]
+pub fn check_dimensions(max_depth: usize, max_buffer_size: usize) -> Result<(), CMTError> {
+    // `max_buffer_size & (max_buffer_size - 1)` is 0 if and only if `max_buffer_size` is a power of 2 or 0
+    if max_depth == 0
+        || max_depth > MAX_SUPPORTED_DEPTH
+        || max_buffer_size == 0
+        || max_buffer_size & (max_buffer_size - 1) != 0
+    {
+        solana_logging!(
+            "Invalid merkle roll dimensions: max depth {}, max buffer size {}",
+            max_depth,
+            max_buffer_size
+        );
+        return Err(CMTError::InvalidDepthOrBufferSize);
+    }
+    Ok(())
+}
+
+/// Number of bytes used to store a `ChangeLog` (or `Path`) of depth `max_depth`
+pub fn change_log_size(max_depth: usize) -> usize {
+    (max_depth + 1) * size_of::<Node>() + 2 * size_of::<u32>()
+}
+
+/// Number of bytes used to store a merkle roll with the given dimensions.
+/// This is equal to `size_of::<MerkleRoll<MAX_DEPTH, MAX_BUFFER_SIZE>>()`.
+pub fn merkle_roll_size(max_depth: usize, max_buffer_size: usize) -> Result<usize, CMTError> {
+    check_dimensions(max_depth, max_buffer_size)?;
+    Ok(size_of::<MerkleRollMetadata>() + (max_buffer_size + 1) * change_log_size(max_depth))
+}
+
+/// Counters stored at the start of every merkle roll
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[repr(C)]
+pub struct MerkleRollMetadata {
+    pub sequence_number: u64,
+    /// Index of most recent root & changes
+    pub active_index: u64,
+    /// Number of active changes we are tracking
+    pub buffer_size: u64,
+}
+
+unsafe impl Zeroable for MerkleRollMetadata {}
+unsafe impl Pod for MerkleRollMetadata {}
+
+fn change_log_ref(change_logs: &[u8], max_depth: usize, i: usize) -> ChangeLogRef<'_> {
+    let size = change_log_size(max_depth);
+    let (root, rest) = change_logs[i * size..(i + 1) * size].split_at(size_of::<Node>());
+    let (path, rest) = rest.split_at(max_depth * size_of::<Node>());
+    ChangeLogRef {
+        root: bytemuck::from_bytes(root),
+        path: bytemuck::cast_slice(path),
+        index: *bytemuck::from_bytes(&rest[..size_of::<u32>()]),
+    }
+}
+
+fn change_log_mut(change_logs: &mut [u8], max_depth: usize, i: usize) -> ChangeLogMut<'_> {
+    let size = change_log_size(max_depth);
+    let (root, rest) = change_logs[i * size..(i + 1) * size].split_at_mut(size_of::<Node>());
+    let (path, rest) = rest.split_at_mut(max_depth * size_of::<Node>());
+    ChangeLogMut {
+        root: bytemuck::from_bytes_mut(root),
+        path: bytemuck::cast_slice_mut(path),
+        index: bytemuck::from_bytes_mut(&mut rest[..size_of::<u32>()]),
+    }
+}
+
+fn path_ref(bytes: &[u8], max_depth: usize) -> PathRef<'_> {
+    let (proof, rest) = bytes.split_at(max_depth * size_of::<Node>());
+    let (leaf, rest) = rest.split_at(size_of::<Node>());
+    PathRef {
+        proof: bytemuck::cast_slice(proof),
+        leaf: bytemuck::from_bytes(leaf),
+        index: *bytemuck::from_bytes(&rest[..size_of::<u32>()]),
+    }
+}
+
+fn path_mut(bytes: &mut [u8], max_depth: usize) -> PathMut<'_> {
+    let (proof, rest) = bytes.split_at_mut(max_depth * size_of::<Node>());
+    let (leaf, rest) = rest.split_at_mut(size_of::<Node>());
+    PathMut {
+        proof: bytemuck::cast_slice_mut(proof),
+        leaf: bytemuck::from_bytes_mut(leaf),
+        index: bytemuck::from_bytes_mut(&mut rest[..size_of::<u32>()]),
+    }
+}
+
+/// Hashes `old_root`, the root of a change log of depth `old_depth`, up to the root of
+/// `change_log` with empty right siblings, filling in the path above `old_depth`
+fn reroot_change_log<H: Hasher>(
+    change_log: &mut ChangeLogMut<'_>,
+    old_root: Node,
+    old_depth: usize,
+) {
+    let mut node = old_root;
+    for level in old_depth..change_log.path.len() {
+        change_log.path[level] = node;
+        hash_to_parent::<H>(&mut node, &empty_node::<H>(level as u32), true);
+    }
+    *change_log.root = node;
+}
+
+/// Same as [MerkleRollMut::migrate_from], but in place: `bytes` starts with a merkle roll of
+/// depth `old_max_depth` and buffer size `old_max_buffer_size`, and is overwritten with
+/// the same merkle roll of depth `max_depth` and buffer size `max_buffer_size`.
+/// Returns the current root.
+///
+/// Neither dimension can shrink, so that every change log is kept and moves to a higher
+/// offset. This lets a tree be migrated inside of its resized account without a second copy.
+pub fn migrate_in_place<H: Hasher>(
+    bytes: &mut [u8],
+    old_max_depth: usize,
+    old_max_buffer_size: usize,
+    max_depth: usize,
+    max_buffer_size: usize,
+) -> Result<Node, CMTError> {
+    check_dimensions(old_max_depth, old_max_buffer_size)?;
+    if old_max_depth > max_depth || old_max_buffer_size > max_buffer_size {
+        solana_logging!(
+            "Cannot migrate a tree of max depth {} and max buffer size {} in place to max depth {} and max buffer size {}",
+            old_max_depth,
+            old_max_buffer_size,
+            max_depth,
+            max_buffer_size
+        );
+        return Err(CMTError::InvalidDepthOrBufferSize);
+    }
+    if bytes.len() != merkle_roll_size(max_depth, max_buffer_size)? {
+        return Err(CMTError::InvalidMerkleRollBytes);
+    }
+    let (metadata, rest) = bytes.split_at_mut(size_of::<MerkleRollMetadata>());
+    // Metadata is unchanged, since the active change log keeps its index in the larger buffer
+    let metadata: MerkleRollMetadata =
+        *bytemuck::try_from_bytes(metadata).map_err(|_| CMTError::InvalidMerkleRollBytes)?;
+    let old_size = change_log_size(old_max_depth);
+    let size = change_log_size(max_depth);
+    // Nodes of the change log or path being moved, which can overlap their new location
+    let mut nodes = [EMPTY; MAX_SUPPORTED_DEPTH];
+
+    // The rightmost proof is stored last in both layouts, so it is moved first
+    let old_rightmost_proof = path_ref(&rest[old_max_buffer_size * old_size..], old_max_depth);
+    nodes[..old_max_depth].copy_from_slice(old_rightmost_proof.proof);
+    let leaf = *old_rightmost_proof.leaf;
+    let index = old_rightmost_proof.index;
+    let rightmost_proof_bytes = &mut rest[max_buffer_size * size..];
+    rightmost_proof_bytes.fill(0);
+    let rightmost_proof = path_mut(rightmost_proof_bytes, max_depth);
+    rightmost_proof.proof[..old_max_depth].copy_from_slice(&nodes[..old_max_depth]);
+    for (level, node) in rightmost_proof.proof[old_max_depth..]
+        .iter_mut()
+        .enumerate()
+    {
+        *node = empty_node::<H>((old_max_depth + level) as u32);
+    }
+    *rightmost_proof.leaf = leaf;
+    *rightmost_proof.index = index;
+
+    // Slot i of the new buffer holds the change log of an old slot at most i, so moving
+    // them from the last slot down only overwrites old slots that were already moved
+    let mask = max_buffer_size as u64 - 1;
+    let old_mask = old_max_buffer_size as u64 - 1;
+    for i in (0..max_buffer_size).rev() {
+        let age = metadata.active_index.wrapping_sub(i as u64) & mask;
+        let old_change_log = if age < metadata.buffer_size {
+            let old_change_log = change_log_ref(
+                rest,
+                old_max_depth,
+                (metadata.active_index.wrapping_sub(age) & old_mask) as usize,
+            );
+            nodes[..old_max_depth].copy_from_slice(old_change_log.path);
+            Some((*old_change_log.root, old_change_log.index))
+        } else {
+            None
+        };
+        rest[i * size..(i + 1) * size].fill(0);
+        if let Some((old_root, index)) = old_change_log {
+            let mut change_log = change_log_mut(rest, max_depth, i);
+            change_log.path[..old_max_depth].copy_from_slice(&nodes[..old_max_depth]);
+            reroot_change_log::<H>(&mut change_log, old_root, old_max_depth);
+            *change_log.index = index;
+        }
+    }
+    Ok(*change_log_ref(rest, max_depth, metadata.active_index as usize).root)
+}
+
+/// Read-only view of a merkle roll whose dimensions are only known at runtime.
+///
+/// The borrowed bytes use the same layout as `MerkleRoll<MAX_DEPTH, MAX_BUFFER_SIZE>`.
+pub struct MerkleRollRef<'a, H: Hasher = Keccak> {
+    max_depth: usize,
+    max_buffer_size: usize,
+    metadata: &'a MerkleRollMetadata,
+    change_logs: &'a [u8],
+    rightmost_proof: PathRef<'a>,
+    staleness_policy: StalenessPolicy,
+    compute_stats: Cell<ComputeStats>,
+    _hasher: PhantomData<H>,
+}
+
+impl<'a> MerkleRollRef<'a> {
+    pub fn new(
+        bytes: &'a [u8],
+        max_depth: usize,
+        max_buffer_size: usize,
+    ) -> Result<Self, CMTError> {
+        Self::with_hasher(bytes, max_depth, max_buffer_size, Keccak)
+    }
+}
+
+impl<'a, H: Hasher> MerkleRollRef<'a, H> {
+    /// Same as `new`, but nodes are hashed with `H` instead of keccak
+    pub fn with_hasher(
+        bytes: &'a [u8],
+        max_depth: usize,
+        max_buffer_size: usize,
+        _hasher: H,
+    ) -> Result<Self, CMTError> {
+        if bytes.len() != merkle_roll_size(max_depth, max_buffer_size)? {
+            return Err(CMTError::InvalidMerkleRollBytes);
+        }
+        let (metadata, rest) = bytes.split_at(size_of::<MerkleRollMetadata>());
+        let metadata =
+            bytemuck::try_from_bytes(metadata).map_err(|_| CMTError::InvalidMerkleRollBytes)?;
+        let (change_logs, rightmost_proof) =
+            rest.split_at(max_buffer_size * change_log_size(max_depth));
+        Ok(Self {
+            max_depth,
+            max_buffer_size,
+            metadata,
+            change_logs,
+            rightmost_proof: path_ref(rightmost_proof, max_depth),
+            staleness_policy: StalenessPolicy::FullReplay,
+            compute_stats: Cell::new(ComputeStats::default()),
+            _hasher: PhantomData,
+        })
+    }
+
+    /// Sets how proofs for roots that are no longer in the buffer are handled.
+    /// Defaults to [StalenessPolicy::FullReplay].
+    pub fn with_staleness_policy(mut self, staleness_policy: StalenessPolicy) -> Self {
+        self.staleness_policy = staleness_policy;
+        self
+    }
+
+    /// Returns the work performed by this view's operations so far, see [ComputeStats]
+    pub fn compute_stats(&self) -> ComputeStats {
+        self.compute_stats.get()
+    }
+
+    /// Returns the work performed by this view's operations so far and resets the counters
+    pub fn take_compute_stats(&self) -> ComputeStats {
+        self.compute_stats.take()
+    }
+
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    pub fn max_buffer_size(&self) -> usize {
+        self.max_buffer_size
+    }
+
+    pub fn sequence_number(&self) -> u64 {
+        self.metadata.sequence_number
+    }
+
+    pub fn active_index(&self) -> u64 {
+        self.metadata.active_index
+    }
+
+    pub fn buffer_size(&self) -> u64 {
+        self.metadata.buffer_size
+    }
+
+    pub fn rightmost_proof(&self) -> PathRef<'a> {
+        self.rightmost_proof
+    }
+
+    /// Returns the change log stored at position `i` of the changelog buffer
+    pub fn change_log(&self, i: usize) -> ChangeLogRef<'a> {
+        change_log_ref(self.change_logs, self.max_depth, i)
+    }
+
+    /// Returns the most recent change log
+    pub fn get_change_log(&self) -> ChangeLogRef<'a> {
+        self.change_log(self.metadata.active_index as usize)
+    }
+
+    /// Copies the counters, the change logs that are still in the buffer
+    /// and the rightmost proof into a [MerkleRollSnapshot]
+    #[cfg(feature = "std")]
+    pub fn to_snapshot(&self) -> MerkleRollSnapshot {
+        let mask = self.max_buffer_size as u64 - 1;
+        let buffer_size = self.metadata.buffer_size;
+        let change_logs = (0..buffer_size)
+            .map(|i| {
+                let j = self.metadata.active_index.wrapping_sub(buffer_size - 1 - i) & mask;
+                let change_log = self.change_log(j as usize);
+                ChangeLogSnapshot {
+                    root: *change_log.root,
+                    path: change_log.path.to_vec(),
+                    index: change_log.index,
+                }
+            })
+            .collect();
+        MerkleRollSnapshot {
+            version: SNAPSHOT_VERSION,
+            max_depth: self.max_depth as u32,
+            max_buffer_size: self.max_buffer_size as u32,
+            sequence_number: self.metadata.sequence_number,
+            active_index: self.metadata.active_index,
+            change_logs,
+            rightmost_proof: PathSnapshot {
+                proof: self.rightmost_proof.proof.to_vec(),
+                leaf: *self.rightmost_proof.leaf,
+                index: self.rightmost_proof.index,
+            },
+        }
+    }
+
+    pub fn prove_leaf(
+        &self,
+        current_root: Node,
+        leaf: Node,
+        proof_vec: &[Node],
+        leaf_index: u32,
+    ) -> Result<Node, CMTError> {
+        if leaf_index > self.rightmost_proof.index {
+            solana_logging!(
+                "Received an index larger than the rightmost index {} > {}",
+                leaf_index,
+                self.rightmost_proof.index
+            );
+            Err(CMTError::LeafIndexOutOfBounds)
+        } else {
+            let mut proof = [EMPTY; MAX_SUPPORTED_DEPTH];
+            let proof = &mut proof[..self.max_depth];
+            fill_in_proof::<H>(proof_vec, proof)?;
+            let valid_root = self.check_valid_leaf(current_root, leaf, proof, leaf_index, true)?;
+            if !valid_root {
+                solana_logging!("Proof failed to verify");
+                return Err(CMTError::InvalidProof);
+            }
+            Ok(Node::default())
+        }
+    }
+
+    /// Same as `prove_leaf`, but the leaf is given as its raw bytes, which are hashed with
+    /// `leaf_hash_scheme`. Returns the leaf node that was proven.
+    ///
+    /// This lets callers check that some exact data is in the tree without knowing how
+    /// it is hashed into a leaf.
+    pub fn prove_leaf_preimage(
+        &self,
+        current_root: Node,
+        leaf_hash_scheme: LeafHashScheme,
+        leaf_preimage: &[u8],
+        proof_vec: &[Node],
+        leaf_index: u32,
+    ) -> Result<Node, CMTError> {
+        let leaf = leaf_hash_scheme.hash_leaf(leaf_preimage);
+        count_hashes(&self.compute_stats, 1);
+        self.prove_leaf(current_root, leaf, proof_vec, leaf_index)?;
+        Ok(leaf)
+    }
+
+    /// Proves several leaves at once with a multiproof for `current_root`, see [crate::multiproof].
+    /// `leaves` are `(index, leaf)` pairs sorted by increasing index.
+    ///
+    /// The multiproof is expanded into one proof per leaf, which is checked like in `prove_leaf`,
+    /// so it may be for a recent root as long as none of the leaves were modified since.
+    #[cfg(feature = "std")]
+    pub fn prove_leaves(
+        &self,
+        current_root: Node,
+        leaves: &[(u32, Node)],
+        multiproof: &[Node],
+    ) -> Result<Node, CMTError> {
+        let proofs = expand_multiproof::<H>(leaves, multiproof, self.max_depth as u32)?;
+        for ((leaf_index, leaf), proof) in leaves.iter().zip(proofs.iter()) {
+            self.prove_leaf(current_root, *leaf, proof, *leaf_index)?;
+        }
+        Ok(Node::default())
+    }
+
+    /// Proves that `leaf` was at `leaf_index` when the tree had root `root`, which must be
+    /// in the change log buffer or in `root_history`.
+    ///
+    /// Unlike `prove_leaf`, the proof is not fast-forwarded: it must be valid for `root`
+    /// itself, and the leaf may have been modified since.
+    pub fn prove_leaf_at_root(
+        &self,
+        root_history: &RootHistoryRef<'_>,
+        root: Node,
+        leaf: Node,
+        proof_vec: &[Node],
+        leaf_index: u32,
+    ) -> Result<Node, CMTError> {
+        if leaf_index as u64 >= 1 << self.max_depth {
+            return Err(CMTError::LeafIndexOutOfBounds);
+        }
+        if root_history.find(root).is_none() && self.find_root_in_changelog(root).is_none() {
+            solana_logging!("Root not found in change log buffer or root history");
+            return Err(CMTError::RootNotFound);
+        }
+        let mut proof = [EMPTY; MAX_SUPPORTED_DEPTH];
+        let proof = &mut proof[..self.max_depth];
+        fill_in_proof::<H>(proof_vec, proof)?;
+        count_hashes(&self.compute_stats, self.max_depth);
+        if recompute::<H>(leaf, proof, leaf_index) != root {
+            solana_logging!("Proof failed to verify");
+            return Err(CMTError::InvalidProof);
+        }
+        Ok(Node::default())
+    }
+
+    /// Proves that the leaf at `leaf_index` is empty in the current tree.
+    ///
+    /// Leaves at or after the rightmost index have never been written,
+    /// so they are empty whatever the supplied proof is.
+    pub fn prove_empty_leaf(
+        &self,
+        current_root: Node,
+        proof_vec: &[Node],
+        leaf_index: u32,
+    ) -> Result<Node, CMTError> {
+        if leaf_index as u64 >= 1 << self.max_depth {
+            return Err(CMTError::LeafIndexOutOfBounds);
+        }
+        if leaf_index >= self.rightmost_proof.index {
+            return Ok(Node::default());
+        }
+        self.prove_leaf(current_root, EMPTY, proof_vec, leaf_index)
+    }
+
+    /// Proves that every leaf at or after index `leaf_count` is empty in the current tree,
+    /// meaning that the tree holds no leaves past index `leaf_count - 1`.
+    ///
+    /// `proof_vec` is the proof of the empty leaf at index `leaf_count`. Once fast-forwarded,
+    /// each of its nodes that is a right sibling must be the root of an empty subtree.
+    pub fn prove_leaf_count(
+        &self,
+        current_root: Node,
+        proof_vec: &[Node],
+        leaf_count: u32,
+    ) -> Result<Node, CMTError> {
+        // This also covers every `leaf_count` that is larger than the tree capacity
+        if leaf_count >= self.rightmost_proof.index {
+            return Ok(Node::default());
+        }
+        let mut proof = [EMPTY; MAX_SUPPORTED_DEPTH];
+        let proof = &mut proof[..self.max_depth];
+        fill_in_proof::<H>(proof_vec, proof)?;
+        let valid_root = self.check_valid_leaf(current_root, EMPTY, proof, leaf_count, true)?;
+        if !valid_root {
+            solana_logging!("Proof failed to verify");
+            return Err(CMTError::InvalidProof);
+        }
+        for (i, node) in proof.iter().enumerate() {
+            if (leaf_count >> i) & 1 == 0 && *node != empty_node::<H>(i as u32) {
+                solana_logging!("Found a non-empty subtree at level {}", i);
+                return Err(CMTError::LeafCountExceeded);
+            }
+        }
+        Ok(Node::default())
+    }
+
+    /// Brings a stale proof up to date, so that off-chain clients can repair
+    /// their proofs from the merkle roll account instead of re-querying an indexer.
+    ///
+    /// `proof` must be a proof of `leaf` at `leaf_index` for `root`, which is some recent root
+    /// of the tree. It is fast-forwarded in place through the change logs written since `root`,
+    /// the same way the program does when a stale proof is submitted, so it must hold exactly
+    /// `max_depth` nodes. Returns the current root, for which `proof` is now valid.
+    ///
+    /// Fails with `LeafContentsModified` if the leaf itself was replaced since `root`.
+    pub fn refresh_proof(
+        &self,
+        root: Node,
+        leaf: Node,
+        proof: &mut [Node],
+        leaf_index: u32,
+    ) -> Result<Node, CMTError> {
+        if leaf_index as u64 >= 1 << self.max_depth || leaf_index > self.rightmost_proof.index {
+            return Err(CMTError::LeafIndexOutOfBounds);
+        }
+        if proof.len() != self.max_depth {
+            return Err(CMTError::ProofLengthMismatch);
+        }
+        if !self.check_valid_leaf(root, leaf, proof, leaf_index, true)? {
+            return Err(CMTError::InvalidProof);
+        }
+        Ok(*self.get_change_log().root)
+    }
+
+    /// Modifies the `proof` for leaf at `leaf_index`
+    /// in place by fast-forwarding the given `proof` through the
+    /// `changelog`s, starting at index `changelog_buffer_index`
+    /// Returns false if the leaf was updated in the change log
+    #[inline(always)]
+    fn fast_forward_proof(
+        &self,
+        leaf: &mut Node,
+        proof: &mut [Node],
+        leaf_index: u32,
+        mut changelog_buffer_index: u64,
+        use_full_buffer: bool,
+    ) -> bool {
+        solana_logging!(
+            "Fast-forwarding proof, starting index {}",
+            changelog_buffer_index
+        );
+        let mask: usize = self.max_buffer_size - 1;
+
+        let mut updated_leaf = *leaf;
+        log_compute!();
+        // Modifies proof by iterating through the change log
+        loop {
+            // If use_full_buffer is false, this loop will terminate if the initial value of changelog_buffer_index is the active index
+            if !use_full_buffer && changelog_buffer_index == self.metadata.active_index {
+                break;
+            }
+            changelog_buffer_index = (changelog_buffer_index + 1) & mask as u64;
+            count_change_logs_scanned(&self.compute_stats, 1);
+            if self
+                .change_log(changelog_buffer_index as usize)
+                .update_proof_or_leaf(leaf_index, proof, &mut updated_leaf)
+            {
+                count_proof_nodes_patched(&self.compute_stats, 1);
+            }
+            // If use_full_buffer is true, this loop will do 1 full pass of the change logs
+            if use_full_buffer && changelog_buffer_index == self.metadata.active_index {
+                break;
+            }
+        }
+        log_compute!();
+        let proof_leaf_unchanged = updated_leaf == *leaf;
+        *leaf = updated_leaf;
+        proof_leaf_unchanged
+    }
+
+    #[inline(always)]
+    fn find_root_in_changelog(&self, current_root: Node) -> Option<u64> {
+        let mask: usize = self.max_buffer_size - 1;
+        for i in 0..self.metadata.buffer_size {
+            let j = self.metadata.active_index.wrapping_sub(i) & mask as u64;
+            count_change_logs_scanned(&self.compute_stats, 1);
+            if *self.change_log(j as usize).root == current_root {
+                return Some(j);
+            }
+        }
+        None
+    }
+
+    #[inline(always)]
+    fn check_valid_leaf(
+        &self,
+        current_root: Node,
+        leaf: Node,
+        proof: &mut [Node],
+        leaf_index: u32,
+        allow_inferred_proof: bool,
+    ) -> Result<bool, CMTError> {
+        let mask: usize = self.max_buffer_size - 1;
+        let (changelog_index, use_full_buffer) = match self.find_root_in_changelog(current_root) {
+            Some(matching_changelog_index) => (matching_changelog_index, false),
+            None => {
+                let replayed = if allow_inferred_proof {
+                    match self.staleness_policy {
+                        StalenessPolicy::RejectUnknownRoot => 0,
+                        StalenessPolicy::ReplayLast(n) => self.metadata.buffer_size.min(n as u64),
+                        StalenessPolicy::FullReplay => self.metadata.buffer_size,
+                    }
+                } else {
+                    0
+                };
+                if replayed == 0 {
+                    return Err(CMTError::RootNotFound);
+                }
+                solana_logging!(
+                    "Failed to find root in change log -> replaying {} change logs",
+                    replayed
+                );
+                // Start right before the oldest replayed change log
+                (
+                    self.metadata.active_index.wrapping_sub(replayed) & mask as u64,
+                    true,
+                )
+            }
+        };
+        let mut updatable_leaf_node = leaf;
+        let proof_leaf_unchanged = self.fast_forward_proof(
+            &mut updatable_leaf_node,
+            proof,
+            leaf_index,
+            changelog_index,
+            use_full_buffer,
+        );
+        if !proof_leaf_unchanged {
+            return Err(CMTError::LeafContentsModified);
+        }
+        count_hashes(&self.compute_stats, self.max_depth);
+        Ok(recompute::<H>(updatable_leaf_node, proof, leaf_index) == *self.get_change_log().root)
+    }
+}
+
+/// Mutable view of a merkle roll whose dimensions are only known at runtime.
+///
+/// Allows trees of any depth up to [MAX_SUPPORTED_DEPTH] and any power of 2 buffer size
+/// to be modified in place, for example directly inside of account data.
+/// The borrowed bytes use the same layout as `MerkleRoll<MAX_DEPTH, MAX_BUFFER_SIZE>`.
+pub struct MerkleRollMut<'a, H: Hasher = Keccak> {
+    max_depth: usize,
+    max_buffer_size: usize,
+    metadata: &'a mut MerkleRollMetadata,
+    change_logs: &'a mut [u8],
+    rightmost_proof: PathMut<'a>,
+    staleness_policy: StalenessPolicy,
+    compute_stats: Cell<ComputeStats>,
+    _hasher: PhantomData<H>,
+}
+
+impl<'a> MerkleRollMut<'a> {
+    pub fn new(
+        bytes: &'a mut [u8],
+        max_depth: usize,
+        max_buffer_size: usize,
+    ) -> Result<Self, CMTError> {
+        Self::with_hasher(bytes, max_depth, max_buffer_size, Keccak)
+    }
+}
+
+impl<'a, H: Hasher> MerkleRollMut<'a, H> {
+    /// Same as `new`, but nodes are hashed with `H` instead of keccak
+    pub fn with_hasher(
+        bytes: &'a mut [u8],
+        max_depth: usize,
+        max_buffer_size: usize,
+        _hasher: H,
+    ) -> Result<Self, CMTError> {
+        if bytes.len() != merkle_roll_size(max_depth, max_buffer_size)? {
+            return Err(CMTError::InvalidMerkleRollBytes);
+        }
+        let (metadata, rest) = bytes.split_at_mut(size_of::<MerkleRollMetadata>());
+        let metadata =
+            bytemuck::try_from_bytes_mut(metadata).map_err(|_| CMTError::InvalidMerkleRollBytes)?;
+        let (change_logs, rightmost_proof) =
+            rest.split_at_mut(max_buffer_size * change_log_size(max_depth));
+        Ok(Self {
+            max_depth,
+            max_buffer_size,
+            metadata,
+            change_logs,
+            rightmost_proof: path_mut(rightmost_proof, max_depth),
+            staleness_policy: StalenessPolicy::FullReplay,
+            compute_stats: Cell::new(ComputeStats::default()),
+            _hasher: PhantomData,
+        })
+    }
+
+    /// See [MerkleRollRef::with_staleness_policy]
+    pub fn with_staleness_policy(mut self, staleness_policy: StalenessPolicy) -> Self {
+        self.staleness_policy = staleness_policy;
+        self
+    }
+
+    /// Borrows this merkle roll as a read-only view
+    pub fn view(&self) -> MerkleRollRef<'_, H> {
+        MerkleRollRef {
+            max_depth: self.max_depth,
+            max_buffer_size: self.max_buffer_size,
+            metadata: self.metadata,
+            change_logs: self.change_logs,
+            rightmost_proof: self.rightmost_proof.view(),
+            staleness_policy: self.staleness_policy,
+            compute_stats: Cell::new(self.compute_stats.get()),
+            _hasher: PhantomData,
+        }
+    }
+
+    /// Runs `f` on a read-only view, then keeps the work it performed in this view's stats
+    fn with_view<T>(&self, f: impl FnOnce(&MerkleRollRef<'_, H>) -> T) -> T {
+        let view = self.view();
+        let result = f(&view);
+        self.compute_stats.set(view.compute_stats.get());
+        result
+    }
+
+    /// See [MerkleRollRef::compute_stats]
+    pub fn compute_stats(&self) -> ComputeStats {
+        self.compute_stats.get()
+    }
+
+    /// See [MerkleRollRef::take_compute_stats]
+    pub fn take_compute_stats(&self) -> ComputeStats {
+        self.compute_stats.take()
+    }
+
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    pub fn max_buffer_size(&self) -> usize {
+        self.max_buffer_size
+    }
+
+    pub fn sequence_number(&self) -> u64 {
+        self.metadata.sequence_number
+    }
+
+    /// Returns the most recent change log
+    pub fn get_change_log(&self) -> ChangeLogRef<'_> {
+        change_log_ref(
+            self.change_logs,
+            self.max_depth,
+            self.metadata.active_index as usize,
+        )
+    }
+
+    /// Overwrites this merkle roll with the contents of `old`, a merkle roll that can have
+    /// a smaller `max_depth` and a different `max_buffer_size`. Returns the current root.
+    ///
+    /// If this merkle roll is deeper, the old tree becomes its leftmost subtree: every change log
+    /// and the rightmost proof are hashed up to the new root with empty right siblings, so that
+    /// leaves keep their index. Only the most recent change logs that fit in this buffer are kept.
+    pub fn migrate_from(&mut self, old: &MerkleRollRef<'_, H>) -> Result<Node, CMTError> {
+        let old_depth = old.max_depth;
+        if old_depth > self.max_depth {
+            solana_logging!(
+                "Cannot migrate a tree of depth {} to depth {}",
+                old_depth,
+                self.max_depth
+            );
+            return Err(CMTError::InvalidDepthOrBufferSize);
+        }
+        let mask = self.max_buffer_size as u64 - 1;
+        let old_mask = old.max_buffer_size as u64 - 1;
+        let kept = (old.metadata.buffer_size as usize).min(self.max_buffer_size);
+        let active_index = old.metadata.active_index & mask;
+        self.change_logs.fill(0);
+        for age in 0..kept as u64 {
+            let old_change_log =
+                old.change_log((old.metadata.active_index.wrapping_sub(age) & old_mask) as usize);
+            let mut change_log = change_log_mut(
+                self.change_logs,
+                self.max_depth,
+                (active_index.wrapping_sub(age) & mask) as usize,
+            );
+            change_log.path[..old_depth].copy_from_slice(old_change_log.path);
+            reroot_change_log::<H>(&mut change_log, *old_change_log.root, old_depth);
+            *change_log.index = old_change_log.index;
+            count_hashes(&self.compute_stats, self.max_depth - old_depth);
+        }
+        self.metadata.sequence_number = old.metadata.sequence_number;
+        self.metadata.active_index = if kept == 0 { 0 } else { active_index };
+        self.metadata.buffer_size = kept as u64;
+
+        let old_rightmost_proof = old.rightmost_proof;
+        self.rightmost_proof.proof[..old_depth].copy_from_slice(old_rightmost_proof.proof);
+        for (level, node) in self.rightmost_proof.proof[old_depth..]
+            .iter_mut()
+            .enumerate()
+        {
+            *node = empty_node::<H>((old_depth + level) as u32);
+        }
+        *self.rightmost_proof.leaf = *old_rightmost_proof.leaf;
+        *self.rightmost_proof.index = old_rightmost_proof.index;
+        Ok(*self.get_change_log().root)
+    }
+
+    /// See [MerkleRollRef::to_snapshot]
+    #[cfg(feature = "std")]
+    pub fn to_snapshot(&self) -> MerkleRollSnapshot {
+        self.view().to_snapshot()
+    }
+
+    /// Overwrites this merkle roll with the contents of `snapshot`.
+    ///
+    /// The snapshot may come from a merkle roll with a different `max_buffer_size`.
+    /// If it holds more change logs than this buffer, only the most recent ones are kept,
+    /// so proofs for the oldest roots can no longer be fast-forwarded.
+    #[cfg(feature = "std")]
+    pub fn load_snapshot(&mut self, snapshot: &MerkleRollSnapshot) -> Result<(), CMTError> {
+        snapshot.validate(self.max_depth)?;
+        let mask = self.max_buffer_size as u64 - 1;
+        let kept = snapshot.change_logs.len().min(self.max_buffer_size);
+        let active_index = snapshot.active_index & mask;
+        self.change_logs.fill(0);
+        for (i, change_log) in snapshot.change_logs[snapshot.change_logs.len() - kept..]
+            .iter()
+            .enumerate()
+        {
+            let j = active_index.wrapping_sub((kept - 1 - i) as u64) & mask;
+            let stored = change_log_mut(self.change_logs, self.max_depth, j as usize);
+            *stored.root = change_log.root;
+            stored.path.copy_from_slice(&change_log.path);
+            *stored.index = change_log.index;
+        }
+        self.metadata.sequence_number = snapshot.sequence_number;
+        self.metadata.active_index = if kept == 0 { 0 } else { active_index };
+        self.metadata.buffer_size = kept as u64;
+        self.rightmost_proof
+            .proof
+            .copy_from_slice(&snapshot.rightmost_proof.proof);
+        *self.rightmost_proof.leaf = snapshot.rightmost_proof.leaf;
+        *self.rightmost_proof.index = snapshot.rightmost_proof.index;
+        Ok(())
+    }
+
+    pub fn initialize(&mut self) -> Result<Node, CMTError> {
+        let max_depth = self.max_depth;
+        for (i, node) in self.rightmost_proof.proof.iter_mut().enumerate() {
+            *node = empty_node::<H>(i as u32);
+        }
+        *self.rightmost_proof.leaf = EMPTY;
+        *self.rightmost_proof.index = 0;
+        let change_log = change_log_mut(self.change_logs, max_depth, 0);
+        for (i, node) in change_log.path.iter_mut().enumerate() {
+            *node = empty_node::<H>(i as u32);
+        }
+        *change_log.root = empty_node::<H>(max_depth as u32);
+        self.metadata.sequence_number = 0;
+        self.metadata.active_index = 0;
+        self.metadata.buffer_size = 1;
+        Ok(*change_log.root)
+    }
+
+    pub fn initialize_with_root(
+        &mut self,
+        root: Node,
+        rightmost_leaf: Node,
+        proof_vec: &[Node],
+        index: u32,
+    ) -> Result<Node, CMTError> {
+        if proof_vec.len() != self.max_depth {
+            return Err(CMTError::ProofLengthMismatch);
+        }
+        if index >= 1 << self.max_depth {
+            return Err(CMTError::LeafIndexOutOfBounds);
+        }
+        count_hashes(&self.compute_stats, self.max_depth);
+        // The first change log holds the path of the rightmost leaf, so that replaying it
+        // while fast-forwarding a proof keeps the proof valid
+        let mut change_log = change_log_mut(self.change_logs, self.max_depth, 0);
+        if root != change_log.replace_and_recompute_path::<H>(index, rightmost_leaf, proof_vec) {
+            solana_logging!("Root does not match the rightmost leaf and proof");
+            return Err(CMTError::RootMismatchOnInit);
+        }
+        self.rightmost_proof.proof.copy_from_slice(proof_vec);
+        *self.rightmost_proof.index = index + 1;
+        *self.rightmost_proof.leaf = rightmost_leaf;
+        self.metadata.sequence_number = 1;
+        self.metadata.active_index = 0;
+        self.metadata.buffer_size = 1;
+        Ok(root)
+    }
+
+    pub fn prove_leaf(
+        &self,
+        current_root: Node,
+        leaf: Node,
+        proof_vec: &[Node],
+        leaf_index: u32,
+    ) -> Result<Node, CMTError> {
+        self.with_view(|view| view.prove_leaf(current_root, leaf, proof_vec, leaf_index))
+    }
+
+    /// See [MerkleRollRef::prove_leaf_preimage]
+    pub fn prove_leaf_preimage(
+        &self,
+        current_root: Node,
+        leaf_hash_scheme: LeafHashScheme,
+        leaf_preimage: &[u8],
+        proof_vec: &[Node],
+        leaf_index: u32,
+    ) -> Result<Node, CMTError> {
+        self.with_view(|view| {
+            view.prove_leaf_preimage(
+                current_root,
+                leaf_hash_scheme,
+                leaf_preimage,
+                proof_vec,
+                leaf_index,
+            )
+        })
+    }
+
+    /// See [MerkleRollRef::prove_leaves]
+    #[cfg(feature = "std")]
+    pub fn prove_leaves(
+        &self,
+        current_root: Node,
+        leaves: &[(u32, Node)],
+        multiproof: &[Node],
+    ) -> Result<Node, CMTError> {
+        self.with_view(|view| view.prove_leaves(current_root, leaves, multiproof))
+    }
+
+    /// See [MerkleRollRef::prove_leaf_at_root]
+    pub fn prove_leaf_at_root(
+        &self,
+        root_history: &RootHistoryRef<'_>,
+        root: Node,
+        leaf: Node,
+        proof_vec: &[Node],
+        leaf_index: u32,
+    ) -> Result<Node, CMTError> {
+        self.with_view(|view| {
+            view.prove_leaf_at_root(root_history, root, leaf, proof_vec, leaf_index)
+        })
+    }
+
+    pub fn prove_empty_leaf(
+        &self,
+        current_root: Node,
+        proof_vec: &[Node],
+        leaf_index: u32,
+    ) -> Result<Node, CMTError> {
+        self.with_view(|view| view.prove_empty_leaf(current_root, proof_vec, leaf_index))
+    }
+
+    pub fn prove_leaf_count(
+        &self,
+        current_root: Node,
+        proof_vec: &[Node],
+        leaf_count: u32,
+    ) -> Result<Node, CMTError> {
+        self.with_view(|view| view.prove_leaf_count(current_root, proof_vec, leaf_count))
+    }
+
+    /// See [MerkleRollRef::refresh_proof]
+    pub fn refresh_proof(
+        &self,
+        root: Node,
+        leaf: Node,
+        proof: &mut [Node],
+        leaf_index: u32,
+    ) -> Result<Node, CMTError> {
+        self.with_view(|view| view.refresh_proof(root, leaf, proof, leaf_index))
+    }
+
+    /// Only used to initialize right most path for a completely empty tree
+    #[inline(always)]
+    fn initialize_tree_from_append(&mut self, leaf: Node) -> Result<Node, CMTError> {
+        let mut proof = [EMPTY; MAX_SUPPORTED_DEPTH];
+        let proof = &mut proof[..self.max_depth];
+        proof.copy_from_slice(self.rightmost_proof.proof);
+        count_hashes(&self.compute_stats, self.max_depth);
+        let old_root = recompute::<H>(EMPTY, proof, 0);
+        if old_root == empty_node::<H>(self.max_depth as u32) {
+            self.try_apply_proof(old_root, EMPTY, leaf, proof, 0, false)
+        } else {
+            Err(CMTError::TreeAlreadyInitialized)
+        }
+    }
+
+    /// Basic operation that always succeeds
+    pub fn append(&mut self, mut node: Node) -> Result<Node, CMTError> {
+        if node == EMPTY {
+            return Err(CMTError::CannotAppendEmptyNode);
+        }
+        let rightmost_index = *self.rightmost_proof.index;
+        if rightmost_index >= 1 << self.max_depth {
+            return Err(CMTError::TreeFull);
+        }
+        if rightmost_index == 0 {
+            return self.initialize_tree_from_append(node);
+        }
+        let leaf = node;
+        let intersection = rightmost_index.trailing_zeros() as usize;
+        let mut change_list = [EMPTY; MAX_SUPPORTED_DEPTH];
+        let mut intersection_node = *self.rightmost_proof.leaf;
+
+        let rightmost_proof = &mut self.rightmost_proof.proof;
+        for (i, change) in change_list.iter_mut().enumerate().take(self.max_depth) {
+            *change = node;
+            if i < intersection {
+                // Compute proof to the appended node from empty nodes
+                let sibling = empty_node::<H>(i as u32);
+                hash_to_parent::<H>(
+                    &mut intersection_node,
+                    &rightmost_proof[i],
+                    ((rightmost_index - 1) >> i) & 1 == 0,
+                );
+                hash_to_parent::<H>(&mut node, &sibling, true);
+                rightmost_proof[i] = sibling;
+            } else if i == intersection {
+                // Compute the where the new node intersects the main tree
+                hash_to_parent::<H>(&mut node, &intersection_node, false);
+                rightmost_proof[intersection] = intersection_node;
+            } else {
+                // Update the change list path up to the root
+                hash_to_parent::<H>(
+                    &mut node,
+                    &rightmost_proof[i],
+                    ((rightmost_index - 1) >> i) & 1 == 0,
+                );
+            }
+        }
+        // One hash per level, plus one per level below the intersection for the previous leaf
+        count_hashes(&self.compute_stats, self.max_depth + intersection);
+
+        self.update_internal_counters();
+        let change_log = change_log_mut(
+            self.change_logs,
+            self.max_depth,
+            self.metadata.active_index as usize,
+        );
+        *change_log.root = node;
+        change_log
+            .path
+            .copy_from_slice(&change_list[..self.max_depth]);
+        *change_log.index = rightmost_index;
+        *self.rightmost_proof.index = rightmost_index + 1;
+        *self.rightmost_proof.leaf = leaf;
+        Ok(node)
+    }
+
+    /// Appends `leaves` to the tree, in order, starting at the rightmost index.
+    ///
+    /// Leaves are grouped into the largest aligned, full subtrees possible. Each subtree is
+    /// hashed once and then grafted onto the rightmost path, producing a single change log.
+    /// A batch of `n` leaves produces at most `2 * log2(n)` change logs instead of `n`.
+    ///
+    /// Either all leaves are appended, or none are. An empty batch is a no-op.
+    pub fn append_batch(&mut self, leaves: &[Node]) -> Result<Node, CMTError> {
+        if leaves.contains(&EMPTY) {
+            return Err(CMTError::CannotAppendEmptyNode);
+        }
+        let rightmost_index = *self.rightmost_proof.index as usize;
+        if rightmost_index + leaves.len() > 1 << self.max_depth {
+            return Err(CMTError::TreeFull);
+        }
+        let mut root = *self.get_change_log().root;
+        let mut start = 0;
+        while start < leaves.len() {
+            let index = rightmost_index + start;
+            let remaining = leaves.len() - start;
+            // The subtree must fit in the remaining leaves and be aligned with its starting index
+            let mut subtree_depth = (usize::BITS - 1 - remaining.leading_zeros()) as usize;
+            if index != 0 {
+                subtree_depth = subtree_depth.min(index.trailing_zeros() as usize);
+            }
+            let subtree = &leaves[start..start + (1 << subtree_depth)];
+
+            // Hash every leaf but the last one into the proof of the subtree's rightmost leaf
+            let mut subtree_proof = [EMPTY; MAX_SUPPORTED_DEPTH];
+            for (i, leaf) in subtree[..subtree.len() - 1].iter().enumerate() {
+                let mut node = *leaf;
+                let mut level = 0;
+                while (i >> level) & 1 == 1 {
+                    hash_to_parent::<H>(&mut node, &subtree_proof[level], false);
+                    level += 1;
+                }
+                count_hashes(&self.compute_stats, level);
+                subtree_proof[level] = node;
+            }
+            root =
+                self.graft_subtree(subtree[subtree.len() - 1], &subtree_proof[..subtree_depth])?;
+            start += subtree.len();
+        }
+        Ok(root)
+    }
+
+    /// Attaches a precomputed, full subtree of `power(2, subtree_depth)` leaves to the tree,
+    /// starting at the rightmost index, in a single operation.
+    ///
+    /// The rightmost index must be a multiple of the subtree's leaf count, and
+    /// `subtree_rightmost_proof` must prove `subtree_rightmost_leaf` against `subtree_root`.
+    /// Produces a single change log, for the path of the subtree's rightmost leaf.
+    pub fn append_subtree(
+        &mut self,
+        subtree_root: Node,
+        subtree_depth: u32,
+        subtree_rightmost_leaf: Node,
+        subtree_rightmost_proof: &[Node],
+    ) -> Result<Node, CMTError> {
+        if subtree_rightmost_proof.len() != subtree_depth as usize {
+            return Err(CMTError::ProofLengthMismatch);
+        }
+        if subtree_rightmost_leaf == EMPTY {
+            return Err(CMTError::CannotAppendEmptyNode);
+        }
+        let rightmost_index = *self.rightmost_proof.index as u64;
+        if subtree_depth as usize > self.max_depth
+            || rightmost_index + (1 << subtree_depth) > 1 << self.max_depth
+        {
+            return Err(CMTError::TreeFull);
+        }
+        if rightmost_index & ((1 << subtree_depth) - 1) != 0 {
+            solana_logging!(
+                "Subtree of depth {} cannot start at index {}",
+                subtree_depth,
+                rightmost_index
+            );
+            return Err(CMTError::SubtreeNotAligned);
+        }
+        let subtree_rightmost_index = (1 << subtree_depth) - 1;
+        count_hashes(&self.compute_stats, subtree_depth as usize);
+        if recompute::<H>(
+            subtree_rightmost_leaf,
+            subtree_rightmost_proof,
+            subtree_rightmost_index,
+        ) != subtree_root
+        {
+            return Err(CMTError::InvalidProof);
+        }
+        self.graft_subtree(subtree_rightmost_leaf, subtree_rightmost_proof)
+    }
+
+    /// Attaches a full subtree at the rightmost index, given the subtree's rightmost leaf
+    /// and its proof within the subtree. The depth of the subtree is the length of the proof.
+    ///
+    /// The caller must ensure that the rightmost index is a multiple of the subtree's
+    /// leaf count and that the tree has room for the subtree.
+    fn graft_subtree(
+        &mut self,
+        subtree_rightmost_leaf: Node,
+        subtree_proof: &[Node],
+    ) -> Result<Node, CMTError> {
+        let subtree_depth = subtree_proof.len();
+        let rightmost_index = *self.rightmost_proof.index;
+        if rightmost_index == 0 {
+            count_hashes(&self.compute_stats, self.max_depth);
+            if recompute::<H>(EMPTY, self.rightmost_proof.proof, 0)
+                != empty_node::<H>(self.max_depth as u32)
+            {
+                return Err(CMTError::TreeAlreadyInitialized);
+            }
+        }
+        // Level at which the rightmost path of the tree meets the rightmost path of the subtree
+        let intersection = rightmost_index.trailing_zeros() as usize;
+        let mut change_list = [EMPTY; MAX_SUPPORTED_DEPTH];
+        let mut node = subtree_rightmost_leaf;
+        let mut intersection_node = *self.rightmost_proof.leaf;
+
+        let rightmost_proof = &mut self.rightmost_proof.proof;
+        for (i, change) in change_list.iter_mut().enumerate().take(self.max_depth) {
+            *change = node;
+            if i < intersection && rightmost_index > 0 {
+                hash_to_parent::<H>(
+                    &mut intersection_node,
+                    &rightmost_proof[i],
+                    ((rightmost_index - 1) >> i) & 1 == 0,
+                );
+            }
+            if i < subtree_depth {
+                // The subtree's rightmost leaf is always the right child within the subtree
+                hash_to_parent::<H>(&mut node, &subtree_proof[i], false);
+                rightmost_proof[i] = subtree_proof[i];
+            } else if i < intersection {
+                // Compute proof to the subtree root from empty nodes
+                let sibling = empty_node::<H>(i as u32);
+                hash_to_parent::<H>(&mut node, &sibling, true);
+                rightmost_proof[i] = sibling;
+            } else if i == intersection {
+                // Compute the where the subtree intersects the main tree
+                hash_to_parent::<H>(&mut node, &intersection_node, false);
+                rightmost_proof[intersection] = intersection_node;
+            } else {
+                // Update the change list path up to the root
+                hash_to_parent::<H>(
+                    &mut node,
+                    &rightmost_proof[i],
+                    ((rightmost_index - 1) >> i) & 1 == 0,
+                );
+            }
+        }
+        // One hash per level, plus one per level below the intersection for the previous leaf
+        if rightmost_index > 0 {
+            count_hashes(&self.compute_stats, self.max_depth + intersection);
+        } else {
+            count_hashes(&self.compute_stats, self.max_depth);
+        }
+
+        let subtree_rightmost_index = rightmost_index + (1 << subtree_depth) - 1;
+        self.update_internal_counters();
+        let change_log = change_log_mut(
+            self.change_logs,
+            self.max_depth,
+            self.metadata.active_index as usize,
+        );
+        *change_log.root = node;
+        change_log
+            .path
+            .copy_from_slice(&change_list[..self.max_depth]);
+        *change_log.index = subtree_rightmost_index;
+        *self.rightmost_proof.index = subtree_rightmost_index + 1;
+        *self.rightmost_proof.leaf = subtree_rightmost_leaf;
+        Ok(node)
+    }
+
+    /// Convenience function for `set_leaf`
+    /// On write conflict:
+    /// Will append
+    pub fn fill_empty_or_append(
+        &mut self,
+        current_root: Node,
+        leaf: Node,
+        proof_vec: &[Node],
+        index: u32,
+    ) -> Result<Node, CMTError> {
+        let mut proof = [EMPTY; MAX_SUPPORTED_DEPTH];
+        let proof = &mut proof[..self.max_depth];
+        fill_in_proof::<H>(proof_vec, proof)?;
+        log_compute!();
+        let root = match self.try_apply_proof(current_root, EMPTY, leaf, proof, index, false) {
+            Ok(new_root) => Ok(new_root),
+            Err(error) => match error {
+                CMTError::LeafContentsModified => self.append(leaf),
+                _ => Err(error),
+            },
+        };
+        log_compute!();
+        root
+    }
+
+    /// On write conflict:
+    /// Will fail by returning None
+    pub fn set_leaf(
+        &mut self,
+        current_root: Node,
+        previous_leaf: Node,
+        new_leaf: Node,
+        proof_vec: &[Node],
+        index: u32,
+    ) -> Result<Node, CMTError> {
+        if index > *self.rightmost_proof.index {
+            Err(CMTError::LeafIndexOutOfBounds)
+        } else {
+            let mut proof = [EMPTY; MAX_SUPPORTED_DEPTH];
+            let proof = &mut proof[..self.max_depth];
+            fill_in_proof::<H>(proof_vec, proof)?;
+            log_compute!();
+            let root =
+                self.try_apply_proof(current_root, previous_leaf, new_leaf, proof, index, true);
+            log_compute!();
+            root
+        }
+    }
+
+    /// Replaces several leaves at once. Each update is a tuple of
+    /// `(index, previous_leaf, new_leaf, proof)`, where every proof is for `current_root`.
+    ///
+    /// All proofs are fast-forwarded and validated before any leaf is written, so either every
+    /// leaf is replaced or none are. One change log is written per update, in order, and the
+    /// remaining proofs are fast-forwarded through each of them.
+    ///
+    /// Proofs are fast-forwarded in place, so each of them must hold exactly `max_depth` nodes.
+    pub fn set_leaves(
+        &mut self,
+        current_root: Node,
+        updates: &mut [(u32, Node, Node, &mut [Node])],
+    ) -> Result<Node, CMTError> {
+        let rightmost_index = *self.rightmost_proof.index;
+        for i in 0..updates.len() {
+            let (checked, unchecked) = updates.split_at_mut(i);
+            let (index, previous_leaf, _, proof) = &mut unchecked[0];
+            // Same bounds as `update_buffers_from_proof`, so that no update fails once written
+            if *index >= 1 << self.max_depth || *index > rightmost_index {
+                return Err(CMTError::LeafIndexOutOfBounds);
+            }
+            if checked.iter().any(|(other_index, ..)| other_index == index) {
+                return Err(CMTError::DuplicateLeafIndex);
+            }
+            if proof.len() != self.max_depth {
+                return Err(CMTError::ProofLengthMismatch);
+            }
+            let valid_root = self.with_view(|view| {
+                view.check_valid_leaf(current_root, *previous_leaf, proof, *index, true)
+            })?;
+            if !valid_root {
+                return Err(CMTError::InvalidProof);
+            }
+        }
+
+        log_compute!();
+        let mut root = *self.get_change_log().root;
+        for i in 0..updates.len() {
+            let (applied, pending) = updates.split_at_mut(i + 1);
+            let (index, _, new_leaf, proof) = &applied[i];
+            root = self.update_buffers_from_proof(*new_leaf, proof, *index)?;
+            let change_log = change_log_ref(
+                self.change_logs,
+                self.max_depth,
+                self.metadata.active_index as usize,
+            );
+            for (other_index, _, _, proof) in pending.iter_mut() {
+                // Indices are distinct, so only the proof is updated
+                let mut leaf = EMPTY;
+                if change_log.update_proof_or_leaf(*other_index, proof, &mut leaf) {
+                    count_proof_nodes_patched(&self.compute_stats, 1);
+                }
+            }
+        }
+        log_compute!();
+        Ok(root)
+    }
+
+    /// Same as `set_leaves`, but the proofs of the leaves are given as a multiproof for
+    /// `current_root`, see [crate::multiproof]. Each replacement is a tuple of
+    /// `(index, previous_leaf, new_leaf)`, and they are sorted by increasing index.
+    #[cfg(feature = "std")]
+    pub fn set_leaves_with_multiproof(
+        &mut self,
+        current_root: Node,
+        replacements: &[(u32, Node, Node)],
+        multiproof: &[Node],
+    ) -> Result<Node, CMTError> {
+        let previous_leaves: Vec<(u32, Node)> = replacements
+            .iter()
+            .map(|(index, previous_leaf, _)| (*index, *previous_leaf))
+            .collect();
+        let mut proofs =
+            expand_multiproof::<H>(&previous_leaves, multiproof, self.max_depth as u32)?;
+        let mut updates: Vec<(u32, Node, Node, &mut [Node])> = replacements
+            .iter()
+            .zip(proofs.iter_mut())
+            .map(|((index, previous_leaf, new_leaf), proof)| {
+                (*index, *previous_leaf, *new_leaf, proof.as_mut_slice())
+            })
+            .collect();
+        self.set_leaves(current_root, &mut updates)
+    }
+
+    /// Empties the leaf at `index` and tracks it in `free_list`,
+    /// so that it can later be reused with `insert_into_free_slot`.
+    /// On write conflict:
+    /// Will fail, leaving the free list untouched
+    pub fn remove_leaf(
+        &mut self,
+        current_root: Node,
+        previous_leaf: Node,
+        proof_vec: &[Node],
+        index: u32,
+        free_list: &mut FreeListMut,
+    ) -> Result<Node, CMTError> {
+        if index >= *self.rightmost_proof.index {
+            return Err(CMTError::LeafIndexOutOfBounds);
+        }
+        if free_list.is_full() && !free_list.contains(index) {
+            return Err(CMTError::FreeListFull);
+        }
+        let root = self.set_leaf(current_root, previous_leaf, EMPTY, proof_vec, index)?;
+        free_list.push(index)?;
+        Ok(root)
+    }
+
+    /// Writes `leaf` to the emptied leaf at `index`, which must be tracked by `free_list`.
+    /// The leaf is no longer tracked afterwards.
+    /// On write conflict:
+    /// Will fail, leaving the free list untouched
+    pub fn insert_into_free_slot(
+        &mut self,
+        current_root: Node,
+        leaf: Node,
+        proof_vec: &[Node],
+        index: u32,
+        free_list: &mut FreeListMut,
+    ) -> Result<Node, CMTError> {
+        if leaf == EMPTY {
+            return Err(CMTError::CannotAppendEmptyNode);
+        }
+        if !free_list.contains(index) {
+            return Err(CMTError::LeafNotFree);
+        }
+        let root = self.set_leaf(current_root, EMPTY, leaf, proof_vec, index)?;
+        free_list.remove(index);
+        Ok(root)
+    }
+
+    /// Note: Enabling `allow_inferred_proof` will fast forward the given proof through the
+    /// change logs allowed by the staleness policy in the case that the supplied root is not in the buffer.
+    #[inline(always)]
+    fn try_apply_proof(
+        &mut self,
+        current_root: Node,
+        leaf: Node,
+        new_leaf: Node,
+        proof: &mut [Node],
+        leaf_index: u32,
+        allow_inferred_proof: bool,
+    ) -> Result<Node, CMTError> {
+        solana_logging!("Active Index: {}", self.metadata.active_index);
+        solana_logging!("Rightmost Index: {}", self.rightmost_proof.index);
+        solana_logging!("Buffer Size: {}", self.metadata.buffer_size);
+        solana_logging!("Leaf Index: {}", leaf_index);
+        let valid_root = self.with_view(|view| {
+            view.check_valid_leaf(current_root, leaf, proof, leaf_index, allow_inferred_proof)
+        })?;
+        if !valid_root {
+            return Err(CMTError::InvalidProof);
+        }
+        self.update_buffers_from_proof(new_leaf, proof, leaf_index)
+    }
+
+    /// Implements circular addition for changelog buffer index
+    fn update_internal_counters(&mut self) {
+        let mask: usize = self.max_buffer_size - 1;
+        self.metadata.active_index += 1;
+        self.metadata.active_index &= mask as u64;
+        if self.metadata.buffer_size < self.max_buffer_size as u64 {
+            self.metadata.buffer_size += 1;
+        }
+        self.metadata.sequence_number = self.metadata.sequence_number.saturating_add(1);
+    }
+
+    /// Writes a new change log and creates a new root from a proof that is valid
+    /// for the root at `self.active_index`.
+    /// Fails without writing anything if `index` is past the leaf after the rightmost leaf
+    fn update_buffers_from_proof(
+        &mut self,
+        start: Node,
+        proof: &[Node],
+        index: u32,
+    ) -> Result<Node, CMTError> {
+        let rightmost_index = *self.rightmost_proof.index;
+        if index >= 1 << self.max_depth || index > rightmost_index {
+            return Err(CMTError::LeafIndexOutOfBounds);
+        }
+        self.update_internal_counters();
+        let mut change_log = change_log_mut(
+            self.change_logs,
+            self.max_depth,
+            self.metadata.active_index as usize,
+        );
+        // Also updates change_log's current root
+        let root = change_log.replace_and_recompute_path::<H>(index, start, proof);
+        count_hashes(&self.compute_stats, self.max_depth);
+        // Update rightmost path if possible
+        let rightmost_proof = &mut self.rightmost_proof;
+        if rightmost_index < (1 << self.max_depth) {
+            if index < rightmost_index {
+                if change_log.view().update_proof_or_leaf(
+                    rightmost_index - 1,
+                    rightmost_proof.proof,
+                    rightmost_proof.leaf,
+                ) {
+                    count_proof_nodes_patched(&self.compute_stats, 1);
+                }
+            } else {
+                solana_logging!("Appending rightmost leaf");
+                rightmost_proof.proof.copy_from_slice(proof);
+                *rightmost_proof.index = index + 1;
+                *rightmost_proof.leaf = change_log.view().get_leaf();
+            }
+        }
+        Ok(root)
+    }
+}
@@ -0,0 +1,114 @@
+//! Multiproofs prove several leaves of a tree at once, without repeating the proof nodes
+//! that their paths share or that can be computed from the other leaves.
+//!
+//! A multiproof for leaves sorted by increasing index holds, level by level from the leaves
+//! up and from left to right within a level, the sibling of every node on the paths of the
+//! leaves that is not itself on one of these paths.
+use crate::{error::CMTError, hasher::Hasher, state::Node};
+
+/// Checks that `leaves` is not empty and sorted by strictly increasing index
+fn check_leaves(leaves: &[(u32, Node)]) -> Result<(), CMTError> {
+    if leaves.is_empty() || leaves.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+        return Err(CMTError::InvalidMultiproofLeaves);
+    }
+    Ok(())
+}
+
+/// Recomputes the root of a tree of depth `max_depth` from a multiproof of `leaves`,
+/// given as `(index, leaf)` pairs sorted by increasing index.
+///
+/// `leaves` is used as scratch space to hash each level, so it is overwritten.
+pub fn recompute_multiproof<H: Hasher>(
+    leaves: &mut [(u32, Node)],
+    proof: &[Node],
+    max_depth: u32,
+) -> Result<Node, CMTError> {
+    check_leaves(leaves)?;
+    if leaves[leaves.len() - 1].0 as u64 >= 1 << max_depth {
+        return Err(CMTError::LeafIndexOutOfBounds);
+    }
+    let mut proof = proof.iter();
+    let mut len = leaves.len();
+    for _ in 0..max_depth {
+        // Parents are written over the nodes they are hashed from
+        let mut read = 0;
+        let mut write = 0;
+        while read < len {
+            let (index, node) = leaves[read];
+            let parent = if index & 1 == 1 {
+                let sibling = proof.next().ok_or(CMTError::ProofLengthMismatch)?;
+                H::hash_pair(sibling, &node)
+            } else if read + 1 < len && leaves[read + 1].0 == index + 1 {
+                read += 1;
+                H::hash_pair(&node, &leaves[read].1)
+            } else {
+                let sibling = proof.next().ok_or(CMTError::ProofLengthMismatch)?;
+                H::hash_pair(&node, sibling)
+            };
+            leaves[write] = (index >> 1, parent);
+            read += 1;
+            write += 1;
+        }
+        len = write;
+    }
+    if proof.next().is_some() {
+        return Err(CMTError::ProofLengthMismatch);
+    }
+    Ok(leaves[0].1)
+}
+
+/// Expands a multiproof of `leaves` that covers the `levels` lowest levels of the tree
+/// into one proof of `levels` nodes per leaf, in the same order as `leaves`.
+///
+/// Nodes above `levels` are left out, so that they can be filled in from a canopy.
+#[cfg(feature = "std")]
+pub fn expand_multiproof<H: Hasher>(
+    leaves: &[(u32, Node)],
+    proof: &[Node],
+    levels: u32,
+) -> Result<Vec<Vec<Node>>, CMTError> {
+    check_leaves(leaves)?;
+    let mut proofs = vec![Vec::with_capacity(levels as usize); leaves.len()];
+    let mut proof = proof.iter();
+    // Nodes of the current level that are on the path of a leaf, sorted by index
+    let mut level_nodes = leaves.to_vec();
+    for level in 0..levels {
+        // Every node of the level that is either on a path or a sibling of one, sorted by index
+        let mut known = Vec::with_capacity(2 * level_nodes.len());
+        for (i, (index, node)) in level_nodes.iter().enumerate() {
+            let sibling_index = index ^ 1;
+            let sibling_is_known = match index & 1 {
+                0 => level_nodes.get(i + 1).map(|(next, _)| *next) == Some(sibling_index),
+                _ => i > 0 && level_nodes[i - 1].0 == sibling_index,
+            };
+            if sibling_is_known {
+                known.push((*index, *node));
+            } else {
+                let sibling = *proof.next().ok_or(CMTError::ProofLengthMismatch)?;
+                if index & 1 == 1 {
+                    known.push((sibling_index, sibling));
+                    known.push((*index, *node));
+                } else {
+                    known.push((*index, *node));
+                    known.push((sibling_index, sibling));
+                }
+            }
+        }
+        for ((leaf_index, _), leaf_proof) in leaves.iter().zip(proofs.iter_mut()) {
+            let sibling_index = (leaf_index >> level) ^ 1;
+            // Siblings of every path are known, so the search always succeeds
+            let position = known
+                .binary_search_by_key(&sibling_index, |(index, _)| *index)
+                .map_err(|_| CMTError::InvalidProof)?;
+            leaf_proof.push(known[position].1);
+        }
+        level_nodes = known
+            .chunks_exact(2)
+            .map(|pair| (pair[0].0 >> 1, H::hash_pair(&pair[0].1, &pair[1].1)))
+            .collect();
+    }
+    if proof.next().is_some() {
+        return Err(CMTError::ProofLengthMismatch);
+    }
+    Ok(proofs)
+}
@@ -0,0 +1,169 @@
+use crate::{error::CMTError, hasher::Hasher, merkle_roll_view::MerkleRollRef, state::Node};
+use bytemuck::{Pod, Zeroable};
+use core::mem::size_of;
+
+/// Number of bytes used to store a root history that keeps up to `capacity` roots.
+///
+/// A [RootHistoryMetadata] is followed by `capacity` entries, used as a circular buffer.
+pub fn root_history_size(capacity: usize) -> usize {
+    size_of::<RootHistoryMetadata>() + capacity * size_of::<RootHistoryEntry>()
+}
+
+/// Root that a merkle roll had at some point
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct RootHistoryEntry {
+    /// Sequence number of the merkle roll when it had this root
+    pub seq: u64,
+    /// Slot at which the root was recorded, which can be later than the slot at which
+    /// the merkle roll had this root
+    pub slot: u64,
+    pub root: Node,
+}
+
+unsafe impl Zeroable for RootHistoryEntry {}
+unsafe impl Pod for RootHistoryEntry {}
+
+/// Counters stored at the start of every root history
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[repr(C)]
+pub struct RootHistoryMetadata {
+    /// Number of recorded roots, at most the capacity of the root history
+    pub len: u64,
+    /// Index of the most recent root
+    pub active_index: u64,
+}
+
+unsafe impl Zeroable for RootHistoryMetadata {}
+unsafe impl Pod for RootHistoryMetadata {}
+
+fn split_root_history(bytes: &[u8]) -> Result<(&RootHistoryMetadata, &[u8]), CMTError> {
+    if bytes.len() < root_history_size(1) {
+        return Err(CMTError::InvalidRootHistoryBytes);
+    }
+    let (metadata, entries) = bytes.split_at(size_of::<RootHistoryMetadata>());
+    let metadata: &RootHistoryMetadata =
+        bytemuck::try_from_bytes(metadata).map_err(|_| CMTError::InvalidRootHistoryBytes)?;
+    let capacity = (entries.len() / size_of::<RootHistoryEntry>()) as u64;
+    if metadata.len > capacity || metadata.active_index >= capacity {
+        return Err(CMTError::InvalidRootHistoryBytes);
+    }
+    Ok((metadata, entries))
+}
+
+/// Read-only view of the roots that a merkle roll had, kept for much longer than
+/// its change log buffer so that proofs for old roots can still be verified
+pub struct RootHistoryRef<'a> {
+    metadata: &'a RootHistoryMetadata,
+    entries: &'a [RootHistoryEntry],
+}
+
+impl<'a> RootHistoryRef<'a> {
+    pub fn new(bytes: &'a [u8]) -> Result<Self, CMTError> {
+        let (metadata, entries) = split_root_history(bytes)?;
+        let entries =
+            bytemuck::try_cast_slice(entries).map_err(|_| CMTError::InvalidRootHistoryBytes)?;
+        Ok(Self { metadata, entries })
+    }
+
+    /// Maximum number of roots that are kept at once
+    pub fn capacity(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.metadata.len as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.metadata.len == 0
+    }
+
+    /// Returns the `age`-th most recent root, where 0 is the most recent one
+    pub fn get(&self, age: usize) -> Option<RootHistoryEntry> {
+        if age >= self.len() {
+            return None;
+        }
+        let capacity = self.capacity();
+        Some(self.entries[(self.metadata.active_index as usize + capacity - age) % capacity])
+    }
+
+    /// Returns the most recently recorded root
+    pub fn latest(&self) -> Option<RootHistoryEntry> {
+        self.get(0)
+    }
+
+    /// Returns the most recent entry for `root`
+    pub fn find(&self, root: Node) -> Option<RootHistoryEntry> {
+        (0..self.len())
+            .filter_map(|age| self.get(age))
+            .find(|entry| entry.root == root)
+    }
+}
+
+/// Mutable view of the roots that a merkle roll had, see [RootHistoryRef]
+pub struct RootHistoryMut<'a> {
+    metadata: &'a mut RootHistoryMetadata,
+    entries: &'a mut [RootHistoryEntry],
+}
+
+impl<'a> RootHistoryMut<'a> {
+    pub fn new(bytes: &'a mut [u8]) -> Result<Self, CMTError> {
+        split_root_history(bytes)?;
+        let (metadata, entries) = bytes.split_at_mut(size_of::<RootHistoryMetadata>());
+        Ok(Self {
+            metadata: bytemuck::try_from_bytes_mut(metadata)
+                .map_err(|_| CMTError::InvalidRootHistoryBytes)?,
+            entries: bytemuck::try_cast_slice_mut(entries)
+                .map_err(|_| CMTError::InvalidRootHistoryBytes)?,
+        })
+    }
+
+    pub fn view(&self) -> RootHistoryRef<'_> {
+        RootHistoryRef {
+            metadata: self.metadata,
+            entries: self.entries,
+        }
+    }
+
+    /// Records `entry` as the most recent root, overwriting the oldest one if the history is full
+    pub fn push(&mut self, entry: RootHistoryEntry) {
+        let capacity = self.entries.len() as u64;
+        if self.metadata.len > 0 {
+            self.metadata.active_index = (self.metadata.active_index + 1) % capacity;
+        }
+        self.entries[self.metadata.active_index as usize] = entry;
+        if self.metadata.len < capacity {
+            self.metadata.len += 1;
+        }
+    }
+
+    /// Records every root of the change log buffer of `merkle_roll` that is more recent than
+    /// the latest recorded root, oldest first. Returns the number of recorded roots.
+    ///
+    /// Roots are only kept by the change log buffer for `max_buffer_size` operations,
+    /// so this must be called at least that often for the history to have no gaps.
+    pub fn record<H: Hasher>(&mut self, merkle_roll: &MerkleRollRef<'_, H>, slot: u64) -> usize {
+        let latest_seq = self.view().latest().map(|entry| entry.seq);
+        let mask = merkle_roll.max_buffer_size() as u64 - 1;
+        let mut recorded = 0;
+        for age in (0..merkle_roll.buffer_size()).rev() {
+            let seq = match merkle_roll.sequence_number().checked_sub(age) {
+                Some(seq) => seq,
+                None => continue,
+            };
+            if matches!(latest_seq, Some(latest_seq) if seq <= latest_seq) {
+                continue;
+            }
+            let change_log = merkle_roll
+                .change_log((merkle_roll.active_index().wrapping_sub(age) & mask) as usize);
+            self.push(RootHistoryEntry {
+                seq,
+                slot,
+                root: *change_log.root,
+            });
+            recorded += 1;
+        }
+        recorded
+    }
+}
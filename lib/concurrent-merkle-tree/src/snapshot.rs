@@ -0,0 +1,75 @@
+use crate::{error::CMTError, state::Node};
+
+/// Version written by `to_snapshot`, and the only version accepted by `load_snapshot`
+pub const SNAPSHOT_VERSION: u8 = 1;
+
+/// Portable copy of a merkle roll that does not depend on its const-generic layout.
+///
+/// Snapshots can be loaded into a merkle roll with a different `max_buffer_size`,
+/// in which case only the most recent change logs that fit in its buffer are kept.
+/// The hash function is not recorded, so snapshots must be loaded with the same `Hasher`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "borsh",
+    derive(borsh::BorshSerialize, borsh::BorshDeserialize)
+)]
+pub struct MerkleRollSnapshot {
+    pub version: u8,
+    pub max_depth: u32,
+    /// Buffer size of the merkle roll the snapshot was taken from
+    pub max_buffer_size: u32,
+    pub sequence_number: u64,
+    /// Index of the most recent change log in the buffer the snapshot was taken from
+    pub active_index: u64,
+    /// Change logs that are still in the buffer, oldest first.
+    /// The last one holds the current root.
+    pub change_logs: Vec<ChangeLogSnapshot>,
+    pub rightmost_proof: PathSnapshot,
+}
+
+/// Portable copy of a `ChangeLog`
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "borsh",
+    derive(borsh::BorshSerialize, borsh::BorshDeserialize)
+)]
+pub struct ChangeLogSnapshot {
+    pub root: Node,
+    pub path: Vec<Node>,
+    pub index: u32,
+}
+
+/// Portable copy of a `Path`
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "borsh",
+    derive(borsh::BorshSerialize, borsh::BorshDeserialize)
+)]
+pub struct PathSnapshot {
+    pub proof: Vec<Node>,
+    pub leaf: Node,
+    pub index: u32,
+}
+
+impl MerkleRollSnapshot {
+    /// Checks that the snapshot can be loaded into a merkle roll of depth `max_depth`
+    pub fn validate(&self, max_depth: usize) -> Result<(), CMTError> {
+        if self.version != SNAPSHOT_VERSION {
+            return Err(CMTError::UnsupportedSnapshotVersion);
+        }
+        if self.max_depth as usize != max_depth
+            || self.change_logs.len() > self.max_buffer_size as usize
+            || self.rightmost_proof.proof.len() != max_depth
+            || self
+                .change_logs
+                .iter()
+                .any(|change_log| change_log.path.len() != max_depth)
+        {
+            return Err(CMTError::InvalidSnapshot);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,385 @@
+use crate::{
+    error::CMTError,
+    hasher::{Hasher, Keccak},
+    merkle_roll_view::{MerkleRollMetadata, StalenessPolicy},
+    state::{Node, EMPTY},
+};
+use bytemuck::{Pod, Zeroable};
+use core::{marker::PhantomData, mem::size_of};
+
+/// Depth of a sparse merkle tree: there is one level per bit of a key
+pub const SPARSE_DEPTH: usize = 256;
+
+/// Key of a leaf in a sparse merkle tree. Keys are read as big-endian 256-bit leaf indices.
+pub type Key = [u8; 32];
+
+/// Returns bit `level` of `key`, counting from the least significant bit.
+/// It is set if the node at height `level` on the path of `key` is a right child.
+pub fn key_bit(key: &Key, level: usize) -> bool {
+    (key[31 - level / 8] >> (level % 8)) & 1 == 1
+}
+
+/// Returns the highest level at which the paths of `a` and `b` are siblings,
+/// or `None` if the keys are equal
+fn critbit(a: &Key, b: &Key) -> Option<usize> {
+    a.iter()
+        .zip(b)
+        .position(|(x, y)| x != y)
+        .map(|i| (31 - i) * 8 + 7 - (a[i] ^ b[i]).leading_zeros() as usize)
+}
+
+/// Hashes two siblings into their parent.
+///
+/// The root of an empty subtree is `EMPTY` at every height, instead of the hash of its children,
+/// so that empty subtrees never have to be hashed and empty siblings can be left out of proofs.
+pub fn hash_sparse_pair<H: Hasher>(left: &Node, right: &Node) -> Node {
+    if *left == EMPTY && *right == EMPTY {
+        EMPTY
+    } else {
+        H::hash_pair(left, right)
+    }
+}
+
+/// Recomputes the root of a sparse merkle tree from the value at `key` and its proof
+pub fn recompute_sparse<H: Hasher>(key: &Key, value: Node, proof: &[Node]) -> Node {
+    let mut node = value;
+    for (level, sibling) in proof.iter().enumerate() {
+        node = if key_bit(key, level) {
+            hash_sparse_pair::<H>(sibling, &node)
+        } else {
+            hash_sparse_pair::<H>(&node, sibling)
+        };
+    }
+    node
+}
+
+/// Expands a compressed proof into `proof`, which must hold [SPARSE_DEPTH] nodes.
+///
+/// Bit `level` of `non_empty`, as read by [key_bit], is set if the sibling at that level
+/// is not empty, in which case it is the next node of `nodes`. Other siblings are `EMPTY`.
+pub fn decompress_proof(
+    non_empty: &Key,
+    nodes: &[Node],
+    proof: &mut [Node],
+) -> Result<(), CMTError> {
+    if proof.len() != SPARSE_DEPTH {
+        return Err(CMTError::ProofLengthMismatch);
+    }
+    let mut nodes = nodes.iter();
+    for (level, sibling) in proof.iter_mut().enumerate() {
+        *sibling = if key_bit(non_empty, level) {
+            *nodes.next().ok_or(CMTError::ProofLengthMismatch)?
+        } else {
+            EMPTY
+        };
+    }
+    if nodes.next().is_some() {
+        return Err(CMTError::ProofLengthMismatch);
+    }
+    Ok(())
+}
+
+/// Compresses a proof of [SPARSE_DEPTH] nodes for [decompress_proof]
+#[cfg(feature = "std")]
+pub fn compress_proof(proof: &[Node]) -> (Key, Vec<Node>) {
+    let mut non_empty = [0; 32];
+    let mut nodes = vec![];
+    for (level, sibling) in proof.iter().enumerate() {
+        if *sibling != EMPTY {
+            non_empty[31 - level / 8] |= 1 << (level % 8);
+            nodes.push(*sibling);
+        }
+    }
+    (non_empty, nodes)
+}
+
+/// Records the path of a leaf of a sparse merkle tree after it was written
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct SparseChangeLog {
+    /// Root of the tree after the write
+    pub root: Node,
+    /// Key of the leaf that was written
+    pub key: Key,
+    /// Nodes on the path of `key`, from its new value up to the child of the root
+    pub path: [Node; SPARSE_DEPTH],
+}
+
+unsafe impl Zeroable for SparseChangeLog {}
+unsafe impl Pod for SparseChangeLog {}
+
+/// Number of bytes used to store a sparse merkle roll that keeps `max_buffer_size` change logs
+pub fn sparse_merkle_roll_size(max_buffer_size: usize) -> Result<usize, CMTError> {
+    if max_buffer_size == 0 || max_buffer_size & (max_buffer_size - 1) != 0 {
+        solana_logging!("Invalid max buffer size {}", max_buffer_size);
+        return Err(CMTError::InvalidDepthOrBufferSize);
+    }
+    Ok(size_of::<MerkleRollMetadata>() + max_buffer_size * size_of::<SparseChangeLog>())
+}
+
+/// Read-only view of a sparse merkle roll: a merkle tree of depth [SPARSE_DEPTH]
+/// whose leaves are addressed by a [Key] instead of an index.
+///
+/// Like a merkle roll, it keeps a buffer of change logs so that proofs for recent roots
+/// can be fast-forwarded, which lets several writes to the tree land in the same slot.
+/// A leaf is absent if its value is `EMPTY`, so proving that a key has no value
+/// is the same as proving that its value is `EMPTY`.
+pub struct SparseMerkleRollRef<'a, H: Hasher = Keccak> {
+    metadata: &'a MerkleRollMetadata,
+    change_logs: &'a [SparseChangeLog],
+    staleness_policy: StalenessPolicy,
+    _hasher: PhantomData<H>,
+}
+
+impl<'a> SparseMerkleRollRef<'a> {
+    pub fn new(bytes: &'a [u8], max_buffer_size: usize) -> Result<Self, CMTError> {
+        Self::with_hasher(bytes, max_buffer_size, Keccak)
+    }
+}
+
+impl<'a, H: Hasher> SparseMerkleRollRef<'a, H> {
+    /// Same as `new`, but nodes are hashed with `H` instead of keccak
+    pub fn with_hasher(
+        bytes: &'a [u8],
+        max_buffer_size: usize,
+        _hasher: H,
+    ) -> Result<Self, CMTError> {
+        if bytes.len() != sparse_merkle_roll_size(max_buffer_size)? {
+            return Err(CMTError::InvalidMerkleRollBytes);
+        }
+        let (metadata, change_logs) = bytes.split_at(size_of::<MerkleRollMetadata>());
+        Ok(Self {
+            metadata: bytemuck::try_from_bytes(metadata)
+                .map_err(|_| CMTError::InvalidMerkleRollBytes)?,
+            change_logs: bytemuck::try_cast_slice(change_logs)
+                .map_err(|_| CMTError::InvalidMerkleRollBytes)?,
+            staleness_policy: StalenessPolicy::FullReplay,
+            _hasher: PhantomData,
+        })
+    }
+
+    /// See [MerkleRollRef::with_staleness_policy](crate::merkle_roll_view::MerkleRollRef::with_staleness_policy)
+    pub fn with_staleness_policy(mut self, staleness_policy: StalenessPolicy) -> Self {
+        self.staleness_policy = staleness_policy;
+        self
+    }
+
+    pub fn max_buffer_size(&self) -> usize {
+        self.change_logs.len()
+    }
+
+    pub fn sequence_number(&self) -> u64 {
+        self.metadata.sequence_number
+    }
+
+    /// Returns the most recent change log
+    pub fn get_change_log(&self) -> &'a SparseChangeLog {
+        &self.change_logs[self.metadata.active_index as usize]
+    }
+
+    /// Proves that the value at `key` is `value` in the current tree.
+    ///
+    /// `proof` must be a proof for `current_root`, which is some recent root of the tree,
+    /// and hold exactly [SPARSE_DEPTH] nodes. It is fast-forwarded in place.
+    pub fn prove_leaf(
+        &self,
+        current_root: Node,
+        key: &Key,
+        value: Node,
+        proof: &mut [Node],
+    ) -> Result<Node, CMTError> {
+        self.check_valid_leaf(current_root, key, value, proof)?;
+        Ok(Node::default())
+    }
+
+    #[inline(always)]
+    fn find_root_in_changelog(&self, current_root: Node) -> Option<u64> {
+        let mask = self.change_logs.len() as u64 - 1;
+        (0..self.metadata.buffer_size)
+            .map(|i| self.metadata.active_index.wrapping_sub(i) & mask)
+            .find(|j| self.change_logs[*j as usize].root == current_root)
+    }
+
+    /// Fast-forwards `proof` through the change logs written since `current_root`,
+    /// then checks that it proves `value` at `key` in the current tree
+    fn check_valid_leaf(
+        &self,
+        current_root: Node,
+        key: &Key,
+        value: Node,
+        proof: &mut [Node],
+    ) -> Result<(), CMTError> {
+        if proof.len() != SPARSE_DEPTH {
+            return Err(CMTError::ProofLengthMismatch);
+        }
+        let mask = self.change_logs.len() as u64 - 1;
+        let (mut changelog_buffer_index, replayed) = match self.find_root_in_changelog(current_root)
+        {
+            Some(matching_changelog_index) => (
+                matching_changelog_index,
+                self.metadata
+                    .active_index
+                    .wrapping_sub(matching_changelog_index)
+                    & mask,
+            ),
+            None => {
+                let replayed = match self.staleness_policy {
+                    StalenessPolicy::RejectUnknownRoot => 0,
+                    StalenessPolicy::ReplayLast(n) => self.metadata.buffer_size.min(n as u64),
+                    StalenessPolicy::FullReplay => self.metadata.buffer_size,
+                };
+                if replayed == 0 {
+                    return Err(CMTError::RootNotFound);
+                }
+                solana_logging!(
+                    "Failed to find root in change log -> replaying {} change logs",
+                    replayed
+                );
+                // Start right before the oldest replayed change log
+                (
+                    self.metadata.active_index.wrapping_sub(replayed) & mask,
+                    replayed,
+                )
+            }
+        };
+        let mut updated_value = value;
+        for _ in 0..replayed {
+            changelog_buffer_index = (changelog_buffer_index + 1) & mask;
+            let change_log = &self.change_logs[changelog_buffer_index as usize];
+            match critbit(key, &change_log.key) {
+                Some(level) => proof[level] = change_log.path[level],
+                None => updated_value = change_log.path[0],
+            }
+        }
+        if updated_value != value {
+            return Err(CMTError::LeafContentsModified);
+        }
+        if recompute_sparse::<H>(key, value, proof) != self.get_change_log().root {
+            solana_logging!("Proof failed to verify");
+            return Err(CMTError::InvalidProof);
+        }
+        Ok(())
+    }
+}
+
+/// Mutable view of a sparse merkle roll, see [SparseMerkleRollRef]
+pub struct SparseMerkleRollMut<'a, H: Hasher = Keccak> {
+    metadata: &'a mut MerkleRollMetadata,
+    change_logs: &'a mut [SparseChangeLog],
+    staleness_policy: StalenessPolicy,
+    _hasher: PhantomData<H>,
+}
+
+impl<'a> SparseMerkleRollMut<'a> {
+    pub fn new(bytes: &'a mut [u8], max_buffer_size: usize) -> Result<Self, CMTError> {
+        Self::with_hasher(bytes, max_buffer_size, Keccak)
+    }
+}
+
+impl<'a, H: Hasher> SparseMerkleRollMut<'a, H> {
+    /// Same as `new`, but nodes are hashed with `H` instead of keccak
+    pub fn with_hasher(
+        bytes: &'a mut [u8],
+        max_buffer_size: usize,
+        _hasher: H,
+    ) -> Result<Self, CMTError> {
+        if bytes.len() != sparse_merkle_roll_size(max_buffer_size)? {
+            return Err(CMTError::InvalidMerkleRollBytes);
+        }
+        let (metadata, change_logs) = bytes.split_at_mut(size_of::<MerkleRollMetadata>());
+        Ok(Self {
+            metadata: bytemuck::try_from_bytes_mut(metadata)
+                .map_err(|_| CMTError::InvalidMerkleRollBytes)?,
+            change_logs: bytemuck::try_cast_slice_mut(change_logs)
+                .map_err(|_| CMTError::InvalidMerkleRollBytes)?,
+            staleness_policy: StalenessPolicy::FullReplay,
+            _hasher: PhantomData,
+        })
+    }
+
+    /// See [MerkleRollRef::with_staleness_policy](crate::merkle_roll_view::MerkleRollRef::with_staleness_policy)
+    pub fn with_staleness_policy(mut self, staleness_policy: StalenessPolicy) -> Self {
+        self.staleness_policy = staleness_policy;
+        self
+    }
+
+    /// Borrows this sparse merkle roll as a read-only view
+    pub fn view(&self) -> SparseMerkleRollRef<'_, H> {
+        SparseMerkleRollRef {
+            metadata: self.metadata,
+            change_logs: self.change_logs,
+            staleness_policy: self.staleness_policy,
+            _hasher: PhantomData,
+        }
+    }
+
+    pub fn sequence_number(&self) -> u64 {
+        self.metadata.sequence_number
+    }
+
+    /// Returns the most recent change log
+    pub fn get_change_log(&self) -> &SparseChangeLog {
+        &self.change_logs[self.metadata.active_index as usize]
+    }
+
+    /// Initializes an empty tree, whose root is `EMPTY`
+    pub fn initialize(&mut self) -> Result<Node, CMTError> {
+        // Zeroed in place, since a change log is too large for the on-chain stack
+        bytemuck::bytes_of_mut(&mut self.change_logs[0]).fill(0);
+        self.metadata.sequence_number = 0;
+        self.metadata.active_index = 0;
+        self.metadata.buffer_size = 1;
+        Ok(EMPTY)
+    }
+
+    /// See [SparseMerkleRollRef::prove_leaf]
+    pub fn prove_leaf(
+        &self,
+        current_root: Node,
+        key: &Key,
+        value: Node,
+        proof: &mut [Node],
+    ) -> Result<Node, CMTError> {
+        self.view().prove_leaf(current_root, key, value, proof)
+    }
+
+    /// Replaces the value at `key`, which must be `previous_value`, with `new_value`.
+    /// Inserting a key replaces an `EMPTY` value, and removing it writes an `EMPTY` value.
+    ///
+    /// `proof` must be a proof for `current_root`, which is some recent root of the tree,
+    /// and hold exactly [SPARSE_DEPTH] nodes. It is fast-forwarded in place.
+    /// On write conflict:
+    /// Will fail by returning `LeafContentsModified`
+    pub fn set_leaf(
+        &mut self,
+        current_root: Node,
+        key: &Key,
+        previous_value: Node,
+        new_value: Node,
+        proof: &mut [Node],
+    ) -> Result<Node, CMTError> {
+        self.view()
+            .check_valid_leaf(current_root, key, previous_value, proof)?;
+
+        let mask = self.change_logs.len() as u64 - 1;
+        self.metadata.active_index = (self.metadata.active_index + 1) & mask;
+        if self.metadata.buffer_size < self.change_logs.len() as u64 {
+            self.metadata.buffer_size += 1;
+        }
+        self.metadata.sequence_number = self.metadata.sequence_number.saturating_add(1);
+
+        let change_log = &mut self.change_logs[self.metadata.active_index as usize];
+        change_log.key = *key;
+        let mut node = new_value;
+        for (level, sibling) in proof.iter().enumerate() {
+            change_log.path[level] = node;
+            node = if key_bit(key, level) {
+                hash_sparse_pair::<H>(sibling, &node)
+            } else {
+                hash_sparse_pair::<H>(&node, sibling)
+            };
+        }
+        change_log.root = node;
+        Ok(node)
+    }
+}
@@ -1,3 +1,4 @@
+use crate::hasher::Hasher;
 use crate::utils::hash_to_parent;
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -13,8 +14,8 @@ pub struct ChangeLog<const MAX_DEPTH: usize> {
     pub _padding: u32,
 }
 
-impl<const MAX_DEPTH: usize> ChangeLog<MAX_DEPTH> {
-    pub fn default() -> Self {
+impl<const MAX_DEPTH: usize> Default for ChangeLog<MAX_DEPTH> {
+    fn default() -> Self {
         Self {
             root: EMPTY,
             path: [EMPTY; MAX_DEPTH],
@@ -22,7 +23,9 @@ impl<const MAX_DEPTH: usize> ChangeLog<MAX_DEPTH> {
             _padding: 0,
         }
     }
+}
 
+impl<const MAX_DEPTH: usize> ChangeLog<MAX_DEPTH> {
     pub fn new(root: Node, path: [Node; MAX_DEPTH], index: u32) -> Self {
         Self {
             root,
@@ -36,15 +39,33 @@ impl<const MAX_DEPTH: usize> ChangeLog<MAX_DEPTH> {
         self.path[0]
     }
 
-    /// Sets all change log values from a leaf and valid proof
-    pub fn replace_and_recompute_path(&mut self, index: u32, mut node: Node, proof: &[Node]) -> Node {
-        self.index = index;
-        for (i, sibling) in proof.iter().enumerate() {
-            self.path[i] = node;
-            hash_to_parent(&mut node, sibling, self.index >> i & 1 == 0);
+    /// Borrows this change log as a runtime-sized view
+    pub fn view(&self) -> ChangeLogRef<'_> {
+        ChangeLogRef {
+            root: &self.root,
+            path: &self.path,
+            index: self.index,
         }
-        self.root = node;
-        node
+    }
+
+    /// Mutably borrows this change log as a runtime-sized view
+    pub fn view_mut(&mut self) -> ChangeLogMut<'_> {
+        ChangeLogMut {
+            root: &mut self.root,
+            path: &mut self.path,
+            index: &mut self.index,
+        }
+    }
+
+    /// Sets all change log values from a leaf and valid proof
+    pub fn replace_and_recompute_path<H: Hasher>(
+        &mut self,
+        index: u32,
+        node: Node,
+        proof: &[Node],
+    ) -> Node {
+        self.view_mut()
+            .replace_and_recompute_path::<H>(index, node, proof)
     }
 
     pub fn update_proof_or_leaf(
@@ -52,18 +73,83 @@ impl<const MAX_DEPTH: usize> ChangeLog<MAX_DEPTH> {
         leaf_index: u32,
         proof: &mut [Node; MAX_DEPTH],
         leaf: &mut Node,
-    ) {
-        let padding: usize = 32 - MAX_DEPTH;
+    ) -> bool {
+        self.view().update_proof_or_leaf(leaf_index, proof, leaf)
+    }
+}
+
+/// Runtime-sized, read-only view of a [ChangeLog]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ChangeLogRef<'a> {
+    /// Historical root value before Path was applied
+    pub root: &'a Node,
+    /// Nodes of off-chain merkle tree
+    pub path: &'a [Node],
+    /// Bitmap of node parity (used when hashing)
+    pub index: u32,
+}
+
+impl<'a> ChangeLogRef<'a> {
+    pub fn get_leaf(&self) -> Node {
+        self.path[0]
+    }
+
+    /// Applies this change log to the proof of `leaf_index`, or to `leaf` if this change log
+    /// replaced it. Returns true if a node of the proof was overwritten.
+    pub fn update_proof_or_leaf(
+        &self,
+        leaf_index: u32,
+        proof: &mut [Node],
+        leaf: &mut Node,
+    ) -> bool {
+        let max_depth = self.path.len();
+        let padding: usize = 32 - max_depth;
         if leaf_index != self.index {
             // This bit math is used to identify which node in the proof
             // we need to swap for a corresponding node in a saved change log
             let common_path_len = ((leaf_index ^ self.index) << padding).leading_zeros() as usize;
-            let critbit_index = (MAX_DEPTH - 1) - common_path_len;
+            let critbit_index = (max_depth - 1) - common_path_len;
             proof[critbit_index] = self.path[critbit_index];
+            true
         } else {
             *leaf = self.get_leaf();
+            false
+        }
+    }
+}
+
+/// Runtime-sized, mutable view of a [ChangeLog]
+#[derive(Debug, PartialEq)]
+pub struct ChangeLogMut<'a> {
+    pub root: &'a mut Node,
+    pub path: &'a mut [Node],
+    pub index: &'a mut u32,
+}
+
+impl<'a> ChangeLogMut<'a> {
+    pub fn view(&self) -> ChangeLogRef<'_> {
+        ChangeLogRef {
+            root: self.root,
+            path: self.path,
+            index: *self.index,
         }
     }
+
+    /// Sets all change log values from a leaf and valid proof
+    pub fn replace_and_recompute_path<H: Hasher>(
+        &mut self,
+        index: u32,
+        mut node: Node,
+        proof: &[Node],
+    ) -> Node {
+        *self.index = index;
+        for (i, sibling) in proof.iter().enumerate() {
+            self.path[i] = node;
+            hash_to_parent::<H>(&mut node, sibling, index >> i & 1 == 0);
+        }
+        *self.root = node;
+        node
+    }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -75,6 +161,17 @@ pub struct Path<const MAX_DEPTH: usize> {
     pub _padding: u32,
 }
 
+impl<const MAX_DEPTH: usize> Path<MAX_DEPTH> {
+    /// Borrows this path as a runtime-sized view
+    pub fn view(&self) -> PathRef<'_> {
+        PathRef {
+            proof: &self.proof,
+            leaf: &self.leaf,
+            index: self.index,
+        }
+    }
+}
+
 impl<const MAX_DEPTH: usize> Default for Path<MAX_DEPTH> {
     fn default() -> Self {
         Self {
@@ -86,5 +183,31 @@ impl<const MAX_DEPTH: usize> Default for Path<MAX_DEPTH> {
     }
 }
 
+/// Runtime-sized, read-only view of a [Path]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PathRef<'a> {
+    pub proof: &'a [Node],
+    pub leaf: &'a Node,
+    pub index: u32,
+}
+
+/// Runtime-sized, mutable view of a [Path]
+#[derive(Debug, PartialEq)]
+pub struct PathMut<'a> {
+    pub proof: &'a mut [Node],
+    pub leaf: &'a mut Node,
+    pub index: &'a mut u32,
+}
+
+impl<'a> PathMut<'a> {
+    pub fn view(&self) -> PathRef<'_> {
+        PathRef {
+            proof: self.proof,
+            leaf: self.leaf,
+            index: *self.index,
+        }
+    }
+}
+
 pub type Node = [u8; 32];
 pub const EMPTY: Node = [0_u8; 32];
@@ -0,0 +1,321 @@
+//! Differential tests that drive random interleavings of operations against a `MerkleRoll`
+//! and a model built on top of the `merkle-tree-reference` oracle.
+//!
+//! Proofs are taken from any past version of the tree, so most of them are stale and have to be
+//! fast-forwarded through the change log buffer, or fall out of it entirely.
+//! Failing cases are shrunk and saved under `proptest-regressions`, so they are replayed first.
+use concurrent_merkle_tree::error::CMTError;
+use concurrent_merkle_tree::merkle_roll::MerkleRoll;
+use concurrent_merkle_tree::state::{Node, EMPTY};
+use merkle_tree_reference::{recompute, Keccak, MerkleTree};
+use proptest::prelude::*;
+
+const DEPTH: usize = 5;
+const BUFFER_SIZE: usize = 8;
+/// Root that none of the generated trees has
+const UNKNOWN_ROOT: Node = [0xff; 32];
+
+#[derive(Clone, Debug)]
+enum Op {
+    Append {
+        leaf: Node,
+    },
+    /// `age` is the number of change logs written since the proof was taken
+    SetLeaf {
+        age: usize,
+        index: u32,
+        new_leaf: Node,
+    },
+    FillEmptyOrAppend {
+        age: usize,
+        index: u32,
+        leaf: Node,
+    },
+    /// Current proof, but for a root that the tree never had, so the full buffer is replayed
+    SetLeafWithUnknownRoot {
+        index: u32,
+        new_leaf: Node,
+    },
+    ProveLeaf {
+        age: usize,
+        index: u32,
+    },
+}
+
+fn leaf() -> impl Strategy<Value = Node> {
+    any::<Node>().prop_filter("leaves must not be empty", |leaf| *leaf != EMPTY)
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    // Proofs are sometimes older than the whole buffer
+    let age = 0..2 * BUFFER_SIZE + 2;
+    let index = 0..(1u32 << DEPTH);
+    prop_oneof![
+        3 => leaf().prop_map(|leaf| Op::Append { leaf }),
+        1 => Just(Op::Append { leaf: EMPTY }),
+        3 => (age.clone(), index.clone(), leaf())
+            .prop_map(|(age, index, new_leaf)| Op::SetLeaf { age, index, new_leaf }),
+        2 => (age.clone(), index.clone(), leaf())
+            .prop_map(|(age, index, leaf)| Op::FillEmptyOrAppend { age, index, leaf }),
+        1 => (index.clone(), leaf())
+            .prop_map(|(index, new_leaf)| Op::SetLeafWithUnknownRoot { index, new_leaf }),
+        2 => (age, index).prop_map(|(age, index)| Op::ProveLeaf { age, index }),
+    ]
+}
+
+/// Expected behavior of a `MerkleRoll`, derived from the full history of the reference tree
+struct Model {
+    tree: MerkleTree,
+    /// Leaves of the tree after each change log, indexed by sequence number
+    history: Vec<Vec<Node>>,
+    /// Leaf index written by each change log, `None` for the one written by `initialize`
+    written: Vec<Option<u32>>,
+    /// Sequence number of the first change log of the tree
+    first_seq: usize,
+    rightmost_index: u32,
+}
+
+impl Model {
+    fn new() -> Self {
+        let leaves = vec![EMPTY; 1 << DEPTH];
+        Self {
+            tree: MerkleTree::new(leaves.clone()),
+            history: vec![leaves],
+            written: vec![None],
+            first_seq: 0,
+            rightmost_index: 0,
+        }
+    }
+
+    /// Model of a tree created with `initialize_with_root` from `initial_leaves`, whose first
+    /// change log is the path of the rightmost leaf, at sequence number 1
+    fn with_leaves(initial_leaves: &[Node]) -> Self {
+        let mut leaves = vec![EMPTY; 1 << DEPTH];
+        leaves[..initial_leaves.len()].copy_from_slice(initial_leaves);
+        let rightmost_index = initial_leaves.len() as u32;
+        Self {
+            tree: MerkleTree::new(leaves.clone()),
+            history: vec![leaves.clone(), leaves],
+            written: vec![None, Some(rightmost_index - 1)],
+            first_seq: 1,
+            rightmost_index,
+        }
+    }
+
+    fn seq(&self) -> usize {
+        self.history.len() - 1
+    }
+
+    /// Returns the leaves, root and proof of `index` of the tree `age` change logs ago
+    fn stale(&self, age: usize, index: u32) -> (usize, Node, Node, Vec<Node>) {
+        let seq = self.seq().saturating_sub(age).max(self.first_seq);
+        let leaves = &self.history[seq];
+        let tree = MerkleTree::new(leaves.clone());
+        (
+            seq,
+            tree.get_root(),
+            leaves[index as usize],
+            tree.get_proof_of_leaf(index as usize),
+        )
+    }
+
+    /// Mirrors `check_valid_leaf`: fast-forwards a proof for `leaf` taken at `seq`
+    /// through the change logs that are still in the buffer. `seq` is `None` for a proof
+    /// whose root the tree never had.
+    fn check_valid_leaf(
+        &self,
+        seq: Option<usize>,
+        leaf: Node,
+        proof: &[Node],
+        index: u32,
+        allow_inferred_proof: bool,
+    ) -> Result<(), CMTError> {
+        let now = self.seq();
+        let oldest_in_buffer = (now + 1).saturating_sub(BUFFER_SIZE);
+        let first_replayed = match seq {
+            Some(seq) if seq >= oldest_in_buffer => seq + 1,
+            _ if allow_inferred_proof => oldest_in_buffer,
+            _ => return Err(CMTError::RootNotFound),
+        };
+        let replayed = &self.written[first_replayed..=now];
+
+        // Only nodes that are written by a replayed change log are brought up to date
+        let current_proof = self.tree.get_proof_of_leaf(index as usize);
+        let mut fast_forwarded = proof.to_vec();
+        for (level, node) in fast_forwarded.iter_mut().enumerate() {
+            let sibling = (index >> level) ^ 1;
+            if replayed
+                .iter()
+                .flatten()
+                .any(|written| written >> level == sibling)
+            {
+                *node = current_proof[level];
+            }
+        }
+        if replayed.contains(&Some(index)) && self.tree.get_leaf(index as usize) != leaf {
+            return Err(CMTError::LeafContentsModified);
+        }
+        if recompute::<Keccak>(leaf, &fast_forwarded, index) != self.tree.get_root() {
+            return Err(CMTError::InvalidProof);
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, leaf: Node, index: u32) {
+        self.tree.add_leaf(leaf, index as usize);
+        let mut leaves = self.history[self.seq()].clone();
+        leaves[index as usize] = leaf;
+        self.history.push(leaves);
+        self.written.push(Some(index));
+        self.rightmost_index = self.rightmost_index.max(index + 1);
+    }
+
+    fn append(&mut self, leaf: Node) -> Result<(), CMTError> {
+        if leaf == EMPTY {
+            return Err(CMTError::CannotAppendEmptyNode);
+        }
+        if self.rightmost_index == 1 << DEPTH {
+            return Err(CMTError::TreeFull);
+        }
+        self.write(leaf, self.rightmost_index);
+        Ok(())
+    }
+
+    fn apply(&mut self, op: &Op) -> Result<(), CMTError> {
+        match *op {
+            Op::Append { leaf } => self.append(leaf),
+            Op::SetLeaf {
+                age,
+                index,
+                new_leaf,
+            } => {
+                if index > self.rightmost_index {
+                    return Err(CMTError::LeafIndexOutOfBounds);
+                }
+                let (seq, _, leaf, proof) = self.stale(age, index);
+                self.check_valid_leaf(Some(seq), leaf, &proof, index, true)?;
+                self.write(new_leaf, index);
+                Ok(())
+            }
+            Op::SetLeafWithUnknownRoot { index, new_leaf } => {
+                if index > self.rightmost_index {
+                    return Err(CMTError::LeafIndexOutOfBounds);
+                }
+                let (_, _, leaf, proof) = self.stale(0, index);
+                self.check_valid_leaf(None, leaf, &proof, index, true)?;
+                self.write(new_leaf, index);
+                Ok(())
+            }
+            Op::FillEmptyOrAppend { age, index, leaf } => {
+                let (seq, _, _, proof) = self.stale(age, index);
+                match self.check_valid_leaf(Some(seq), EMPTY, &proof, index, false) {
+                    Ok(()) if index > self.rightmost_index => Err(CMTError::LeafIndexOutOfBounds),
+                    Ok(()) => {
+                        self.write(leaf, index);
+                        Ok(())
+                    }
+                    Err(CMTError::LeafContentsModified) => self.append(leaf),
+                    Err(err) => Err(err),
+                }
+            }
+            Op::ProveLeaf { age, index } => {
+                if index > self.rightmost_index {
+                    return Err(CMTError::LeafIndexOutOfBounds);
+                }
+                let (seq, _, leaf, proof) = self.stale(age, index);
+                self.check_valid_leaf(Some(seq), leaf, &proof, index, true)
+            }
+        }
+    }
+}
+
+fn apply(
+    merkle_roll: &mut MerkleRoll<DEPTH, BUFFER_SIZE>,
+    model: &Model,
+    op: &Op,
+) -> Result<(), CMTError> {
+    match *op {
+        Op::Append { leaf } => merkle_roll.append(leaf),
+        Op::SetLeaf {
+            age,
+            index,
+            new_leaf,
+        } => {
+            let (_, root, leaf, proof) = model.stale(age, index);
+            merkle_roll.set_leaf(root, leaf, new_leaf, &proof, index)
+        }
+        Op::FillEmptyOrAppend { age, index, leaf } => {
+            let (_, root, _, proof) = model.stale(age, index);
+            merkle_roll.fill_empty_or_append(root, leaf, &proof, index)
+        }
+        Op::SetLeafWithUnknownRoot { index, new_leaf } => {
+            let (_, _, leaf, proof) = model.stale(0, index);
+            merkle_roll.set_leaf(UNKNOWN_ROOT, leaf, new_leaf, &proof, index)
+        }
+        Op::ProveLeaf { age, index } => {
+            let (_, root, leaf, proof) = model.stale(age, index);
+            merkle_roll.prove_leaf(root, leaf, &proof, index)
+        }
+    }
+    .map(|_| ())
+}
+
+/// Applies `ops` to `merkle_roll` and checks it against `model` after each of them
+fn check_ops(
+    merkle_roll: &mut MerkleRoll<DEPTH, BUFFER_SIZE>,
+    model: &mut Model,
+    ops: &[Op],
+) -> Result<(), TestCaseError> {
+    for (i, op) in ops.iter().enumerate() {
+        let result = apply(merkle_roll, model, op);
+        let expected = model.apply(op);
+        prop_assert_eq!(result, expected, "op {}: {:?}", i, op);
+
+        prop_assert_eq!(merkle_roll.get_change_log().root, model.tree.get_root());
+        prop_assert_eq!(merkle_roll.sequence_number, model.seq() as u64);
+        let rightmost_proof = &merkle_roll.rightmost_proof;
+        prop_assert_eq!(rightmost_proof.index, model.rightmost_index);
+        // The rightmost proof is no longer maintained once the tree is full
+        if 0 < model.rightmost_index && model.rightmost_index < 1 << DEPTH {
+            let rightmost_leaf = model.rightmost_index as usize - 1;
+            prop_assert_eq!(rightmost_proof.leaf, model.tree.get_leaf(rightmost_leaf));
+            prop_assert_eq!(
+                rightmost_proof.proof.to_vec(),
+                model.tree.get_proof_of_leaf(rightmost_leaf)
+            );
+        }
+    }
+    Ok(())
+}
+
+proptest! {
+    // Every case replays up to 200 operations, set `PROPTEST_CASES` to run more of them
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn merkle_roll_matches_reference(ops in prop::collection::vec(op(), 1..200)) {
+        let mut merkle_roll = MerkleRoll::<DEPTH, BUFFER_SIZE>::new();
+        merkle_roll.initialize().unwrap();
+        let mut model = Model::new();
+        check_ops(&mut merkle_roll, &mut model, &ops)?;
+    }
+
+    #[test]
+    fn merkle_roll_initialized_with_root_matches_reference(
+        initial_leaves in prop::collection::vec(leaf(), 1..(1 << DEPTH)),
+        ops in prop::collection::vec(op(), 1..200),
+    ) {
+        let mut model = Model::with_leaves(&initial_leaves);
+        let rightmost_leaf = initial_leaves.len() - 1;
+        let mut merkle_roll = MerkleRoll::<DEPTH, BUFFER_SIZE>::new();
+        merkle_roll
+            .initialize_with_root(
+                model.tree.get_root(),
+                initial_leaves[rightmost_leaf],
+                &model.tree.get_proof_of_leaf(rightmost_leaf),
+                rightmost_leaf as u32,
+            )
+            .unwrap();
+        check_ops(&mut merkle_roll, &mut model, &ops)?;
+    }
+}
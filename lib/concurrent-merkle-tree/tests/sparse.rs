@@ -0,0 +1,343 @@
+//! Tests for the sparse merkle roll, checked against a naive sparse merkle tree
+//! that recomputes every node from the full set of keys.
+use concurrent_merkle_tree::error::CMTError;
+use concurrent_merkle_tree::hasher::{Hasher, Keccak};
+use concurrent_merkle_tree::merkle_roll_view::StalenessPolicy;
+use concurrent_merkle_tree::sparse_merkle_roll::{
+    compress_proof, decompress_proof, key_bit, sparse_merkle_roll_size, Key, SparseMerkleRollMut,
+    SparseMerkleRollRef, SPARSE_DEPTH,
+};
+use concurrent_merkle_tree::state::{Node, EMPTY};
+use rand::thread_rng;
+use rand::{self, Rng};
+use std::collections::BTreeMap;
+
+const BUFFER_SIZE: usize = 8;
+
+/// Sparse merkle tree that stores every non-empty value
+#[derive(Default)]
+struct ReferenceTree {
+    values: BTreeMap<Key, Node>,
+}
+
+impl ReferenceTree {
+    fn set(&mut self, key: Key, value: Node) {
+        if value == EMPTY {
+            self.values.remove(&key);
+        } else {
+            self.values.insert(key, value);
+        }
+    }
+
+    fn get(&self, key: &Key) -> Node {
+        self.values.get(key).copied().unwrap_or(EMPTY)
+    }
+
+    fn root(&self) -> Node {
+        let leaves: Vec<_> = self.values.iter().map(|(k, v)| (*k, *v)).collect();
+        subtree_root(&leaves, SPARSE_DEPTH)
+    }
+
+    /// Returns the siblings of the path of `key`, from the leaf up
+    fn proof(&self, key: &Key) -> Vec<Node> {
+        (0..SPARSE_DEPTH)
+            .map(|level| {
+                let siblings: Vec<_> = self
+                    .values
+                    .iter()
+                    .filter(|(k, _)| highest_different_bit(k, key) == Some(level))
+                    .map(|(k, v)| (*k, *v))
+                    .collect();
+                subtree_root(&siblings, level)
+            })
+            .collect()
+    }
+}
+
+/// Returns the height at which the paths of `a` and `b` are siblings
+fn highest_different_bit(a: &Key, b: &Key) -> Option<usize> {
+    (0..SPARSE_DEPTH)
+        .rev()
+        .find(|level| key_bit(a, *level) != key_bit(b, *level))
+}
+
+/// Root of the subtree of height `height` that holds `leaves`, which all share the same path
+/// above that height
+fn subtree_root(leaves: &[(Key, Node)], height: usize) -> Node {
+    if leaves.is_empty() {
+        return EMPTY;
+    }
+    if height == 0 {
+        return leaves[0].1;
+    }
+    let (right, left): (Vec<_>, Vec<_>) =
+        leaves.iter().partition(|(key, _)| key_bit(key, height - 1));
+    let left = subtree_root(&left, height - 1);
+    let right = subtree_root(&right, height - 1);
+    if left == EMPTY && right == EMPTY {
+        EMPTY
+    } else {
+        Keccak::hash_pair(&left, &right)
+    }
+}
+
+/// Backs a sparse merkle roll with u64s so that the bytes are correctly aligned
+fn sparse_merkle_roll_data() -> Vec<u64> {
+    vec![0_u64; sparse_merkle_roll_size(BUFFER_SIZE).unwrap() / 8]
+}
+
+fn random_value(rng: &mut impl Rng) -> Node {
+    rng.gen::<Node>()
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_sparse_dimensions() {
+    assert!(matches!(
+        sparse_merkle_roll_size(0),
+        Err(CMTError::InvalidDepthOrBufferSize)
+    ));
+    assert!(matches!(
+        sparse_merkle_roll_size(6),
+        Err(CMTError::InvalidDepthOrBufferSize)
+    ));
+    let mut data = sparse_merkle_roll_data();
+    let bytes: &mut [u8] = bytemuck::cast_slice_mut(&mut data);
+    assert!(matches!(
+        SparseMerkleRollRef::new(bytes, BUFFER_SIZE / 2),
+        Err(CMTError::InvalidMerkleRollBytes)
+    ));
+    assert!(matches!(
+        SparseMerkleRollMut::new(&mut bytes[8..], BUFFER_SIZE),
+        Err(CMTError::InvalidMerkleRollBytes)
+    ));
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_sparse_insert_and_prove() {
+    let mut rng = thread_rng();
+    let mut data = sparse_merkle_roll_data();
+    let mut sparse =
+        SparseMerkleRollMut::new(bytemuck::cast_slice_mut(&mut data), BUFFER_SIZE).unwrap();
+    let mut tree = ReferenceTree::default();
+    assert_eq!(sparse.initialize().unwrap(), EMPTY);
+    assert_eq!(tree.root(), EMPTY);
+
+    // Every key is absent from an empty tree
+    let key: Key = rng.gen();
+    let mut proof = tree.proof(&key);
+    sparse.prove_leaf(EMPTY, &key, EMPTY, &mut proof).unwrap();
+
+    for _ in 0..(2 * BUFFER_SIZE) {
+        let key: Key = rng.gen();
+        let value = random_value(&mut rng);
+        let mut proof = tree.proof(&key);
+        let root = sparse
+            .set_leaf(sparse.get_change_log().root, &key, EMPTY, value, &mut proof)
+            .unwrap();
+        tree.set(key, value);
+        assert_eq!(root, tree.root());
+    }
+    assert_eq!(sparse.sequence_number(), 2 * BUFFER_SIZE as u64);
+
+    let view = sparse.view();
+    let root = tree.root();
+    for (key, value) in tree.values.iter() {
+        let proof = tree.proof(key);
+        view.prove_leaf(root, key, *value, &mut proof.clone())
+            .unwrap();
+        // A present key can't be proven absent
+        assert!(matches!(
+            view.prove_leaf(root, key, EMPTY, &mut proof.clone()),
+            Err(CMTError::InvalidProof)
+        ));
+    }
+    let absent: Key = rng.gen();
+    let mut proof = tree.proof(&absent);
+    view.prove_leaf(tree.root(), &absent, EMPTY, &mut proof)
+        .unwrap();
+    let mut proof = tree.proof(&absent);
+    assert!(matches!(
+        view.prove_leaf(tree.root(), &absent, EMPTY, &mut proof[1..]),
+        Err(CMTError::ProofLengthMismatch)
+    ));
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_sparse_concurrent_writes() {
+    let mut rng = thread_rng();
+    let mut data = sparse_merkle_roll_data();
+    let mut sparse =
+        SparseMerkleRollMut::new(bytemuck::cast_slice_mut(&mut data), BUFFER_SIZE).unwrap();
+    let mut tree = ReferenceTree::default();
+    sparse.initialize().unwrap();
+
+    // Neighbouring keys share most of their path, so their proofs depend on each other
+    let mut keys = vec![];
+    for i in 0..BUFFER_SIZE as u8 {
+        let mut key = [0; 32];
+        key[31] = i;
+        keys.push(key);
+    }
+    for key in keys.iter().step_by(2) {
+        let value = random_value(&mut rng);
+        sparse
+            .set_leaf(tree.root(), key, EMPTY, value, &mut tree.proof(key))
+            .unwrap();
+        tree.set(*key, value);
+    }
+
+    // Every write uses a proof for the same root, so all but the first one are fast-forwarded
+    let root = tree.root();
+    let proofs: Vec<_> = keys.iter().map(|key| tree.proof(key)).collect();
+    let previous_values: Vec<_> = keys.iter().map(|key| tree.get(key)).collect();
+    for ((key, proof), previous_value) in keys.iter().zip(&proofs).zip(&previous_values) {
+        let value = if rng.gen_bool(0.25) {
+            EMPTY
+        } else {
+            random_value(&mut rng)
+        };
+        let mut proof = proof.clone();
+        sparse
+            .set_leaf(root, key, *previous_value, value, &mut proof)
+            .unwrap();
+        tree.set(*key, value);
+        assert_eq!(sparse.get_change_log().root, tree.root());
+        // The proof was fast-forwarded to the new tree, so it stays usable
+        assert_eq!(proof, tree.proof(key));
+    }
+
+    // The first key was written since its proof was taken
+    assert!(matches!(
+        sparse.set_leaf(
+            root,
+            &keys[0],
+            previous_values[0],
+            EMPTY,
+            &mut proofs[0].clone()
+        ),
+        Err(CMTError::LeafContentsModified)
+    ));
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_sparse_leaf_contents_modified() {
+    let mut rng = thread_rng();
+    let mut data = sparse_merkle_roll_data();
+    let mut sparse =
+        SparseMerkleRollMut::new(bytemuck::cast_slice_mut(&mut data), BUFFER_SIZE).unwrap();
+    let mut tree = ReferenceTree::default();
+    sparse.initialize().unwrap();
+
+    let key: Key = rng.gen();
+    let stale_proof = tree.proof(&key);
+    let value = random_value(&mut rng);
+    sparse
+        .set_leaf(EMPTY, &key, EMPTY, value, &mut stale_proof.clone())
+        .unwrap();
+    tree.set(key, value);
+
+    // Inserting the same key again with the empty tree's proof conflicts with the first write
+    assert!(matches!(
+        sparse.set_leaf(
+            EMPTY,
+            &key,
+            EMPTY,
+            random_value(&mut rng),
+            &mut stale_proof.clone()
+        ),
+        Err(CMTError::LeafContentsModified)
+    ));
+    // It succeeds once the stale proof expects the value that was written
+    let new_value = random_value(&mut rng);
+    sparse
+        .set_leaf(EMPTY, &key, value, new_value, &mut stale_proof.clone())
+        .unwrap();
+    tree.set(key, new_value);
+    assert_eq!(sparse.get_change_log().root, tree.root());
+
+    // Removing the only key restores the empty root
+    sparse
+        .set_leaf(tree.root(), &key, new_value, EMPTY, &mut tree.proof(&key))
+        .unwrap();
+    assert_eq!(sparse.get_change_log().root, EMPTY);
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_sparse_staleness_policy() {
+    let mut rng = thread_rng();
+    let mut data = sparse_merkle_roll_data();
+    let bytes: &mut [u8] = bytemuck::cast_slice_mut(&mut data);
+    let mut sparse = SparseMerkleRollMut::new(bytes, BUFFER_SIZE).unwrap();
+    let mut tree = ReferenceTree::default();
+    sparse.initialize().unwrap();
+
+    let key: Key = rng.gen();
+    let stale_proof = tree.proof(&key);
+    // The first write only changes the bottom of the path of `key`, and is dropped from
+    // the buffer by the other writes, which only change its top
+    let mut sibling = key;
+    sibling[31] ^= 1;
+    let value = random_value(&mut rng);
+    sparse
+        .set_leaf(EMPTY, &sibling, EMPTY, value, &mut tree.proof(&sibling))
+        .unwrap();
+    tree.set(sibling, value);
+    for _ in 0..BUFFER_SIZE {
+        let mut other: Key = rng.gen();
+        other[0] = !key[0];
+        let value = random_value(&mut rng);
+        sparse
+            .set_leaf(tree.root(), &other, EMPTY, value, &mut tree.proof(&other))
+            .unwrap();
+        tree.set(other, value);
+    }
+
+    // The empty root fell out of the buffer
+    let sparse = sparse.with_staleness_policy(StalenessPolicy::RejectUnknownRoot);
+    assert!(matches!(
+        sparse.prove_leaf(EMPTY, &key, EMPTY, &mut stale_proof.clone()),
+        Err(CMTError::RootNotFound)
+    ));
+    // Replaying the buffer is not enough to patch the proof for the dropped writes
+    let sparse = sparse.with_staleness_policy(StalenessPolicy::FullReplay);
+    assert!(matches!(
+        sparse.prove_leaf(EMPTY, &key, EMPTY, &mut stale_proof.clone()),
+        Err(CMTError::InvalidProof)
+    ));
+    sparse
+        .prove_leaf(tree.root(), &key, EMPTY, &mut tree.proof(&key))
+        .unwrap();
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_sparse_proof_compression() {
+    let mut rng = thread_rng();
+    let mut tree = ReferenceTree::default();
+    for _ in 0..16 {
+        tree.set(rng.gen(), random_value(&mut rng));
+    }
+    let key: Key = rng.gen();
+    let proof = tree.proof(&key);
+    let (non_empty, nodes) = compress_proof(&proof);
+    // Only the top of the tree has non-empty siblings
+    assert!(nodes.len() < 32);
+
+    let mut decompressed = vec![EMPTY; SPARSE_DEPTH];
+    decompress_proof(&non_empty, &nodes, &mut decompressed).unwrap();
+    assert_eq!(decompressed, proof);
+
+    assert!(matches!(
+        decompress_proof(&non_empty, &nodes[1..], &mut decompressed),
+        Err(CMTError::ProofLengthMismatch)
+    ));
+    let mut extra_nodes = nodes.clone();
+    extra_nodes.push(EMPTY);
+    assert!(matches!(
+        decompress_proof(&non_empty, &extra_nodes, &mut decompressed),
+        Err(CMTError::ProofLengthMismatch)
+    ));
+    assert!(matches!(
+        decompress_proof(&non_empty, &nodes, &mut decompressed[1..]),
+        Err(CMTError::ProofLengthMismatch)
+    ));
+}
@@ -1,7 +1,24 @@
+use concurrent_merkle_tree::canopy::{
+    canopy_size, migrate_canopy_in_place, required_canopy_depth, Canopy, CanopyMut,
+    MAX_TRANSACTION_SIZE, PROOF_NODE_TRANSACTION_SIZE,
+};
 use concurrent_merkle_tree::error::CMTError;
+use concurrent_merkle_tree::free_list::{free_list_size, FreeListMut, FreeListRef};
+use concurrent_merkle_tree::hasher::{Hasher, Keccak, LeafHashScheme, Sha256, EMPTY_NODES_LEN};
 use concurrent_merkle_tree::merkle_roll::MerkleRoll;
+use concurrent_merkle_tree::merkle_roll_view::{
+    merkle_roll_size, migrate_in_place, ComputeStats, MerkleRollMut, MerkleRollRef, StalenessPolicy,
+};
+use concurrent_merkle_tree::multiproof::{expand_multiproof, recompute_multiproof};
+use concurrent_merkle_tree::root_history::{root_history_size, RootHistoryMut, RootHistoryRef};
+use concurrent_merkle_tree::snapshot::SNAPSHOT_VERSION;
 use concurrent_merkle_tree::state::{Node, EMPTY};
-use merkle_tree_reference::MerkleTree;
+use concurrent_merkle_tree::utils::{empty_node, hash_to_parent};
+use merkle_tree_reference::changelog::{ChangeLogEvent, ChangeLogEventError};
+use merkle_tree_reference::persistent::{
+    FileNodeStore, MemoryNodeStore, NodeStore, PersistentMerkleTree, PersistentTreeError,
+};
+use merkle_tree_reference::{HistoryError, MerkleTree};
 use rand::thread_rng;
 use rand::{self, Rng};
 use tokio;
@@ -90,7 +107,7 @@ async fn test_prove_leaf() {
     let num_leaves_to_try = 10;
     for _ in 0..num_leaves_to_try {
         let leaf_idx = rng.gen_range(0, 1 << DEPTH);
-        let last_leaf_idx = off_chain_tree.leaf_nodes.len() - 1;
+        let last_leaf_idx = off_chain_tree.capacity() as usize - 1;
         let root = off_chain_tree.get_root();
         let leaf = off_chain_tree.get_leaf(leaf_idx);
         let old_proof = off_chain_tree.get_proof_of_leaf(leaf_idx);
@@ -131,7 +148,36 @@ async fn test_initialize_with_root() {
         tree.add_leaf(rng.gen::<[u8; 32]>(), i);
     }
 
-    let last_leaf_idx = tree.leaf_nodes.len() - 1;
+    let last_leaf_idx = tree.capacity() as usize - 1;
+    let proof = tree.get_proof_of_leaf(last_leaf_idx);
+    assert!(matches!(
+        merkle_roll.initialize_with_root(
+            rng.gen::<Node>(),
+            tree.get_leaf(last_leaf_idx),
+            &proof,
+            last_leaf_idx as u32,
+        ),
+        Err(CMTError::RootMismatchOnInit)
+    ));
+    assert!(matches!(
+        merkle_roll.initialize_with_root(
+            tree.get_root(),
+            tree.get_leaf(last_leaf_idx),
+            &proof[..DEPTH - 1],
+            last_leaf_idx as u32,
+        ),
+        Err(CMTError::ProofLengthMismatch)
+    ));
+    assert!(matches!(
+        merkle_roll.initialize_with_root(
+            tree.get_root(),
+            tree.get_leaf(last_leaf_idx),
+            &proof,
+            1 << DEPTH,
+        ),
+        Err(CMTError::LeafIndexOutOfBounds)
+    ));
+
     merkle_roll
         .initialize_with_root(
             tree.get_root(),
@@ -397,3 +443,1899 @@ async fn test_append_bug_repro_2() {
     last_rmp = merkle_roll.rightmost_proof;
     assert_eq!(merkle_roll.get_change_log().root, tree.get_root());
 }
+
+#[tokio::test(threaded_scheduler)]
+async fn test_merkle_roll_size() {
+    assert_eq!(
+        merkle_roll_size(DEPTH, BUFFER_SIZE).unwrap(),
+        std::mem::size_of::<MerkleRoll<DEPTH, BUFFER_SIZE>>()
+    );
+    assert_eq!(
+        merkle_roll_size(30, 2048).unwrap(),
+        std::mem::size_of::<MerkleRoll<30, 2048>>()
+    );
+    assert!(matches!(
+        merkle_roll_size(31, 64),
+        Err(CMTError::InvalidDepthOrBufferSize)
+    ));
+    assert!(matches!(
+        merkle_roll_size(14, 48),
+        Err(CMTError::InvalidDepthOrBufferSize)
+    ));
+}
+
+#[tokio::test(threaded_scheduler)]
+/// Runtime-sized merkle roll with dimensions that have no `MerkleRoll` instantiation
+async fn test_merkle_roll_view() {
+    let depth = 9;
+    let buffer_size = 16;
+    let mut rng = thread_rng();
+    let mut tree = MerkleTree::new(vec![EMPTY; 1 << depth]);
+
+    // Back the roll with u64s so that the bytes are correctly aligned
+    let mut data = vec![0_u64; merkle_roll_size(depth, buffer_size).unwrap() / 8];
+    let bytes: &mut [u8] = bytemuck::cast_slice_mut(&mut data);
+    assert!(matches!(
+        MerkleRollMut::new(&mut bytes[8..], depth, buffer_size),
+        Err(CMTError::InvalidMerkleRollBytes)
+    ));
+    let mut merkle_roll = MerkleRollMut::new(bytes, depth, buffer_size).unwrap();
+    merkle_roll.initialize().unwrap();
+    assert_eq!(*merkle_roll.get_change_log().root, tree.get_root());
+
+    for i in 0..(1 << depth) {
+        let leaf = rng.gen::<Node>();
+        merkle_roll.append(leaf).unwrap();
+        tree.add_leaf(leaf, i);
+        assert_eq!(*merkle_roll.get_change_log().root, tree.get_root());
+    }
+    assert!(matches!(
+        merkle_roll.append(rng.gen::<Node>()),
+        Err(CMTError::TreeFull)
+    ));
+
+    for _ in 0..(1 << depth) {
+        let index = rng.gen_range(0, 1 << depth);
+        let leaf = rng.gen::<Node>();
+        merkle_roll
+            .set_leaf(
+                tree.get_root(),
+                tree.get_leaf(index),
+                leaf,
+                &tree.get_proof_of_leaf(index),
+                index as u32,
+            )
+            .unwrap();
+        tree.add_leaf(leaf, index);
+        assert_eq!(*merkle_roll.get_change_log().root, tree.get_root());
+    }
+
+    let index = rng.gen_range(0, 1 << depth);
+    merkle_roll
+        .prove_leaf(
+            tree.get_root(),
+            tree.get_leaf(index),
+            &tree.get_proof_of_leaf(index),
+            index as u32,
+        )
+        .unwrap();
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_append_batch() {
+    let (mut merkle_roll, mut tree) = setup();
+    let mut rng = thread_rng();
+    merkle_roll.initialize().unwrap();
+
+    let mut tree_size = 0;
+    let mut batch_size = 1;
+    while tree_size + batch_size <= (1 << DEPTH) {
+        let leaves: Vec<Node> = (0..batch_size).map(|_| rng.gen::<Node>()).collect();
+        let seq = merkle_roll.sequence_number;
+        merkle_roll.append_batch(&leaves).unwrap();
+        for (i, leaf) in leaves.iter().enumerate() {
+            tree.add_leaf(*leaf, tree_size + i);
+        }
+        tree_size += batch_size;
+
+        assert_eq!(merkle_roll.get_change_log().root, tree.get_root());
+        assert!(merkle_roll.sequence_number - seq <= 2 * DEPTH as u64);
+        assert_eq!(merkle_roll.rightmost_proof.index as usize, tree_size);
+        assert_eq!(
+            merkle_roll.rightmost_proof.leaf,
+            tree.get_leaf(tree_size - 1)
+        );
+        assert_eq!(
+            merkle_roll.rightmost_proof.proof.to_vec(),
+            tree.get_proof_of_leaf(tree_size - 1)
+        );
+        batch_size = batch_size * 3 % 37 + 1;
+    }
+
+    // Single appends still work after batches
+    let leaf = rng.gen::<Node>();
+    merkle_roll.append(leaf).unwrap();
+    tree.add_leaf(leaf, tree_size);
+    assert_eq!(merkle_roll.get_change_log().root, tree.get_root());
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_append_batch_fast_forward() {
+    let (mut merkle_roll, mut tree) = setup();
+    let mut rng = thread_rng();
+    merkle_roll.initialize().unwrap();
+
+    let leaves: Vec<Node> = (0..13).map(|_| rng.gen::<Node>()).collect();
+    merkle_roll.append_batch(&leaves).unwrap();
+    for (i, leaf) in leaves.iter().enumerate() {
+        tree.add_leaf(*leaf, i);
+    }
+
+    // Proofs issued before a batch are fast-forwarded through the batch's change logs
+    let root = tree.get_root();
+    let proofs: Vec<Vec<Node>> = (0..leaves.len())
+        .map(|i| tree.get_proof_of_leaf(i))
+        .collect();
+    let batch: Vec<Node> = (0..22).map(|_| rng.gen::<Node>()).collect();
+    merkle_roll.append_batch(&batch).unwrap();
+    for (i, leaf) in batch.iter().enumerate() {
+        tree.add_leaf(*leaf, leaves.len() + i);
+    }
+    for (i, proof) in proofs.iter().enumerate() {
+        let new_leaf = rng.gen::<Node>();
+        merkle_roll
+            .set_leaf(root, leaves[i], new_leaf, proof, i as u32)
+            .unwrap();
+        tree.add_leaf(new_leaf, i);
+        assert_eq!(merkle_roll.get_change_log().root, tree.get_root());
+    }
+
+    // Batches are all or nothing
+    let seq = merkle_roll.sequence_number;
+    let mut invalid_batch: Vec<Node> = (0..5).map(|_| rng.gen::<Node>()).collect();
+    invalid_batch[3] = EMPTY;
+    assert!(matches!(
+        merkle_roll.append_batch(&invalid_batch),
+        Err(CMTError::CannotAppendEmptyNode)
+    ));
+    assert_eq!(merkle_roll.sequence_number, seq);
+    assert_eq!(merkle_roll.get_change_log().root, tree.get_root());
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_append_subtree() {
+    let (mut merkle_roll, mut tree) = setup();
+    let mut rng = thread_rng();
+    merkle_roll.initialize().unwrap();
+
+    let mut tree_size = 0;
+    for subtree_depth in [3, 0, 0, 1, 2, 4, 5, 6, 7] {
+        // Compute the full subtree off-chain
+        let leaves: Vec<Node> = (0..(1 << subtree_depth))
+            .map(|_| rng.gen::<Node>())
+            .collect();
+        let subtree = MerkleTree::new(leaves.clone());
+        let last = leaves.len() - 1;
+
+        merkle_roll
+            .append_subtree(
+                subtree.get_root(),
+                subtree_depth as u32,
+                subtree.get_leaf(last),
+                &subtree.get_proof_of_leaf(last),
+            )
+            .unwrap();
+        for (i, leaf) in leaves.iter().enumerate() {
+            tree.add_leaf(*leaf, tree_size + i);
+        }
+        tree_size += leaves.len();
+
+        assert_eq!(merkle_roll.get_change_log().root, tree.get_root());
+        assert_eq!(
+            merkle_roll.rightmost_proof.proof.to_vec(),
+            tree.get_proof_of_leaf(tree_size - 1)
+        );
+    }
+
+    // Misaligned subtrees are rejected: the rightmost index is now 256
+    let leaves: Vec<Node> = (0..512).map(|_| rng.gen::<Node>()).collect();
+    let subtree = MerkleTree::new(leaves);
+    assert!(matches!(
+        merkle_roll.append_subtree(
+            subtree.get_root(),
+            9,
+            subtree.get_leaf(511),
+            &subtree.get_proof_of_leaf(511),
+        ),
+        Err(CMTError::SubtreeNotAligned)
+    ));
+
+    // Subtrees must match their root and depth
+    let leaves: Vec<Node> = (0..256).map(|_| rng.gen::<Node>()).collect();
+    let subtree = MerkleTree::new(leaves);
+    assert!(matches!(
+        merkle_roll.append_subtree(
+            rng.gen::<Node>(),
+            8,
+            subtree.get_leaf(255),
+            &subtree.get_proof_of_leaf(255),
+        ),
+        Err(CMTError::InvalidProof)
+    ));
+    assert!(matches!(
+        merkle_roll.append_subtree(subtree.get_root(), 8, subtree.get_leaf(255), &[]),
+        Err(CMTError::ProofLengthMismatch)
+    ));
+
+    // Proofs are still valid for leaves inside of appended subtrees
+    let index = rng.gen_range(0, tree_size);
+    let leaf = rng.gen::<Node>();
+    merkle_roll
+        .set_leaf(
+            tree.get_root(),
+            tree.get_leaf(index),
+            leaf,
+            &tree.get_proof_of_leaf(index),
+            index as u32,
+        )
+        .unwrap();
+    tree.add_leaf(leaf, index);
+    assert_eq!(merkle_roll.get_change_log().root, tree.get_root());
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_free_list() {
+    let (mut merkle_roll, mut tree) = setup();
+    let mut rng = thread_rng();
+    merkle_roll.initialize().unwrap();
+
+    for i in 0..64 {
+        let leaf = rng.gen::<Node>();
+        merkle_roll.append(leaf).unwrap();
+        tree.add_leaf(leaf, i);
+    }
+
+    let mut free_list_bytes = vec![0_u32; free_list_size(4) / 4];
+    let mut free_list =
+        FreeListMut::new(bytemuck::cast_slice_mut(free_list_bytes.as_mut_slice())).unwrap();
+
+    // Emptied leaves are tracked until the free list is full
+    for index in [3, 17, 42, 5] {
+        merkle_roll
+            .remove_leaf(
+                tree.get_root(),
+                tree.get_leaf(index),
+                &tree.get_proof_of_leaf(index),
+                index as u32,
+                &mut free_list,
+            )
+            .unwrap();
+        tree.add_leaf(EMPTY, index);
+        assert_eq!(merkle_roll.get_change_log().root, tree.get_root());
+    }
+    assert_eq!(free_list.view().slots(), &[3, 17, 42, 5]);
+    let seq = merkle_roll.sequence_number;
+    assert!(matches!(
+        merkle_roll.remove_leaf(
+            tree.get_root(),
+            tree.get_leaf(9),
+            &tree.get_proof_of_leaf(9),
+            9,
+            &mut free_list,
+        ),
+        Err(CMTError::FreeListFull)
+    ));
+    assert!(matches!(
+        merkle_roll.remove_leaf(
+            tree.get_root(),
+            EMPTY,
+            &tree.get_proof_of_leaf(64),
+            64,
+            &mut free_list,
+        ),
+        Err(CMTError::LeafIndexOutOfBounds)
+    ));
+    assert_eq!(merkle_roll.sequence_number, seq);
+
+    // Only tracked leaves can be reused
+    let leaf = rng.gen::<Node>();
+    assert!(matches!(
+        merkle_roll.insert_into_free_slot(
+            tree.get_root(),
+            leaf,
+            &tree.get_proof_of_leaf(9),
+            9,
+            &mut free_list,
+        ),
+        Err(CMTError::LeafNotFree)
+    ));
+
+    // Stale proofs for a free slot are fast-forwarded
+    let root = tree.get_root();
+    let proof = tree.get_proof_of_leaf(17);
+    let new_leaf = rng.gen::<Node>();
+    merkle_roll
+        .set_leaf(
+            tree.get_root(),
+            tree.get_leaf(30),
+            new_leaf,
+            &tree.get_proof_of_leaf(30),
+            30,
+        )
+        .unwrap();
+    tree.add_leaf(new_leaf, 30);
+    merkle_roll
+        .insert_into_free_slot(root, leaf, &proof, 17, &mut free_list)
+        .unwrap();
+    tree.add_leaf(leaf, 17);
+    assert_eq!(merkle_roll.get_change_log().root, tree.get_root());
+    assert_eq!(free_list.view().slots(), &[3, 5, 42]);
+
+    // The free list can be read back from its bytes
+    let free_list = FreeListRef::new(bytemuck::cast_slice(free_list_bytes.as_slice())).unwrap();
+    assert_eq!(free_list.capacity(), 4);
+    assert_eq!(free_list.slots(), &[3, 5, 42]);
+    assert!(FreeListRef::new(&[0_u8; 3]).is_err());
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_exclusion_proofs() {
+    let (mut merkle_roll, mut tree) = setup();
+    let mut rng = thread_rng();
+    merkle_roll.initialize().unwrap();
+
+    for i in 0..100 {
+        let leaf = rng.gen::<Node>();
+        merkle_roll.append(leaf).unwrap();
+        tree.add_leaf(leaf, i);
+    }
+    merkle_roll
+        .set_leaf(
+            tree.get_root(),
+            tree.get_leaf(40),
+            EMPTY,
+            &tree.get_proof_of_leaf(40),
+            40,
+        )
+        .unwrap();
+    tree.add_leaf(EMPTY, 40);
+
+    // Empty leaves can be proven, written leaves cannot
+    merkle_roll
+        .prove_empty_leaf(tree.get_root(), &tree.get_proof_of_leaf(40), 40)
+        .unwrap();
+    merkle_roll
+        .prove_empty_leaf(tree.get_root(), &tree.get_proof_of_leaf(500), 500)
+        .unwrap();
+    assert!(merkle_roll
+        .prove_empty_leaf(tree.get_root(), &tree.get_proof_of_leaf(41), 41)
+        .is_err());
+    assert!(matches!(
+        merkle_roll.prove_empty_leaf(tree.get_root(), &[], 1 << DEPTH),
+        Err(CMTError::LeafIndexOutOfBounds)
+    ));
+
+    // Leaf counts can be proven with a proof of the first leaf past the count
+    let root = tree.get_root();
+    let proof = tree.get_proof_of_leaf(100);
+    let empty_leaf_proof = tree.get_proof_of_leaf(40);
+    merkle_roll.prove_leaf_count(root, &proof, 100).unwrap();
+    assert!(matches!(
+        merkle_roll.prove_leaf_count(root, &tree.get_proof_of_leaf(40), 40),
+        Err(CMTError::LeafCountExceeded)
+    ));
+    assert!(merkle_roll
+        .prove_leaf_count(root, &tree.get_proof_of_leaf(99), 99)
+        .is_err());
+
+    // Stale proofs are fast-forwarded, as long as no leaf was added past the count
+    let new_leaf = rng.gen::<Node>();
+    merkle_roll
+        .set_leaf(
+            root,
+            tree.get_leaf(7),
+            new_leaf,
+            &tree.get_proof_of_leaf(7),
+            7,
+        )
+        .unwrap();
+    tree.add_leaf(new_leaf, 7);
+    merkle_roll.prove_leaf_count(root, &proof, 100).unwrap();
+    merkle_roll
+        .prove_empty_leaf(root, &empty_leaf_proof, 40)
+        .unwrap();
+
+    let new_leaf = rng.gen::<Node>();
+    merkle_roll.append(new_leaf).unwrap();
+    tree.add_leaf(new_leaf, 100);
+    assert!(merkle_roll.prove_leaf_count(root, &proof, 100).is_err());
+    merkle_roll
+        .prove_leaf_count(tree.get_root(), &tree.get_proof_of_leaf(101), 101)
+        .unwrap();
+
+    // Trailing leaves that were emptied do not count
+    merkle_roll
+        .set_leaf(
+            tree.get_root(),
+            new_leaf,
+            EMPTY,
+            &tree.get_proof_of_leaf(100),
+            100,
+        )
+        .unwrap();
+    tree.add_leaf(EMPTY, 100);
+    merkle_roll
+        .prove_leaf_count(tree.get_root(), &tree.get_proof_of_leaf(100), 100)
+        .unwrap();
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_set_leaves() {
+    let (mut merkle_roll, mut tree) = setup();
+    let mut rng = thread_rng();
+    merkle_roll.initialize().unwrap();
+
+    for i in 0..(1 << DEPTH) {
+        let leaf = rng.gen::<Node>();
+        merkle_roll.append(leaf).unwrap();
+        tree.add_leaf(leaf, i);
+    }
+
+    // Swap two leaves, and replace a few others, with proofs for the same root
+    let root = tree.get_root();
+    let indices = [3, 9, 1000, 1001, 8191, 4];
+    let proofs: Vec<Vec<Node>> = indices.iter().map(|i| tree.get_proof_of_leaf(*i)).collect();
+    let previous_leaves: Vec<Node> = indices.iter().map(|i| tree.get_leaf(*i)).collect();
+    let stale_proof = tree.get_proof_of_leaf(7);
+    let mut new_leaves: Vec<Node> = indices.iter().map(|_| rng.gen::<Node>()).collect();
+    new_leaves[0] = previous_leaves[1];
+    new_leaves[1] = previous_leaves[0];
+
+    // Proofs are fast-forwarded past unrelated updates
+    let leaf = rng.gen::<Node>();
+    merkle_roll
+        .set_leaf(root, tree.get_leaf(5), leaf, &tree.get_proof_of_leaf(5), 5)
+        .unwrap();
+    tree.add_leaf(leaf, 5);
+
+    // Proofs are fast-forwarded in place, so every call gets its own copy
+    fn batch<'a>(
+        indices: &[usize],
+        previous_leaves: &[Node],
+        new_leaves: &[Node],
+        proofs: &'a mut [Vec<Node>],
+    ) -> Vec<(u32, Node, Node, &'a mut [Node])> {
+        proofs
+            .iter_mut()
+            .enumerate()
+            .map(|(i, proof)| {
+                (
+                    indices[i] as u32,
+                    previous_leaves[i],
+                    new_leaves[i],
+                    proof.as_mut_slice(),
+                )
+            })
+            .collect()
+    }
+
+    // Updates are all or nothing
+    let seq = merkle_roll.sequence_number;
+    let mut invalid_proofs = proofs.clone();
+    let mut invalid_updates = batch(&indices, &previous_leaves, &new_leaves, &mut invalid_proofs);
+    invalid_updates[3].1 = rng.gen::<Node>();
+    assert!(merkle_roll.set_leaves(root, &mut invalid_updates).is_err());
+    let mut duplicate_proofs = proofs.clone();
+    let mut duplicate_updates = batch(
+        &indices,
+        &previous_leaves,
+        &new_leaves,
+        &mut duplicate_proofs,
+    );
+    duplicate_updates[5].0 = 9;
+    assert!(matches!(
+        merkle_roll.set_leaves(root, &mut duplicate_updates),
+        Err(CMTError::DuplicateLeafIndex)
+    ));
+    let mut short_proofs = proofs.clone();
+    short_proofs[2].pop();
+    assert!(matches!(
+        merkle_roll.set_leaves(
+            root,
+            &mut batch(&indices, &previous_leaves, &new_leaves, &mut short_proofs)
+        ),
+        Err(CMTError::ProofLengthMismatch)
+    ));
+    // The last leaf is past the end of the full tree, and checked before any leaf is written
+    let mut out_of_bounds_proofs = proofs.clone();
+    out_of_bounds_proofs[5] = tree.get_proof_of_leaf(0);
+    let mut out_of_bounds_updates = batch(
+        &indices,
+        &previous_leaves,
+        &new_leaves,
+        &mut out_of_bounds_proofs,
+    );
+    out_of_bounds_updates[5].0 = 1 << DEPTH;
+    out_of_bounds_updates[5].1 = tree.get_leaf(0);
+    assert!(matches!(
+        merkle_roll.set_leaves(root, &mut out_of_bounds_updates),
+        Err(CMTError::LeafIndexOutOfBounds)
+    ));
+    assert_eq!(merkle_roll.sequence_number, seq);
+    assert_eq!(merkle_roll.get_change_log().root, tree.get_root());
+
+    let mut batch_proofs = proofs.clone();
+    merkle_roll
+        .set_leaves(
+            root,
+            &mut batch(&indices, &previous_leaves, &new_leaves, &mut batch_proofs),
+        )
+        .unwrap();
+    for (i, index) in indices.iter().enumerate() {
+        tree.add_leaf(new_leaves[i], *index);
+    }
+    assert_eq!(merkle_roll.sequence_number, seq + indices.len() as u64);
+    assert_eq!(merkle_roll.get_change_log().root, tree.get_root());
+    assert_eq!(tree.get_leaf(3), previous_leaves[1]);
+    assert_eq!(tree.get_leaf(9), previous_leaves[0]);
+
+    // Proofs issued before the batch are fast-forwarded through each of its change logs
+    let leaf = rng.gen::<Node>();
+    assert!(matches!(
+        merkle_roll.set_leaf(root, previous_leaves[2], leaf, &proofs[2], 1000),
+        Err(CMTError::LeafContentsModified)
+    ));
+    merkle_roll
+        .set_leaf(root, tree.get_leaf(7), leaf, &stale_proof, 7)
+        .unwrap();
+    tree.add_leaf(leaf, 7);
+    assert_eq!(merkle_roll.get_change_log().root, tree.get_root());
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_sha256_hasher() {
+    let mut merkle_roll = MerkleRoll::<DEPTH, BUFFER_SIZE, Sha256>::new();
+    let mut tree = MerkleTree::with_hasher(vec![EMPTY; 1 << DEPTH], Sha256);
+    let mut rng = thread_rng();
+    merkle_roll.initialize().unwrap();
+    assert_eq!(merkle_roll.get_change_log().root, tree.get_root());
+    assert_ne!(tree.get_root(), setup().1.get_root());
+
+    for i in 0..100 {
+        let leaf = rng.gen::<Node>();
+        merkle_roll.append(leaf).unwrap();
+        tree.add_leaf(leaf, i);
+        assert_eq!(merkle_roll.get_change_log().root, tree.get_root());
+    }
+
+    let root = tree.get_root();
+    for _ in 0..BUFFER_SIZE {
+        let index = rng.gen_range(0, 100);
+        let leaf = rng.gen::<Node>();
+        merkle_roll
+            .set_leaf(
+                root,
+                tree.get_leaf(index),
+                leaf,
+                &tree.get_proof_of_leaf(index),
+                index as u32,
+            )
+            .unwrap_or_else(|_| {
+                merkle_roll
+                    .set_leaf(
+                        tree.get_root(),
+                        tree.get_leaf(index),
+                        leaf,
+                        &tree.get_proof_of_leaf(index),
+                        index as u32,
+                    )
+                    .unwrap()
+            });
+        tree.add_leaf(leaf, index);
+        assert_eq!(merkle_roll.get_change_log().root, tree.get_root());
+    }
+}
+
+/// Keccak without the precomputed empty-node table
+#[derive(Copy, Clone, Default)]
+struct UncachedKeccak;
+
+impl Hasher for UncachedKeccak {
+    fn hash_pair(left: &Node, right: &Node) -> Node {
+        Keccak::hash_pair(left, right)
+    }
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_empty_node_table() {
+    assert_eq!(Sha256::empty_node(0), EMPTY);
+    for level in 0..EMPTY_NODES_LEN as u32 + 2 {
+        assert_eq!(Keccak::empty_node(level), UncachedKeccak::empty_node(level));
+        let child = Sha256::empty_node(level);
+        assert_eq!(
+            Sha256::empty_node(level + 1),
+            Sha256::hash_pair(&child, &child)
+        );
+    }
+
+    let mut merkle_roll = MerkleRoll::<DEPTH, BUFFER_SIZE, UncachedKeccak>::new();
+    merkle_roll.initialize().unwrap();
+    let (_, tree) = setup();
+    assert_eq!(merkle_roll.get_change_log().root, tree.get_root());
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_invalid_dimensions_and_proofs() {
+    assert!(matches!(
+        MerkleRoll::<31, 8>::new().initialize(),
+        Err(CMTError::InvalidDepthOrBufferSize)
+    ));
+    assert!(matches!(
+        MerkleRoll::<5, 3>::new().append([1; 32]),
+        Err(CMTError::InvalidDepthOrBufferSize)
+    ));
+
+    let (mut merkle_roll, tree) = setup();
+    merkle_roll.initialize().unwrap();
+    let mut proof = tree.get_proof_of_leaf(0);
+    proof.push(EMPTY);
+    assert!(matches!(
+        merkle_roll.set_leaf(tree.get_root(), EMPTY, [1; 32], &proof, 0),
+        Err(CMTError::ProofLengthMismatch)
+    ));
+    assert!(matches!(
+        merkle_roll.prove_leaf(tree.get_root(), EMPTY, &proof, 0),
+        Err(CMTError::ProofLengthMismatch)
+    ));
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_refresh_proof() {
+    let (mut merkle_roll, mut tree) = setup();
+    let mut rng = thread_rng();
+    merkle_roll.initialize().unwrap();
+    for i in 0..256 {
+        let leaf = rng.gen::<Node>();
+        merkle_roll.append(leaf).unwrap();
+        tree.add_leaf(leaf, i);
+    }
+
+    let root = tree.get_root();
+    let leaf = tree.get_leaf(7);
+    let mut proof = tree.get_proof_of_leaf(7);
+    let mut modified_proof = tree.get_proof_of_leaf(8);
+    for _ in 0..BUFFER_SIZE / 2 {
+        let index = rng.gen_range(9, 256);
+        let new_leaf = rng.gen::<Node>();
+        merkle_roll
+            .set_leaf(
+                tree.get_root(),
+                tree.get_leaf(index),
+                new_leaf,
+                &tree.get_proof_of_leaf(index),
+                index as u32,
+            )
+            .unwrap();
+        tree.add_leaf(new_leaf, index);
+    }
+    let new_leaf = rng.gen::<Node>();
+    merkle_roll
+        .set_leaf(
+            tree.get_root(),
+            tree.get_leaf(8),
+            new_leaf,
+            &tree.get_proof_of_leaf(8),
+            8,
+        )
+        .unwrap();
+    tree.add_leaf(new_leaf, 8);
+
+    // Clients only have the bytes of the merkle roll account
+    let merkle_roll =
+        MerkleRollRef::new(bytemuck::bytes_of(&merkle_roll), DEPTH, BUFFER_SIZE).unwrap();
+    assert!(matches!(
+        merkle_roll.refresh_proof(root, leaf, &mut proof[..DEPTH - 1], 7),
+        Err(CMTError::ProofLengthMismatch)
+    ));
+    assert!(matches!(
+        merkle_roll.refresh_proof(root, rng.gen::<Node>(), &mut proof.clone(), 7),
+        Err(CMTError::InvalidProof)
+    ));
+    assert!(matches!(
+        merkle_roll.refresh_proof(root, tree.get_leaf(0), &mut modified_proof, 8),
+        Err(CMTError::LeafContentsModified)
+    ));
+
+    assert_eq!(
+        merkle_roll
+            .refresh_proof(root, leaf, &mut proof, 7)
+            .unwrap(),
+        tree.get_root()
+    );
+    assert_eq!(proof, tree.get_proof_of_leaf(7));
+    merkle_roll
+        .prove_leaf(tree.get_root(), leaf, &proof, 7)
+        .unwrap();
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_snapshot() {
+    let (mut merkle_roll, mut tree) = setup();
+    let mut rng = thread_rng();
+    merkle_roll.initialize().unwrap();
+    for i in 0..100 {
+        let leaf = rng.gen::<Node>();
+        merkle_roll.append(leaf).unwrap();
+        tree.add_leaf(leaf, i);
+    }
+    let old_root = tree.get_root();
+    let old_proof = tree.get_proof_of_leaf(3);
+    for _ in 0..BUFFER_SIZE / 2 {
+        let index = rng.gen_range(4, 100);
+        let leaf = rng.gen::<Node>();
+        merkle_roll
+            .set_leaf(
+                tree.get_root(),
+                tree.get_leaf(index),
+                leaf,
+                &tree.get_proof_of_leaf(index),
+                index as u32,
+            )
+            .unwrap();
+        tree.add_leaf(leaf, index);
+    }
+
+    // Restoring into the same layout gives back the exact same bytes
+    let snapshot = merkle_roll.to_snapshot().unwrap();
+    assert_eq!(snapshot.version, SNAPSHOT_VERSION);
+    assert_eq!(snapshot.change_logs.len(), BUFFER_SIZE);
+    assert_eq!(snapshot.change_logs.last().unwrap().root, tree.get_root());
+    let restored = MerkleRoll::<DEPTH, BUFFER_SIZE>::from_snapshot(&snapshot).unwrap();
+    assert_eq!(
+        bytemuck::bytes_of(&restored),
+        bytemuck::bytes_of(&merkle_roll)
+    );
+
+    // A bigger buffer keeps every change log, so old proofs can still be fast-forwarded
+    let mut bigger = MerkleRoll::<DEPTH, { BUFFER_SIZE * 2 }>::from_snapshot(&snapshot).unwrap();
+    assert_eq!(
+        bigger.to_snapshot().unwrap().change_logs,
+        snapshot.change_logs
+    );
+    let leaf = rng.gen::<Node>();
+    bigger
+        .set_leaf(old_root, tree.get_leaf(3), leaf, &old_proof, 3)
+        .unwrap();
+    tree.add_leaf(leaf, 3);
+    assert_eq!(bigger.get_change_log().root, tree.get_root());
+    let leaf = rng.gen::<Node>();
+    bigger.append(leaf).unwrap();
+    tree.add_leaf(leaf, 100);
+    assert_eq!(bigger.get_change_log().root, tree.get_root());
+
+    // A smaller buffer only keeps the most recent change logs
+    let smaller = MerkleRoll::<DEPTH, 16>::from_snapshot(&snapshot).unwrap();
+    let smaller_snapshot = smaller.to_snapshot().unwrap();
+    assert_eq!(smaller_snapshot.sequence_number, snapshot.sequence_number);
+    assert_eq!(
+        smaller_snapshot.change_logs,
+        snapshot.change_logs[BUFFER_SIZE - 16..]
+    );
+    assert_eq!(smaller_snapshot.rightmost_proof, snapshot.rightmost_proof);
+
+    let mut unsupported = snapshot.clone();
+    unsupported.version += 1;
+    assert!(matches!(
+        MerkleRoll::<DEPTH, BUFFER_SIZE>::from_snapshot(&unsupported),
+        Err(CMTError::UnsupportedSnapshotVersion)
+    ));
+    assert!(matches!(
+        MerkleRoll::<{ DEPTH - 1 }, BUFFER_SIZE>::from_snapshot(&snapshot),
+        Err(CMTError::InvalidSnapshot)
+    ));
+
+    #[cfg(feature = "borsh")]
+    {
+        use borsh::{BorshDeserialize, BorshSerialize};
+        let bytes = snapshot.try_to_vec().unwrap();
+        let deserialized =
+            concurrent_merkle_tree::snapshot::MerkleRollSnapshot::try_from_slice(&bytes).unwrap();
+        assert_eq!(deserialized, snapshot);
+    }
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_migrate() {
+    const NEW_DEPTH: usize = DEPTH + 2;
+    let (mut merkle_roll, mut tree) = setup();
+    let mut rng = thread_rng();
+    merkle_roll.initialize().unwrap();
+    let mut leaves = vec![EMPTY; 1 << NEW_DEPTH];
+    for (i, leaf) in leaves.iter_mut().enumerate().take(100) {
+        *leaf = rng.gen::<Node>();
+        merkle_roll.append(*leaf).unwrap();
+        tree.add_leaf(*leaf, i);
+    }
+    let old_root = tree.get_root();
+    let old_proof = tree.get_proof_of_leaf(3);
+    for _ in 0..BUFFER_SIZE / 2 {
+        let index = rng.gen_range(4, 100);
+        let leaf = rng.gen::<Node>();
+        merkle_roll
+            .set_leaf(
+                tree.get_root(),
+                tree.get_leaf(index),
+                leaf,
+                &tree.get_proof_of_leaf(index),
+                index as u32,
+            )
+            .unwrap();
+        tree.add_leaf(leaf, index);
+        leaves[index] = leaf;
+    }
+
+    // The old tree becomes the leftmost subtree of the deeper tree
+    let mut deeper_tree = MerkleTree::new(leaves);
+    let mut deeper = MerkleRoll::<NEW_DEPTH, { BUFFER_SIZE * 2 }>::new();
+    assert_eq!(
+        deeper.migrate_from(&merkle_roll).unwrap(),
+        deeper_tree.get_root()
+    );
+    assert_eq!(deeper.sequence_number, merkle_roll.sequence_number);
+    assert_eq!(deeper.buffer_size, BUFFER_SIZE as u64);
+    assert!(matches!(
+        merkle_roll.migrate_from(&deeper),
+        Err(CMTError::InvalidDepthOrBufferSize)
+    ));
+
+    // Proofs for old roots are padded with empty nodes and fast-forwarded
+    let mut padded_proof = old_proof.clone();
+    padded_proof.extend((DEPTH..NEW_DEPTH).map(|level| deeper_tree.get_proof_of_leaf(0)[level]));
+    let mut padded_root = old_root;
+    for level in DEPTH..NEW_DEPTH {
+        padded_root = Keccak::hash_pair(&padded_root, &Keccak::empty_node(level as u32));
+    }
+    let leaf = rng.gen::<Node>();
+    deeper
+        .set_leaf(padded_root, tree.get_leaf(3), leaf, &padded_proof, 3)
+        .unwrap();
+    deeper_tree.add_leaf(leaf, 3);
+    assert_eq!(deeper.get_change_log().root, deeper_tree.get_root());
+
+    for i in 100..(1 << DEPTH) + 10 {
+        let leaf = rng.gen::<Node>();
+        deeper.append(leaf).unwrap();
+        deeper_tree.add_leaf(leaf, i);
+    }
+    assert_eq!(deeper.get_change_log().root, deeper_tree.get_root());
+}
+
+#[tokio::test(threaded_scheduler)]
+/// Migrating in place gives the same merkle roll as migrating to a new buffer
+async fn test_migrate_in_place() {
+    let depth = 6;
+    let buffer_size = 8;
+    let mut rng = thread_rng();
+    // Back the roll with u64s so that the bytes are correctly aligned
+    let mut old_data = vec![0_u64; merkle_roll_size(depth, buffer_size).unwrap() / 8];
+    let mut tree = MerkleTree::new(vec![EMPTY; 1 << depth]);
+    let mut merkle_roll =
+        MerkleRollMut::new(bytemuck::cast_slice_mut(&mut old_data), depth, buffer_size).unwrap();
+    merkle_roll.initialize().unwrap();
+    for i in 0..20 {
+        let leaf = rng.gen::<Node>();
+        merkle_roll.append(leaf).unwrap();
+        tree.add_leaf(leaf, i);
+    }
+    // The active change log is near the start of the buffer, so older ones wrap around
+    for _ in 0..buffer_size + 3 {
+        let index = rng.gen_range(0, 20);
+        let leaf = rng.gen::<Node>();
+        merkle_roll
+            .set_leaf(
+                tree.get_root(),
+                tree.get_leaf(index),
+                leaf,
+                &tree.get_proof_of_leaf(index),
+                index as u32,
+            )
+            .unwrap();
+        tree.add_leaf(leaf, index);
+    }
+    let old_bytes: &[u8] = bytemuck::cast_slice(&old_data);
+    let old_roll = MerkleRollRef::new(old_bytes, depth, buffer_size).unwrap();
+
+    for (new_depth, new_buffer_size) in [(6, 8), (9, 8), (6, 32), (9, 16)] {
+        let size = merkle_roll_size(new_depth, new_buffer_size).unwrap();
+        let mut expected_data = vec![0_u64; size / 8];
+        let mut expected = MerkleRollMut::new(
+            bytemuck::cast_slice_mut(&mut expected_data),
+            new_depth,
+            new_buffer_size,
+        )
+        .unwrap();
+        let root = expected.migrate_from(&old_roll).unwrap();
+
+        // The old roll is at the start of the bytes, followed by garbage
+        let mut data = vec![u64::MAX; size / 8];
+        let bytes: &mut [u8] = bytemuck::cast_slice_mut(&mut data);
+        bytes[..old_bytes.len()].copy_from_slice(old_bytes);
+        assert_eq!(
+            migrate_in_place::<Keccak>(bytes, depth, buffer_size, new_depth, new_buffer_size)
+                .unwrap(),
+            root
+        );
+        assert_eq!(data, expected_data);
+    }
+
+    // Dimensions cannot shrink
+    let mut data = old_data.clone();
+    assert!(matches!(
+        migrate_in_place::<Keccak>(
+            bytemuck::cast_slice_mut(&mut data),
+            depth,
+            buffer_size,
+            depth,
+            buffer_size / 2
+        ),
+        Err(CMTError::InvalidDepthOrBufferSize)
+    ));
+    assert!(matches!(
+        migrate_in_place::<Keccak>(
+            bytemuck::cast_slice_mut(&mut data),
+            depth,
+            buffer_size,
+            depth - 1,
+            buffer_size
+        ),
+        Err(CMTError::InvalidDepthOrBufferSize)
+    ));
+    assert!(matches!(
+        migrate_in_place::<Keccak>(
+            bytemuck::cast_slice_mut(&mut data),
+            depth,
+            buffer_size,
+            depth + 1,
+            buffer_size
+        ),
+        Err(CMTError::InvalidMerkleRollBytes)
+    ));
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_staleness_policy() {
+    let (mut merkle_roll, mut tree) = setup();
+    let mut rng = thread_rng();
+    merkle_roll.initialize().unwrap();
+    for i in 0..16 {
+        let leaf = rng.gen::<Node>();
+        merkle_roll.append(leaf).unwrap();
+        tree.add_leaf(leaf, i);
+    }
+    let stale_root = tree.get_root();
+    let stale_leaf = tree.get_leaf(0);
+    let stale_proof = tree.get_proof_of_leaf(0);
+
+    // Leaves 5 and 8 are both under nodes of the proof of leaf 0
+    for index in (0..BUFFER_SIZE).map(|i| if i == 0 { 5 } else { 8 }) {
+        let leaf = rng.gen::<Node>();
+        merkle_roll
+            .set_leaf(
+                tree.get_root(),
+                tree.get_leaf(index),
+                leaf,
+                &tree.get_proof_of_leaf(index),
+                index as u32,
+            )
+            .unwrap();
+        tree.add_leaf(leaf, index);
+    }
+    // The stale root was just dropped from the buffer, and the oldest change log updates leaf 5
+    let prove = |policy| {
+        merkle_roll
+            .view()
+            .unwrap()
+            .with_staleness_policy(policy)
+            .prove_leaf(stale_root, stale_leaf, &stale_proof, 0)
+    };
+    assert_eq!(prove(StalenessPolicy::FullReplay), Ok(EMPTY));
+    assert_eq!(
+        prove(StalenessPolicy::RejectUnknownRoot),
+        Err(CMTError::RootNotFound)
+    );
+    assert_eq!(
+        prove(StalenessPolicy::ReplayLast(0)),
+        Err(CMTError::RootNotFound)
+    );
+    assert_eq!(
+        prove(StalenessPolicy::ReplayLast(BUFFER_SIZE as u32 - 1)),
+        Err(CMTError::InvalidProof)
+    );
+    assert_eq!(
+        prove(StalenessPolicy::ReplayLast(BUFFER_SIZE as u32)),
+        Ok(EMPTY)
+    );
+    assert_eq!(prove(StalenessPolicy::ReplayLast(u32::MAX)), Ok(EMPTY));
+
+    // Writes follow the policy too, while proofs for roots in the buffer are always accepted
+    let new_leaf = rng.gen::<Node>();
+    let mut strict = merkle_roll
+        .view_mut()
+        .unwrap()
+        .with_staleness_policy(StalenessPolicy::RejectUnknownRoot);
+    assert_eq!(
+        strict.set_leaf(stale_root, stale_leaf, new_leaf, &stale_proof, 0),
+        Err(CMTError::RootNotFound)
+    );
+    strict
+        .set_leaf(
+            tree.get_root(),
+            stale_leaf,
+            new_leaf,
+            &tree.get_proof_of_leaf(0),
+            0,
+        )
+        .unwrap();
+    tree.add_leaf(new_leaf, 0);
+    assert_eq!(merkle_roll.get_change_log().root, tree.get_root());
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_compute_stats() {
+    let (mut merkle_roll, mut tree) = setup();
+    let mut rng = thread_rng();
+    merkle_roll.initialize().unwrap();
+    let mut merkle_roll = merkle_roll.view_mut().unwrap();
+    for i in 0..8 {
+        let leaf = rng.gen::<Node>();
+        merkle_roll.append(leaf).unwrap();
+        tree.add_leaf(leaf, i);
+    }
+    // The first append checks the empty tree, then writes the rightmost path like `set_leaf`.
+    // Every other append hashes one node per level, plus the previous leaf up to the intersection
+    assert_eq!(
+        merkle_roll.take_compute_stats(),
+        ComputeStats {
+            hashes: (3 * DEPTH + 7 * DEPTH + (1 + 2 + 1)) as u32,
+            change_logs_scanned: 1,
+            proof_nodes_patched: 0,
+        }
+    );
+
+    let stale_root = tree.get_root();
+    let stale_leaf = tree.get_leaf(0);
+    let stale_proof = tree.get_proof_of_leaf(0);
+    for index in [1, 2, 4] {
+        let leaf = rng.gen::<Node>();
+        merkle_roll
+            .set_leaf(
+                tree.get_root(),
+                tree.get_leaf(index),
+                leaf,
+                &tree.get_proof_of_leaf(index),
+                index as u32,
+            )
+            .unwrap();
+        tree.add_leaf(leaf, index);
+    }
+    merkle_roll.take_compute_stats();
+
+    // The stale root is found after 4 change logs, then the proof is fast-forwarded through 3 of
+    // them, each patching a different level. The rightmost proof also gets patched.
+    let new_leaf = rng.gen::<Node>();
+    merkle_roll
+        .set_leaf(stale_root, stale_leaf, new_leaf, &stale_proof, 0)
+        .unwrap();
+    tree.add_leaf(new_leaf, 0);
+    assert_eq!(
+        merkle_roll.take_compute_stats(),
+        ComputeStats {
+            hashes: 2 * DEPTH as u32,
+            change_logs_scanned: 4 + 3,
+            proof_nodes_patched: 3 + 1,
+        }
+    );
+    assert_eq!(merkle_roll.get_change_log().root, &tree.get_root());
+
+    // Read-only views count their own work
+    let view = merkle_roll.view();
+    view.prove_leaf(tree.get_root(), new_leaf, &tree.get_proof_of_leaf(0), 0)
+        .unwrap();
+    assert_eq!(
+        view.compute_stats(),
+        ComputeStats {
+            hashes: DEPTH as u32,
+            change_logs_scanned: 1,
+            proof_nodes_patched: 0,
+        }
+    );
+    assert_eq!(merkle_roll.compute_stats(), ComputeStats::default());
+}
+
+fn node_from_hex(hex: &str) -> Node {
+    let mut node = EMPTY;
+    for (i, byte) in node.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap();
+    }
+    node
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_prove_leaf_preimage() {
+    assert_eq!(
+        LeafHashScheme::Keccak.hash_leaf(b"abc"),
+        node_from_hex("4e03657aea45a94fc7d47ba826c8d667c0d1e6e33a64a036ec44f58fa12d6c45")
+    );
+    assert_eq!(
+        LeafHashScheme::Sha256.hash_leaf(b"abc"),
+        node_from_hex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+    );
+    for scheme in [LeafHashScheme::Keccak, LeafHashScheme::Sha256] {
+        assert_eq!(LeafHashScheme::try_from(scheme.id()), Ok(scheme));
+    }
+    assert_eq!(
+        LeafHashScheme::try_from(2),
+        Err(CMTError::UnknownLeafHashScheme)
+    );
+
+    let (mut merkle_roll, mut tree) = setup();
+    let mut rng = thread_rng();
+    merkle_roll.initialize().unwrap();
+    let preimages: Vec<Vec<u8>> = (0..8)
+        .map(|i| {
+            (0..rng.gen_range(0, 200))
+                .map(|_| rng.gen::<u8>())
+                .chain([i])
+                .collect()
+        })
+        .collect();
+    for (i, preimage) in preimages.iter().enumerate() {
+        let leaf = LeafHashScheme::Keccak.hash_leaf(preimage);
+        merkle_roll.append(leaf).unwrap();
+        tree.add_leaf(leaf, i);
+    }
+
+    let root = tree.get_root();
+    for (i, preimage) in preimages.iter().enumerate() {
+        assert_eq!(
+            merkle_roll.prove_leaf_preimage(
+                root,
+                LeafHashScheme::Keccak,
+                preimage,
+                &tree.get_proof_of_leaf(i),
+                i as u32,
+            ),
+            Ok(tree.get_leaf(i))
+        );
+    }
+    // The preimage must be hashed with the same scheme, and match the leaf at the given index
+    let proof = tree.get_proof_of_leaf(3);
+    assert_eq!(
+        merkle_roll.prove_leaf_preimage(root, LeafHashScheme::Sha256, &preimages[3], &proof, 3),
+        Err(CMTError::InvalidProof)
+    );
+    assert_eq!(
+        merkle_roll.prove_leaf_preimage(root, LeafHashScheme::Keccak, &preimages[4], &proof, 3),
+        Err(CMTError::InvalidProof)
+    );
+    let mut modified = preimages[3].clone();
+    modified[0] ^= 1;
+    assert_eq!(
+        merkle_roll.prove_leaf_preimage(root, LeafHashScheme::Keccak, &modified, &proof, 3),
+        Err(CMTError::InvalidProof)
+    );
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_root_history() {
+    let (mut merkle_roll, mut tree) = setup();
+    let mut rng = thread_rng();
+    merkle_roll.initialize().unwrap();
+
+    // Back the history with u64s so that the bytes are correctly aligned
+    let capacity = 4 * BUFFER_SIZE;
+    let mut data = vec![0_u64; root_history_size(capacity) / 8];
+    let bytes: &mut [u8] = bytemuck::cast_slice_mut(&mut data);
+    assert!(matches!(
+        RootHistoryRef::new(&bytes[..root_history_size(0)]),
+        Err(CMTError::InvalidRootHistoryBytes)
+    ));
+    assert!(matches!(
+        RootHistoryMut::new(&mut bytes[4..]),
+        Err(CMTError::InvalidRootHistoryBytes)
+    ));
+    let mut root_history = RootHistoryMut::new(bytes).unwrap();
+    assert!(root_history.view().is_empty());
+    let initial_root = tree.get_root();
+    assert_eq!(root_history.record(&merkle_roll.view().unwrap(), 1), 1);
+    assert_eq!(root_history.view().latest().unwrap().root, initial_root);
+
+    // Record every half buffer, so that no root is missed
+    let mut snapshot = None;
+    for i in 0..3 * BUFFER_SIZE {
+        let leaf = rng.gen::<Node>();
+        if i < BUFFER_SIZE {
+            merkle_roll.append(leaf).unwrap();
+            tree.add_leaf(leaf, i);
+        } else {
+            let index = rng.gen_range(0, BUFFER_SIZE);
+            merkle_roll
+                .set_leaf(
+                    tree.get_root(),
+                    tree.get_leaf(index),
+                    leaf,
+                    &tree.get_proof_of_leaf(index),
+                    index as u32,
+                )
+                .unwrap();
+            tree.add_leaf(leaf, index);
+        }
+        if i == BUFFER_SIZE - 1 {
+            snapshot = Some((tree.get_root(), tree.get_leaf(3), tree.get_proof_of_leaf(3)));
+        }
+        if i % (BUFFER_SIZE / 2) == BUFFER_SIZE / 2 - 1 {
+            let slot = i as u64 + 2;
+            assert_eq!(
+                root_history.record(&merkle_roll.view().unwrap(), slot),
+                BUFFER_SIZE / 2
+            );
+        }
+    }
+    let history = root_history.view();
+    assert_eq!(history.len(), 3 * BUFFER_SIZE + 1);
+    assert_eq!(history.latest().unwrap().root, tree.get_root());
+    for age in 0..history.len() {
+        assert_eq!(
+            history.get(age).unwrap().seq,
+            merkle_roll.sequence_number - age as u64
+        );
+    }
+
+    // The snapshot root is long gone from the change log buffer, but is still in the history
+    let (root, leaf, proof) = snapshot.unwrap();
+    let entry = history.find(root).unwrap();
+    assert_eq!(entry.seq, BUFFER_SIZE as u64);
+    assert_eq!(entry.slot, BUFFER_SIZE as u64 + 1);
+    assert_eq!(
+        merkle_roll.prove_leaf_at_root(&history, root, leaf, &proof, 3),
+        Ok(EMPTY)
+    );
+    assert_eq!(
+        merkle_roll.prove_leaf_at_root(&history, root, rng.gen::<Node>(), &proof, 3),
+        Err(CMTError::InvalidProof)
+    );
+    assert_eq!(
+        merkle_roll.prove_leaf_at_root(&history, rng.gen::<Node>(), leaf, &proof, 3),
+        Err(CMTError::RootNotFound)
+    );
+    // Roots in the change log buffer do not have to be recorded
+    let empty_data = vec![0_u64; root_history_size(1) / 8];
+    let empty_history = RootHistoryRef::new(bytemuck::cast_slice(&empty_data)).unwrap();
+    assert_eq!(
+        merkle_roll.prove_leaf_at_root(
+            &empty_history,
+            tree.get_root(),
+            tree.get_leaf(3),
+            &tree.get_proof_of_leaf(3),
+            3
+        ),
+        Ok(EMPTY)
+    );
+    assert_eq!(
+        merkle_roll.prove_leaf_at_root(&empty_history, root, leaf, &proof, 3),
+        Err(CMTError::RootNotFound)
+    );
+
+    // Once full, the oldest roots are overwritten
+    for _ in 0..BUFFER_SIZE {
+        merkle_roll.append(rng.gen::<Node>()).unwrap();
+    }
+    assert_eq!(
+        root_history.record(&merkle_roll.view().unwrap(), 0),
+        BUFFER_SIZE
+    );
+    let history = root_history.view();
+    assert_eq!(history.len(), capacity);
+    assert!(history.get(capacity).is_none());
+    assert_eq!(
+        history.get(capacity - 1).unwrap().seq,
+        merkle_roll.sequence_number + 1 - capacity as u64
+    );
+    assert!(history.find(initial_root).is_none());
+    assert!(history.find(root).is_some());
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_canopy() {
+    let (mut merkle_roll, mut tree) = setup();
+    let mut rng = thread_rng();
+    merkle_roll.initialize().unwrap();
+
+    assert_eq!(canopy_size(0), 0);
+    assert_eq!(canopy_size(3), 14 * 32);
+    // Room for 10 proof nodes
+    let transaction_size = MAX_TRANSACTION_SIZE - 10 * PROOF_NODE_TRANSACTION_SIZE;
+    assert_eq!(
+        required_canopy_depth(DEPTH as u32, transaction_size, MAX_TRANSACTION_SIZE),
+        DEPTH as u32 - 10
+    );
+    assert_eq!(
+        required_canopy_depth(DEPTH as u32, transaction_size - 200, MAX_TRANSACTION_SIZE),
+        0
+    );
+    assert_eq!(
+        required_canopy_depth(DEPTH as u32, 2 * MAX_TRANSACTION_SIZE, MAX_TRANSACTION_SIZE),
+        DEPTH as u32
+    );
+
+    assert!(matches!(
+        Canopy::new(&[0; 33], DEPTH as u32),
+        Err(CMTError::InvalidCanopyBytes)
+    ));
+    assert!(matches!(
+        Canopy::new(&[0; 3 * 32], DEPTH as u32),
+        Err(CMTError::InvalidCanopyBytes)
+    ));
+    assert!(matches!(
+        Canopy::new(&[0; 14 * 32], 2),
+        Err(CMTError::InvalidCanopyBytes)
+    ));
+
+    let canopy_depth = 4;
+    let mut canopy_bytes = vec![0; canopy_size(canopy_depth)];
+    let mut canopy = CanopyMut::new(&mut canopy_bytes, DEPTH as u32).unwrap();
+    assert_eq!(canopy.view().depth(), canopy_depth);
+    assert_eq!(canopy.view().proof_len(), DEPTH as u32 - canopy_depth);
+    for i in 0..200 {
+        let leaf = rng.gen::<Node>();
+        merkle_roll.append(leaf).unwrap();
+        tree.add_leaf(leaf, i);
+        let change_log = merkle_roll.get_change_log().view();
+        canopy.update(change_log.path, change_log.index).unwrap();
+    }
+    assert!(matches!(
+        canopy.update(&[EMPTY; DEPTH - 1], 0),
+        Err(CMTError::ProofLengthMismatch)
+    ));
+
+    let canopy = canopy.view();
+    // Leaves in subtrees that were never written are rebuilt with empty nodes
+    for index in [0, 5, 199, 200, 5000, (1 << DEPTH) - 1] {
+        let full_proof = tree.get_proof_of_leaf(index);
+        let truncated = canopy.truncate_proof(&full_proof);
+        assert_eq!(truncated.len(), DEPTH - canopy_depth as usize);
+        let mut proof = truncated.to_vec();
+        canopy
+            .rebuild_proof::<Keccak>(index as u32, &mut proof)
+            .unwrap();
+        assert_eq!(proof, full_proof);
+
+        // Extra nodes are kept instead of canopy nodes
+        let mut proof = full_proof[..DEPTH - 1].to_vec();
+        canopy
+            .rebuild_proof::<Keccak>(index as u32, &mut proof)
+            .unwrap();
+        assert_eq!(proof, full_proof);
+    }
+    assert!(matches!(
+        canopy.rebuild_proof::<Keccak>(1 << DEPTH, &mut vec![]),
+        Err(CMTError::LeafIndexOutOfBounds)
+    ));
+
+    // The tree becomes the leftmost subtree of a deeper tree
+    let new_depth = DEPTH as u32 + 2;
+    let mut new_tree = MerkleTree::new(vec![EMPTY; 1 << new_depth]);
+    for i in 0..200 {
+        new_tree.add_leaf(tree.get_leaf(i), i);
+    }
+    let mut new_canopy_bytes = vec![0; canopy_size(canopy_depth + 2)];
+    let mut new_canopy = CanopyMut::new(&mut new_canopy_bytes, new_depth).unwrap();
+    new_canopy.migrate_from(&canopy).unwrap();
+    // Nodes above the old root are filled in from the path of the rightmost leaf
+    let index = 199;
+    let mut path = vec![new_tree.get_leaf(index)];
+    for (level, sibling) in new_tree.get_proof_of_leaf(index).iter().enumerate() {
+        let mut node = path[level];
+        hash_to_parent::<Keccak>(&mut node, sibling, index >> level & 1 == 0);
+        path.push(node);
+    }
+    path.pop();
+    new_canopy.update(&path, index as u32).unwrap();
+    let new_canopy = new_canopy.view();
+    for index in [0, 199, 200, (1 << DEPTH) + 1] {
+        let full_proof = new_tree.get_proof_of_leaf(index);
+        let mut proof = new_canopy.truncate_proof(&full_proof).to_vec();
+        new_canopy
+            .rebuild_proof::<Keccak>(index as u32, &mut proof)
+            .unwrap();
+        assert_eq!(proof, full_proof);
+    }
+    // The new canopy can't cache levels below the old one
+    let mut deep_canopy_bytes = vec![0; canopy_size(canopy_depth + 3)];
+    let mut deep_canopy = CanopyMut::new(&mut deep_canopy_bytes, new_depth).unwrap();
+    assert!(matches!(
+        deep_canopy.migrate_from(&canopy),
+        Err(CMTError::InvalidCanopyBytes)
+    ));
+}
+
+#[tokio::test(threaded_scheduler)]
+/// Migrating a canopy in place gives the same canopy as migrating it to new bytes
+async fn test_migrate_canopy_in_place() {
+    let mut rng = thread_rng();
+    let canopy_depth = 4;
+    let len = canopy_size(canopy_depth);
+    let old_canopy_bytes: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+    let old_canopy = Canopy::new(&old_canopy_bytes, DEPTH as u32).unwrap();
+
+    for depth_increase in [0, 1, 3] {
+        let new_depth = DEPTH as u32 + depth_increase;
+        let mut expected = vec![0; len];
+        CanopyMut::new(&mut expected, new_depth)
+            .unwrap()
+            .migrate_from(&old_canopy)
+            .unwrap();
+        // The canopies overlap unless the shift is at least the size of a canopy
+        for shift in [0, 40, len - 8, len + 100] {
+            let mut bytes = vec![u8::MAX; len + shift];
+            bytes[..len].copy_from_slice(&old_canopy_bytes);
+            migrate_canopy_in_place(&mut bytes, len, DEPTH as u32, new_depth).unwrap();
+            assert_eq!(bytes[shift..], expected[..]);
+        }
+    }
+    let mut bytes = old_canopy_bytes.clone();
+    assert!(matches!(
+        migrate_canopy_in_place(&mut bytes, len, DEPTH as u32, DEPTH as u32 - 1),
+        Err(CMTError::InvalidCanopyBytes)
+    ));
+    assert!(matches!(
+        migrate_canopy_in_place(&mut bytes, len - 32, DEPTH as u32, DEPTH as u32),
+        Err(CMTError::InvalidCanopyBytes)
+    ));
+}
+
+#[tokio::test(threaded_scheduler)]
+/// The reference tree only stores non-empty nodes, so it can mirror deep trees
+async fn test_reference_tree_depth() {
+    let depth = 24;
+    let buffer_size = 8;
+    let mut rng = thread_rng();
+    let mut tree = MerkleTree::new_empty(depth as u32);
+    assert_eq!(tree.depth(), depth as u32);
+    assert_eq!(tree.capacity(), 1 << depth);
+
+    let mut data = vec![0_u64; merkle_roll_size(depth, buffer_size).unwrap() / 8];
+    let mut merkle_roll =
+        MerkleRollMut::new(bytemuck::cast_slice_mut(&mut data), depth, buffer_size).unwrap();
+    merkle_roll.initialize().unwrap();
+    assert_eq!(*merkle_roll.get_change_log().root, tree.get_root());
+
+    let mut leaves = vec![];
+    for i in 0..100 {
+        let leaf = rng.gen::<Node>();
+        merkle_roll.append(leaf).unwrap();
+        tree.add_leaf(leaf, i);
+        leaves.push(leaf);
+        assert_eq!(*merkle_roll.get_change_log().root, tree.get_root());
+    }
+    for _ in 0..100 {
+        // Only leaves up to the rightmost index can be replaced
+        let index = rng.gen_range(0, leaves.len());
+        let leaf = if rng.gen_bool(0.5) {
+            EMPTY
+        } else {
+            rng.gen::<Node>()
+        };
+        merkle_roll
+            .set_leaf(
+                tree.get_root(),
+                tree.get_leaf(index),
+                leaf,
+                &tree.get_proof_of_leaf(index),
+                index as u32,
+            )
+            .unwrap();
+        tree.add_leaf(leaf, index);
+        assert_eq!(*merkle_roll.get_change_log().root, tree.get_root());
+    }
+
+    // Building a tree from its leaves gives the same root as setting them one by one
+    let built = MerkleTree::new(leaves.clone());
+    let mut incremental = MerkleTree::new_empty(7);
+    for (i, leaf) in leaves.iter().enumerate() {
+        incremental.add_leaf(*leaf, i);
+    }
+    assert_eq!(built.depth(), 7);
+    assert_eq!(built.get_root(), incremental.get_root());
+    assert_eq!(
+        built.get_proof_of_leaf(42),
+        incremental.get_proof_of_leaf(42)
+    );
+    // Removing every leaf restores the empty tree
+    for i in 0..leaves.len() {
+        incremental.remove_leaf(i);
+    }
+    assert_eq!(incremental.get_root(), empty_node::<Keccak>(7));
+}
+
+#[tokio::test(threaded_scheduler)]
+/// The persistent tree matches the in-memory one, survives a reopen and skips replayed batches
+async fn test_persistent_reference_tree() {
+    let depth = 10;
+    let mut rng = thread_rng();
+    let dir = std::env::temp_dir().join(format!("cmt-persistent-{}", rng.gen::<u64>()));
+    let mut tree = MerkleTree::new_empty(depth);
+    let mut persistent = PersistentMerkleTree::new(FileNodeStore::open(&dir, depth).unwrap());
+    let mut in_memory = PersistentMerkleTree::new(MemoryNodeStore::new(depth));
+    assert_eq!(persistent.get_root().unwrap(), tree.get_root());
+    assert_eq!(persistent.seq(), None);
+
+    for seq in 0..20 {
+        let batch: Vec<(usize, Node)> = (0..8)
+            .map(|_| {
+                let index = rng.gen_range(0, 1 << depth);
+                let leaf = if rng.gen_bool(0.2) {
+                    EMPTY
+                } else {
+                    rng.gen::<Node>()
+                };
+                (index, leaf)
+            })
+            .collect();
+        for (index, leaf) in batch.iter() {
+            tree.add_leaf(*leaf, *index);
+        }
+        assert!(persistent.apply_batch(seq, &batch).unwrap());
+        assert!(in_memory.apply_batch(seq, &batch).unwrap());
+        assert_eq!(persistent.get_root().unwrap(), tree.get_root());
+        assert_eq!(in_memory.get_root().unwrap(), tree.get_root());
+    }
+    for _ in 0..20 {
+        let index = rng.gen_range(0, 1 << depth);
+        assert_eq!(persistent.get_leaf(index).unwrap(), tree.get_leaf(index));
+        assert_eq!(
+            persistent.get_proof_of_leaf(index).unwrap(),
+            tree.get_proof_of_leaf(index)
+        );
+    }
+    assert!(matches!(
+        persistent.get_leaf(1 << depth),
+        Err(PersistentTreeError::LeafIndexOutOfBounds(_))
+    ));
+
+    // Batches that were already applied are skipped
+    assert!(!persistent
+        .apply_batch(19, &[(0, rng.gen::<Node>())])
+        .unwrap());
+    assert_eq!(persistent.get_root().unwrap(), tree.get_root());
+    drop(persistent);
+
+    // A partially written batch is dropped when reopening the store
+    std::fs::write(dir.join("wal"), b"CMTWAL01garbage").unwrap();
+    let persistent = PersistentMerkleTree::new(FileNodeStore::open(&dir, depth).unwrap());
+    assert_eq!(persistent.seq(), Some(19));
+    assert_eq!(persistent.get_root().unwrap(), tree.get_root());
+    assert_eq!(
+        persistent.get_proof_of_leaf(7).unwrap(),
+        tree.get_proof_of_leaf(7)
+    );
+    assert_eq!(std::fs::metadata(dir.join("wal")).unwrap().len(), 0);
+    assert_eq!(persistent.store().seq(), in_memory.store().seq());
+    drop(persistent);
+
+    assert!(matches!(
+        FileNodeStore::open(&dir, depth + 1),
+        Err(PersistentTreeError::DepthMismatch { .. })
+    ));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(threaded_scheduler)]
+/// Change log events rebuild the tree in any order, and gaps are reported until backfilled
+async fn test_apply_changelog_event() {
+    let (mut merkle_roll, _) = setup();
+    let mut rng = thread_rng();
+    let id = rng.gen::<[u8; 32]>();
+    merkle_roll.initialize().unwrap();
+    let mut events = vec![ChangeLogEvent::from_change_log(
+        &merkle_roll.get_change_log().view(),
+        id,
+        merkle_roll.sequence_number,
+    )];
+    for i in 0..43 {
+        if i < 20 || rng.gen_bool(0.5) {
+            merkle_roll.append(rng.gen::<Node>()).unwrap();
+        } else {
+            let index = rng.gen_range(0, 20);
+            let proof = merkle_roll_proof(&events, index);
+            merkle_roll
+                .set_leaf(
+                    merkle_roll.get_change_log().root,
+                    proof.0,
+                    rng.gen::<Node>(),
+                    &proof.1,
+                    index,
+                )
+                .unwrap();
+        }
+        events.push(ChangeLogEvent::from_change_log(
+            &merkle_roll.get_change_log().view(),
+            id,
+            merkle_roll.sequence_number,
+        ));
+    }
+
+    // Events applied in order are checked against the tree
+    let mut in_order = MerkleTree::new_empty(DEPTH as u32);
+    for event in events.iter() {
+        in_order.apply_changelog_event(event).unwrap();
+    }
+    assert_eq!(in_order.get_root(), merkle_roll.get_change_log().root);
+    assert_eq!(in_order.last_applied_seq(&id), Some(43));
+    assert!(in_order.missing_seqs(&id).is_empty());
+
+    // Skipped events are reported, and can be backfilled later
+    let mut tree = MerkleTree::new_empty(DEPTH as u32);
+    let skipped = |event: &&ChangeLogEvent| event.seq >= 10 && event.seq % 10 < 3;
+    for event in events.iter().filter(|event| !skipped(event)) {
+        tree.apply_changelog_event(event).unwrap();
+    }
+    assert_eq!(tree.last_applied_seq(&id), Some(43));
+    assert_eq!(tree.missing_seqs(&id), &[10..13, 20..23, 30..33, 40..43]);
+    for event in events.iter().rev().filter(skipped) {
+        tree.apply_changelog_event(event).unwrap();
+    }
+    assert!(tree.missing_seqs(&id).is_empty());
+    assert_eq!(tree.get_root(), in_order.get_root());
+    for i in 0..40 {
+        assert_eq!(tree.get_proof_of_leaf(i), in_order.get_proof_of_leaf(i));
+    }
+    assert_eq!(tree.missing_seqs(&[0; 32]), &[]);
+
+    // Replayed events are rejected, and so are events that disagree with applied ones
+    let mut replayed = events[43].clone();
+    assert_eq!(
+        tree.apply_changelog_event(&replayed),
+        Err(ChangeLogEventError::OutOfOrder {
+            seq: 43,
+            last_seq: 43
+        })
+    );
+    replayed.path[DEPTH].node = rng.gen::<Node>();
+    assert_eq!(
+        tree.apply_changelog_event(&replayed),
+        Err(ChangeLogEventError::Conflict {
+            seq: 43,
+            node_index: 1
+        })
+    );
+    let mut forged = events[43].clone();
+    forged.seq = 44;
+    forged.index += 1;
+    assert_eq!(
+        tree.apply_changelog_event(&forged),
+        Err(ChangeLogEventError::InvalidPathNode { level: 0 })
+    );
+    forged.path.pop();
+    assert!(matches!(
+        tree.apply_changelog_event(&forged),
+        Err(ChangeLogEventError::PathLengthMismatch { .. })
+    ));
+    assert_eq!(tree.get_root(), in_order.get_root());
+}
+
+/// Returns the leaf at `index` and its proof, from the tree rebuilt from `events`
+fn merkle_roll_proof(events: &[ChangeLogEvent], index: u32) -> (Node, Vec<Node>) {
+    let mut tree = MerkleTree::new_empty(DEPTH as u32);
+    for event in events.iter() {
+        tree.apply_changelog_event(event).unwrap();
+    }
+    (
+        tree.get_leaf(index as usize),
+        tree.get_proof_of_leaf(index as usize),
+    )
+}
+
+#[tokio::test(threaded_scheduler)]
+/// Proofs against older roots are served as of their sequence number, and still replace leaves
+async fn test_historical_proofs() {
+    let (mut merkle_roll, mut off_chain_tree) = setup();
+    let mut rng = thread_rng();
+    merkle_roll.initialize().unwrap();
+    off_chain_tree.set_history_window(BUFFER_SIZE as u64);
+    let mut roots = vec![off_chain_tree.get_root()];
+    let mut proofs = vec![off_chain_tree.get_proof_of_leaf(0)];
+    for i in 0..BUFFER_SIZE - 1 {
+        let leaf = rng.gen::<Node>();
+        merkle_roll.append(leaf).unwrap();
+        off_chain_tree.add_leaf(leaf, i);
+        roots.push(off_chain_tree.get_root());
+        proofs.push(off_chain_tree.get_proof_of_leaf(0));
+    }
+    assert_eq!(off_chain_tree.seq(), merkle_roll.sequence_number);
+    for (seq, root) in roots.iter().enumerate() {
+        assert_eq!(off_chain_tree.get_root_at(seq as u64), Ok(*root));
+        assert_eq!(
+            off_chain_tree.get_proof_of_leaf_at(0, seq as u64).as_ref(),
+            Ok(&proofs[seq])
+        );
+    }
+    assert_eq!(
+        off_chain_tree.get_root_at(BUFFER_SIZE as u64),
+        Err(HistoryError::NotReached {
+            seq: BUFFER_SIZE as u64,
+            last_seq: BUFFER_SIZE as u64 - 1
+        })
+    );
+
+    // A proof that a slow client built against an older root is still accepted on-chain
+    let seq = 2;
+    let proof = off_chain_tree.get_proof_of_leaf_at(0, seq).unwrap();
+    let previous_leaf = off_chain_tree.get_proof_of_leaf_at(1, seq).unwrap()[0];
+    let new_leaf = rng.gen::<Node>();
+    merkle_roll
+        .set_leaf(
+            off_chain_tree.get_root_at(seq).unwrap(),
+            previous_leaf,
+            new_leaf,
+            &proof,
+            0,
+        )
+        .unwrap();
+    off_chain_tree.add_leaf(new_leaf, 0);
+    roots.push(off_chain_tree.get_root());
+    assert_eq!(merkle_roll.get_change_log().root, off_chain_tree.get_root());
+
+    // Pruned versions can no longer be read, later ones still can
+    off_chain_tree.prune_history(10);
+    assert_eq!(off_chain_tree.history_start(), 10);
+    let pruned = Err(HistoryError::Pruned {
+        seq: 9,
+        history_start: 10,
+    });
+    assert_eq!(off_chain_tree.get_root_at(9), pruned);
+    assert_eq!(
+        off_chain_tree.get_proof_of_leaf_at(0, 9).map(|_| EMPTY),
+        pruned
+    );
+    for seq in 10..BUFFER_SIZE {
+        assert_eq!(off_chain_tree.get_root_at(seq as u64), Ok(roots[seq]));
+        assert_eq!(
+            off_chain_tree.get_proof_of_leaf_at(0, seq as u64).as_ref(),
+            Ok(&proofs[seq])
+        );
+    }
+
+    // Versions that fall out of the window are pruned as the tree changes
+    off_chain_tree.set_history_window(5);
+    for i in 0..10 {
+        let leaf = rng.gen::<Node>();
+        off_chain_tree.add_leaf(leaf, i);
+        roots.push(off_chain_tree.get_root());
+    }
+    let seq = off_chain_tree.seq();
+    assert_eq!(off_chain_tree.history_start(), seq - 5);
+    assert_eq!(
+        off_chain_tree.get_root_at(seq - 6),
+        Err(HistoryError::Pruned {
+            seq: seq - 6,
+            history_start: seq - 5
+        })
+    );
+    for seq in seq - 5..=seq {
+        assert_eq!(off_chain_tree.get_root_at(seq), Ok(roots[seq as usize]));
+    }
+
+    // History is opt-in, so a tree without a window can only be read at its last change
+    let (_, mut tree) = setup();
+    tree.add_leaf(rng.gen::<Node>(), 0);
+    assert_eq!(tree.get_root_at(1), Ok(tree.get_root()));
+    assert!(matches!(
+        tree.get_root_at(0),
+        Err(HistoryError::Pruned { .. })
+    ));
+}
+
+#[tokio::test(threaded_scheduler)]
+/// A multiproof proves several leaves with fewer nodes than their proofs, even when stale
+async fn test_multiproof() {
+    let (mut merkle_roll, mut off_chain_tree) = setup();
+    let mut rng = thread_rng();
+    merkle_roll.initialize().unwrap();
+    for i in 0..100 {
+        let leaf = rng.gen::<Node>();
+        merkle_roll.append(leaf).unwrap();
+        off_chain_tree.add_leaf(leaf, i);
+    }
+    let root = off_chain_tree.get_root();
+    let mut indices: Vec<usize> = vec![10, 11, 42, 64, 99];
+    indices.extend((0..5).map(|_| rng.gen_range(0, 100)));
+    indices.sort_unstable();
+    indices.dedup();
+    let leaves: Vec<(u32, Node)> = indices
+        .iter()
+        .map(|i| (*i as u32, off_chain_tree.get_leaf(*i)))
+        .collect();
+    let multiproof = off_chain_tree.get_multiproof(&indices, DEPTH as u32);
+    // Leaves 10 and 11 are siblings, so their paths share every node
+    assert!(multiproof.len() < (indices.len() - 1) * DEPTH);
+    assert_eq!(
+        recompute_multiproof::<Keccak>(&mut leaves.clone(), &multiproof, DEPTH as u32),
+        Ok(root)
+    );
+    let proofs = expand_multiproof::<Keccak>(&leaves, &multiproof, DEPTH as u32).unwrap();
+    for (i, proof) in indices.iter().zip(proofs.iter()) {
+        assert_eq!(*proof, off_chain_tree.get_proof_of_leaf(*i));
+    }
+    // A multiproof of the lowest levels expands into truncated proofs
+    let levels = 5;
+    let truncated = off_chain_tree.get_multiproof(&indices, levels);
+    let proofs = expand_multiproof::<Keccak>(&leaves, &truncated, levels).unwrap();
+    for (i, proof) in indices.iter().zip(proofs.iter()) {
+        assert_eq!(
+            *proof,
+            off_chain_tree.get_proof_of_leaf(*i)[..levels as usize]
+        );
+    }
+
+    // Malformed multiproofs are rejected
+    let mut unsorted = leaves.clone();
+    unsorted.swap(0, 1);
+    assert_eq!(
+        recompute_multiproof::<Keccak>(&mut unsorted, &multiproof, DEPTH as u32),
+        Err(CMTError::InvalidMultiproofLeaves)
+    );
+    assert_eq!(
+        recompute_multiproof::<Keccak>(&mut [], &multiproof, DEPTH as u32),
+        Err(CMTError::InvalidMultiproofLeaves)
+    );
+    assert_eq!(
+        recompute_multiproof::<Keccak>(&mut leaves.clone(), &multiproof[1..], DEPTH as u32),
+        Err(CMTError::ProofLengthMismatch)
+    );
+    let mut long = multiproof.clone();
+    long.push(EMPTY);
+    assert_eq!(
+        expand_multiproof::<Keccak>(&leaves, &long, DEPTH as u32),
+        Err(CMTError::ProofLengthMismatch)
+    );
+    let mut modified = leaves.clone();
+    modified[2].1 = rng.gen::<Node>();
+    assert!(merkle_roll
+        .prove_leaves(root, &modified, &multiproof)
+        .is_err());
+
+    // The multiproof is fast-forwarded past changes to other leaves
+    let other = (0..100).find(|i| !indices.contains(i)).unwrap();
+    let new_leaf = rng.gen::<Node>();
+    merkle_roll
+        .set_leaf(
+            root,
+            off_chain_tree.get_leaf(other),
+            new_leaf,
+            &off_chain_tree.get_proof_of_leaf(other),
+            other as u32,
+        )
+        .unwrap();
+    off_chain_tree.add_leaf(new_leaf, other);
+    merkle_roll
+        .prove_leaves(root, &leaves, &multiproof)
+        .unwrap();
+
+    let replacements: Vec<(u32, Node, Node)> = leaves
+        .iter()
+        .map(|(index, leaf)| (*index, *leaf, rng.gen::<Node>()))
+        .collect();
+    merkle_roll
+        .set_leaves_with_multiproof(root, &replacements, &multiproof)
+        .unwrap();
+    for (index, _, new_leaf) in replacements.iter() {
+        off_chain_tree.add_leaf(*new_leaf, *index as usize);
+    }
+    assert_eq!(merkle_roll.get_change_log().root, off_chain_tree.get_root());
+    // Leaves were replaced, so the multiproof no longer proves them
+    assert_eq!(
+        merkle_roll.set_leaves_with_multiproof(root, &replacements, &multiproof),
+        Err(CMTError::LeafContentsModified)
+    );
+}
@@ -0,0 +1,183 @@
+//! Ingestion of the `ChangeLogEvent`s emitted by gummyroll, so that a [MerkleTree] can
+//! mirror an on-chain tree, including when events are missed and backfilled later.
+use crate::{Hasher, MerkleTree, Node};
+use borsh::{BorshDeserialize, BorshSerialize};
+use concurrent_merkle_tree::state::ChangeLogRef;
+use std::ops::Range;
+
+/// Node of the path of a [ChangeLogEvent], with the same layout as gummyroll's `PathNode`
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PathNode {
+    pub node: Node,
+    /// Index of the node in the tree, where the root is 1
+    pub index: u32,
+}
+
+/// Same layout as gummyroll's `ChangeLogEvent`, so that events read from transaction logs
+/// can be deserialized with `ChangeLogEvent::try_from_slice` after their discriminator
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ChangeLogEvent {
+    /// Public key of the merkle roll
+    pub id: [u8; 32],
+    /// Nodes of the path of the changed leaf, from the leaf up to the root
+    pub path: Vec<PathNode>,
+    /// Number of successful operations on the tree, used to find gaps to backfill
+    pub seq: u64,
+    /// Index of the changed leaf
+    pub index: u32,
+}
+
+impl ChangeLogEvent {
+    /// Builds the event that gummyroll emits for `change_log`
+    pub fn from_change_log(change_log: &ChangeLogRef<'_>, id: [u8; 32], seq: u64) -> Self {
+        let depth = change_log.path.len() as u32;
+        let mut path: Vec<PathNode> = change_log
+            .path
+            .iter()
+            .enumerate()
+            .map(|(level, node)| PathNode {
+                node: *node,
+                index: (1 << (depth - level as u32)) + (change_log.index >> level),
+            })
+            .collect();
+        path.push(PathNode {
+            node: *change_log.root,
+            index: 1,
+        });
+        Self {
+            id,
+            path,
+            seq,
+            index: change_log.index,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum ChangeLogEventError {
+    #[error("Event path has {actual} nodes, expected {expected}")]
+    PathLengthMismatch { expected: usize, actual: usize },
+    #[error("Event path node at level {level} does not match the leaf index")]
+    InvalidPathNode { level: usize },
+    /// The event was already applied, or is older than the first event applied for its tree
+    #[error("Event {seq} is out of order, last applied event is {last_seq}")]
+    OutOfOrder { seq: u64, last_seq: u64 },
+    /// The event disagrees with the event applied with the same sequence number
+    #[error("Event {seq} conflicts with node {node_index} of the tree")]
+    Conflict { seq: u64, node_index: u32 },
+}
+
+/// Sequence numbers of the events applied for a tree id
+#[derive(Debug)]
+pub(crate) struct AppliedSeqs {
+    last_seq: u64,
+    /// Sequence numbers before `last_seq` that were not applied, in increasing order
+    missing: Vec<Range<u64>>,
+}
+
+impl AppliedSeqs {
+    /// Removes `seq` from the missing sequence numbers, returns false if it was not missing
+    fn backfill(&mut self, seq: u64) -> bool {
+        let position = match self.missing.iter().position(|range| range.contains(&seq)) {
+            Some(position) => position,
+            None => return false,
+        };
+        let range = self.missing.remove(position);
+        if seq + 1 < range.end {
+            self.missing.insert(position, seq + 1..range.end);
+        }
+        if range.start < seq {
+            self.missing.insert(position, range.start..seq);
+        }
+        true
+    }
+}
+
+impl<H: Hasher> MerkleTree<H> {
+    /// Applies the nodes of the path of `event`.
+    ///
+    /// Events of a tree can be applied in any order: a node is only overwritten by an event
+    /// with a larger sequence number than the event that last wrote it, and sequence numbers
+    /// skipped between applied events are reported by `missing_seqs` until they are applied.
+    /// The sequence number of the tree becomes the largest applied one.
+    pub fn apply_changelog_event(
+        &mut self,
+        event: &ChangeLogEvent,
+    ) -> Result<(), ChangeLogEventError> {
+        let expected = self.depth as usize + 1;
+        if event.path.len() != expected {
+            return Err(ChangeLogEventError::PathLengthMismatch {
+                expected,
+                actual: event.path.len(),
+            });
+        }
+        let leaf_node_idx = (1 << self.depth) + event.index as u64;
+        for (level, path_node) in event.path.iter().enumerate() {
+            if path_node.index as u64 != leaf_node_idx >> level {
+                return Err(ChangeLogEventError::InvalidPathNode { level });
+            }
+        }
+
+        let seq = event.seq;
+        if let Some(applied) = self.applied_seqs.get_mut(&event.id) {
+            let last_seq = applied.last_seq;
+            if seq <= last_seq && !applied.backfill(seq) {
+                return Err(self.already_applied_error(event, last_seq));
+            }
+        }
+        for (level, path_node) in event.path.iter().enumerate() {
+            self.write_node(path_node.index as u64, level as u32, path_node.node, seq);
+        }
+        self.seq = self.seq.max(seq);
+        let applied = self.applied_seqs.entry(event.id).or_insert(AppliedSeqs {
+            last_seq: seq,
+            missing: vec![],
+        });
+        if seq > applied.last_seq + 1 {
+            applied.missing.push(applied.last_seq + 1..seq);
+        }
+        applied.last_seq = applied.last_seq.max(seq);
+        self.prune_versions();
+        Ok(())
+    }
+
+    /// Error for an event whose sequence number was already applied
+    fn already_applied_error(&self, event: &ChangeLogEvent, last_seq: u64) -> ChangeLogEventError {
+        // Nodes written by the applied event must be the same, unless they were pruned
+        for path_node in event.path.iter() {
+            let written = self
+                .versions
+                .get(&(path_node.index as u64))
+                .and_then(|versions| {
+                    versions
+                        .iter()
+                        .rev()
+                        .find(|(version_seq, _)| *version_seq == event.seq)
+                });
+            if matches!(written, Some((_, node)) if *node != path_node.node) {
+                return ChangeLogEventError::Conflict {
+                    seq: event.seq,
+                    node_index: path_node.index,
+                };
+            }
+        }
+        ChangeLogEventError::OutOfOrder {
+            seq: event.seq,
+            last_seq,
+        }
+    }
+
+    /// Sequence number of the last event applied for the tree `id`
+    pub fn last_applied_seq(&self, id: &[u8; 32]) -> Option<u64> {
+        self.applied_seqs.get(id).map(|applied| applied.last_seq)
+    }
+
+    /// Ranges of sequence numbers between the first and the last event applied for the
+    /// tree `id` that were not applied, and must be backfilled
+    pub fn missing_seqs(&self, id: &[u8; 32]) -> &[Range<u64>] {
+        match self.applied_seqs.get(id) {
+            Some(applied) => &applied.missing,
+            None => &[],
+        }
+    }
+}
@@ -1,217 +1,345 @@
-use solana_program::keccak::hashv;
-use std::cell::RefCell;
-use std::collections::VecDeque;
-use std::iter::FromIterator;
-use std::rc::Rc;
+use changelog::AppliedSeqs;
+pub use concurrent_merkle_tree::hasher::{Hasher, Keccak, Sha256};
+pub use concurrent_merkle_tree::utils::{empty_node, recompute};
+use std::collections::{BTreeMap, HashMap};
+use std::marker::PhantomData;
+
+pub mod changelog;
+pub mod persistent;
 
 pub type Node = [u8; 32];
 pub const EMPTY: Node = [0; 32];
 
-/// Max number of concurrent changes to tree supported before having to regenerate proofs
-pub const MAX_SIZE: usize = 64;
+/// Largest depth supported by a `MerkleTree`, so that node indices fit in a `u64`
+pub const MAX_SUPPORTED_DEPTH: u32 = 63;
 
-/// Max depth of the Merkle tree
-pub const MAX_DEPTH: usize = 14;
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum HistoryError {
+    #[error("Sequence number {seq} was pruned, history starts at {history_start}")]
+    Pruned { seq: u64, history_start: u64 },
+    #[error("Sequence number {seq} is after the last change {last_seq}")]
+    NotReached { seq: u64, last_seq: u64 },
+}
 
-pub const PADDING: usize = 32 - MAX_DEPTH;
+/// Off-chain merkle tree of any depth, used to mirror the trees stored on-chain.
+///
+/// Only nodes that differ from the empty subtree of their level are stored, so memory is
+/// proportional to the number of non-empty leaves times the depth, and setting a leaf or
+/// getting its proof takes `depth` steps.
+///
+/// Nodes are keyed by their index in a full binary tree, where the root is 1 and the children
+/// of node `i` are `2i` and `2i + 1`, so the leaf at index `i` is node `2^depth + i`.
+///
+/// Like a merkle roll, the tree has a sequence number that is incremented by every change.
+/// When a history window is set with `set_history_window`, previous values of the nodes are
+/// kept for that many changes, so that roots and proofs can be served as of an older sequence
+/// number. Older values are pruned as the tree changes, so memory stays bounded.
+pub struct MerkleTree<H: Hasher = Keccak> {
+    depth: u32,
+    nodes: HashMap<u64, Node>,
+    /// Empty subtree of each level, from an empty leaf up to the root
+    empty_nodes: Vec<Node>,
+    /// Sequence number of the last change
+    seq: u64,
+    /// Values of each written node by sequence number, from the last one that is not after
+    /// the start of the history. The first version of a node is its value before it was
+    /// first written.
+    versions: HashMap<u64, Vec<(u64, Node)>>,
+    /// Nodes written by each change whose versions may not have been pruned yet
+    writes: BTreeMap<u64, Vec<u64>>,
+    /// Number of changes before the last one for which nodes can still be read
+    history_window: u64,
+    /// Oldest sequence number for which nodes can still be read, regardless of the window
+    history_start: u64,
+    /// Change log events applied for each tree id
+    applied_seqs: HashMap<[u8; 32], AppliedSeqs>,
+    _hasher: PhantomData<H>,
+}
 
-/// Used for node parity when hashing
-pub const MASK: usize = MAX_SIZE - 1;
+impl MerkleTree {
+    /// Builds a tree that holds `leaves`, padded with empty leaves up to the next power of 2
+    pub fn new(leaves: Vec<Node>) -> Self {
+        Self::with_hasher(leaves, Keccak)
+    }
 
-/// Recomputes root of the Merkle tree from Node & proof
-pub fn recompute(mut leaf: Node, proof: &[Node], index: u32) -> Node {
-    for (i, s) in proof.iter().enumerate() {
-        if index >> i & 1 == 0 {
-            let res = hashv(&[&leaf, s.as_ref()]);
-            leaf.copy_from_slice(res.as_ref());
-        } else {
-            let res = hashv(&[s.as_ref(), &leaf]);
-            leaf.copy_from_slice(res.as_ref());
-        }
+    /// Creates a tree of depth `depth` where every leaf is empty
+    pub fn new_empty(depth: u32) -> Self {
+        Self::new_empty_with_hasher(depth, Keccak)
     }
-    leaf
 }
 
-// Off-chain implentation to keep track of nodes
-pub struct MerkleTree {
-    pub leaf_nodes: Vec<Rc<RefCell<TreeNode>>>,
-    pub root: Node,
-}
+impl<H: Hasher> MerkleTree<H> {
+    /// Same as `new`, but nodes are hashed with `H` instead of keccak
+    pub fn with_hasher(leaves: Vec<Node>, hasher: H) -> Self {
+        let depth = leaves.len().next_power_of_two().trailing_zeros();
+        let mut tree = Self::new_empty_with_hasher(depth, hasher);
+        let mut level_nodes: Vec<u64> = vec![];
+        for (i, leaf) in leaves.into_iter().enumerate() {
+            let node_idx = (1 << depth) + i as u64;
+            if tree.set_node(node_idx, 0, leaf) {
+                level_nodes.push(node_idx);
+            }
+        }
+        // Hash the non-empty nodes level by level, so that each parent is computed once
+        for level in 1..=depth {
+            let mut parents: Vec<u64> = level_nodes.iter().map(|node_idx| node_idx >> 1).collect();
+            parents.dedup();
+            for parent_idx in parents.iter() {
+                let parent = H::hash_pair(
+                    &tree.get_node_at(parent_idx << 1),
+                    &tree.get_node_at((parent_idx << 1) + 1),
+                );
+                tree.set_node(*parent_idx, level, parent);
+            }
+            level_nodes = parents;
+        }
+        tree
+    }
 
-impl MerkleTree {
-    /// Calculates updated root from the passed leaves
-    pub fn new(leaves: Vec<Node>) -> Self {
-        let mut leaf_nodes = vec![];
-        for (i, node) in leaves.iter().enumerate() {
-            let mut tree_node = TreeNode::new_empty(0, i as u128);
-            tree_node.node = *node;
-            leaf_nodes.push(Rc::new(RefCell::new(tree_node)));
+    /// Same as `new_empty`, but nodes are hashed with `H` instead of keccak
+    pub fn new_empty_with_hasher(depth: u32, _hasher: H) -> Self {
+        assert!(
+            depth <= MAX_SUPPORTED_DEPTH,
+            "Depth {} is larger than {}",
+            depth,
+            MAX_SUPPORTED_DEPTH
+        );
+        Self {
+            depth,
+            nodes: HashMap::new(),
+            empty_nodes: (0..=depth).map(|level| empty_node::<H>(level)).collect(),
+            seq: 0,
+            versions: HashMap::new(),
+            writes: BTreeMap::new(),
+            history_window: 0,
+            history_start: 0,
+            applied_seqs: HashMap::new(),
+            _hasher: PhantomData,
         }
-        let root = MerkleTree::build_root(&leaf_nodes);
-        Self { leaf_nodes, root }
-    }
-
-    /// Builds root from stack of leaves
-    pub fn build_root(leaves: &Vec<Rc<RefCell<TreeNode>>>) -> Node {
-        let mut tree = VecDeque::from_iter(leaves.iter().map(Rc::clone));
-        let mut seq_num = leaves.len() as u128;
-        while tree.len() > 1 {
-            let left = tree.pop_front().unwrap();
-            let level = left.borrow().level;
-            let right = if level != tree[0].borrow().level {
-                let node = Rc::new(RefCell::new(TreeNode::new_empty(level, seq_num)));
-                seq_num += 1;
-                node
-            } else {
-                tree.pop_front().unwrap()
-            };
-            let mut hashed_parent = EMPTY;
-
-            hashed_parent
-                .copy_from_slice(hashv(&[&left.borrow().node, &right.borrow().node]).as_ref());
-            let parent = Rc::new(RefCell::new(TreeNode::new(
-                hashed_parent,
-                left.clone(),
-                right.clone(),
-                level + 1,
-                seq_num,
-            )));
-            left.borrow_mut().assign_parent(parent.clone());
-            right.borrow_mut().assign_parent(parent.clone());
-            tree.push_back(parent);
-            seq_num += 1;
+    }
+
+    pub fn depth(&self) -> u32 {
+        self.depth
+    }
+
+    /// Number of leaves of the tree, including empty ones
+    pub fn capacity(&self) -> u64 {
+        1 << self.depth
+    }
+
+    /// Returns the node at `node_idx`, where the root is 1
+    fn get_node_at(&self, node_idx: u64) -> Node {
+        let level = self.depth - (63 - node_idx.leading_zeros());
+        match self.nodes.get(&node_idx) {
+            Some(node) => *node,
+            None => self.empty_nodes[level as usize],
         }
+    }
+
+    /// Stores `node` at `node_idx`, which is at height `level`.
+    /// Returns false if the node is empty, in which case it is not stored.
+    fn set_node(&mut self, node_idx: u64, level: u32, node: Node) -> bool {
+        if node == self.empty_nodes[level as usize] {
+            self.nodes.remove(&node_idx);
+            false
+        } else {
+            self.nodes.insert(node_idx, node);
+            true
+        }
+    }
 
-        let root = tree[0].borrow().node;
-        root
+    fn leaf_node_idx(&self, leaf_idx: usize) -> u64 {
+        assert!(
+            (leaf_idx as u64) < self.capacity(),
+            "Leaf index {} is out of bounds for a tree of depth {}",
+            leaf_idx,
+            self.depth
+        );
+        (1 << self.depth) + leaf_idx as u64
     }
 
-    /// Traverses TreeNodes upwards to root from a Leaf TreeNode
-    /// hashing along the way
+    /// Returns the siblings of the path of the leaf at `idx`, from the leaf up
     pub fn get_proof_of_leaf(&self, idx: usize) -> Vec<Node> {
-        let mut proof = vec![];
-        let mut node = self.leaf_nodes[idx].clone();
-        loop {
-            let ref_node = node.clone();
-            if ref_node.borrow().parent.is_none() {
-                break;
+        let mut node_idx = self.leaf_node_idx(idx);
+        let mut proof = Vec::with_capacity(self.depth as usize);
+        while node_idx > 1 {
+            proof.push(self.get_node_at(node_idx ^ 1));
+            node_idx >>= 1;
+        }
+        proof
+    }
+
+    /// Records `node` as the value of the node at `node_idx` after the change `seq`.
+    /// The current node is only replaced if it was not written by a later change.
+    fn write_node(&mut self, node_idx: u64, level: u32, node: Node, seq: u64) {
+        let current = self.get_node_at(node_idx);
+        let versions = self
+            .versions
+            .entry(node_idx)
+            .or_insert_with(|| vec![(0, current)]);
+        let position = versions.partition_point(|(version_seq, _)| *version_seq <= seq);
+        versions.insert(position, (seq, node));
+        if position == versions.len() - 1 {
+            self.set_node(node_idx, level, node);
+        }
+        self.writes.entry(seq).or_default().push(node_idx);
+    }
+
+    /// Drops the versions of the nodes written before the start of the history that are no
+    /// longer needed to read the tree from there on
+    fn prune_versions(&mut self) {
+        let history_start = self.history_start();
+        let kept = self.writes.split_off(&history_start.saturating_add(1));
+        let pruned = std::mem::replace(&mut self.writes, kept);
+        for node_idx in pruned.into_values().flatten() {
+            if let Some(versions) = self.versions.get_mut(&node_idx) {
+                let position =
+                    versions.partition_point(|(version_seq, _)| *version_seq <= history_start);
+                if position > 1 {
+                    versions.drain(..position - 1);
+                }
             }
-            let parent = ref_node.borrow().parent.as_ref().unwrap().clone();
-            if parent.borrow().left.as_ref().unwrap().borrow().id == ref_node.borrow().id {
-                proof.push(parent.borrow().right.as_ref().unwrap().borrow().node);
-            } else {
-                proof.push(parent.borrow().left.as_ref().unwrap().borrow().node);
+        }
+    }
+
+    /// Returns the node at `node_idx` as it was after the change `seq`
+    fn get_node_at_seq(&self, node_idx: u64, seq: u64) -> Node {
+        match self.versions.get(&node_idx) {
+            Some(versions) => {
+                // The first version is never after `history_start`, so this never underflows
+                let position = versions.partition_point(|(version_seq, _)| *version_seq <= seq);
+                versions[position - 1].1
             }
-            node = parent;
+            None => self.get_node_at(node_idx),
         }
-        proof
     }
 
-    /// Updates root from an updated leaf node set at index: `idx`
-    fn update_root_from_leaf(&mut self, leaf_idx: usize) {
-        let mut node = self.leaf_nodes[leaf_idx].clone();
-        loop {
-            let ref_node = node.clone();
-            if ref_node.borrow().parent.is_none() {
-                self.root = ref_node.borrow().node;
-                break;
+    /// Returns the multiproof of the leaves at `indices` that covers the `levels` lowest levels
+    /// of the tree, see `concurrent_merkle_tree::multiproof`. `levels` is `depth()` to prove the
+    /// leaves against the root, or less when the top of the tree is cached in a canopy.
+    ///
+    /// The proof is for the leaves sorted by increasing index, without duplicates.
+    pub fn get_multiproof(&self, indices: &[usize], levels: u32) -> Vec<Node> {
+        let mut node_idxs: Vec<u64> = indices.iter().map(|idx| self.leaf_node_idx(*idx)).collect();
+        node_idxs.sort_unstable();
+        node_idxs.dedup();
+        let mut proof = vec![];
+        for _ in 0..levels.min(self.depth) {
+            for (i, node_idx) in node_idxs.iter().enumerate() {
+                let sibling_idx = node_idx ^ 1;
+                // Siblings on the path of another leaf are computed by the verifier
+                let prev_is_sibling = i > 0 && node_idxs[i - 1] == sibling_idx;
+                let next_is_sibling = node_idxs.get(i + 1) == Some(&sibling_idx);
+                if !prev_is_sibling && !next_is_sibling {
+                    proof.push(self.get_node_at(sibling_idx));
+                }
             }
-            let parent = ref_node.borrow().parent.as_ref().unwrap().clone();
-            let hash = if parent.borrow().left.as_ref().unwrap().borrow().id == ref_node.borrow().id
-            {
-                hashv(&[
-                    &ref_node.borrow().node,
-                    &parent.borrow().right.as_ref().unwrap().borrow().node,
-                ])
+            node_idxs = node_idxs.iter().map(|node_idx| node_idx >> 1).collect();
+            node_idxs.dedup();
+        }
+        proof
+    }
+
+    /// Recomputes the path of the leaf at `leaf_idx` up to the root, as a new change
+    fn update_root_from_leaf(&mut self, leaf_idx: usize, leaf: Node) {
+        self.seq += 1;
+        let seq = self.seq;
+        let mut node_idx = self.leaf_node_idx(leaf_idx);
+        let mut node = leaf;
+        self.write_node(node_idx, 0, node, seq);
+        for level in 1..=self.depth {
+            let sibling = self.get_node_at(node_idx ^ 1);
+            node = if node_idx & 1 == 0 {
+                H::hash_pair(&node, &sibling)
             } else {
-                hashv(&[
-                    &parent.borrow().left.as_ref().unwrap().borrow().node,
-                    &ref_node.borrow().node,
-                ])
+                H::hash_pair(&sibling, &node)
             };
-            node = parent;
-            node.borrow_mut().node.copy_from_slice(hash.as_ref());
+            node_idx >>= 1;
+            self.write_node(node_idx, level, node, seq);
         }
+        self.prune_versions();
     }
 
+    /// Returns the leaf at `idx`
     pub fn get_node(&self, idx: usize) -> Node {
-        self.leaf_nodes[idx].borrow().node
+        self.get_leaf(idx)
     }
 
     pub fn get_root(&self) -> Node {
-        self.root
+        self.get_node_at(1)
     }
 
     pub fn add_leaf(&mut self, leaf: Node, leaf_idx: usize) {
-        self.leaf_nodes[leaf_idx].borrow_mut().node = leaf;
-        self.update_root_from_leaf(leaf_idx)
+        self.update_root_from_leaf(leaf_idx, leaf)
     }
 
     pub fn remove_leaf(&mut self, leaf_idx: usize) {
-        self.leaf_nodes[leaf_idx].borrow_mut().node = EMPTY;
-        self.update_root_from_leaf(leaf_idx)
+        self.update_root_from_leaf(leaf_idx, EMPTY)
     }
 
     pub fn get_leaf(&self, leaf_idx: usize) -> Node {
-        self.leaf_nodes[leaf_idx].borrow().node.clone()
+        self.get_node_at(self.leaf_node_idx(leaf_idx))
     }
-}
 
-#[derive(Clone)]
-pub struct TreeNode {
-    pub node: Node,
-    left: Option<Rc<RefCell<TreeNode>>>,
-    right: Option<Rc<RefCell<TreeNode>>>,
-    parent: Option<Rc<RefCell<TreeNode>>>,
-    level: u32,
-    /// ID needed to figure out whether we came from left or right child node
-    /// when hashing path upwards
-    id: u128,
-}
+    /// Sequence number of the last change, which is 0 for a tree that was just built
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
 
-impl TreeNode {
-    pub fn new(
-        node: Node,
-        left: Rc<RefCell<TreeNode>>,
-        right: Rc<RefCell<TreeNode>>,
-        level: u32,
-        id: u128,
-    ) -> Self {
-        Self {
-            node,
-            left: Some(left),
-            right: Some(right),
-            parent: None,
-            level,
-            id,
+    /// Fails if the tree can't be read as it was after the change `seq`
+    fn check_history(&self, seq: u64) -> Result<(), HistoryError> {
+        let history_start = self.history_start();
+        if seq < history_start {
+            return Err(HistoryError::Pruned { seq, history_start });
         }
+        if seq > self.seq {
+            return Err(HistoryError::NotReached {
+                seq,
+                last_seq: self.seq,
+            });
+        }
+        Ok(())
     }
 
-    pub fn new_empty(level: u32, id: u128) -> Self {
-        Self {
-            node: empty_node(level),
-            left: None,
-            right: None,
-            parent: None,
-            level,
-            id,
+    /// Returns the root as it was after the change `seq`
+    pub fn get_root_at(&self, seq: u64) -> Result<Node, HistoryError> {
+        self.check_history(seq)?;
+        Ok(self.get_node_at_seq(1, seq))
+    }
+
+    /// Returns the proof of the leaf at `idx` as it was after the change `seq`
+    pub fn get_proof_of_leaf_at(&self, idx: usize, seq: u64) -> Result<Vec<Node>, HistoryError> {
+        self.check_history(seq)?;
+        let mut node_idx = self.leaf_node_idx(idx);
+        let mut proof = Vec::with_capacity(self.depth as usize);
+        while node_idx > 1 {
+            proof.push(self.get_node_at_seq(node_idx ^ 1, seq));
+            node_idx >>= 1;
         }
+        Ok(proof)
     }
 
-    /// Allows to propagate parent assignment
-    pub fn assign_parent(&mut self, parent: Rc<RefCell<TreeNode>>) {
-        self.parent = Some(parent);
+    /// Oldest sequence number for which roots and proofs can be read
+    pub fn history_start(&self) -> u64 {
+        self.history_start
+            .max(self.seq.saturating_sub(self.history_window))
+    }
+
+    /// Keeps the versions of nodes needed to read the tree as it was up to `window` changes
+    /// before the last one. The window is 0 by default, so only the current tree can be read.
+    ///
+    /// Versions are only kept from the moment the window is set, and shrinking the window
+    /// prunes the versions that fall out of it.
+    pub fn set_history_window(&mut self, window: u64) {
+        // Versions before the current start of the history may already be pruned
+        self.history_start = self.history_start();
+        self.history_window = window;
+        self.prune_versions();
     }
-}
 
-/// Calculates hash of empty nodes up to level i
-/// TODO: cache this
-pub fn empty_node(level: u32) -> Node {
-    let mut data = EMPTY;
-    if level != 0 {
-        let lower_empty = empty_node(level - 1);
-        let hash = hashv(&[&lower_empty, &lower_empty]);
-        data.copy_from_slice(hash.as_ref());
+    /// Drops the versions of nodes that are only needed to read the tree before `seq`
+    pub fn prune_history(&mut self, seq: u64) {
+        self.history_start = self.history_start.max(seq.min(self.seq));
+        self.prune_versions();
     }
-    data
 }
@@ -0,0 +1,415 @@
+//! Merkle tree whose nodes are kept in a [NodeStore] instead of in memory, so that
+//! indexers can restart without replaying every change of a tree.
+//!
+//! Nodes are keyed like in [MerkleTree](crate::MerkleTree): the root is node 1 and the
+//! children of node `i` are `2i` and `2i + 1`. Only non-empty nodes are stored.
+use crate::{empty_node, Hasher, Keccak, Node, EMPTY};
+use concurrent_merkle_tree::hasher::LeafHashScheme;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+/// Largest depth of a tree stored by a [FileNodeStore], whose node file has a slot
+/// for every node of the tree
+pub const MAX_FILE_STORE_DEPTH: u32 = 32;
+
+#[derive(Debug, thiserror::Error)]
+pub enum PersistentTreeError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Node store is corrupted: {0}")]
+    Corrupted(&'static str),
+    #[error("Node store holds a tree of depth {stored}, expected {expected}")]
+    DepthMismatch { stored: u32, expected: u32 },
+    #[error("Depth {0} is not supported by this node store")]
+    UnsupportedDepth(u32),
+    #[error("Leaf index {0} is out of bounds")]
+    LeafIndexOutOfBounds(usize),
+}
+
+pub type Result<T> = std::result::Result<T, PersistentTreeError>;
+
+/// Key-value storage for the nodes of a [PersistentMerkleTree]
+pub trait NodeStore {
+    /// Depth of the stored tree
+    fn depth(&self) -> u32;
+
+    /// Sequence number of the last committed batch, if any
+    fn seq(&self) -> Option<u64>;
+
+    /// Returns the node at `node_idx`, or `None` if it is empty
+    fn get(&self, node_idx: u64) -> Result<Option<Node>>;
+
+    /// Writes `nodes`, where `None` removes a node, and records `seq` as the sequence number
+    /// of the last committed batch. Either the whole batch is persisted or none of it is.
+    fn commit(&mut self, seq: u64, nodes: &[(u64, Option<Node>)]) -> Result<()>;
+}
+
+/// Node store that is lost when dropped, for tests and short-lived mirrors
+pub struct MemoryNodeStore {
+    depth: u32,
+    seq: Option<u64>,
+    nodes: HashMap<u64, Node>,
+}
+
+impl MemoryNodeStore {
+    pub fn new(depth: u32) -> Self {
+        Self {
+            depth,
+            seq: None,
+            nodes: HashMap::new(),
+        }
+    }
+}
+
+impl NodeStore for MemoryNodeStore {
+    fn depth(&self) -> u32 {
+        self.depth
+    }
+
+    fn seq(&self) -> Option<u64> {
+        self.seq
+    }
+
+    fn get(&self, node_idx: u64) -> Result<Option<Node>> {
+        Ok(self.nodes.get(&node_idx).copied())
+    }
+
+    fn commit(&mut self, seq: u64, nodes: &[(u64, Option<Node>)]) -> Result<()> {
+        for (node_idx, node) in nodes.iter() {
+            match node {
+                Some(node) => self.nodes.insert(*node_idx, *node),
+                None => self.nodes.remove(node_idx),
+            };
+        }
+        self.seq = Some(seq);
+        Ok(())
+    }
+}
+
+const NODES_MAGIC: &[u8; 8] = b"CMTNODES";
+const WAL_MAGIC: &[u8; 8] = b"CMTWAL01";
+/// Magic, depth, flags and sequence number, padded to the size of a node
+const HEADER_SIZE: u64 = 32;
+/// Set in the header flags once a batch has been committed
+const HAS_SEQ: u32 = 1;
+/// Node index, presence flag and node
+const WAL_ENTRY_SIZE: usize = 8 + 1 + 32;
+
+/// Node index and node, where `None` removes the node
+type NodeWrite = (u64, Option<Node>);
+
+/// Node store kept in a directory on the local file system.
+///
+/// Nodes are stored in the `nodes` file, at an offset derived from their index, so proofs
+/// are read directly from disk. The file is sparse: slots of empty nodes are never written,
+/// so most file systems only allocate space for non-empty nodes.
+///
+/// Batches are first written to the `wal` file. A batch that was fully written to it before
+/// a crash is applied when the store is opened again, and a partially written one is dropped.
+pub struct FileNodeStore {
+    depth: u32,
+    seq: Option<u64>,
+    nodes: File,
+    wal: File,
+}
+
+impl FileNodeStore {
+    /// Opens the node store in `dir`, or creates an empty one for a tree of depth `depth`
+    pub fn open(dir: impl AsRef<Path>, depth: u32) -> Result<Self> {
+        if depth > MAX_FILE_STORE_DEPTH {
+            return Err(PersistentTreeError::UnsupportedDepth(depth));
+        }
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        let open_options = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .clone();
+        let mut store = Self {
+            depth,
+            seq: None,
+            nodes: open_options.open(dir.join("nodes"))?,
+            wal: open_options.open(dir.join("wal"))?,
+        };
+        if store.nodes.metadata()?.len() == 0 {
+            store.write_header()?;
+            store.nodes.sync_all()?;
+        } else {
+            store.read_header()?;
+        }
+        store.recover()?;
+        Ok(store)
+    }
+
+    fn write_header(&mut self) -> Result<()> {
+        let mut header = [0; HEADER_SIZE as usize];
+        header[..8].copy_from_slice(NODES_MAGIC);
+        header[8..12].copy_from_slice(&self.depth.to_le_bytes());
+        let flags = if self.seq.is_some() { HAS_SEQ } else { 0 };
+        header[12..16].copy_from_slice(&flags.to_le_bytes());
+        header[16..24].copy_from_slice(&self.seq.unwrap_or(0).to_le_bytes());
+        self.nodes.seek(SeekFrom::Start(0))?;
+        self.nodes.write_all(&header)?;
+        Ok(())
+    }
+
+    fn read_header(&mut self) -> Result<()> {
+        let mut header = [0; HEADER_SIZE as usize];
+        self.nodes.seek(SeekFrom::Start(0))?;
+        self.nodes.read_exact(&mut header)?;
+        if &header[..8] != NODES_MAGIC {
+            return Err(PersistentTreeError::Corrupted("invalid node file header"));
+        }
+        let stored = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        if stored != self.depth {
+            return Err(PersistentTreeError::DepthMismatch {
+                stored,
+                expected: self.depth,
+            });
+        }
+        let flags = u32::from_le_bytes(header[12..16].try_into().unwrap());
+        let seq = u64::from_le_bytes(header[16..24].try_into().unwrap());
+        self.seq = if flags & HAS_SEQ != 0 {
+            Some(seq)
+        } else {
+            None
+        };
+        Ok(())
+    }
+
+    fn slot_offset(node_idx: u64) -> u64 {
+        HEADER_SIZE + node_idx * 32
+    }
+
+    /// Applies the batch left in the write-ahead log by a crash, if it was fully written
+    fn recover(&mut self) -> Result<()> {
+        let mut record = vec![];
+        self.wal.seek(SeekFrom::Start(0))?;
+        self.wal.read_to_end(&mut record)?;
+        if record.is_empty() {
+            return Ok(());
+        }
+        if let Some((seq, nodes)) = decode_wal_record(&record) {
+            self.apply(seq, &nodes)?;
+        }
+        self.clear_wal()
+    }
+
+    fn clear_wal(&mut self) -> Result<()> {
+        self.wal.set_len(0)?;
+        self.wal.sync_all()?;
+        Ok(())
+    }
+
+    /// Writes a batch to the node file. Writing the same batch twice has no further effect.
+    fn apply(&mut self, seq: u64, nodes: &[(u64, Option<Node>)]) -> Result<()> {
+        for (node_idx, node) in nodes.iter() {
+            self.nodes
+                .seek(SeekFrom::Start(Self::slot_offset(*node_idx)))?;
+            self.nodes.write_all(&node.unwrap_or(EMPTY))?;
+        }
+        self.seq = Some(seq);
+        self.write_header()?;
+        self.nodes.sync_all()?;
+        Ok(())
+    }
+}
+
+/// Serializes a batch, followed by the keccak hash of everything before it
+fn encode_wal_record(seq: u64, nodes: &[(u64, Option<Node>)]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(24 + nodes.len() * WAL_ENTRY_SIZE + 32);
+    record.extend_from_slice(WAL_MAGIC);
+    record.extend_from_slice(&seq.to_le_bytes());
+    record.extend_from_slice(&(nodes.len() as u64).to_le_bytes());
+    for (node_idx, node) in nodes.iter() {
+        record.extend_from_slice(&node_idx.to_le_bytes());
+        record.push(node.is_some() as u8);
+        record.extend_from_slice(&node.unwrap_or(EMPTY));
+    }
+    let checksum = LeafHashScheme::Keccak.hash_leaf(&record);
+    record.extend_from_slice(&checksum);
+    record
+}
+
+/// Returns `None` if the record was not fully written
+fn decode_wal_record(record: &[u8]) -> Option<(u64, Vec<NodeWrite>)> {
+    if record.len() < 24 + 32 || &record[..8] != WAL_MAGIC {
+        return None;
+    }
+    let (body, checksum) = record.split_at(record.len() - 32);
+    let len = u64::from_le_bytes(body[16..24].try_into().ok()?) as usize;
+    if body.len() != 24 + len.checked_mul(WAL_ENTRY_SIZE)?
+        || LeafHashScheme::Keccak.hash_leaf(body) != checksum
+    {
+        return None;
+    }
+    let seq = u64::from_le_bytes(body[8..16].try_into().ok()?);
+    let nodes = body[24..]
+        .chunks_exact(WAL_ENTRY_SIZE)
+        .map(|entry| {
+            let node_idx = u64::from_le_bytes(entry[..8].try_into().unwrap());
+            let node: Node = entry[9..].try_into().unwrap();
+            (node_idx, if entry[8] == 1 { Some(node) } else { None })
+        })
+        .collect();
+    Some((seq, nodes))
+}
+
+impl NodeStore for FileNodeStore {
+    fn depth(&self) -> u32 {
+        self.depth
+    }
+
+    fn seq(&self) -> Option<u64> {
+        self.seq
+    }
+
+    fn get(&self, node_idx: u64) -> Result<Option<Node>> {
+        let offset = Self::slot_offset(node_idx);
+        if offset + 32 > self.nodes.metadata()?.len() {
+            return Ok(None);
+        }
+        let mut node = EMPTY;
+        // Positioned reads don't move a shared cursor, so concurrent readers can't interleave
+        self.nodes.read_exact_at(&mut node, offset)?;
+        // Slots that were never written read as zeros
+        Ok(if node == EMPTY { None } else { Some(node) })
+    }
+
+    fn commit(&mut self, seq: u64, nodes: &[(u64, Option<Node>)]) -> Result<()> {
+        self.wal.seek(SeekFrom::Start(0))?;
+        self.wal.write_all(&encode_wal_record(seq, nodes))?;
+        self.wal.sync_all()?;
+        self.apply(seq, nodes)?;
+        self.clear_wal()
+    }
+}
+
+/// Merkle tree mirror backed by a [NodeStore].
+///
+/// Changes are applied in batches identified by increasing sequence numbers, like the
+/// `seq` of change log events, so that a batch replayed after a restart is skipped.
+pub struct PersistentMerkleTree<S: NodeStore, H: Hasher = Keccak> {
+    store: S,
+    /// Empty subtree of each level, from an empty leaf up to the root
+    empty_nodes: Vec<Node>,
+    _hasher: PhantomData<H>,
+}
+
+impl<S: NodeStore> PersistentMerkleTree<S> {
+    pub fn new(store: S) -> Self {
+        Self::with_hasher(store, Keccak)
+    }
+}
+
+impl<S: NodeStore, H: Hasher> PersistentMerkleTree<S, H> {
+    /// Same as `new`, but nodes are hashed with `H` instead of keccak
+    pub fn with_hasher(store: S, _hasher: H) -> Self {
+        let empty_nodes = (0..=store.depth())
+            .map(|level| empty_node::<H>(level))
+            .collect();
+        Self {
+            store,
+            empty_nodes,
+            _hasher: PhantomData,
+        }
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub fn into_store(self) -> S {
+        self.store
+    }
+
+    pub fn depth(&self) -> u32 {
+        self.store.depth()
+    }
+
+    /// Sequence number of the last applied batch
+    pub fn seq(&self) -> Option<u64> {
+        self.store.seq()
+    }
+
+    fn get_node_at(&self, node_idx: u64) -> Result<Node> {
+        let level = self.depth() - (63 - node_idx.leading_zeros());
+        Ok(self
+            .store
+            .get(node_idx)?
+            .unwrap_or(self.empty_nodes[level as usize]))
+    }
+
+    fn leaf_node_idx(&self, leaf_idx: usize) -> Result<u64> {
+        if leaf_idx as u64 >= 1 << self.depth() {
+            return Err(PersistentTreeError::LeafIndexOutOfBounds(leaf_idx));
+        }
+        Ok((1 << self.depth()) + leaf_idx as u64)
+    }
+
+    pub fn get_root(&self) -> Result<Node> {
+        self.get_node_at(1)
+    }
+
+    pub fn get_leaf(&self, leaf_idx: usize) -> Result<Node> {
+        self.get_node_at(self.leaf_node_idx(leaf_idx)?)
+    }
+
+    /// Returns the siblings of the path of the leaf at `idx`, from the leaf up
+    pub fn get_proof_of_leaf(&self, idx: usize) -> Result<Vec<Node>> {
+        let mut node_idx = self.leaf_node_idx(idx)?;
+        let mut proof = Vec::with_capacity(self.depth() as usize);
+        while node_idx > 1 {
+            proof.push(self.get_node_at(node_idx ^ 1)?);
+            node_idx >>= 1;
+        }
+        Ok(proof)
+    }
+
+    /// Sets the leaves in `leaves`, given as `(index, leaf)` pairs, as batch `seq`.
+    ///
+    /// Returns false without changing the tree if a batch with a sequence number of at least
+    /// `seq` was already applied, so that batches can be replayed after a restart.
+    pub fn apply_batch(&mut self, seq: u64, leaves: &[(usize, Node)]) -> Result<bool> {
+        if matches!(self.seq(), Some(last_seq) if seq <= last_seq) {
+            return Ok(false);
+        }
+        // Nodes written by this batch, which shadow the stored ones
+        let mut written: HashMap<u64, Node> = HashMap::new();
+        let get_node = |written: &HashMap<u64, Node>, node_idx: u64| match written.get(&node_idx) {
+            Some(node) => Ok(*node),
+            None => self.get_node_at(node_idx),
+        };
+        for (leaf_idx, leaf) in leaves.iter() {
+            let mut node_idx = self.leaf_node_idx(*leaf_idx)?;
+            let mut node = *leaf;
+            written.insert(node_idx, node);
+            while node_idx > 1 {
+                let sibling = get_node(&written, node_idx ^ 1)?;
+                node = if node_idx & 1 == 0 {
+                    H::hash_pair(&node, &sibling)
+                } else {
+                    H::hash_pair(&sibling, &node)
+                };
+                node_idx >>= 1;
+                written.insert(node_idx, node);
+            }
+        }
+        let mut nodes: Vec<(u64, Option<Node>)> = written
+            .into_iter()
+            .map(|(node_idx, node)| {
+                let level = self.depth() - (63 - node_idx.leading_zeros());
+                let is_empty = node == self.empty_nodes[level as usize];
+                (node_idx, if is_empty { None } else { Some(node) })
+            })
+            .collect();
+        nodes.sort_unstable_by_key(|(node_idx, _)| *node_idx);
+        self.store.commit(seq, &nodes)?;
+        Ok(true)
+    }
+}